@@ -0,0 +1,155 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::User,
+    db::{Database, Record},
+    util::{unix_millis, ApiError},
+};
+
+mod router;
+
+pub use router::router;
+
+lazy_static! {
+    static ref NAME_REGEX: Regex = Regex::new(r"^[a-z0-9][a-z0-9_-]{0,31}$").unwrap();
+}
+
+/// A short code that can be queued instead of a url, such as `@intro`.
+///
+/// Aliases are global, so the same jingles can be used in every room.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alias {
+    /// Name without the `@` prefix
+    pub name: String,
+    pub url: String,
+
+    /// Username of the superuser who created this
+    pub created_by: String,
+
+    /// Milliseconds since the unix epoch
+    pub created_at: u64,
+}
+
+impl Alias {
+    /// What has to be typed before the name to use an alias
+    pub const PREFIX: char = '@';
+
+    const MAX_URL_LENGTH: usize = 2000;
+
+    /// Creates an alias, replacing any alias with the same name
+    pub async fn create(
+        db: &Database,
+        name: String,
+        url: String,
+        user: &User,
+    ) -> Result<Self, ApiError> {
+        if !is_valid_name(&name) {
+            return Err(ApiError::Invalid("Alias name"));
+        }
+
+        let url = url.trim().to_string();
+
+        // Aliases pointing to other aliases are not resolved
+        let is_valid_url = !url.is_empty()
+            && url.len() <= Self::MAX_URL_LENGTH
+            && !url.starts_with(Self::PREFIX)
+            && !url.contains(char::is_whitespace);
+
+        if !is_valid_url {
+            return Err(ApiError::Invalid("Alias url"));
+        }
+
+        let alias = Self {
+            name,
+            url,
+            created_by: user.username.clone(),
+            created_at: unix_millis(),
+        };
+
+        db.query("UPDATE type::thing($tb, $id) CONTENT $alias")
+            .bind(("tb", "alias"))
+            .bind(("id", &alias.name))
+            .bind(("alias", &alias))
+            .await?
+            .check()?;
+
+        Ok(alias)
+    }
+
+    pub async fn all(db: &Database) -> Result<Vec<Self>, ApiError> {
+        let aliases = db
+            .query("SELECT * FROM alias ORDER BY name")
+            .await?
+            .take::<Vec<Self>>(0)?;
+
+        Ok(aliases)
+    }
+
+    pub async fn delete(db: &Database, name: &str) -> Result<(), ApiError> {
+        let deleted = db
+            .query("DELETE type::thing($tb, $id) RETURN BEFORE")
+            .bind(("tb", "alias"))
+            .bind(("id", name))
+            .await?
+            .take::<Vec<Record>>(0)?;
+
+        if deleted.is_empty() {
+            return Err(ApiError::NotFound("Alias"));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the url if the input is a known alias, otherwise the input as is,
+    /// so it can be parsed normally.
+    pub async fn expand(db: &Database, input: String) -> Result<String, ApiError> {
+        let Some(name) = input.trim().strip_prefix(Self::PREFIX) else {
+            return Ok(input);
+        };
+
+        if !is_valid_name(name) {
+            return Ok(input);
+        }
+
+        let alias = db
+            .query("SELECT * FROM type::thing($tb, $id)")
+            .bind(("tb", "alias"))
+            .bind(("id", name))
+            .await?
+            .take::<Option<Self>>(0)?;
+
+        Ok(alias.map(|a| a.url).unwrap_or(input))
+    }
+}
+
+/// Names are short, lowercase, and can contain dashes and underscores
+fn is_valid_name(name: &str) -> bool {
+    NAME_REGEX.is_match(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_valid_name;
+
+    #[test]
+    fn validates_names() {
+        for name in ["intro", "jingle-2", "station_id", "a"] {
+            assert!(is_valid_name(name), "{} should be valid", name);
+        }
+
+        for name in [
+            "",
+            "@intro",
+            "Intro",
+            "-intro",
+            "in tro",
+            "https://",
+            &"a".repeat(33),
+        ] {
+            assert!(!is_valid_name(name), "{} should be invalid", name);
+        }
+    }
+}
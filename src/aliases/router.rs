@@ -0,0 +1,54 @@
+use axum::{
+    extract::{Path, State},
+    routing::{delete, get, put},
+    Json,
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    auth::{Session, Superuser},
+    server::{Context, Router},
+    util::ApiError,
+};
+
+use super::Alias;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", get(get_aliases))
+        .route("/:name", put(set_alias))
+        .route("/:name", delete(remove_alias))
+}
+
+async fn get_aliases(_: Session, State(context): Context) -> Result<Json<Vec<Alias>>, ApiError> {
+    let aliases = Alias::all(&context.db).await?;
+
+    Ok(Json(aliases))
+}
+
+#[derive(Deserialize)]
+struct AliasBody {
+    url: String,
+}
+
+async fn set_alias(
+    Superuser(session): Superuser,
+    State(context): Context,
+    Path(name): Path<String>,
+    Json(body): Json<AliasBody>,
+) -> Result<Json<Alias>, ApiError> {
+    let alias = Alias::create(&context.db, name, body.url, &session.user).await?;
+
+    Ok(Json(alias))
+}
+
+async fn remove_alias(
+    _: Superuser,
+    State(context): Context,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    Alias::delete(&context.db, &name).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,13 +1,48 @@
 use std::fmt::Debug;
 
-use super::{new::StreamConsumer, Sample, SAMPLE_IN_BYTES};
-use std::io::Read;
+use super::{
+    new::StreamConsumer,
+    output::{output_format, Converter, OutputFormat},
+    Sample,
+};
+use std::{
+    io::{Read, Write},
+    ops::RangeInclusive,
+    process::{Child, ChildStdout, Command, Stdio},
+    thread,
+};
 
-/// Implements streaming a .wav file
+/// How audio is encoded for a listener
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Uncompressed, see [WaveStream]
+    #[default]
+    Wave,
+    /// Compressed, for listeners with limited bandwidth, see [OpusStream]
+    Opus,
+}
+
+/// A stream of audio in one of the supported encodings
+#[derive(Debug)]
+pub enum EncodedStream {
+    Wave(WaveStream),
+    Opus(OpusStream),
+}
+
+/// Implements streaming Ogg/Opus, by piping the samples of a [WaveStream] through ffmpeg
+pub struct OpusStream {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+/// Implements streaming a .wav file, in the configured [OutputFormat]
 pub struct WaveStream {
     underlying: StreamConsumer,
     did_write_header: bool,
     header: WaveHeader,
+    converter: Converter,
+    /// Converted bytes that did not fit in the last read
+    pending: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -21,26 +56,187 @@ struct WaveHeader {
     channel_count: u16,
     sample_rate: u32,
     bit_depth: u16,
+
+    /// Length of the data in bytes, unknown for live streams
+    data_length: Option<u32>,
 }
 
 impl WaveStream {
     pub const MIME: &'static str = "audio/wav";
+    pub const EXTENSION: &'static str = "wav";
 
     pub fn new(underlying: StreamConsumer) -> Self {
-        let header = WaveHeader {
-            channel_count: super::CHANNEL_COUNT as u16,
-            sample_rate: super::SAMPLE_RATE as u32,
-            bit_depth: 16,
-        };
+        let format = output_format();
 
         Self {
-            header,
+            header: WaveHeader::new(format, None),
             underlying,
             did_write_header: false,
+            converter: Converter::new(format),
+            pending: vec![],
+        }
+    }
+
+    /// Creates a stream of only the samples, for transports that describe the format themselves
+    pub fn headerless(underlying: StreamConsumer) -> Self {
+        Self {
+            did_write_header: true,
+            ..Self::new(underlying)
+        }
+    }
+
+    /// Encodes samples as a standalone .wav file, with a real length unlike the stream
+    pub fn encode(samples: &[Sample]) -> Vec<u8> {
+        Self::encode_as(samples, output_format())
+    }
+
+    fn encode_as(samples: &[Sample], format: OutputFormat) -> Vec<u8> {
+        let data = samples_to_bytes(&Converter::new(format).convert(samples));
+        let mut result = WaveHeader::new(format, Some(data.len() as u32)).to_bytes();
+
+        result.extend(data);
+        result
+    }
+}
+
+impl Encoding {
+    /// Picks an encoding from an `Accept` header, falling back to [Encoding::Wave]
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        let accepts_opus = accept
+            .into_iter()
+            .flat_map(|a| a.split(','))
+            .map(|t| t.split(';').next().unwrap_or_default().trim())
+            .any(|t| t == OpusStream::MIME || t == "audio/opus");
+
+        if accepts_opus {
+            Self::Opus
+        } else {
+            Self::Wave
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Wave => WaveStream::MIME,
+            Self::Opus => OpusStream::MIME,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Wave => WaveStream::EXTENSION,
+            Self::Opus => OpusStream::EXTENSION,
         }
     }
 }
 
+impl EncodedStream {
+    /// Creates a stream in `encoding`, where `bit_rate` in kbps only applies to compressed ones
+    pub fn new(
+        underlying: StreamConsumer,
+        encoding: Encoding,
+        bit_rate: u32,
+    ) -> std::io::Result<Self> {
+        match encoding {
+            Encoding::Wave => Ok(Self::Wave(WaveStream::new(underlying))),
+            Encoding::Opus => OpusStream::new(underlying, bit_rate).map(Self::Opus),
+        }
+    }
+}
+
+impl Read for EncodedStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Wave(x) => x.read(buf),
+            Self::Opus(x) => x.read(buf),
+        }
+    }
+}
+
+impl OpusStream {
+    pub const MIME: &'static str = "audio/ogg";
+    pub const EXTENSION: &'static str = "ogg";
+
+    /// The bit rate in kbps, unless the listener or the room asks for another
+    pub const DEFAULT_BIT_RATE: u32 = 96;
+
+    /// The bit rates libopus supports in kbps
+    pub const BIT_RATES: RangeInclusive<u32> = 6..=510;
+
+    /// Ogg pages are flushed this often in microseconds, instead of every second by default
+    const PAGE_DURATION: &'static str = "100000";
+
+    /// Spawns ffmpeg and a thread feeding it samples, which ends once ffmpeg exits
+    pub fn new(underlying: StreamConsumer, bit_rate: u32) -> std::io::Result<Self> {
+        let format = output_format();
+
+        let mut child = Command::new("ffmpeg")
+            .arg("-hide_banner")
+            .args(["-loglevel", "error"])
+            .args(["-f", "s16le"])
+            .args(["-ar", &format.sample_rate.to_string()])
+            .args(["-ac", &format.channels.to_string()])
+            .args(["-i", "pipe:"])
+            .args(["-c:a", "libopus", "-b:a", &format!("{}k", bit_rate)])
+            .args(["-page_duration", Self::PAGE_DURATION])
+            .args(["-flush_packets", "1"])
+            .args(["-f", "ogg", "pipe:"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let mut samples = WaveStream::headerless(underlying);
+
+        thread::Builder::new()
+            .name("opus_encoding".to_string())
+            .spawn(move || {
+                let mut buf = vec![0; 4096];
+
+                loop {
+                    let bytes_read = samples.read(&mut buf).unwrap_or_default();
+
+                    // Nothing is read once the stream has ended
+                    if bytes_read == 0 || stdin.write_all(&buf[..bytes_read]).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(Self { child, stdout })
+    }
+}
+
+impl Debug for OpusStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OpusStream")
+    }
+}
+
+impl Read for OpusStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Drop for OpusStream {
+    fn drop(&mut self) {
+        // The feeding thread stops once ffmpeg is gone
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn samples_to_bytes(samples: &[Sample]) -> Vec<u8> {
+    samples
+        .iter()
+        .map(|s| (s * i16::MAX as Sample) as i16)
+        .flat_map(|s| s.to_le_bytes())
+        .collect()
+}
+
 impl Debug for WaveStream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "WaveStream")
@@ -63,17 +259,27 @@ impl Read for WaveStream {
 
         let body_buf = &mut buf[bytes_written..];
 
-        let mut samples = vec![0f32; body_buf.len() / (SAMPLE_IN_BYTES / 2)];
-        let amount_of_samples = self.underlying.read(&mut samples);
+        // Converting may give a few frames less than asked for, so this reads until there is enough
+        while self.pending.len() < body_buf.len() {
+            let missing = body_buf.len() - self.pending.len();
+            let frames = missing.div_ceil(self.header.channel_count as usize * 2);
 
-        let samples_in_bytes: Vec<_> = samples[..amount_of_samples]
-            .iter()
-            .map(|s| (s * i16::MAX as Sample) as i16)
-            .flat_map(|s| s.to_le_bytes())
-            .collect();
+            let mut samples = vec![0.; self.converter.samples_for(frames)];
+            let amount_of_samples = self.underlying.read(&mut samples);
 
-        body_buf[..samples_in_bytes.len()].copy_from_slice(&samples_in_bytes);
-        bytes_written += samples_in_bytes.len();
+            if amount_of_samples == 0 {
+                break;
+            }
+
+            let converted = self.converter.convert(&samples[..amount_of_samples]);
+            self.pending.extend(samples_to_bytes(&converted));
+        }
+
+        let length = self.pending.len().min(body_buf.len());
+
+        body_buf[..length].copy_from_slice(&self.pending[..length]);
+        self.pending.drain(..length);
+        bytes_written += length;
 
         Ok(bytes_written)
     }
@@ -93,9 +299,6 @@ impl WaveHeader {
     // ChunkID: Contains the letters "RIFF" in ASCII form, change last number to 80 if "RIFX" is used
     const CHUNK_ID: HeaderValue = HeaderValue::Ascii("RIFF");
 
-    // This is set to max because vinyl is a live audio stream
-    const CHUNK_SIZE: HeaderValue = HeaderValue::FourBytes(i32::MAX as u32);
-
     // Format: Contains the letters "WAVE"
     const FORMAT: HeaderValue = HeaderValue::Ascii("WAVE");
 
@@ -111,7 +314,27 @@ impl WaveHeader {
     // Subchunk2ID: Contains the letters "data"
     const DATA_CHUNK_ID: HeaderValue = HeaderValue::Ascii("data");
 
+    // Size of everything in the header after the chunk size
+    const HEADER_REMAINDER: u32 = 36;
+
+    fn new(format: OutputFormat, data_length: Option<u32>) -> Self {
+        Self {
+            channel_count: format.channels as u16,
+            sample_rate: format.sample_rate as u32,
+            bit_depth: 16,
+            data_length,
+        }
+    }
+
     fn to_bytes(&self) -> Vec<u8> {
+        // This is set to max when vinyl is streaming live audio
+        let data_length = self.data_length.unwrap_or(i32::MAX as u32);
+
+        let chunk_size = HeaderValue::FourBytes(match self.data_length {
+            Some(length) => Self::HEADER_REMAINDER + length,
+            None => i32::MAX as u32,
+        });
+
         let num_channels = HeaderValue::TwoBytes(self.channel_count);
         let sample_rate = HeaderValue::FourBytes(self.sample_rate);
 
@@ -122,12 +345,11 @@ impl WaveHeader {
         let block_align = HeaderValue::TwoBytes(self.channel_count * self.bit_depth / 8);
         let bits_per_sample = HeaderValue::TwoBytes(self.bit_depth);
 
-        // This is set to max because vinyl is a live audio stream
-        let data_chunk_size = HeaderValue::FourBytes(i32::MAX as u32);
+        let data_chunk_size = HeaderValue::FourBytes(data_length);
 
         [
             Self::CHUNK_ID,
-            Self::CHUNK_SIZE,
+            chunk_size,
             Self::FORMAT,
             Self::FMT_CHUNK_ID,
             Self::FMT_CHUNK_SIZE,
@@ -145,3 +367,62 @@ impl WaveHeader {
         .collect()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Encoding, OutputFormat, WaveStream};
+
+    #[test]
+    fn picks_encoding_from_accept() {
+        assert_eq!(Encoding::from_accept(None), Encoding::Wave);
+        assert_eq!(Encoding::from_accept(Some("*/*")), Encoding::Wave);
+        assert_eq!(Encoding::from_accept(Some("audio/wav")), Encoding::Wave);
+        assert_eq!(
+            Encoding::from_accept(Some("audio/wav;q=0.5, audio/ogg")),
+            Encoding::Opus
+        );
+        assert_eq!(
+            Encoding::from_accept(Some("audio/opus; codecs=opus")),
+            Encoding::Opus
+        );
+    }
+
+    #[test]
+    fn encodes_standalone_wave() {
+        let samples = vec![0.5; 100];
+        let bytes = WaveStream::encode(&samples);
+
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(read_u32(4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(read_u32(40), 200);
+        assert_eq!(bytes.len(), 44 + 200);
+    }
+
+    #[test]
+    fn encodes_output_format() {
+        let format = OutputFormat {
+            sample_rate: 22050,
+            channels: 1,
+        };
+
+        let samples = vec![0.5; 400];
+        let bytes = WaveStream::encode_as(&samples, format);
+
+        let read_u16 =
+            |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        assert_eq!(read_u16(22), 1);
+        assert_eq!(read_u32(24), 22050);
+        assert_eq!(read_u32(28), 22050 * 2);
+        assert_eq!(read_u16(32), 2);
+
+        // Half of the 200 frames
+        assert_eq!(read_u32(40), 100 * 2);
+    }
+}
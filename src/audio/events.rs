@@ -9,6 +9,12 @@ use super::PlayerId;
 pub enum AudioEvent {
     /// The player is now playing a new sink
     Next { player: PlayerId },
+    /// A voice track finished playing on top of the music
+    VoiceEnded { player: PlayerId },
+    /// The player was paused or resumed
+    PlaybackStateChanged { player: PlayerId, paused: bool },
+    /// Playback moved to `offset` in the current sink, see [Player::seek](super::Player::seek)
+    Seeked { player: PlayerId, offset: usize },
     /// The player advanced ahead
     Time {
         player: PlayerId,
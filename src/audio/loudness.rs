@@ -0,0 +1,266 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use super::{Sample, CHANNEL_COUNT, SAMPLE_RATE};
+
+/// Measures the integrated loudness of a track as described by EBU R128,
+/// so tracks from different sources can be played at the same perceived loudness.
+///
+/// Samples are K-weighted, split into 400ms blocks overlapping by 75%,
+/// and blocks that are silent or much quieter than the rest are gated out.
+#[derive(Debug)]
+pub struct LoudnessMeter {
+    filters: [KWeighting; CHANNEL_COUNT],
+
+    /// Frames left before the current step is complete
+    remaining: usize,
+    /// Sum of the weighted squares of the current step
+    current: f64,
+    /// Mean squares of the last steps, making up a block
+    steps: VecDeque<f64>,
+    /// Mean square of every block that is not silent
+    blocks: Vec<f64>,
+    /// Highest absolute sample, used to avoid clipping when boosting
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    /// The loudness every track is brought to, in LUFS, matching ReplayGain 2.0
+    pub const TARGET: f32 = -18.;
+    const MAX_BOOST: f32 = 12.;
+    const MAX_CUT: f32 = 24.;
+
+    /// Frames in a step, which is how far blocks overlap
+    const STEP: usize = SAMPLE_RATE / 10;
+    const STEPS_PER_BLOCK: usize = 4;
+
+    const ABSOLUTE_GATE: f64 = -70.;
+    const RELATIVE_GATE: f64 = -10.;
+
+    pub fn new() -> Self {
+        Self {
+            filters: [KWeighting::new(), KWeighting::new()],
+            remaining: Self::STEP,
+            current: 0.,
+            steps: VecDeque::with_capacity(Self::STEPS_PER_BLOCK),
+            blocks: vec![],
+            peak: 0.,
+        }
+    }
+
+    /// Measures a chunk of interleaved samples
+    pub fn process(&mut self, samples: &[Sample]) {
+        for frame in samples.chunks_exact(CHANNEL_COUNT) {
+            for (filter, sample) in self.filters.iter_mut().zip(frame) {
+                let weighted = filter.process(*sample as f64);
+
+                self.current += weighted * weighted;
+                self.peak = self.peak.max(sample.abs());
+            }
+
+            self.remaining -= 1;
+
+            if self.remaining == 0 {
+                self.end_step();
+            }
+        }
+    }
+
+    /// Returns the integrated loudness in LUFS,
+    /// or [None] if nothing audible was measured yet
+    pub fn integrated(&self) -> Option<f32> {
+        let mean = |blocks: &mut dyn Iterator<Item = &f64>| {
+            let (sum, count) = blocks.fold((0., 0), |(sum, count), x| (sum + x, count + 1));
+            (count > 0).then(|| sum / count as f64)
+        };
+
+        let ungated = mean(&mut self.blocks.iter())?;
+        let threshold = from_loudness(to_loudness(ungated) + Self::RELATIVE_GATE);
+
+        mean(&mut self.blocks.iter().filter(|b| **b > threshold)).map(|x| to_loudness(x) as f32)
+    }
+
+    /// Returns the gain in dB that brings the track to [LoudnessMeter::TARGET],
+    /// limited so its loudest peak does not clip
+    pub fn gain(&self) -> Option<f32> {
+        let loudness = self.integrated()?;
+        let headroom = -20. * self.peak.max(f32::EPSILON).log10();
+
+        Some(
+            (Self::TARGET - loudness)
+                .clamp(-Self::MAX_CUT, Self::MAX_BOOST)
+                .min(headroom),
+        )
+    }
+
+    fn end_step(&mut self) {
+        if self.steps.len() == Self::STEPS_PER_BLOCK {
+            self.steps.pop_front();
+        }
+
+        self.steps.push_back(self.current / Self::STEP as f64);
+        self.current = 0.;
+        self.remaining = Self::STEP;
+
+        if self.steps.len() < Self::STEPS_PER_BLOCK {
+            return;
+        }
+
+        // Channels are summed, so this is the block power
+        let block = self.steps.iter().sum::<f64>() / Self::STEPS_PER_BLOCK as f64;
+
+        if to_loudness(block) > Self::ABSOLUTE_GATE {
+            self.blocks.push(block);
+        }
+    }
+}
+
+/// Scales samples by a gain in dB
+pub fn apply_gain(samples: &mut [Sample], gain: f32) {
+    let factor = 10_f32.powf(gain / 20.);
+
+    for sample in samples.iter_mut() {
+        *sample *= factor;
+    }
+}
+
+fn to_loudness(power: f64) -> f64 {
+    -0.691 + 10. * power.max(f64::MIN_POSITIVE).log10()
+}
+
+fn from_loudness(loudness: f64) -> f64 {
+    10_f64.powf((loudness + 0.691) / 10.)
+}
+
+/// The two filters of ITU-R BS.1770, approximating how loud frequencies are perceived
+#[derive(Debug)]
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new() -> Self {
+        let rate = SAMPLE_RATE as f64;
+
+        Self {
+            shelf: Biquad::high_shelf(rate, 1681.974450955533, 0.7071752369554196, 3.99984385397),
+            high_pass: Biquad::high_pass(rate, 38.13547087602444, 0.5003270373238773),
+        }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
+
+#[derive(Debug)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn high_shelf(rate: f64, frequency: f64, q: f64, gain: f64) -> Self {
+        let a = 10_f64.powf(gain / 40.);
+        let w0 = 2. * PI * frequency / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q);
+        let root = 2. * a.sqrt() * alpha;
+
+        Self::normalized(
+            [
+                a * ((a + 1.) + (a - 1.) * cos + root),
+                -2. * a * ((a - 1.) + (a + 1.) * cos),
+                a * ((a + 1.) + (a - 1.) * cos - root),
+            ],
+            [
+                (a + 1.) - (a - 1.) * cos + root,
+                2. * ((a - 1.) - (a + 1.) * cos),
+                (a + 1.) - (a - 1.) * cos - root,
+            ],
+        )
+    }
+
+    fn high_pass(rate: f64, frequency: f64, q: f64) -> Self {
+        let w0 = 2. * PI * frequency / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q);
+
+        Self::normalized(
+            [(1. + cos) / 2., -(1. + cos), (1. + cos) / 2.],
+            [1. + alpha, -2. * cos, 1. - alpha],
+        )
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|x| x / a[0]),
+            a: [a[1] / a[0], a[2] / a[0]],
+            state: [0., 0.],
+        }
+    }
+
+    /// Transposed direct form II
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+
+        self.state[0] = self.b[1] * input - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[1] * output;
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LoudnessMeter;
+    use crate::audio::{Sample, SAMPLE_RATE};
+
+    fn sine(amplitude: f32, seconds: usize) -> Vec<Sample> {
+        (0..SAMPLE_RATE * seconds)
+            .flat_map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let sample = amplitude * (2. * std::f32::consts::PI * 1000. * t).sin();
+
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn measures_integrated_loudness() {
+        // A 1kHz sine at -20 dBFS on both channels is about -20 LUFS
+        let mut meter = LoudnessMeter::new();
+        meter.process(&sine(0.1, 5));
+
+        let loudness = meter.integrated().unwrap();
+        assert!((loudness + 20.).abs() < 0.5, "{loudness}");
+
+        let gain = meter.gain().unwrap();
+        assert!((gain - 2.).abs() < 0.5, "{gain}");
+    }
+
+    #[test]
+    fn gain_does_not_clip() {
+        let mut meter = LoudnessMeter::new();
+
+        // A single loud peak in a quiet track
+        let mut samples = sine(0.01, 5);
+        samples[1000] = 0.9;
+
+        meter.process(&samples);
+
+        let gain = meter.gain().unwrap();
+        assert!(0.9 * 10_f32.powf(gain / 20.) <= 1.);
+    }
+
+    #[test]
+    fn ignores_silence() {
+        let mut meter = LoudnessMeter::new();
+        meter.process(&vec![0.; SAMPLE_RATE * 4]);
+
+        assert!(meter.integrated().is_none());
+        assert!(meter.gain().is_none());
+    }
+}
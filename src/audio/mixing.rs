@@ -0,0 +1,289 @@
+use std::{collections::VecDeque, env, f32::consts::FRAC_PI_2, fmt::Debug, time::Duration};
+
+use lazy_static::lazy_static;
+
+use crate::ingest::{Sink, SinkId};
+
+use super::{Sample, CHANNEL_COUNT, SAMPLES_PER_SEC, SAMPLE_RATE};
+
+lazy_static! {
+    static ref DUCKING_CONFIG: DuckingConfig = DuckingConfig::from_env();
+}
+
+/// Reads and validates the ducking config, so mistakes are caught on startup
+pub fn init_ducking_config() -> &'static impl Debug {
+    &*DUCKING_CONFIG
+}
+
+/// Controls how music is lowered under voice tracks
+#[derive(Debug, Clone, Copy)]
+struct DuckingConfig {
+    /// How much quieter the music gets in dB, set with `VINYL_DUCK_AMOUNT_DB`
+    amount: f32,
+    /// How long the music takes to go down and back up, set with `VINYL_DUCK_FADE_MS`
+    fade: Duration,
+}
+
+impl DuckingConfig {
+    const DEFAULT_AMOUNT: f32 = 12.;
+    const DEFAULT_FADE: Duration = Duration::from_millis(500);
+    const MAX_FADE: Duration = Duration::from_secs(5);
+
+    fn from_env() -> Self {
+        let amount = env::var("VINYL_DUCK_AMOUNT_DB")
+            .map(|x| x.parse::<f32>().expect("Duck amount must be a number"))
+            .unwrap_or(Self::DEFAULT_AMOUNT);
+
+        let fade = env::var("VINYL_DUCK_FADE_MS")
+            .map(|x| x.parse::<u64>().expect("Duck fade must be a number"))
+            .map(Duration::from_millis)
+            .unwrap_or(Self::DEFAULT_FADE);
+
+        assert!(
+            (0. ..=60.).contains(&amount),
+            "Duck amount must be between 0 and 60 dB"
+        );
+
+        assert!(
+            fade <= Self::MAX_FADE,
+            "Duck fade must be at most {}ms",
+            Self::MAX_FADE.as_millis()
+        );
+
+        Self { amount, fade }
+    }
+
+    /// The gain music is lowered to, as a factor
+    fn ducked_gain(&self) -> f32 {
+        10_f32.powf(-self.amount / 20.)
+    }
+
+    /// How much the gain changes per frame while fading
+    fn gain_step(&self) -> f32 {
+        let frames = self.fade.as_secs_f32() * SAMPLE_RATE as f32;
+
+        if frames < 1. {
+            1.
+        } else {
+            (1. - self.ducked_gain()) / frames
+        }
+    }
+}
+
+/// Plays voice tracks on top of the music, lowering the music while they play.
+///
+/// When a voice track is added, the music fades down first, then the voice starts.
+/// Voice tracks play one after another, and when the last one ends,
+/// the music fades back up to full volume.
+#[derive(Debug)]
+pub struct Ducker {
+    config: DuckingConfig,
+
+    voices: VecDeque<Sink>,
+    /// Offset in the voice currently playing
+    offset: usize,
+    /// The gain of the music, as a factor
+    gain: f32,
+}
+
+impl Ducker {
+    /// How much of a voice track has to be loaded before it starts,
+    /// so it does not cut out while loading
+    const START_THRESHOLD: usize = SAMPLES_PER_SEC * 2;
+
+    pub fn new() -> Self {
+        Self::with_config(*DUCKING_CONFIG)
+    }
+
+    fn with_config(config: DuckingConfig) -> Self {
+        Self {
+            config,
+            voices: Default::default(),
+            offset: 0,
+            gain: 1.,
+        }
+    }
+
+    pub fn push(&mut self, voice: Sink) {
+        self.voices.push_back(voice);
+    }
+
+    /// Returns the voice to preload, if any
+    pub fn preload(&self) -> Option<SinkId> {
+        self.voices
+            .iter()
+            .find(|s| !s.is_complete())
+            .filter(|s| !s.is_pending())
+            .map(|s| s.id())
+    }
+
+    /// Lowers the music in a chunk of interleaved samples and mixes in the voice,
+    /// returning true if a voice track ended
+    pub fn process(&mut self, buf: &mut [Sample]) -> bool {
+        if self.voices.is_empty() && self.gain >= 1. {
+            return false;
+        }
+
+        let ducked_gain = self.config.ducked_gain();
+        let step = self.config.gain_step();
+
+        let mut voice = vec![0.; buf.len()];
+        let mut ended = false;
+
+        // The voice starts once the music is fully down
+        let voice_read = match self.voices.front() {
+            Some(sink) if self.gain <= ducked_gain && self.can_start(sink) => {
+                let read = sink.read(self.offset, &mut voice);
+                self.offset += read;
+
+                if sink.is_complete() && self.offset >= sink.available() {
+                    sink.consume();
+
+                    self.voices.pop_front();
+                    self.offset = 0;
+                    ended = true;
+                }
+
+                read
+            }
+            _ => 0,
+        };
+
+        let target = if self.voices.is_empty() && voice_read == 0 {
+            1.
+        } else {
+            ducked_gain
+        };
+
+        for (frame, voice_frame) in buf
+            .chunks_mut(CHANNEL_COUNT)
+            .zip(voice.chunks(CHANNEL_COUNT))
+        {
+            self.gain = if self.gain > target {
+                (self.gain - step).max(target)
+            } else {
+                (self.gain + step).min(target)
+            };
+
+            for (sample, voice_sample) in frame.iter_mut().zip(voice_frame) {
+                *sample = (*sample * self.gain + voice_sample).clamp(-1., 1.);
+            }
+        }
+
+        ended
+    }
+
+    fn can_start(&self, sink: &Sink) -> bool {
+        // Once started, it keeps playing even if loading falls behind
+        self.offset > 0 || sink.is_complete() || sink.available() >= Self::START_THRESHOLD
+    }
+}
+
+/// Scales samples by `volume`, which is clamped between 0 and 1
+pub fn apply_volume(samples: &mut [Sample], volume: f32) {
+    let volume = volume.clamp(0., 1.);
+
+    if volume == 1. {
+        return;
+    }
+
+    for sample in samples.iter_mut() {
+        *sample *= volume;
+    }
+}
+
+/// Fades `outgoing` out while fading `incoming` in, with an equal power curve so loudness stays even.
+/// `position` is where the samples are within a fade of `length` samples.
+pub fn crossfade(outgoing: &mut [Sample], incoming: &[Sample], position: usize, length: usize) {
+    for (i, (sample, incoming)) in outgoing.iter_mut().zip(incoming).enumerate() {
+        // Both channels of a frame are faded the same
+        let frame = position + i - (position + i) % CHANNEL_COUNT;
+        let angle = (frame as f32 / length as f32).min(1.) * FRAC_PI_2;
+
+        *sample = *sample * angle.cos() + incoming * angle.sin();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{apply_volume, crossfade, Ducker, DuckingConfig};
+    use crate::{
+        audio::SAMPLES_PER_SEC,
+        ingest::{InternalSink, SinkLength},
+    };
+
+    #[test]
+    fn ducks_under_voice_and_restores() {
+        let config = DuckingConfig {
+            amount: 20.,
+            fade: Duration::from_millis(100),
+        };
+
+        let mut ducker = Ducker::with_config(config);
+
+        let voice = Arc::new(InternalSink::new(SinkLength::Unknown));
+        voice.write(&vec![0.25; SAMPLES_PER_SEC / 2]);
+        voice.seal();
+
+        ducker.push(voice.clone());
+
+        // The music fades down before the voice starts
+        let mut buf = vec![1.; SAMPLES_PER_SEC / 10];
+        ducker.process(&mut buf);
+
+        assert!((buf.last().unwrap() - config.ducked_gain()).abs() < 0.01);
+        assert!(buf.iter().all(|s| *s <= 1.));
+
+        // The voice plays on top of the ducked music
+        let mut buf = vec![1.; SAMPLES_PER_SEC / 10];
+        ducker.process(&mut buf);
+
+        assert!((buf[0] - (config.ducked_gain() + 0.25)).abs() < 0.01);
+
+        // After the voice ends, the music comes back up
+        let ended = (0..10)
+            .filter(|_| ducker.process(&mut vec![1.; SAMPLES_PER_SEC / 10]))
+            .count();
+
+        assert_eq!(ended, 1);
+        assert!(voice.is_consumed());
+        assert_eq!(ducker.gain, 1.);
+    }
+
+    #[test]
+    fn crossfades_evenly() {
+        let mut outgoing = vec![1.; 8];
+        crossfade(&mut outgoing, &[1.; 8], 0, 8);
+
+        // Both channels of a frame are faded the same
+        assert!(outgoing.chunks(2).all(|frame| frame[0] == frame[1]));
+
+        assert_eq!(outgoing[0], 1.);
+        assert!((outgoing[4] - 2f32.sqrt()).abs() < 0.01);
+
+        // Without anything to fade into, it fades to silence
+        let mut outgoing = vec![1.; 4];
+        crossfade(&mut outgoing, &[0.; 4], 4, 8);
+
+        assert!(outgoing[0] < 1.);
+        assert!(outgoing[2] < outgoing[0]);
+    }
+
+    #[test]
+    fn attenuates_by_volume() {
+        let mut buf = vec![0.8, -0.5, 0.];
+        apply_volume(&mut buf, 0.25);
+
+        assert_eq!(buf, vec![0.2, -0.125, 0.]);
+
+        // Out of range volumes are clamped
+        let mut buf = vec![0.5];
+        apply_volume(&mut buf, 2.);
+        assert_eq!(buf, vec![0.5]);
+
+        apply_volume(&mut buf, -1.);
+        assert_eq!(buf, vec![0.]);
+    }
+}
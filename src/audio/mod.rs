@@ -1,6 +1,10 @@
 mod decoding;
 mod encoding;
 mod events;
+mod loudness;
+mod mixing;
+mod normalization;
+mod output;
 mod playback;
 mod processing;
 mod source;
@@ -13,6 +17,10 @@ pub use decoding::raw_samples_from_bytes;
 pub use encoding::*;
 pub use events::*;
 pub use ingest::Input;
+pub use loudness::LoudnessMeter;
+pub use mixing::init_ducking_config;
+pub use normalization::init_normalization_config;
+pub use output::{init_output_format, output_format};
 pub use playback::*;
 pub use timeline::*;
 pub use track::Track;
@@ -42,9 +50,10 @@ pub use config::*;
 pub mod new {
 
     use std::sync::Weak;
-    use std::time::Duration;
+    use std::time::{Duration, SystemTime};
     use std::{fmt::Debug, sync::Arc};
 
+    use crossbeam::atomic::AtomicCell;
     use parking_lot::{Mutex, RwLock};
     use ringbuf::{Consumer, Producer, RingBuffer};
 
@@ -55,71 +64,160 @@ pub mod new {
     type StreamConsumerId = u64;
 
     /// Represents a stream of audio that can be consumed from multiple places.
+    ///
+    /// Every consumer has its own bounded buffer, so writing never waits for a consumer.
+    /// If a consumer reads slower than real-time and its buffer overflows, it skips ahead
+    /// to the delay it was created with the next time it reads, see [StreamConsumer::read].
     pub struct Stream {
         me: Weak<Stream>,
-        entries: Mutex<Vec<(StreamConsumerId, Producer<Sample>)>>,
+        entries: Mutex<Vec<StreamEntry>>,
 
         /// Preloaded samples a consumer will be filled with
         preloaded: RwLock<Vec<Sample>>,
+
+        /// How many samples to keep in the preload
+        history: AtomicCell<usize>,
+
+        /// When the last written sample will have played
+        written_until: Mutex<SystemTime>,
+
+        /// Consumers stop waiting for samples once this is set, see [Stream::close]
+        closed: AtomicCell<bool>,
+    }
+
+    struct StreamEntry {
+        id: StreamConsumerId,
+        producer: Producer<Sample>,
+        /// Set when samples did not fit in the buffer of the consumer
+        lagging: Arc<AtomicCell<bool>>,
     }
 
     impl Stream {
         const PRELOAD_BUFFER_SIZE: usize = SAMPLES_PER_SEC;
 
+        /// Room on top of the delay of a consumer for samples written between its reads,
+        /// so scheduling jitter is not mistaken for falling behind
+        const HEADROOM: usize = SAMPLES_PER_SEC;
+
         pub fn new() -> Arc<Self> {
             Arc::new_cyclic(|me| Stream {
                 me: me.clone(),
                 entries: Default::default(),
                 preloaded: Default::default(),
+                history: Self::PRELOAD_BUFFER_SIZE.into(),
+                written_until: SystemTime::now().into(),
+                closed: false.into(),
             })
         }
 
         /// Create a new consumer preloaded with samples
         pub fn consumer(&self) -> StreamConsumer {
-            let buffer = RingBuffer::new(SAMPLES_PER_SEC);
+            self.delayed_consumer(Self::PRELOAD_BUFFER_SIZE).0
+        }
+
+        /// Create a new consumer that is behind the stream by `delay` samples,
+        /// as long as enough history is kept.
+        ///
+        /// Returns the consumer and when the first sample it reads was live.
+        pub fn delayed_consumer(&self, delay: usize) -> (StreamConsumer, SystemTime) {
+            let buffer = RingBuffer::new(delay + Self::HEADROOM);
 
             let (mut producer, consumer) = buffer.split();
 
             let preloaded = self.preloaded.read();
-            producer.push_slice(&preloaded);
+            let delayed = &preloaded[preloaded.len().saturating_sub(delay)..];
+            producer.push_slice(delayed);
+
+            let live_at = *self.written_until.lock() - samples_to_duration(delayed.len());
 
             let stream_consumer = StreamConsumer {
                 id: ID_COUNTER.fetch_add(1),
                 stream: self.me.clone(),
                 underlying: consumer,
+                lagging: Default::default(),
+                delay,
             };
 
-            self.entries.lock().push((stream_consumer.id, producer));
-            stream_consumer
+            self.entries.lock().push(StreamEntry {
+                id: stream_consumer.id,
+                producer,
+                lagging: stream_consumer.lagging.clone(),
+            });
+            (stream_consumer, live_at)
+        }
+
+        /// Returns up to `amount` of the most recent samples
+        pub fn snapshot(&self, amount: usize) -> Vec<Sample> {
+            let preloaded = self.preloaded.read();
+            preloaded[preloaded.len().saturating_sub(amount)..].to_vec()
+        }
+
+        /// Ends every consumer, so reads return what is buffered instead of waiting for more
+        pub fn close(&self) {
+            self.closed.store(true);
+        }
+
+        /// Set how many samples of history to keep for delayed consumers
+        pub fn keep_history(&self, amount: usize) {
+            self.history.store(amount.max(Self::PRELOAD_BUFFER_SIZE));
+        }
+
+        /// Returns how many samples of history are kept, see [Stream::keep_history]
+        pub fn history(&self) -> usize {
+            self.history.load()
         }
 
         /// Write samples to all consumers and the preload
         pub fn write(&self, buf: &[Sample]) {
             let mut entries = self.entries.lock();
 
-            for (_, producer) in entries.iter_mut() {
-                producer.push_slice(buf);
+            for entry in entries.iter_mut() {
+                if entry.producer.push_slice(buf) < buf.len() {
+                    entry.lagging.store(true);
+                }
             }
 
             self.write_preload(buf);
+            *self.written_until.lock() = SystemTime::now() + samples_to_duration(buf.len());
         }
 
         fn write_preload(&self, buf: &[Sample]) {
             let mut preloaded = self.preloaded.write();
 
             preloaded.extend_from_slice(buf);
-            let overflowing = preloaded.len().saturating_sub(Self::PRELOAD_BUFFER_SIZE);
+            let overflowing = preloaded.len().saturating_sub(self.history.load());
 
             if overflowing > 0 {
                 preloaded.drain(..overflowing);
             }
         }
 
+        /// Refills a consumer that fell behind with the newest `delay` samples it was created with,
+        /// since the samples that overflowed its buffer are lost
+        fn rewind(&self, consumer: &mut StreamConsumer) {
+            // Writing is blocked while this is held, so no samples are missed in between
+            let mut entries = self.entries.lock();
+
+            if !consumer.lagging.swap(false) {
+                return;
+            }
+
+            let stale = consumer.underlying.len();
+            consumer.underlying.discard(stale);
+
+            if let Some(entry) = entries.iter_mut().find(|e| e.id == consumer.id) {
+                let preloaded = self.preloaded.read();
+                let delayed = &preloaded[preloaded.len().saturating_sub(consumer.delay)..];
+
+                entry.producer.push_slice(delayed);
+            }
+        }
+
         /// Remove an entry after the consumer has been dropped
         ///
         /// **This should not ever be called manually.**
         fn remove(&self, id: StreamConsumerId) {
-            self.entries.lock().retain(|(i, _)| i != &id);
+            self.entries.lock().retain(|e| e.id != id);
         }
     }
 
@@ -134,13 +232,30 @@ pub mod new {
         id: StreamConsumerId,
         stream: Weak<Stream>,
         underlying: Consumer<Sample>,
+        lagging: Arc<AtomicCell<bool>>,
+        /// How many samples behind live the consumer started, which it returns to after lagging
+        delay: usize,
     }
 
     impl StreamConsumer {
         /// Read from the consumer, returning how many samples were read
         ///
-        /// **Note: This will block if the ringbuffer is empty, until it is not**
+        /// If the buffer overflowed since the last read, it is refilled with the newest samples
+        /// up to the delay the consumer was created with. A slow reader drops audio to stay
+        /// as close to live as it started, instead of falling further behind, so a synchronized
+        /// consumer keeps its latency.
+        ///
+        /// **Note: This will block if the ringbuffer is empty, until it is not,
+        /// unless the stream was dropped or closed, such as when its room was deleted
+        /// or the server shuts down**
         pub fn read(&mut self, buf: &mut [Sample]) -> usize {
+            if self.lagging.load() {
+                match self.stream.upgrade() {
+                    Some(stream) => stream.rewind(self),
+                    None => self.lagging.store(false),
+                }
+            }
+
             let requested_samples = buf.len();
             let mut samples_read = 0;
 
@@ -148,6 +263,12 @@ pub mod new {
                 samples_read += self.underlying.pop_slice(&mut buf[samples_read..]);
 
                 if samples_read < requested_samples {
+                    let open = self.stream.upgrade().is_some_and(|s| !s.closed.load());
+
+                    if !open {
+                        return samples_read;
+                    }
+
                     let remaining = requested_samples - samples_read;
 
                     // Waiting for buffer ensures minimal busy-wait
@@ -161,10 +282,9 @@ pub mod new {
 
     impl Drop for StreamConsumer {
         fn drop(&mut self) {
-            self.stream
-                .upgrade()
-                .expect("stream is upgraded, because it will always exist")
-                .remove(self.id)
+            if let Some(stream) = self.stream.upgrade() {
+                stream.remove(self.id)
+            }
         }
     }
 
@@ -174,10 +294,137 @@ pub mod new {
         }
     }
 
+    fn samples_to_duration(samples: usize) -> Duration {
+        Duration::from_secs_f64(samples as f64 / SAMPLES_PER_SEC as f64)
+    }
+
     fn wait_for_buffer(samples_to_wait_for: usize) {
         let seconds_per_sample = 1. / SAMPLES_PER_SEC as f32;
         let seconds_to_wait = (samples_to_wait_for as f32) * seconds_per_sample;
 
         spin_sleep::sleep(Duration::from_secs_f32(seconds_to_wait));
     }
+
+    #[cfg(test)]
+    mod test {
+        use std::{
+            thread,
+            time::{Duration, Instant},
+        };
+
+        use super::Stream;
+        use crate::audio::SAMPLES_PER_SEC;
+
+        #[test]
+        fn slow_consumers_skip_to_live() {
+            let stream = Stream::new();
+            let (mut slow, _) = stream.delayed_consumer(0);
+            let (mut fast, _) = stream.delayed_consumer(0);
+
+            let chunk = SAMPLES_PER_SEC / 10;
+            let mut buf = vec![0.; chunk];
+
+            // Three seconds of audio while the slow consumer never reads
+            let started = Instant::now();
+
+            for i in 0..30 {
+                stream.write(&vec![i as f32; chunk]);
+                assert_eq!(fast.read(&mut buf), chunk);
+                assert_eq!(buf[0], i as f32);
+            }
+
+            // Writing never waits for a consumer
+            assert!(started.elapsed() < Duration::from_millis(500));
+
+            // The overflowed buffer is discarded, so the next read waits for live audio
+            let writer = {
+                let stream = stream.clone();
+
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(50));
+                    stream.write(&vec![30.; chunk]);
+                })
+            };
+
+            assert_eq!(slow.read(&mut buf), chunk);
+            assert!(buf.iter().all(|s| *s == 30.));
+
+            writer.join().unwrap();
+            assert_eq!(fast.read(&mut buf), chunk);
+            assert!(buf.iter().all(|s| *s == 30.));
+        }
+
+        #[test]
+        fn closing_ends_waiting_consumers() {
+            let stream = Stream::new();
+            let mut consumer = stream.consumer();
+
+            let closer = {
+                let stream = stream.clone();
+
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(50));
+                    stream.close();
+                })
+            };
+
+            // Nothing is written, so this only returns because the stream was closed
+            let mut buf = vec![0.; SAMPLES_PER_SEC];
+            assert_eq!(consumer.read(&mut buf), 0);
+
+            closer.join().unwrap();
+        }
+
+        #[test]
+        fn consumers_keep_their_preload() {
+            let stream = Stream::new();
+            let chunk = SAMPLES_PER_SEC / 10;
+
+            // A second of history, for the preload
+            for i in 0..10 {
+                stream.write(&vec![i as f32; chunk]);
+            }
+
+            let mut consumer = stream.consumer();
+            let mut buf = vec![0.; chunk];
+
+            // Writing before the first read is not falling behind
+            stream.write(&vec![10.; chunk]);
+
+            for i in 0..11 {
+                assert_eq!(consumer.read(&mut buf), chunk);
+                assert!(buf.iter().all(|s| *s == i as f32));
+            }
+        }
+
+        #[test]
+        fn slow_delayed_consumers_return_to_their_delay() {
+            let stream = Stream::new();
+            let chunk = SAMPLES_PER_SEC / 10;
+
+            stream.keep_history(SAMPLES_PER_SEC * 2);
+
+            for i in 0..10 {
+                stream.write(&vec![i as f32; chunk]);
+            }
+
+            let (mut consumer, _) = stream.delayed_consumer(chunk * 5);
+            let mut buf = vec![0.; chunk];
+
+            stream.write(&vec![10.; chunk]);
+            assert_eq!(consumer.read(&mut buf), chunk);
+            assert!(buf.iter().all(|s| *s == 5.));
+
+            // Three seconds of audio while the consumer never reads
+            for i in 11..41 {
+                stream.write(&vec![i as f32; chunk]);
+            }
+
+            // It continues half a second behind live, like it started
+            for i in 36..41 {
+                assert_eq!(consumer.read(&mut buf), chunk);
+                assert!(buf.iter().all(|s| *s == i as f32));
+            }
+        }
+    }
 }
@@ -0,0 +1,254 @@
+use std::{env, fmt::Debug, time::Duration};
+
+use lazy_static::lazy_static;
+
+use super::{Sample, SAMPLES_PER_SEC};
+
+lazy_static! {
+    static ref NORMALIZATION_CONFIG: NormalizationConfig = NormalizationConfig::from_env();
+}
+
+/// Reads and validates the normalization config, so mistakes are caught on startup
+pub fn init_normalization_config() -> &'static impl Debug {
+    &*NORMALIZATION_CONFIG
+}
+
+/// Controls how quickly dynamic normalization reacts.
+///
+/// The defaults are slow on purpose, so the volume drifts back over several seconds
+/// instead of following the music, which would cause audible pumping.
+#[derive(Debug, Clone, Copy)]
+struct NormalizationConfig {
+    /// The loudness to aim for in dBFS RMS, set with `VINYL_NORMALIZATION_TARGET`
+    target: f32,
+    /// How long it takes to turn down loud parts, set with `VINYL_NORMALIZATION_ATTACK_SECS`
+    attack: Duration,
+    /// How long it takes to turn up quiet parts, set with `VINYL_NORMALIZATION_RELEASE_SECS`
+    release: Duration,
+}
+
+impl NormalizationConfig {
+    const DEFAULT_TARGET: f32 = -20.;
+    const DEFAULT_ATTACK: Duration = Duration::from_secs(4);
+    const DEFAULT_RELEASE: Duration = Duration::from_secs(16);
+
+    fn from_env() -> Self {
+        let target = env::var("VINYL_NORMALIZATION_TARGET")
+            .map(|x| {
+                x.parse::<f32>()
+                    .expect("Normalization target must be a number")
+            })
+            .unwrap_or(Self::DEFAULT_TARGET);
+
+        let attack = env::var("VINYL_NORMALIZATION_ATTACK_SECS")
+            .map(|x| x.parse::<f32>().expect("Attack must be a number"))
+            .map(Duration::from_secs_f32)
+            .unwrap_or(Self::DEFAULT_ATTACK);
+
+        let release = env::var("VINYL_NORMALIZATION_RELEASE_SECS")
+            .map(|x| x.parse::<f32>().expect("Release must be a number"))
+            .map(Duration::from_secs_f32)
+            .unwrap_or(Self::DEFAULT_RELEASE);
+
+        assert!(
+            (-40. ..=Normalizer::CEILING).contains(&target),
+            "Normalization target must be between -40 and {} dBFS",
+            Normalizer::CEILING
+        );
+
+        assert!(
+            !attack.is_zero() && !release.is_zero(),
+            "Attack and release must be above 0"
+        );
+
+        Self {
+            target,
+            attack,
+            release,
+        }
+    }
+}
+
+impl Default for NormalizationConfig {
+    fn default() -> Self {
+        Self {
+            target: Self::DEFAULT_TARGET,
+            attack: Self::DEFAULT_ATTACK,
+            release: Self::DEFAULT_RELEASE,
+        }
+    }
+}
+
+/// Slowly acting automatic gain control, keeping loudness steady over long tracks.
+///
+/// A peak limiter is built in. It reacts instantly when a chunk would go above
+/// [Normalizer::CEILING], and the gain is never raised above the headroom left by
+/// recent peaks, so boosting quiet parts cannot push the limiter into working constantly.
+#[derive(Debug)]
+pub struct Normalizer {
+    config: NormalizationConfig,
+
+    /// Smoothed mean square of audible chunks
+    level: Option<f32>,
+    /// The gain the normalizer is moving towards, in dB
+    gain: f32,
+    /// The gain applied at the end of the last chunk, as a factor
+    applied: f32,
+    /// Highest recent peak, decaying over time
+    peak: f32,
+}
+
+impl Normalizer {
+    /// Output never goes above this, in dBFS
+    const CEILING: f32 = -1.;
+    const MAX_BOOST: f32 = 12.;
+    const MAX_CUT: f32 = 12.;
+
+    /// Chunks quieter than this, in dBFS RMS, do not count towards loudness,
+    /// so silence and breaks between songs are not turned up
+    const GATE: f32 = -50.;
+
+    /// How long loudness is averaged over
+    const MEASURE_WINDOW: Duration = Duration::from_secs(3);
+    /// How long it takes for a peak to be forgotten
+    const PEAK_DECAY: Duration = Duration::from_secs(10);
+
+    pub fn new() -> Self {
+        Self::with_config(*NORMALIZATION_CONFIG)
+    }
+
+    fn with_config(config: NormalizationConfig) -> Self {
+        Self {
+            config,
+            level: None,
+            gain: 0.,
+            applied: 1.,
+            peak: 0.,
+        }
+    }
+
+    /// Applies the gain to a chunk of interleaved samples in place
+    pub fn process(&mut self, buf: &mut [Sample]) {
+        if buf.is_empty() {
+            return;
+        }
+
+        let elapsed = buf.len() as f32 / SAMPLES_PER_SEC as f32;
+
+        let chunk_peak = buf.iter().fold(0_f32, |peak, s| peak.max(s.abs()));
+        let mean_square = buf.iter().map(|s| s * s).sum::<f32>() / buf.len() as f32;
+
+        if to_db(mean_square.sqrt()) > Self::GATE {
+            let coefficient = smoothing(elapsed, Self::MEASURE_WINDOW);
+            let level = self.level.get_or_insert(mean_square);
+
+            *level += (mean_square - *level) * coefficient;
+        }
+
+        if let Some(level) = self.level {
+            let desired =
+                (self.config.target - to_db(level.sqrt())).clamp(-Self::MAX_CUT, Self::MAX_BOOST);
+
+            let time_constant = if desired < self.gain {
+                self.config.attack
+            } else {
+                self.config.release
+            };
+
+            self.gain += (desired - self.gain) * smoothing(elapsed, time_constant);
+        }
+
+        let decay = 1. - smoothing(elapsed, Self::PEAK_DECAY);
+        self.peak = (self.peak * decay).max(chunk_peak);
+
+        let ceiling = from_db(Self::CEILING);
+        let headroom = if self.peak > 0. {
+            ceiling / self.peak
+        } else {
+            f32::MAX
+        };
+
+        let target = from_db(self.gain).min(headroom);
+
+        // Ramping avoids clicks, but would let the start of a sudden peak through
+        let start = if chunk_peak * self.applied > ceiling {
+            target
+        } else {
+            self.applied
+        };
+
+        let step = (target - start) / buf.len() as f32;
+
+        for (i, sample) in buf.iter_mut().enumerate() {
+            *sample *= start + step * i as f32;
+        }
+
+        self.applied = target;
+    }
+}
+
+/// Returns how far a one-pole filter moves towards its input over `elapsed` seconds
+fn smoothing(elapsed: f32, time_constant: Duration) -> f32 {
+    1. - (-elapsed / time_constant.as_secs_f32()).exp()
+}
+
+fn to_db(amplitude: f32) -> f32 {
+    20. * amplitude.max(f32::EPSILON).log10()
+}
+
+fn from_db(db: f32) -> f32 {
+    10_f32.powf(db / 20.)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_db, to_db, NormalizationConfig, Normalizer};
+    use crate::audio::{Sample, STREAM_CHUNK_SIZE};
+
+    fn chunk(amplitude: f32) -> Vec<Sample> {
+        (0..STREAM_CHUNK_SIZE)
+            .map(|i| amplitude * (i as f32 * 0.05).sin())
+            .collect()
+    }
+
+    fn rms(buf: &[Sample]) -> f32 {
+        (buf.iter().map(|s| s * s).sum::<f32>() / buf.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn moves_towards_target_without_clipping() {
+        let config = NormalizationConfig::default();
+        let ceiling = from_db(Normalizer::CEILING);
+
+        for amplitude in [0.02, 0.9] {
+            let mut normalizer = Normalizer::with_config(config);
+            let mut last = vec![];
+
+            // Two minutes of audio
+            for _ in 0..1200 {
+                let mut buf = chunk(amplitude);
+                normalizer.process(&mut buf);
+
+                assert!(buf.iter().all(|s| s.abs() <= ceiling + f32::EPSILON));
+                last = buf;
+            }
+
+            let before = (to_db(rms(&chunk(amplitude))) - config.target).abs();
+            let after = (to_db(rms(&last)) - config.target).abs();
+
+            assert!(after < before);
+        }
+    }
+
+    #[test]
+    fn does_not_boost_silence() {
+        let mut normalizer = Normalizer::with_config(NormalizationConfig::default());
+
+        for _ in 0..100 {
+            let mut buf = chunk(0.0001);
+            normalizer.process(&mut buf);
+        }
+
+        assert_eq!(normalizer.gain, 0.);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{env, fmt::Debug};
+
+use lazy_static::lazy_static;
+
+use super::{Sample, CHANNEL_COUNT, SAMPLE_RATE};
+
+lazy_static! {
+    static ref OUTPUT_FORMAT: OutputFormat = OutputFormat::from_env();
+}
+
+/// Reads and validates the output format, so mistakes are caught on startup
+pub fn init_output_format() -> &'static impl Debug {
+    &*OUTPUT_FORMAT
+}
+
+/// The format listeners receive audio in, see [OutputFormat]
+pub fn output_format() -> OutputFormat {
+    *OUTPUT_FORMAT
+}
+
+/// The sample rate and channel count of the audio sent to listeners.
+///
+/// Audio is always mixed at [SAMPLE_RATE] in stereo, and converted to this when it is encoded,
+/// so a lower rate or mono can be used to save bandwidth.
+/// Set with `VINYL_OUTPUT_SAMPLE_RATE` and `VINYL_OUTPUT_CHANNELS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub sample_rate: usize,
+    pub channels: usize,
+}
+
+impl OutputFormat {
+    /// The format audio is mixed in
+    pub const MIXED: Self = Self {
+        sample_rate: SAMPLE_RATE,
+        channels: CHANNEL_COUNT,
+    };
+
+    const SAMPLE_RATES: [usize; 5] = [22050, 24000, 32000, 44100, 48000];
+
+    fn from_env() -> Self {
+        let sample_rate = env::var("VINYL_OUTPUT_SAMPLE_RATE")
+            .map(|x| {
+                x.parse::<usize>()
+                    .expect("Output sample rate must be a number")
+            })
+            .unwrap_or(Self::MIXED.sample_rate);
+
+        let channels = match env::var("VINYL_OUTPUT_CHANNELS").as_deref() {
+            Ok("mono") | Ok("1") => 1,
+            Ok("stereo") | Ok("2") | Err(_) => 2,
+            Ok(other) => panic!("Unknown output channels {}, must be mono or stereo", other),
+        };
+
+        assert!(
+            Self::SAMPLE_RATES.contains(&sample_rate),
+            "Output sample rate must be one of {:?}",
+            Self::SAMPLE_RATES
+        );
+
+        Self {
+            sample_rate,
+            channels,
+        }
+    }
+
+    /// The size of a frame in bytes, as 16-bit samples
+    pub fn frame_bytes(&self) -> usize {
+        self.channels * 2
+    }
+}
+
+/// Converts mixed samples to an [OutputFormat] a chunk at a time,
+/// downmixing to mono and resampling with linear interpolation.
+#[derive(Debug)]
+pub struct Converter {
+    format: OutputFormat,
+    /// Where the next frame is taken from, in mixed frames after `previous`
+    position: f64,
+    /// The last frame of the previous chunk, so frames between chunks can be interpolated
+    previous: Vec<Sample>,
+}
+
+impl Converter {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            position: 0.,
+            previous: vec![],
+        }
+    }
+
+    /// How many mixed samples to convert to get about `frames` frames
+    pub fn samples_for(&self, frames: usize) -> usize {
+        let mixed_frames = frames * SAMPLE_RATE / self.format.sample_rate;
+        mixed_frames.max(1) * CHANNEL_COUNT
+    }
+
+    pub fn convert(&mut self, samples: &[Sample]) -> Vec<Sample> {
+        let channels = self.format.channels;
+        let mut frames = std::mem::take(&mut self.previous);
+
+        for frame in samples.chunks_exact(CHANNEL_COUNT) {
+            if channels == 1 {
+                frames.push(frame.iter().sum::<Sample>() / CHANNEL_COUNT as Sample);
+            } else {
+                frames.extend_from_slice(frame);
+            }
+        }
+
+        if self.format.sample_rate == SAMPLE_RATE {
+            return frames;
+        }
+
+        let step = SAMPLE_RATE as f64 / self.format.sample_rate as f64;
+        let count = frames.len() / channels;
+        let mut result = vec![];
+
+        while self.position + 1. < count as f64 {
+            let index = self.position as usize;
+            let amount = (self.position - index as f64) as Sample;
+
+            for channel in 0..channels {
+                let from = frames[index * channels + channel];
+                let to = frames[(index + 1) * channels + channel];
+
+                result.push(from + (to - from) * amount);
+            }
+
+            self.position += step;
+        }
+
+        if count > 0 {
+            self.position -= (count - 1) as f64;
+            self.previous = frames[(count - 1) * channels..].to_vec();
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Converter, OutputFormat};
+
+    #[test]
+    fn downmixes_to_mono() {
+        let mut converter = Converter::new(OutputFormat {
+            channels: 1,
+            ..OutputFormat::MIXED
+        });
+
+        assert_eq!(converter.convert(&[1., 0., 0.5, 0.5]), vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn resamples_across_chunks() {
+        let mut converter = Converter::new(OutputFormat {
+            sample_rate: 22050,
+            ..OutputFormat::MIXED
+        });
+
+        // A ramp of 8 stereo frames, converted in uneven chunks
+        let ramp: Vec<_> = (0..8).flat_map(|x| [x as f32, -x as f32]).collect();
+
+        let mut result = converter.convert(&ramp[..6]);
+        result.extend(converter.convert(&ramp[6..]));
+
+        assert_eq!(result, vec![0., 0., 2., -2., 4., -4., 6., -6.]);
+    }
+
+    #[test]
+    fn interpolates_between_frames() {
+        let mut converter = Converter::new(OutputFormat {
+            sample_rate: 48000,
+            channels: 1,
+        });
+
+        let samples = vec![1.; 441 * 2 * 2];
+        let mut converted = converter.convert(&samples[..441 * 2]);
+        converted.extend(converter.convert(&samples[441 * 2..]));
+
+        // 20ms of audio, except the last frame which needs the next chunk to be interpolated
+        assert_eq!(converted.len(), 959);
+        assert!(converted.iter().all(|x| (x - 1.).abs() < 1e-6));
+    }
+}
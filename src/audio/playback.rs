@@ -1,21 +1,27 @@
 use std::{
     sync::{Arc, Weak},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
-    ingest::{Sink, SinkId},
+    ingest::{Sink, SinkId, SinkLength},
     store::{FromId, Id, Store},
+    util::shutdown,
     EventEmitter,
 };
+use crossbeam::atomic::AtomicCell;
 use dashmap::DashMap;
 use log::warn;
+use parking_lot::Mutex;
 
 use super::{
+    loudness::apply_gain,
+    mixing::{apply_volume, crossfade, Ducker},
     new::{Stream, StreamConsumer},
-    AudioEvent, Timeline, PRELOAD_AMOUNT, SAMPLES_PER_SEC, STREAM_CHUNK_DURATION,
-    STREAM_CHUNK_SIZE,
+    normalization::Normalizer,
+    Advancement, AudioEvent, Sample, Timeline, CHANNEL_COUNT, PRELOAD_AMOUNT, SAMPLES_PER_SEC,
+    STREAM_CHUNK_DURATION, STREAM_CHUNK_SIZE,
 };
 
 pub type PlayerId = Id<Player>;
@@ -26,6 +32,30 @@ pub struct Player {
     id: PlayerId,
     timeline: Timeline,
     stream: Arc<Stream>,
+
+    /// Playback will not start while this is true
+    held: AtomicCell<bool>,
+
+    /// Playback stays where it is while this is true, see [Player::pause]
+    paused: AtomicCell<bool>,
+
+    /// Keeps loudness steady when dynamic normalization is enabled
+    normalizer: Mutex<Option<Normalizer>>,
+
+    /// Tracks are played at the same loudness when this is true, see [LoudnessMeter](super::LoudnessMeter)
+    loudness_normalization: AtomicCell<bool>,
+
+    /// Plays voice tracks on top of the music
+    ducker: Mutex<Ducker>,
+
+    /// What everything is scaled by before it is streamed, between 0 and 1
+    volume: AtomicCell<f32>,
+
+    /// The current sink is skipped on the next process when this is true, see [Player::skip]
+    skip_requested: AtomicCell<bool>,
+
+    /// The sink to move to an offset on the next process, see [Player::seek]
+    seek_requested: AtomicCell<Option<(SinkId, usize)>>,
 }
 
 impl Player {
@@ -41,9 +71,155 @@ impl Player {
         self.stream.consumer()
     }
 
+    /// Get a consumer that is behind live playback by `delay`,
+    /// along with when its first sample was live.
+    ///
+    /// **The delay is limited by how much history is kept, see [Player::keep_history].**
+    pub fn delayed_consumer(&self, delay: Duration) -> (StreamConsumer, SystemTime) {
+        self.stream.delayed_consumer(duration_to_samples(delay))
+    }
+
+    /// Keep enough samples to give out consumers delayed by up to `duration`.
+    pub fn keep_history(&self, duration: Duration) {
+        self.stream.keep_history(duration_to_samples(duration))
+    }
+
+    /// Returns how far back consumers can be delayed, see [Player::keep_history]
+    pub fn history(&self) -> Duration {
+        Duration::from_secs_f64(self.stream.history() as f64 / SAMPLES_PER_SEC as f64)
+    }
+
+    /// Ends every consumer, so listeners waiting for audio stop, see [Stream::close]
+    pub fn close_stream(&self) {
+        self.stream.close()
+    }
+
+    /// Returns the most recent samples played, limited by how much history is kept
+    pub fn snapshot(&self, duration: Duration) -> Vec<Sample> {
+        self.stream.snapshot(duration_to_samples(duration))
+    }
+
+    /// Play a voice track on top of the music, lowering the music while it plays.
+    ///
+    /// **See [Ducker] for how voice tracks are mixed in.**
+    pub fn duck(&self, voice: Sink) {
+        self.ducker.lock().push(voice)
+    }
+
     /// Return the sink to preload, if any
     pub fn preload(&self) -> Option<SinkId> {
-        self.timeline.preload()
+        // Voice tracks are short, and should not wait for the music to be loaded
+        self.ducker
+            .lock()
+            .preload()
+            .or_else(|| self.timeline.preload())
+    }
+
+    /// Hold playback, streaming silence until it is released.
+    pub fn hold(&self) {
+        self.held.store(true)
+    }
+
+    pub fn release(&self) {
+        self.held.store(false)
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held.load()
+    }
+
+    /// Enable or disable dynamic normalization
+    pub fn set_normalization(&self, enabled: bool) {
+        let mut normalizer = self.normalizer.lock();
+
+        match (enabled, normalizer.is_some()) {
+            (true, false) => *normalizer = Some(Normalizer::new()),
+            (false, true) => *normalizer = None,
+            _ => {}
+        }
+    }
+
+    /// Enable or disable playing tracks at the same loudness
+    pub fn set_loudness_normalization(&self, enabled: bool) {
+        self.loudness_normalization.store(enabled)
+    }
+
+    /// Returns how far into the current sink playback is
+    pub fn position(&self) -> Duration {
+        Duration::from_secs_f64(self.timeline.offset.load() as f64 / SAMPLES_PER_SEC as f64)
+    }
+
+    /// Sets how long consecutive sinks are mixed together for, [Duration::ZERO] to play them back to back
+    pub fn set_crossfade(&self, duration: Duration) {
+        self.timeline.crossfade.store(duration_to_samples(duration));
+    }
+
+    /// Sets whether the current sink plays again once it ends, in which case it does not crossfade,
+    /// since the sink that follows it is not the one that plays next
+    pub fn set_repeating(&self, repeating: bool) {
+        self.timeline.repeating.store(repeating);
+    }
+
+    /// Sets what everything is scaled by, clamped between 0 and 1
+    pub fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.clamp(0., 1.));
+    }
+
+    /// Stops advancing playback, so listeners hear silence until it is resumed.
+    /// Returns false if the player was already paused.
+    pub fn pause(&self) -> bool {
+        !self.paused.swap(true)
+    }
+
+    /// Continues playback from where it was paused, returning false if it was not paused
+    pub fn resume(&self) -> bool {
+        self.paused.swap(false)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load()
+    }
+
+    /// Skips the current sink the next time the player processes,
+    /// which is then reported as consumed like a sink that finished playing.
+    pub fn skip(&self) {
+        self.skip_requested.store(true);
+    }
+
+    /// Moves playback of the current sink to `position` the next time the player processes,
+    /// clamped to the length of the sink. Returns where it moves to,
+    /// or [None] if nothing is playing or it is relayed, since relays are live.
+    ///
+    /// Sinks keep everything that was loaded, so seeking back is immediate,
+    /// and seeking ahead of what is loaded waits until it is.
+    pub fn seek(&self, position: Duration) -> Option<Duration> {
+        let sink = self.timeline.current().filter(|s| !s.is_relayed())?;
+
+        let end = match sink.length() {
+            _ if sink.is_complete() => Some(sink.available()),
+            SinkLength::Exact(x) | SinkLength::Approximate(x) => Some(x),
+            SinkLength::Unknown => None,
+        };
+
+        let offset = match end {
+            Some(end) => duration_to_samples(position).min(end - end % CHANNEL_COUNT),
+            None => duration_to_samples(position),
+        };
+
+        self.seek_requested.store(Some((sink.id(), offset)));
+        Some(Duration::from_secs_f64(
+            offset as f64 / SAMPLES_PER_SEC as f64,
+        ))
+    }
+
+    /// Stops playing everything without reporting it as consumed, which leaves silence
+    pub fn stop(&self) {
+        self.timeline.stop();
+    }
+
+    /// Returns true if anything has been played yet
+    pub fn has_started(&self) -> bool {
+        self.timeline.total_offset.load() > 0
     }
 
     /// Advance the playback by reading from sinks and pushing samples into a ringbuffer.
@@ -51,6 +227,38 @@ impl Player {
     pub fn process(&self) -> ProcessMetadata {
         let mut samples = vec![0.; STREAM_CHUNK_SIZE];
 
+        // Seeking works while paused, so playback continues from there once resumed
+        if let Some((sink, offset)) = self.seek_requested.take() {
+            self.timeline.seek(sink, offset);
+        }
+
+        // Silence keeps streams open, and playback continues where it was after
+        if self.is_held() || self.is_paused() {
+            // Relays are live, so what they receive meanwhile is dropped instead of piling up
+            if let Some(sink) = self.timeline.current().filter(|s| s.is_relayed()) {
+                let live = sink.available();
+
+                self.timeline.seek(sink.id(), live);
+                sink.discard(live);
+            }
+
+            self.stream.write(&samples);
+
+            return ProcessMetadata {
+                new_sink_offset: self.timeline.offset.load(),
+                total_offset: self.timeline.total_offset.load(),
+                difference: 0,
+                consumed_sinks: 0,
+                ended_voices: 0,
+            };
+        }
+
+        let skipped = self.skip_requested.swap(false);
+
+        if skipped {
+            self.timeline.skip();
+        }
+
         let current_offset = self.timeline.offset.load();
         let advancements = self.timeline.advance(samples.len());
 
@@ -58,15 +266,43 @@ impl Player {
         let consumed_sinks = advancements.len().saturating_sub(1);
 
         for (i, advancement) in advancements.into_iter().enumerate() {
+            let start = amount_read;
+
             amount_read += advancement
                 .sink
                 .read(advancement.start_offset, &mut samples[amount_read..]);
 
+            let gain = advancement
+                .sink
+                .gain()
+                .filter(|_| self.loudness_normalization.load());
+
+            if let Some(gain) = gain {
+                apply_gain(&mut samples[start..amount_read], gain);
+            }
+
+            if advancement.overlap > 0 {
+                self.fade_into_next(&advancement, &mut samples[start..amount_read]);
+            }
+
             if i < consumed_sinks && consumed_sinks >= 1 {
                 advancement.sink.consume();
             }
+
+            if advancement.sink.is_relayed() {
+                advancement.sink.discard(advancement.start_offset);
+            }
+        }
+
+        if let Some(normalizer) = self.normalizer.lock().as_mut() {
+            normalizer.process(&mut samples);
         }
 
+        // Ducking after normalization, so the music is not brought back up
+        let ended_voices = self.ducker.lock().process(&mut samples) as usize;
+
+        apply_volume(&mut samples, self.volume.load());
+
         self.stream.write(&samples);
 
         let new_sink_offset = self.timeline.offset.load();
@@ -76,11 +312,41 @@ impl Player {
 
         ProcessMetadata {
             new_sink_offset,
-            consumed_sinks,
+            consumed_sinks: consumed_sinks + skipped as usize,
             total_offset,
             difference,
+            ended_voices,
         }
     }
+
+    /// Fades out the part of a sink that overlaps the next one, mixing in the start of the next one.
+    /// Nothing is mixed in if the sink is the last one, so it fades to silence.
+    fn fade_into_next(&self, advancement: &Advancement, samples: &mut [Sample]) {
+        let fade_start = advancement
+            .sink
+            .available()
+            .saturating_sub(advancement.overlap);
+
+        let start = advancement.start_offset.max(fade_start);
+        let Some(faded) = samples.get_mut(start - advancement.start_offset..) else {
+            return;
+        };
+
+        let position = start - fade_start;
+        let mut incoming = vec![0.; faded.len()];
+
+        if let Some(next) = &advancement.next {
+            next.read(next.start() + position, &mut incoming);
+
+            let gain = next.gain().filter(|_| self.loudness_normalization.load());
+
+            if let Some(gain) = gain {
+                apply_gain(&mut incoming, gain);
+            }
+        }
+
+        crossfade(faded, &incoming, position, advancement.overlap);
+    }
 }
 
 impl Default for Player {
@@ -89,6 +355,14 @@ impl Default for Player {
             id: PlayerId::new(),
             timeline: Timeline::default(),
             stream: Stream::new(),
+            held: false.into(),
+            paused: false.into(),
+            normalizer: None.into(),
+            loudness_normalization: true.into(),
+            ducker: Ducker::new().into(),
+            volume: 1.0.into(),
+            skip_requested: false.into(),
+            seek_requested: None.into(),
         }
     }
 }
@@ -121,8 +395,11 @@ pub struct ProcessMetadata {
     ///
     /// This is 1 or more when the player has finished playing a track, otherwise it is usually 0.
     ///
-    /// **Note that finished can also mean the sink was skipped due to an error.**
+    /// **Note that finished can also mean the sink was skipped, on request or due to an error.**
     pub consumed_sinks: usize,
+
+    /// The amount of voice tracks that finished playing on top of the music.
+    pub ended_voices: usize,
 }
 
 #[derive(Debug)]
@@ -159,6 +436,11 @@ impl Playback {
         Ok(id)
     }
 
+    /// Stops processing a player, so it can be dropped
+    pub fn remove_player(&self, id: PlayerId) {
+        self.players.remove(&id);
+    }
+
     fn store(&self) -> Arc<Store> {
         self.store.upgrade().expect("upgrade store in playback")
     }
@@ -196,14 +478,20 @@ impl Playback {
             for _ in 0..processed.consumed_sinks {
                 emitter.dispatch(AudioEvent::Next { player: player.id })
             }
+
+            for _ in 0..processed.ended_voices {
+                emitter.dispatch(AudioEvent::VoiceEnded { player: player.id })
+            }
         }
     }
 }
 
 fn spawn_preload_thread(playback: Arc<Playback>) {
-    let run = move || loop {
-        playback.preload();
-        thread::sleep(Duration::from_millis(100));
+    let run = move || {
+        while !shutdown::requested() {
+            playback.preload();
+            thread::sleep(Duration::from_millis(100));
+        }
     };
 
     thread::Builder::new()
@@ -213,10 +501,12 @@ fn spawn_preload_thread(playback: Arc<Playback>) {
 }
 
 fn spawn_processing_thread(playback: Arc<Playback>) {
-    let run = move || loop {
-        let now = Instant::now();
-        playback.process();
-        wait_for_next(now);
+    let run = move || {
+        while !shutdown::requested() {
+            let now = Instant::now();
+            playback.process();
+            wait_for_next(now);
+        }
     };
 
     thread::Builder::new()
@@ -225,6 +515,12 @@ fn spawn_processing_thread(playback: Arc<Playback>) {
         .unwrap();
 }
 
+/// Converts a duration to an amount of samples, aligned to a frame
+pub fn duration_to_samples(duration: Duration) -> usize {
+    let samples = (duration.as_secs_f64() * SAMPLES_PER_SEC as f64) as usize;
+    samples - samples % CHANNEL_COUNT
+}
+
 fn wait_for_next(now: Instant) {
     let elapsed = now.elapsed();
     let elapsed_micros = elapsed.as_micros();
@@ -250,3 +546,139 @@ pub fn run_playback(playback: Arc<Playback>) {
     spawn_preload_thread(playback.clone());
     spawn_processing_thread(playback);
 }
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use super::Player;
+    use crate::{
+        audio::{SAMPLES_PER_SEC, STREAM_CHUNK_DURATION, STREAM_CHUNK_SIZE},
+        ingest::{InternalSink, SinkLength},
+    };
+
+    #[test]
+    fn seeks_within_current_sink() {
+        let player = Player::default();
+        assert_eq!(player.seek(Duration::from_secs(1)), None);
+
+        let sink = Arc::new(InternalSink::new(SinkLength::Exact(SAMPLES_PER_SEC * 10)));
+        sink.write(&vec![0.5; SAMPLES_PER_SEC * 10]);
+        sink.seal();
+
+        player.set_sinks(vec![sink]);
+
+        let seek = |secs: u64| {
+            let position = player.seek(Duration::from_secs(secs));
+            player.process();
+            position
+        };
+
+        assert_eq!(seek(4), Some(Duration::from_secs(4)));
+        assert!(player.position() > Duration::from_secs(4));
+        assert!(player.position() < Duration::from_secs(5));
+
+        // Seeking back is just as immediate
+        assert_eq!(seek(1), Some(Duration::from_secs(1)));
+        assert!(player.position() < Duration::from_secs(2));
+
+        // Seeking past the end goes to the end
+        assert_eq!(
+            player.seek(Duration::from_secs(60)),
+            Some(Duration::from_secs(10))
+        );
+
+        // Relays are live, so there is nowhere to seek to
+        player.set_sinks(vec![Arc::new(InternalSink::new_relayed())]);
+        assert_eq!(player.seek(Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn discards_relayed_samples_while_paused() {
+        let player = Player::default();
+        let sink = Arc::new(InternalSink::new_relayed());
+
+        player.set_sinks(vec![sink.clone()]);
+        player.pause();
+
+        // Discarding happens in chunks of a minute
+        let received = SAMPLES_PER_SEC * 61;
+
+        sink.write(&vec![0.5; received]);
+        player.process();
+
+        assert_eq!(sink.read(0, &mut [0.; STREAM_CHUNK_SIZE]), 0);
+
+        // Playback continues live once resumed
+        player.resume();
+        sink.write(&vec![0.5; STREAM_CHUNK_SIZE]);
+
+        assert_eq!(
+            player.process().new_sink_offset,
+            received + STREAM_CHUNK_SIZE
+        );
+    }
+
+    #[test]
+    fn does_not_crossfade_when_repeating() {
+        let player = Player::default();
+
+        player.set_loudness_normalization(false);
+        player.set_crossfade(STREAM_CHUNK_DURATION);
+        player.set_repeating(true);
+
+        let first = Arc::new(InternalSink::new(SinkLength::Exact(STREAM_CHUNK_SIZE)));
+        first.write(&vec![1.; STREAM_CHUNK_SIZE * 2]);
+        first.seal();
+
+        let next = Arc::new(InternalSink::new(SinkLength::Exact(STREAM_CHUNK_SIZE)));
+        next.write(&vec![0.5; STREAM_CHUNK_SIZE * 2]);
+        next.seal();
+
+        player.set_sinks(vec![first, next]);
+        player.process();
+
+        assert!(player
+            .snapshot(STREAM_CHUNK_DURATION)
+            .iter()
+            .all(|s| *s == 1.));
+    }
+
+    #[test]
+    fn crossfades_into_next_sink() {
+        let player = Player::default();
+
+        player.set_loudness_normalization(false);
+        player.set_crossfade(STREAM_CHUNK_DURATION);
+
+        let sink = |value: f32| {
+            let sink = Arc::new(InternalSink::new(SinkLength::Exact(STREAM_CHUNK_SIZE * 4)));
+            sink.write(&vec![value; STREAM_CHUNK_SIZE * 4]);
+            sink.seal();
+            sink
+        };
+
+        player.set_sinks(vec![sink(1.), sink(0.5)]);
+
+        for _ in 0..3 {
+            assert_eq!(player.process().consumed_sinks, 0);
+        }
+
+        // The last chunk of the first sink has the start of the next one mixed in
+        assert_eq!(player.process().consumed_sinks, 0);
+
+        let mixed = player.snapshot(STREAM_CHUNK_DURATION);
+        assert_eq!(mixed[0], 1.);
+        assert!(mixed.iter().all(|s| *s > 0.5));
+
+        // The next sink continues after what was mixed in
+        let processed = player.process();
+
+        assert_eq!(processed.consumed_sinks, 1);
+        assert_eq!(processed.new_sink_offset, STREAM_CHUNK_SIZE * 2);
+        assert!(player
+            .snapshot(STREAM_CHUNK_DURATION)
+            .iter()
+            .all(|s| *s == 0.5));
+    }
+}
@@ -3,7 +3,7 @@ use parking_lot::Mutex;
 
 use crate::ingest::{Sink, SinkId};
 
-use super::PRELOAD_THRESHOLD;
+use super::{CHANNEL_COUNT, PRELOAD_THRESHOLD};
 
 /// A list of consecutive sinks that keeps track of offset and amount loaded.
 ///
@@ -16,6 +16,11 @@ pub struct Timeline {
     pub(super) offset: AtomicCell<usize>,
     /// The total amount of samples that have been advanced.
     pub(super) total_offset: AtomicCell<usize>,
+    /// How many samples consecutive sinks are mixed together for, see [Timeline::overlap]
+    pub(super) crossfade: AtomicCell<usize>,
+    /// True if the current sink plays again after it ends instead of the next one,
+    /// so it is not mixed with the next one, see [Timeline::overlap]
+    pub(super) repeating: AtomicCell<bool>,
 }
 
 impl Timeline {
@@ -23,6 +28,37 @@ impl Timeline {
         *self.sinks.lock() = sinks
     }
 
+    /// Stops playing the current sink, so the next one plays from the start
+    pub fn skip(&self) {
+        if let Some(sink) = self.sinks.lock().iter().find(|s| !s.is_consumed()) {
+            sink.consume();
+        }
+
+        self.offset.store(0);
+    }
+
+    /// Returns the sink that is playing, if any
+    pub fn current(&self) -> Option<Sink> {
+        self.sinks.lock().iter().find(|s| !s.is_consumed()).cloned()
+    }
+
+    /// Moves playback of `sink` to `offset`, returning false if it is no longer the one playing
+    pub fn seek(&self, sink: SinkId, offset: usize) -> bool {
+        match self.current() {
+            Some(current) if current.id() == sink => {
+                self.offset.store(offset);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops playing every sink, so the ones set next play from the start
+    pub fn stop(&self) {
+        self.sinks.lock().clear();
+        self.offset.store(0);
+    }
+
     /// Optionally returns a sink to preload if necessary
     pub fn preload(&self) -> Option<SinkId> {
         let sinks: Vec<_> = self.sinks.lock().iter().cloned().collect();
@@ -43,13 +79,15 @@ impl Timeline {
 
         let available = total_available.saturating_sub(self.offset.load());
 
-        if available > PRELOAD_THRESHOLD {
+        // The next sink has to be loaded before the current one starts fading into it
+        if available > PRELOAD_THRESHOLD + self.crossfade.load() {
             return None;
         }
 
         sinks
             .into_iter()
-            .filter(|s| !s.is_complete())
+            // Relayed sinks are loaded by their relay
+            .filter(|s| !s.is_complete() && !s.is_relayed())
             .take(1)
             .find_map(|s| (!s.is_pending()).then(|| s.id()))
     }
@@ -69,12 +107,21 @@ impl Timeline {
 
         let mut remaining = amount;
         let mut offset = self.offset.load();
+        let mut previous: Option<&Sink> = None;
 
-        for sink in sinks {
+        for (i, sink) in sinks.iter().enumerate() {
             if remaining == 0 {
                 break;
             }
 
+            // Sinks that begin partway in skip ahead when they start playing,
+            // and the part that was mixed into the previous sink is not played again
+            if offset == 0 {
+                offset = sink.start() + previous.map_or(0, |p| self.overlap(p, Some(sink)));
+            }
+
+            let next = sinks.get(i + 1);
+
             let available = sink.available();
             let amount_ahead = available.saturating_sub(offset);
             let amount_to_read = amount_ahead.min(remaining);
@@ -85,12 +132,15 @@ impl Timeline {
                 sink: sink.clone(),
                 start_offset: offset,
                 end_offset: new_offset,
+                next: next.cloned(),
+                overlap: self.overlap(sink, next),
             });
 
             self.total_offset.fetch_add(amount_to_read);
             self.offset.store(new_offset);
 
             offset = 0;
+            previous = Some(sink);
 
             if !sink.is_complete() {
                 break;
@@ -99,6 +149,33 @@ impl Timeline {
 
         result
     }
+
+    /// Returns how many samples at the end of `sink` are mixed with the start of `next`,
+    /// or faded to silence if nothing plays next.
+    ///
+    /// This is at most half of either sink, so short sinks are never mixed with more than two others.
+    /// Sinks that are not fully loaded have no known end, so they do not fade,
+    /// and neither do sinks that are repeated.
+    pub fn overlap(&self, sink: &Sink, next: Option<&Sink>) -> usize {
+        let crossfade = self.crossfade.load();
+
+        if crossfade == 0 || self.repeating.load() || !sink.is_complete() || sink.is_relayed() {
+            return 0;
+        }
+
+        let length = sink.available().saturating_sub(sink.start());
+
+        let next_length = match next {
+            // Relays are live, so they can't be read ahead of
+            Some(next) if next.is_relayed() => return 0,
+            Some(next) if next.is_complete() => next.available().saturating_sub(next.start()),
+            Some(next) if next.expected() > 0 => next.expected().saturating_sub(next.start()),
+            _ => usize::MAX,
+        };
+
+        let overlap = crossfade.min(length / 2).min(next_length / 2);
+        overlap - overlap % CHANNEL_COUNT
+    }
 }
 
 #[derive(Debug)]
@@ -107,4 +184,8 @@ pub struct Advancement {
     pub(super) sink: Sink,
     pub(super) start_offset: usize,
     pub(super) end_offset: usize,
+    /// The sink that plays after this one, which is mixed in during the overlap
+    pub(super) next: Option<Sink>,
+    /// How many samples at the end of the sink are faded out, see [Timeline::overlap]
+    pub(super) overlap: usize,
 }
@@ -233,6 +233,9 @@ mod buffering {
         samples: RwLock<Vec<Sample>>,
         current_size: AtomicCell<usize>,
         max_size: usize,
+
+        /// Amount of samples at the start that have been discarded
+        discarded: AtomicCell<usize>,
     }
 
     impl Buffer {
@@ -246,12 +249,20 @@ mod buffering {
             Self {
                 samples: RwLock::new(samples),
                 current_size: AtomicCell::default(),
+                discarded: AtomicCell::default(),
                 max_size,
             }
         }
 
         pub fn read(&self, offset: usize, buf: &mut [Sample]) -> usize {
             let samples = self.samples.read().unwrap();
+            let discarded = self.discarded.load();
+
+            if offset < discarded {
+                return 0;
+            }
+
+            let offset = offset - discarded;
 
             let available = samples.len();
             let requested = buf.len();
@@ -271,12 +282,20 @@ mod buffering {
 
         pub fn write(&self, offset: usize, buf: &[Sample]) {
             let mut samples = self.samples.write().unwrap();
+            let discarded = self.discarded.load();
 
-            let amount = buf.len();
             let capacity = self.max_size;
 
-            let safe_start = offset.min(capacity);
-            let safe_end = (safe_start + amount).min(capacity);
+            // Samples before the discarded ones cannot be written anymore
+            let start = offset.max(discarded).min(capacity);
+            let end = (offset + buf.len()).min(capacity);
+
+            if end <= start {
+                return;
+            }
+
+            let safe_start = start - discarded;
+            let safe_end = end - discarded;
 
             self.allocate_if_necessary(&mut samples, safe_end);
             self.resize_if_necessary(&mut samples, safe_end);
@@ -284,7 +303,7 @@ mod buffering {
             let range = safe_start..safe_end;
             let amount_written = range.len();
 
-            samples[range].copy_from_slice(&buf[..amount_written]);
+            samples[range].copy_from_slice(&buf[start - offset..end - offset]);
             self.current_size.fetch_add(amount_written);
         }
 
@@ -297,7 +316,24 @@ mod buffering {
         }
 
         pub fn length(&self) -> usize {
-            self.samples.read().unwrap().len()
+            self.samples.read().unwrap().len() + self.discarded.load()
+        }
+
+        /// Frees samples before the offset. Reading them afterwards will return nothing.
+        ///
+        /// This is done in chunks, so it is cheap to call often.
+        pub fn discard(&self, offset: usize) {
+            let mut samples = self.samples.write().unwrap();
+
+            let discarded = self.discarded.load();
+            let amount = offset.saturating_sub(discarded).min(samples.len());
+
+            if amount < Self::CHUNK_SIZE {
+                return;
+            }
+
+            samples.drain(..amount);
+            self.discarded.fetch_add(amount);
         }
 
         pub fn clear(&self) {
@@ -332,6 +368,34 @@ mod buffering {
             write!(f, "{} Samples", self.current_size.load())
         }
     }
+
+    #[cfg(test)]
+    mod test {
+        use super::Buffer;
+
+        #[test]
+        fn discarded_offsets() {
+            let chunk = Buffer::CHUNK_SIZE;
+            let buffer = Buffer::new(chunk * 2);
+
+            let samples: Vec<_> = (0..chunk + 4).map(|x| x as f32).collect();
+            buffer.write_at_end(&samples);
+            buffer.discard(chunk);
+
+            // Offsets before the discarded samples read nothing
+            let mut buf = [0.; 4];
+            assert_eq!(buffer.read(0, &mut buf), 0);
+            assert_eq!(buffer.read(chunk - 2, &mut buf), 0);
+
+            assert_eq!(buffer.read(chunk, &mut buf), 4);
+            assert_eq!(buf, samples[chunk..]);
+
+            // Only the part after the discarded samples is written
+            buffer.write(chunk - 2, &[-1., -2., -3., -4.]);
+            assert_eq!(buffer.read(chunk, &mut buf), 4);
+            assert_eq!(buf, [-3., -4., samples[chunk + 2], samples[chunk + 3]]);
+        }
+    }
 }
 
 pub use buffering::Buffer;
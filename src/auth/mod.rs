@@ -1,7 +1,9 @@
 mod router;
 mod session;
+mod token;
 mod user;
 
 pub use router::router;
 pub use session::*;
+pub use token::*;
 pub use user::*;
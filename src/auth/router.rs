@@ -1,9 +1,9 @@
 use axum::{
-    extract::State,
-    routing::{get, post},
+    extract::{Path, State},
+    routing::{delete, get, post},
     Json,
 };
-use hyper::StatusCode;
+use hyper::{header, HeaderMap, StatusCode};
 use serde::Deserialize;
 use serde_json::{json, Value};
 
@@ -12,19 +12,45 @@ use crate::{
     util::ApiError,
 };
 
-use super::{Session, User};
+use super::{ApiToken, ApiTokenInfo, Session, SessionInfo, User};
 
 pub fn router() -> Router {
     Router::new()
         .route("/user", get(user))
+        .route("/me", get(me))
         .route("/register", post(register_new_user))
         .route("/login", post(login))
+        .route("/logout", post(logout))
+        .route("/sessions", get(sessions))
+        .route("/sessions/rotate", post(rotate_session))
+        .route("/refresh", post(refresh_session))
+        .route("/tokens", get(api_tokens).post(create_api_token))
+        .route("/tokens/:id", delete(revoke_api_token))
+        .route("/sessions/:id", delete(revoke_session))
+}
+
+/// Uses the user agent to describe where a session was created
+fn session_label(headers: &HeaderMap) -> Option<String> {
+    const MAX_LABEL_LENGTH: usize = 200;
+
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.chars().take(MAX_LABEL_LENGTH).collect())
 }
 
 async fn user(session: Session) -> Json<User> {
     Json(session.user)
 }
 
+/// Returns who the request is made as, and the session it is made with
+async fn me(session: Session) -> Json<Value> {
+    Json(json!({
+        "session": session.info(&session),
+        "user": session.user,
+    }))
+}
+
 #[derive(Debug, Deserialize)]
 struct RegisterBody {
     username: String,
@@ -33,10 +59,11 @@ struct RegisterBody {
 
 async fn register_new_user(
     State(context): Context,
+    headers: HeaderMap,
     Json(body): Json<RegisterBody>,
 ) -> Result<(StatusCode, Json<Value>), ApiError> {
     let user = User::create(&context.db, body.username, body.password).await?;
-    let session = Session::create(&context.db, &user).await?;
+    let session = Session::create(&context.db, &user, session_label(&headers)).await?;
 
     let result = json!({
         "token": session.token(),
@@ -54,13 +81,14 @@ struct LoginBody {
 
 async fn login(
     State(context): Context,
+    headers: HeaderMap,
     Json(body): Json<LoginBody>,
 ) -> Result<(StatusCode, Json<Value>), ApiError> {
     let user = User::get(&context.db, &body.username).await?;
     let is_valid = user.validate_password(&body.password);
 
     if is_valid {
-        let session = Session::create(&context.db, &user).await?;
+        let session = Session::create(&context.db, &user, session_label(&headers)).await?;
 
         let result = json!({
             "token": session.token(),
@@ -72,3 +100,104 @@ async fn login(
         Err(ApiError::Unauthorized)
     }
 }
+
+/// Ends the current session, so its token is rejected from now on
+async fn logout(session: Session, State(context): Context) -> Result<StatusCode, ApiError> {
+    session.delete(&context.db).await?;
+    context.rotated_tokens.forget(&session.token());
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn sessions(
+    session: Session,
+    State(context): Context,
+) -> Result<Json<Vec<SessionInfo>>, ApiError> {
+    let sessions = Session::all_for_user(&context.db, &session.user.id).await?;
+
+    Ok(Json(sessions.iter().map(|s| s.info(&session)).collect()))
+}
+
+/// Revokes a session. Revoking the current session logs the user out.
+async fn revoke_session(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    Session::revoke(&context.db, &session.user.id, &id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replaces the token of the current session.
+/// The old token can still be used to reconnect to streams for a short while.
+async fn rotate_session(
+    session: Session,
+    State(context): Context,
+) -> Result<Json<Value>, ApiError> {
+    let rotated = session.rotate(&context.db).await?;
+
+    context
+        .rotated_tokens
+        .insert(session.token(), rotated.token());
+
+    Ok(Json(json!({ "token": rotated.token() })))
+}
+
+/// Replaces the token of the current session, and extends how long it lasts.
+/// Like rotating, the old token can still be used to reconnect to streams for a short while.
+async fn refresh_session(
+    session: Session,
+    State(context): Context,
+) -> Result<Json<Value>, ApiError> {
+    let refreshed = session.refresh(&context.db).await?;
+
+    context
+        .rotated_tokens
+        .insert(session.token(), refreshed.token());
+
+    Ok(Json(json!({
+        "token": refreshed.token(),
+        "expiresAt": refreshed.expires_at(),
+    })))
+}
+
+async fn api_tokens(
+    session: Session,
+    State(context): Context,
+) -> Result<Json<Vec<ApiTokenInfo>>, ApiError> {
+    let tokens = ApiToken::all_for_user(&context.db, &session.user.id).await?;
+
+    Ok(Json(tokens.iter().map(|t| t.info()).collect()))
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateApiTokenBody {
+    name: String,
+}
+
+/// Creates an API token. This is the only time the token is returned.
+async fn create_api_token(
+    session: Session,
+    State(context): Context,
+    Json(body): Json<CreateApiTokenBody>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let (secret, token) = ApiToken::create(&context.db, &session.user, body.name).await?;
+
+    let result = json!({
+        "token": secret,
+        "info": token.info(),
+    });
+
+    Ok((StatusCode::CREATED, Json(result)))
+}
+
+async fn revoke_api_token(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    ApiToken::revoke(&context.db, &session.user.id, &id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -4,50 +4,131 @@ use axum::{
     extract::{FromRef, FromRequestParts, Query},
     RequestPartsExt,
 };
+use dashmap::DashMap;
 use hyper::{header, http::request::Parts, StatusCode};
+use log::info;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    time::{Duration, Instant},
+};
 use surrealdb::sql::Thing;
 use tokio::task::spawn_blocking;
 
 use crate::{
     db::{Database, Record},
-    util::ApiError,
+    util::{unix_millis, ApiError},
     VinylContext,
 };
 
-use super::user::User;
+use super::{
+    user::{User, UserId},
+    ApiToken,
+};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Session {
     pub id: Thing,
     pub user: User,
+
+    /// Identifies the session without revealing its token
+    #[serde(default)]
+    pub public_id: Option<String>,
+
+    /// Describes the device the session was created on
+    #[serde(default)]
+    pub label: Option<String>,
+
+    /// Milliseconds since the unix epoch
+    #[serde(default)]
+    pub created_at: u64,
+
+    /// Milliseconds since the unix epoch
+    #[serde(default)]
+    pub last_used: u64,
+
+    /// Milliseconds since the unix epoch, or 0 if the session was created before sessions expired
+    #[serde(default)]
+    pub expires_at: u64,
+
+    /// Private rooms the session was let into with their password
+    #[serde(default)]
+    pub joined_rooms: Vec<Thing>,
+}
+
+/// A session belonging to a superuser, rejecting anyone else
+#[derive(Clone, Debug)]
+pub struct Superuser(pub Session);
+
+/// A session that may also be identified by a token that was just rotated,
+/// so streams can reconnect while clients are switching tokens.
+#[derive(Clone, Debug)]
+pub struct StreamSession(pub Session);
+
+/// Remembers rotated tokens for a short while, see [StreamSession]
+#[derive(Debug)]
+pub struct RotatedTokens {
+    /// Maps old tokens to new ones, and when they were rotated
+    tokens: DashMap<String, (String, Instant)>,
+    grace: Duration,
+}
+
+#[derive(Serialize)]
+struct NewSession {
+    id: String,
+    user: Thing,
+    public_id: Option<String>,
+    label: Option<String>,
+    created_at: u64,
+    last_used: u64,
+    expires_at: u64,
+    joined_rooms: Vec<Thing>,
+}
+
+/// A session as shown to the user it belongs to
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    pub id: String,
+    pub label: Option<String>,
+    pub created_at: u64,
+    pub last_used: u64,
+    pub expires_at: u64,
+
+    /// True if this is the session making the request
+    pub current: bool,
 }
 
 impl Session {
-    pub async fn create(db: &Database, user: &User) -> Result<Self, ApiError> {
-        let user = user.id.clone();
+    /// How often the last used time is updated, to avoid writing on every request
+    const TOUCH_INTERVAL: u64 = 60 * 1000;
 
-        let token: String = spawn_blocking(|| {
-            let mut rng = thread_rng();
+    pub async fn create(
+        db: &Database,
+        user: &User,
+        label: Option<String>,
+    ) -> Result<Self, ApiError> {
+        let user = user.id.clone();
 
-            std::iter::repeat(())
-                .map(|_| rng.sample(Alphanumeric) as char)
-                .take(32)
-                .collect()
-        })
-        .await
-        .map_err(|e| ApiError::Other(e.into()))?;
+        let (token, public_id) = spawn_blocking(|| (random_string(32), random_string(16)))
+            .await
+            .map_err(|e| ApiError::Other(e.into()))?;
 
-        #[derive(Serialize)]
-        struct NewSession {
-            id: String,
-            user: Thing,
-        }
+        let now = unix_millis();
 
         let session: Record = db
             .create("session")
-            .content(NewSession { id: token, user })
+            .content(NewSession {
+                id: token,
+                user,
+                public_id: Some(public_id),
+                label,
+                created_at: now,
+                last_used: now,
+                expires_at: expiry(now),
+                joined_rooms: vec![],
+            })
             .await?;
 
         let session = Self::get(db, &session.id().to_string()).await?;
@@ -64,9 +145,239 @@ impl Session {
             .ok_or(ApiError::NotFound("session"))
     }
 
+    /// Returns all sessions belonging to a user
+    pub async fn all_for_user(db: &Database, user: &UserId) -> Result<Vec<Self>, ApiError> {
+        let sessions = db
+            .query("SELECT *, user.* FROM session WHERE user = $user ORDER BY last_used DESC")
+            .bind(("user", user))
+            .await?
+            .take::<Vec<Self>>(0)?;
+
+        Ok(sessions)
+    }
+
+    /// Deletes a session of a user, so its token can no longer be used
+    pub async fn revoke(db: &Database, user: &UserId, public_id: &str) -> Result<(), ApiError> {
+        let deleted = db
+            .query("DELETE session WHERE user = $user AND public_id = $public_id RETURN BEFORE")
+            .bind(("user", user))
+            .bind(("public_id", public_id))
+            .await?
+            .take::<Vec<Record>>(0)?;
+
+        if deleted.is_empty() {
+            return Err(ApiError::NotFound("session"));
+        }
+
+        Ok(())
+    }
+
+    /// Deletes this session, logging it out without affecting other sessions of the user.
+    /// If the session is an API token, the token is revoked.
+    pub async fn delete(&self, db: &Database) -> Result<(), ApiError> {
+        db.query("DELETE type::thing($tb, $id)")
+            .bind(("tb", &self.id.tb))
+            .bind(("id", self.token()))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    /// Replaces the token of the session, keeping everything else
+    pub async fn rotate(&self, db: &Database) -> Result<Self, ApiError> {
+        self.replace(db, self.expires_at()).await
+    }
+
+    /// Replaces the token of the session, and restarts its lifetime
+    pub async fn refresh(&self, db: &Database) -> Result<Self, ApiError> {
+        self.replace(db, expiry(unix_millis())).await
+    }
+
+    async fn replace(&self, db: &Database, expires_at: u64) -> Result<Self, ApiError> {
+        if self.is_api_token() {
+            return Err(ApiError::NotAllowed("Replacing an API token"));
+        }
+
+        let token = spawn_blocking(|| random_string(32))
+            .await
+            .map_err(|e| ApiError::Other(e.into()))?;
+
+        let session: Record = db
+            .create("session")
+            .content(NewSession {
+                id: token,
+                user: self.user.id.clone(),
+                public_id: self.public_id.clone(),
+                label: self.label.clone(),
+                created_at: self.created_at,
+                last_used: unix_millis(),
+                expires_at,
+                joined_rooms: self.joined_rooms.clone(),
+            })
+            .await?;
+
+        db.query("DELETE type::thing($tb, $id)")
+            .bind(("tb", "session"))
+            .bind(("id", self.token()))
+            .await?
+            .check()?;
+
+        Self::get(db, &session.id().to_string()).await
+    }
+
+    /// Lets the session into a private room, until it is logged out
+    pub async fn join_room(&mut self, db: &Database, room: &Thing) -> Result<(), ApiError> {
+        if self.joined_rooms.contains(room) {
+            return Ok(());
+        }
+
+        let mut joined_rooms = self.joined_rooms.clone();
+        joined_rooms.push(room.clone());
+
+        // API tokens are stored in their own table, which the id points to
+        db.query("UPDATE $session SET joined_rooms = $joined_rooms")
+            .bind(("session", &self.id))
+            .bind(("joined_rooms", &joined_rooms))
+            .await?
+            .check()?;
+
+        self.joined_rooms = joined_rooms;
+
+        Ok(())
+    }
+
+    /// Marks the session as used, giving it a public id if it was created without one
+    async fn touch(&mut self, db: &Database) -> Result<(), ApiError> {
+        let now = unix_millis();
+
+        if self.public_id.is_some() && now.saturating_sub(self.last_used) < Self::TOUCH_INTERVAL {
+            return Ok(());
+        }
+
+        let public_id = self.public_id.clone().unwrap_or_else(|| random_string(16));
+
+        db.query("UPDATE type::thing($tb, $id) SET last_used = $now, public_id = $public_id")
+            .bind(("tb", "session"))
+            .bind(("id", self.token()))
+            .bind(("now", now))
+            .bind(("public_id", &public_id))
+            .await?
+            .check()?;
+
+        self.public_id = Some(public_id);
+        self.last_used = now;
+
+        Ok(())
+    }
+
+    /// Returns when the session expires, counting from its creation if it has no expiry yet
+    pub fn expires_at(&self) -> u64 {
+        if self.expires_at == 0 {
+            return expiry(self.created_at);
+        }
+
+        self.expires_at
+    }
+
+    /// Returns true if the session was made from an [ApiToken]
+    pub fn is_api_token(&self) -> bool {
+        self.id.tb == ApiToken::TABLE
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at()
+    }
+
     pub fn token(&self) -> String {
         self.id.id.to_string()
     }
+
+    pub fn info(&self, current: &Session) -> SessionInfo {
+        SessionInfo {
+            id: self.public_id.clone().unwrap_or_default(),
+            label: self.label.clone(),
+            created_at: self.created_at,
+            last_used: self.last_used,
+            expires_at: self.expires_at(),
+            current: self.id == current.id,
+        }
+    }
+}
+
+impl RotatedTokens {
+    const DEFAULT_GRACE: Duration = Duration::from_secs(30);
+
+    /// How long an old token keeps working after it was rotated
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    pub fn insert(&self, old: String, new: String) {
+        if self.grace.is_zero() {
+            return;
+        }
+
+        self.tokens.insert(old, (new, Instant::now()));
+    }
+
+    /// Returns the token that replaced `old`, if it was rotated recently enough
+    pub fn resolve(&self, old: &str) -> Option<String> {
+        self.tokens
+            .retain(|_, (_, rotated_at)| rotated_at.elapsed() < self.grace);
+
+        self.tokens.get(old).map(|x| x.0.clone())
+    }
+
+    /// Forgets every token that was rotated into `token`, so they stop working along with it
+    pub fn forget(&self, token: &str) {
+        self.tokens.retain(|_, (new, _)| new != token);
+    }
+}
+
+impl Default for RotatedTokens {
+    /// The grace window is set with `VINYL_SESSION_GRACE_SECS`, and 0 disables it.
+    fn default() -> Self {
+        let grace = env::var("VINYL_SESSION_GRACE_SECS")
+            .map(|x| x.parse::<u64>().expect("Grace window must be a number"))
+            .map(Duration::from_secs)
+            .unwrap_or(Self::DEFAULT_GRACE);
+
+        Self {
+            tokens: Default::default(),
+            grace,
+        }
+    }
+}
+
+/// Returns how long sessions last before they have to be refreshed,
+/// set with `VINYL_SESSION_LIFETIME_SECS`
+pub fn session_lifetime() -> Duration {
+    const DEFAULT_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    env::var("VINYL_SESSION_LIFETIME_SECS")
+        .map(|x| {
+            x.parse::<u64>()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .expect("Session lifetime must be a positive number of seconds")
+        })
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_LIFETIME)
+}
+
+/// Returns when a session created or refreshed at `now` expires
+fn expiry(now: u64) -> u64 {
+    now.saturating_add(session_lifetime().as_millis() as u64)
+}
+
+pub(super) fn random_string(length: usize) -> String {
+    let mut rng = thread_rng();
+
+    std::iter::repeat(())
+        .map(|_| rng.sample(Alphanumeric) as char)
+        .take(length)
+        .collect()
 }
 
 #[derive(Deserialize)]
@@ -74,6 +385,47 @@ struct TokenQuery {
     token: String,
 }
 
+/// Gets the token from the authorization header, or the query if it is not there
+async fn token_from_parts(parts: &mut Parts) -> Result<String, (StatusCode, &'static str)> {
+    let in_query = parts
+        .extract::<Query<TokenQuery>>()
+        .await
+        .ok()
+        .map(|x| format!("Bearer {}", x.token));
+
+    let token = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .or(in_query.as_deref())
+        .ok_or((StatusCode::UNAUTHORIZED, "Missing authorization"))?;
+
+    let parts: Vec<_> = token.split_ascii_whitespace().collect();
+
+    if parts.first() != Some(&"Bearer") {
+        return Err((StatusCode::BAD_REQUEST, "Authorization must be Bearer"));
+    }
+
+    Ok(parts.last().cloned().unwrap_or_default().to_string())
+}
+
+/// Authenticates with an API token instead of a session token
+async fn api_token_session(
+    db: &Database,
+    token: &str,
+) -> Result<Session, (StatusCode, &'static str)> {
+    let mut token = ApiToken::get(db, token)
+        .await
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "Token does not exist"))?;
+
+    token
+        .touch(db)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to update token"))?;
+
+    Ok(token.into_session())
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for Session
 where
@@ -85,30 +437,159 @@ where
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         let context = VinylContext::from_ref(state);
 
-        let in_query = parts
-            .extract::<Query<TokenQuery>>()
+        let token = token_from_parts(parts).await?;
+
+        if ApiToken::is_api_token(&token) {
+            return api_token_session(&context.db, &token).await;
+        }
+
+        let mut session = Self::get(&context.db, &token)
             .await
-            .ok()
-            .map(|x| format!("Bearer {}", x.token));
+            .map_err(|_| (StatusCode::UNAUTHORIZED, "Session does not exist"))?;
 
-        let token = parts
-            .headers
-            .get(header::AUTHORIZATION)
-            .and_then(|x| x.to_str().ok())
-            .or(in_query.as_deref())
-            .ok_or((StatusCode::UNAUTHORIZED, "Missing authorization"))?;
+        if session.is_expired(unix_millis()) {
+            return Err((StatusCode::UNAUTHORIZED, "Session has expired"));
+        }
 
-        let parts: Vec<_> = token.split_ascii_whitespace().collect();
+        session.touch(&context.db).await.map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to update session",
+            )
+        })?;
+
+        Ok(session)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Superuser
+where
+    VinylContext: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
 
-        if parts.first() != Some(&"Bearer") {
-            return Err((StatusCode::BAD_REQUEST, "Authorization must be Bearer"));
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let session = Session::from_request_parts(parts, state).await?;
+
+        if !session.user.superuser {
+            return Err((StatusCode::FORBIDDEN, "Only superusers can do this"));
         }
 
-        let token = parts.last().cloned().unwrap_or_default();
-        let session = Self::get(&context.db, token)
+        Ok(Self(session))
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for StreamSession
+where
+    VinylContext: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let context = VinylContext::from_ref(state);
+
+        let token = token_from_parts(parts).await?;
+
+        if ApiToken::is_api_token(&token) {
+            return api_token_session(&context.db, &token).await.map(Self);
+        }
+
+        if let Ok(session) = Session::get(&context.db, &token).await {
+            if session.is_expired(unix_millis()) {
+                return Err((StatusCode::UNAUTHORIZED, "Session has expired"));
+            }
+
+            return Ok(Self(session));
+        }
+
+        let rotated = context
+            .rotated_tokens
+            .resolve(&token)
+            .ok_or((StatusCode::UNAUTHORIZED, "Session does not exist"))?;
+
+        let session = Session::get(&context.db, &rotated)
             .await
             .map_err(|_| (StatusCode::UNAUTHORIZED, "Session does not exist"))?;
 
-        Ok(session)
+        if session.is_expired(unix_millis()) {
+            return Err((StatusCode::UNAUTHORIZED, "Session has expired"));
+        }
+
+        info!(target: "vinyl::server",
+            "Accepted a rotated token for {} reconnecting to a stream",
+            session.user.username
+        );
+
+        Ok(Self(session))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{expiry, session_lifetime, RotatedTokens, Session};
+    use crate::auth::User;
+
+    fn session(created_at: u64, expires_at: u64) -> Session {
+        Session {
+            id: User::mock("session").id,
+            user: User::mock("user"),
+            public_id: None,
+            label: None,
+            created_at,
+            last_used: created_at,
+            expires_at,
+            joined_rooms: vec![],
+        }
+    }
+
+    #[test]
+    fn rejects_expired_sessions() {
+        let current = session(1000, 5000);
+
+        assert!(!current.is_expired(4999));
+        assert!(current.is_expired(5000));
+
+        // Sessions from before expiry count from their creation
+        let lifetime = session_lifetime().as_millis() as u64;
+        let old = session(1000, 0);
+
+        assert!(!old.is_expired(1000 + lifetime - 1));
+        assert!(old.is_expired(1000 + lifetime));
+    }
+
+    #[test]
+    fn refresh_extends_expiry() {
+        let lifetime = session_lifetime().as_millis() as u64;
+        let original = session(1000, expiry(1000));
+
+        let now = 1000 + lifetime - 1;
+        let refreshed = session(1000, expiry(now));
+
+        assert!(original.is_expired(now + 1));
+        assert!(!refreshed.is_expired(now + 1));
+        assert_eq!(refreshed.expires_at(), now + lifetime);
+    }
+
+    #[test]
+    fn forgets_rotated_tokens_of_logged_out_session() {
+        let tokens = RotatedTokens {
+            tokens: Default::default(),
+            grace: Duration::from_secs(30),
+        };
+
+        tokens.insert("old".to_string(), "new".to_string());
+        tokens.insert("other".to_string(), "another".to_string());
+        assert_eq!(tokens.resolve("old").as_deref(), Some("new"));
+
+        tokens.forget("new");
+
+        assert_eq!(tokens.resolve("old"), None);
+        assert_eq!(tokens.resolve("other").as_deref(), Some("another"));
     }
 }
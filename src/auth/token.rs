@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surrealdb::sql::Thing;
+use tokio::task::spawn_blocking;
+
+use crate::{
+    db::{Database, Record},
+    util::{unix_millis, ApiError},
+};
+
+use super::{random_string, Session, User, UserId};
+
+/// A long-lived token for clients that cannot log in interactively, like bots.
+/// Only a hash of the token is stored, so it is shown once when created and never again.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiToken {
+    /// The hash of the token
+    pub id: Thing,
+    pub user: User,
+
+    /// Identifies the token without revealing it
+    pub public_id: String,
+    pub name: String,
+
+    /// Milliseconds since the unix epoch
+    pub created_at: u64,
+
+    /// Milliseconds since the unix epoch
+    pub last_used: u64,
+
+    /// Private rooms the token was let into, see [Session::joined_rooms]
+    #[serde(default)]
+    pub joined_rooms: Vec<Thing>,
+}
+
+#[derive(Serialize)]
+struct NewApiToken {
+    id: String,
+    user: Thing,
+    public_id: String,
+    name: String,
+    created_at: u64,
+    last_used: u64,
+}
+
+/// An API token as shown to the user it belongs to
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub created_at: u64,
+    pub last_used: u64,
+}
+
+impl ApiToken {
+    pub const TABLE: &str = "api_token";
+
+    /// Distinguishes API tokens from session tokens, which are only alphanumeric
+    const PREFIX: &str = "vinyl_";
+    const MAX_NAME_LENGTH: usize = 100;
+    const TOUCH_INTERVAL: u64 = 60 * 1000;
+
+    /// Creates a token, returning it along with the secret that authenticates it
+    pub async fn create(
+        db: &Database,
+        user: &User,
+        name: String,
+    ) -> Result<(String, Self), ApiError> {
+        let name = name.trim().to_string();
+
+        if name.is_empty() || name.chars().count() > Self::MAX_NAME_LENGTH {
+            return Err(ApiError::Invalid("Name"));
+        }
+
+        let (secret, public_id) = spawn_blocking(|| {
+            (
+                format!("{}{}", Self::PREFIX, random_string(40)),
+                random_string(16),
+            )
+        })
+        .await
+        .map_err(|e| ApiError::Other(e.into()))?;
+
+        let now = unix_millis();
+
+        let _: Record = db
+            .create(Self::TABLE)
+            .content(NewApiToken {
+                id: Self::hash(&secret),
+                user: user.id.clone(),
+                public_id,
+                name,
+                created_at: now,
+                last_used: now,
+            })
+            .await?;
+
+        let token = Self::get(db, &secret).await?;
+
+        Ok((secret, token))
+    }
+
+    /// Gets the token authenticated by `secret`
+    pub async fn get(db: &Database, secret: &str) -> Result<Self, ApiError> {
+        db.query("SELECT *, user.* FROM type::thing($tb, $id)")
+            .bind(("tb", Self::TABLE))
+            .bind(("id", Self::hash(secret)))
+            .await?
+            .take::<Option<Self>>(0)?
+            .ok_or(ApiError::NotFound("token"))
+    }
+
+    /// Returns all tokens belonging to a user
+    pub async fn all_for_user(db: &Database, user: &UserId) -> Result<Vec<Self>, ApiError> {
+        let tokens = db
+            .query("SELECT *, user.* FROM api_token WHERE user = $user ORDER BY created_at DESC")
+            .bind(("user", user))
+            .await?
+            .take::<Vec<Self>>(0)?;
+
+        Ok(tokens)
+    }
+
+    /// Deletes a token of a user, so it can no longer be used
+    pub async fn revoke(db: &Database, user: &UserId, public_id: &str) -> Result<(), ApiError> {
+        let deleted = db
+            .query("DELETE api_token WHERE user = $user AND public_id = $public_id RETURN BEFORE")
+            .bind(("user", user))
+            .bind(("public_id", public_id))
+            .await?
+            .take::<Vec<Record>>(0)?;
+
+        if deleted.is_empty() {
+            return Err(ApiError::NotFound("token"));
+        }
+
+        Ok(())
+    }
+
+    /// Marks the token as used, at most once every [ApiToken::TOUCH_INTERVAL]
+    pub async fn touch(&mut self, db: &Database) -> Result<(), ApiError> {
+        let now = unix_millis();
+
+        if now.saturating_sub(self.last_used) < Self::TOUCH_INTERVAL {
+            return Ok(());
+        }
+
+        db.query("UPDATE $token SET last_used = $now")
+            .bind(("token", &self.id))
+            .bind(("now", now))
+            .await?
+            .check()?;
+
+        self.last_used = now;
+
+        Ok(())
+    }
+
+    /// Returns true if `token` looks like an API token rather than a session token
+    pub fn is_api_token(token: &str) -> bool {
+        token.starts_with(Self::PREFIX)
+    }
+
+    fn hash(secret: &str) -> String {
+        Sha256::digest(secret.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Turns the token into a session that never expires, so it can be used like any other
+    pub fn into_session(self) -> Session {
+        Session {
+            id: self.id,
+            user: self.user,
+            public_id: Some(self.public_id),
+            label: Some(self.name),
+            created_at: self.created_at,
+            last_used: self.last_used,
+            expires_at: u64::MAX,
+            joined_rooms: self.joined_rooms,
+        }
+    }
+
+    pub fn info(&self) -> ApiTokenInfo {
+        ApiTokenInfo {
+            id: self.public_id.clone(),
+            name: self.name.clone(),
+            created_at: self.created_at,
+            last_used: self.last_used,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use surrealdb::sql::Thing;
+
+    use super::ApiToken;
+    use crate::auth::User;
+
+    fn token(secret: &str) -> ApiToken {
+        ApiToken {
+            id: Thing {
+                tb: ApiToken::TABLE.to_string(),
+                id: ApiToken::hash(secret).into(),
+            },
+            user: User::mock("bot"),
+            public_id: "public".to_string(),
+            name: "Bot".to_string(),
+            created_at: 1000,
+            last_used: 1000,
+            joined_rooms: vec![],
+        }
+    }
+
+    #[test]
+    fn hashes_secrets() {
+        let hash = ApiToken::hash("vinyl_secret");
+
+        assert_eq!(hash.len(), 64);
+        assert_ne!(hash, "vinyl_secret");
+        assert_eq!(hash, ApiToken::hash("vinyl_secret"));
+        assert_ne!(hash, ApiToken::hash("vinyl_secreT"));
+    }
+
+    #[test]
+    fn authenticates_as_user() {
+        assert!(ApiToken::is_api_token("vinyl_secret"));
+        assert!(!ApiToken::is_api_token("abcDEF123"));
+
+        let session = token("vinyl_secret").into_session();
+
+        assert_eq!(session.user.id, User::mock("bot").id);
+        assert!(session.is_api_token());
+        assert!(!session.is_expired(u64::MAX - 1));
+
+        // The secret is never part of the session
+        assert_ne!(session.token(), "vinyl_secret");
+    }
+}
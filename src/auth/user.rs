@@ -1,4 +1,7 @@
-use crate::{db::Database, util::ApiError};
+use crate::{
+    db::Database,
+    util::{ApiError, ID_COUNTER},
+};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use surrealdb::sql::Thing;
@@ -19,6 +22,10 @@ pub struct User {
     #[serde(skip_serializing)]
     pub password: String,
     pub display_name: String,
+
+    /// Superusers can moderate and curate anything
+    #[serde(default)]
+    pub superuser: bool,
 }
 
 impl User {
@@ -27,12 +34,7 @@ impl User {
         username: String,
         password: String,
     ) -> Result<Self, ApiError> {
-        let salt = SaltString::generate(&mut OsRng);
-
-        let hashed_password = Scrypt
-            .hash_password(password.as_bytes(), &salt)
-            .map_err(|e| ApiError::Other(e.into()))?
-            .to_string();
+        let hashed_password = hash_password(&password)?;
 
         let user: User = db
             .create("user")
@@ -57,8 +59,24 @@ impl User {
     }
 
     pub fn validate_password(&self, incoming: &str) -> bool {
-        let hashed = PasswordHash::new(&self.password).expect("create password hash");
-        Scrypt.verify_password(incoming.as_bytes(), &hashed).is_ok()
+        verify_password(&self.password, incoming)
+    }
+
+    /// A user for someone listening to a public stream without a session.
+    /// Every guest is distinct, so each of them counts as a listener.
+    pub fn guest() -> User {
+        let id = ID_COUNTER.fetch_add(1);
+
+        User {
+            id: Thing {
+                tb: "guest".to_string(),
+                id: id.to_string().into(),
+            },
+            username: format!("guest-{}", id),
+            password: String::new(),
+            display_name: "Guest".to_string(),
+            superuser: false,
+        }
     }
 
     #[cfg(test)]
@@ -73,6 +91,22 @@ impl User {
             username: name.clone(),
             password: "your mom".to_string(),
             display_name: name,
+            superuser: false,
         }
     }
 }
+
+/// Hashes a password with a random salt, so it can be stored and checked with [verify_password]
+pub fn hash_password(password: &str) -> Result<String, ApiError> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Scrypt
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| ApiError::Other(e.into()))
+}
+
+pub fn verify_password(hash: &str, incoming: &str) -> bool {
+    let hashed = PasswordHash::new(hash).expect("create password hash");
+    Scrypt.verify_password(incoming.as_bytes(), &hashed).is_ok()
+}
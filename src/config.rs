@@ -0,0 +1,147 @@
+use std::panic;
+
+use colored::Colorize;
+use log::{error, info};
+use tokio::runtime;
+
+use crate::{
+    audio, auth, db, ingest,
+    logging::{self, LogColor},
+    queue, rooms, server, track,
+};
+
+/// A setting read from the environment, and how to resolve it
+type Check = (&'static str, fn() -> String);
+
+const CHECKS: [Check; 27] = [
+    ("Server port", || server::port().to_string()),
+    ("CORS origins", || format!("{:?}", server::cors_origins())),
+    ("Duplicate cooldown", || {
+        format!("{:?}", server::duplicate_cooldown())
+    }),
+    ("Add rate limit", || match server::add_rate_limit() {
+        0 => "Unlimited".to_string(),
+        n => format!("{} per minute", n),
+    }),
+    ("Session grace", || {
+        format!("{:?}", auth::RotatedTokens::default().grace())
+    }),
+    ("Session lifetime", || {
+        format!("{:?}", auth::session_lifetime())
+    }),
+    ("URL refresh interval", || {
+        format!("{:?}", track::refresh_interval())
+    }),
+    ("Stream output", || {
+        format!("{:?}", rooms::init_output_config())
+    }),
+    ("Connection policy", || {
+        format!("{:?}", rooms::init_connection_policy())
+    }),
+    ("Output format", || {
+        format!("{:?}", audio::init_output_format())
+    }),
+    ("Normalization", || {
+        format!("{:?}", audio::init_normalization_config())
+    }),
+    ("Ducking", || format!("{:?}", audio::init_ducking_config())),
+    ("Playlist limit", || {
+        format!("{:?}", ingest::init_playlist_limit())
+    }),
+    ("History limit", || {
+        format!("{:?}", queue::init_history_limit())
+    }),
+    ("yt-dlp", || format!("{:?}", ingest::init_ytdlp_path())),
+    // Only whether cookies are used, since the path leads to a logged in session
+    ("yt-dlp cookies", || match ingest::init_ytdlp_cookies() {
+        true => "Enabled".to_string(),
+        false => "Disabled".to_string(),
+    }),
+    ("yt-dlp attempts", || {
+        format!("{:?}", ingest::init_ytdlp_attempts())
+    }),
+    ("yt-dlp jobs", || format!("{:?}", ingest::init_ytdlp_jobs())),
+    ("Outdated yt-dlp", || {
+        format!("{:?}", ingest::outdated_ytdlp())
+    }),
+    ("yt-dlp format", || {
+        format!("{:?}", ingest::init_audio_format())
+    }),
+    ("Stream URL max age", || {
+        format!("{:?}", ingest::init_stream_url_max_age())
+    }),
+    ("Audio cache", || format!("{:?}", ingest::cache_config())),
+    ("Metrics", || server::metrics_enabled().to_string()),
+    ("Event history", || server::sse::history_size().to_string()),
+    ("Event keep-alive", || {
+        format!("{:?}", server::sse::keep_alive_interval())
+    }),
+    ("Log format", || format!("{:?}", logging::log_format())),
+    ("Database", db::describe),
+];
+
+/// Resolves every setting and prints a summary, without starting the server.
+/// The database is only connected to if `check_db` is true, so this works without one.
+///
+/// Returns true if everything is valid.
+pub fn check(check_db: bool) -> bool {
+    let mut valid = true;
+
+    // Invalid settings panic, and the message is reported below instead
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    for (name, resolve) in CHECKS {
+        match panic::catch_unwind(resolve) {
+            Ok(resolved) => info!(target: "vinyl", "{}: {}", name, resolved),
+            Err(payload) => {
+                valid = false;
+
+                let reason = payload
+                    .downcast_ref::<&str>()
+                    .map(|x| x.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "Unknown error".to_string());
+
+                error!(target: "vinyl", "{}: {}", name, reason.color(LogColor::Red));
+            }
+        }
+    }
+
+    panic::set_hook(hook);
+
+    if check_db {
+        valid &= check_database();
+    }
+
+    if valid {
+        info!(target: "vinyl", "{}", "Configuration is valid.".bold());
+    } else {
+        error!(target: "vinyl",
+            "{}",
+            "Configuration is invalid!".bold().color(LogColor::Red)
+        );
+    }
+
+    valid
+}
+
+fn check_database() -> bool {
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build runtime for database check");
+
+    let result = runtime.block_on(db::connect());
+
+    match result {
+        Ok(_) => {
+            info!(target: "vinyl", "Connected to database.");
+            true
+        }
+        Err(err) => {
+            error!(target: "vinyl", "Could not connect to database: {}", err);
+            false
+        }
+    }
+}
@@ -9,6 +9,12 @@ use thiserror::Error as ThisError;
 
 pub type Database = Surreal<Client>;
 
+const ADDRESS: &str = "127.0.0.1:8000";
+const USERNAME: &str = "root";
+const PASSWORD: &str = "root";
+const NAMESPACE: &str = "vinyl";
+const DATABASE: &str = "main";
+
 #[derive(Debug, ThisError)]
 pub enum Error {
     #[error(transparent)]
@@ -25,19 +31,27 @@ pub enum Error {
 }
 
 pub async fn connect() -> Result<Database, surrealdb::Error> {
-    let db = Surreal::new::<Ws>("127.0.0.1:8000").await?;
+    let db = Surreal::new::<Ws>(ADDRESS).await?;
 
     db.signin(Root {
-        username: "root",
-        password: "root",
+        username: USERNAME,
+        password: PASSWORD,
     })
     .await?;
 
-    db.use_ns("vinyl").use_db("main").await?;
+    db.use_ns(NAMESPACE).use_db(DATABASE).await?;
 
     Ok(db)
 }
 
+/// Describes where the database is, without the password
+pub fn describe() -> String {
+    format!(
+        "{}@{} ({}/{}, password redacted)",
+        USERNAME, ADDRESS, NAMESPACE, DATABASE
+    )
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Record {
     #[allow(dead_code)]
@@ -1,7 +1,8 @@
-
-
 mod new {
-    use std::sync::{Arc, Weak};
+    use std::{
+        sync::{Arc, Weak},
+        time::Duration,
+    };
 
     use crossbeam::channel::{unbounded, Receiver, Sender};
     use parking_lot::Mutex;
@@ -10,6 +11,12 @@ mod new {
     pub trait Gateway<E> {
         fn emit(&self, event: E);
         fn poll(&self) -> E;
+
+        /// Like [Gateway::poll], but gives up after `timeout`
+        fn poll_timeout(&self, timeout: Duration) -> Option<E>;
+
+        /// Returns how many events are waiting to be polled
+        fn backlog(&self) -> usize;
     }
 
     /// A bus manages the registration of handlers and emitters.
@@ -38,6 +45,12 @@ mod new {
             self.gateway.emit(event.into_event());
         }
 
+        /// Returns how many events are waiting to be handled.
+        /// If this keeps growing, handlers are too slow to keep up.
+        pub fn backlog(&self) -> usize {
+            self.gateway.backlog()
+        }
+
         /// Create an emitter that can dispatch to this bus
         pub fn emitter(&self) -> Emitter<G, E> {
             Emitter(self.me.clone())
@@ -59,6 +72,49 @@ mod new {
             E: Clone,
         {
             let event = self.gateway.poll();
+            self.handle(event);
+        }
+
+        /// Like [Bus::tick], but returns after `timeout` if no event arrived,
+        /// so the loop calling it can stop.
+        pub fn tick_timeout(&self, timeout: Duration)
+        where
+            E: Clone,
+        {
+            if let Some(event) = self.gateway.poll_timeout(timeout) {
+                self.handle(event);
+            }
+        }
+
+        /// Handles events until `stopped` returns true, then drains the rest.
+        ///
+        /// This blocks on the gateway between events, only waking up every `interval`
+        /// when idle to check whether it should stop, so an idle bus uses no CPU.
+        pub fn run(&self, interval: Duration, stopped: impl Fn() -> bool)
+        where
+            E: Clone,
+        {
+            while !stopped() {
+                self.tick_timeout(interval);
+            }
+
+            self.drain();
+        }
+
+        /// Handles every event that is already waiting, used before shutting down
+        pub fn drain(&self)
+        where
+            E: Clone,
+        {
+            while self.backlog() > 0 {
+                self.tick();
+            }
+        }
+
+        fn handle(&self, event: E)
+        where
+            E: Clone,
+        {
             let handlers = self.handlers.lock();
 
             for handler in &*handlers {
@@ -84,6 +140,14 @@ mod new {
                 .expect("upgrade event bus in emitter")
                 .dispatch(event)
         }
+
+        /// Returns how many events are waiting to be handled by the bus
+        pub fn backlog(&self) -> usize {
+            self.0
+                .upgrade()
+                .expect("upgrade event bus in emitter")
+                .backlog()
+        }
     }
 
     impl<G, E> Clone for Emitter<G, E> {
@@ -177,13 +241,29 @@ mod new {
                 .recv()
                 .expect("channel gateway receives event")
         }
+
+        fn poll_timeout(&self, timeout: Duration) -> Option<E> {
+            self.receiver.recv_timeout(timeout).ok()
+        }
+
+        /// This does not lock, so it is cheap to call often
+        fn backlog(&self) -> usize {
+            self.receiver.len()
+        }
     }
 
     #[cfg(test)]
     mod test {
-        use super::{Bus, Channel, Filter, Handler, IntoEvent};
+        use super::{Bus, Channel, Filter, Gateway, Handler, IntoEvent};
         use parking_lot::Mutex;
-        use std::{sync::Arc, thread, time::Duration};
+        use std::{
+            sync::{
+                atomic::{AtomicBool, AtomicUsize, Ordering},
+                Arc,
+            },
+            thread,
+            time::{Duration, Instant},
+        };
 
         #[derive(Debug, Clone)]
         enum WeatherEvent {
@@ -271,6 +351,113 @@ mod new {
             thread::sleep(Duration::from_millis(10));
         }
 
+        #[test]
+        fn test_backlog() {
+            let channel: Channel<Event> = Channel::new();
+            let bus = Bus::new(channel);
+            let emitter = bus.emitter();
+
+            emitter.dispatch(TimeEvent::Day);
+            emitter.dispatch(TimeEvent::Night);
+            emitter.dispatch(WeatherEvent::Rain);
+
+            assert_eq!(emitter.backlog(), 3);
+
+            bus.tick();
+            assert_eq!(bus.backlog(), 2);
+        }
+
+        #[test]
+        fn test_drain() {
+            let channel: Channel<Event> = Channel::new();
+            let bus = Bus::new(channel);
+            let emitter = bus.emitter();
+
+            // Returns without an event instead of blocking
+            bus.tick_timeout(Duration::from_millis(1));
+
+            emitter.dispatch(TimeEvent::Day);
+            emitter.dispatch(WeatherEvent::Sunny);
+
+            bus.drain();
+            assert_eq!(bus.backlog(), 0);
+        }
+
+        /// A channel that counts how often it was polled
+        #[derive(Default)]
+        struct CountingChannel {
+            channel: Channel<Event>,
+            polls: Arc<AtomicUsize>,
+        }
+
+        impl Gateway<Event> for CountingChannel {
+            fn emit(&self, event: Event) {
+                self.channel.emit(event)
+            }
+
+            fn poll(&self) -> Event {
+                self.polls.fetch_add(1, Ordering::Relaxed);
+                self.channel.poll()
+            }
+
+            fn poll_timeout(&self, timeout: Duration) -> Option<Event> {
+                self.polls.fetch_add(1, Ordering::Relaxed);
+                self.channel.poll_timeout(timeout)
+            }
+
+            fn backlog(&self) -> usize {
+                self.channel.backlog()
+            }
+        }
+
+        #[test]
+        fn test_run_blocks_when_idle() {
+            let channel = CountingChannel::default();
+            let polls = channel.polls.clone();
+            let bus = Bus::new(channel);
+            let emitter = bus.emitter();
+
+            let time_message = Arc::new(Mutex::new("My clock is broken!".to_string()));
+            bus.register(TimeHandler {
+                message: time_message.clone(),
+            });
+
+            let stopped = Arc::new(AtomicBool::new(false));
+            let runner = {
+                let stopped = stopped.clone();
+                thread::spawn(move || {
+                    bus.run(Duration::from_millis(200), || {
+                        stopped.load(Ordering::Relaxed)
+                    })
+                })
+            };
+
+            // Idling for 3 intervals only wakes up about once per interval
+            thread::sleep(Duration::from_millis(600));
+            let idle_polls = polls.load(Ordering::Relaxed);
+            assert!(idle_polls <= 5, "polled {idle_polls} times while idle");
+
+            // An event is still handled right away instead of at the next interval
+            let start = Instant::now();
+            emitter.dispatch(TimeEvent::Day);
+
+            while *time_message.lock() != "It seems to be noon. I feel so sleepy." {
+                assert!(start.elapsed() < Duration::from_millis(100));
+                thread::yield_now();
+            }
+
+            // Events emitted before stopping are still handled, in order
+            emitter.dispatch(TimeEvent::Day);
+            emitter.dispatch(TimeEvent::Night);
+            stopped.store(true, Ordering::Relaxed);
+            runner.join().unwrap();
+
+            assert_eq!(
+                *time_message.lock(),
+                "Sun is going down? Time to get shit done!"
+            );
+        }
+
         #[test]
         fn test_event_system() {
             let channel: Channel<Event> = Channel::new();
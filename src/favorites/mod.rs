@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::User,
+    db::{Database, Record},
+    track::{Metadata, Track},
+    util::{unix_millis, ApiError},
+};
+
+mod router;
+
+pub use router::router;
+
+/// A track picked by a superuser, shown in a public feed
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Favorite {
+    pub fingerprint: String,
+
+    /// Stored so the favorite can be shown even if the source disappears
+    pub metadata: Metadata,
+
+    /// Username of the superuser who picked this
+    pub favorited_by: String,
+
+    /// Milliseconds since the unix epoch
+    pub created_at: u64,
+}
+
+impl Favorite {
+    /// Favorites a track, replacing any favorite with the same fingerprint
+    pub async fn create(db: &Database, track: &Track, user: &User) -> Result<Self, ApiError> {
+        let fingerprint = track.fingerprint();
+
+        if fingerprint.is_empty() {
+            return Err(ApiError::Invalid("Track"));
+        }
+
+        let favorite = Self {
+            fingerprint,
+            metadata: track.metadata.clone(),
+            favorited_by: user.username.clone(),
+            created_at: unix_millis(),
+        };
+
+        db.query("UPDATE type::thing($tb, $id) CONTENT $favorite")
+            .bind(("tb", "favorite"))
+            .bind(("id", &favorite.fingerprint))
+            .bind(("favorite", &favorite))
+            .await?
+            .check()?;
+
+        Ok(favorite)
+    }
+
+    pub async fn all(db: &Database) -> Result<Vec<Self>, ApiError> {
+        let favorites = db
+            .query("SELECT * FROM favorite ORDER BY createdAt DESC")
+            .await?
+            .take::<Vec<Self>>(0)?;
+
+        Ok(favorites)
+    }
+
+    pub async fn delete(db: &Database, fingerprint: &str) -> Result<(), ApiError> {
+        let deleted = db
+            .query("DELETE type::thing($tb, $id) RETURN BEFORE")
+            .bind(("tb", "favorite"))
+            .bind(("id", fingerprint))
+            .await?
+            .take::<Vec<Record>>(0)?;
+
+        if deleted.is_empty() {
+            return Err(ApiError::NotFound("Favorite"));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use axum::{
+    extract::{Path, State},
+    routing::{delete, get, put},
+    Json,
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    auth::Superuser,
+    queue::QueueItemId,
+    server::{Context, Router},
+    util::ApiError,
+};
+
+use super::Favorite;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", get(get_favorites))
+        .route("/", put(add_favorite))
+        .route("/:fingerprint", delete(remove_favorite))
+}
+
+async fn get_favorites(State(context): Context) -> Result<Json<Vec<Favorite>>, ApiError> {
+    let favorites = Favorite::all(&context.db).await?;
+
+    Ok(Json(favorites))
+}
+
+#[derive(Deserialize)]
+struct AddFavoriteBody {
+    /// The room the track was queued in
+    room: String,
+    /// The queue item, which may already have been played
+    item: QueueItemId,
+}
+
+async fn add_favorite(
+    Superuser(session): Superuser,
+    State(context): Context,
+    Json(body): Json<AddFavoriteBody>,
+) -> Result<(StatusCode, Json<Favorite>), ApiError> {
+    let room = context.store.room_store.find_room(&body.room)?;
+
+    let item = context
+        .store
+        .room_store
+        .queue_item(&room, body.item)
+        .ok_or(ApiError::NotFound("Queue item"))?;
+
+    let favorite = Favorite::create(&context.db, item.track(), &session.user).await?;
+
+    Ok((StatusCode::CREATED, Json(favorite)))
+}
+
+async fn remove_favorite(
+    _: Superuser,
+    State(context): Context,
+    Path(fingerprint): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    Favorite::delete(&context.db, &fingerprint).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
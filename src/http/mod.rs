@@ -1 +1,2 @@
+pub mod segmented;
 pub mod stream;
@@ -0,0 +1,264 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::blocking::Client;
+use std::{
+    collections::VecDeque,
+    io::{self, Cursor, Read},
+};
+use thiserror::Error;
+
+lazy_static! {
+    static ref AUDIO_ADAPTATION_SET: Regex =
+        Regex::new(r#"(?s)<AdaptationSet[^>]*audio[^>]*>.*?</AdaptationSet>"#).unwrap();
+    static ref REPRESENTATION: Regex =
+        Regex::new(r#"(?s)<Representation.*?(?:/>|</Representation>)"#).unwrap();
+    static ref BASE_URL: Regex = Regex::new(r#"<BaseURL>([^<]+)</BaseURL>"#).unwrap();
+    static ref INITIALIZATION: Regex =
+        Regex::new(r#"<Initialization[^>]*sourceURL="([^"]+)""#).unwrap();
+    static ref SEGMENT_URL: Regex = Regex::new(r#"<SegmentURL[^>]*media="([^"]+)""#).unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ManifestKind {
+    /// HTTP Live Streaming, a .m3u8 playlist
+    Hls,
+    /// MPEG-DASH, a .mpd document
+    Dash,
+}
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("Failed to fetch manifest")]
+    Fetch,
+
+    #[error("Manifest is not HLS or DASH")]
+    UnknownFormat,
+
+    #[error("Manifest does not list any segments")]
+    NoSegments,
+
+    #[error("Failed to fetch segment {0}")]
+    Segment(String),
+}
+
+impl ManifestKind {
+    /// Guesses the kind of manifest from the URL, returning [None] if it is not one
+    pub fn detect(url: &str) -> Option<Self> {
+        let path = url.split(['?', '#']).next().unwrap_or_default();
+
+        if path.ends_with(".m3u8") {
+            Some(Self::Hls)
+        } else if path.ends_with(".mpd") {
+            Some(Self::Dash)
+        } else {
+            None
+        }
+    }
+
+    /// Determines the kind of manifest from its content
+    fn from_content(content: &str) -> Option<Self> {
+        let content = content.trim_start();
+
+        if content.starts_with("#EXTM3U") {
+            Some(Self::Hls)
+        } else if content.contains("<MPD") {
+            Some(Self::Dash)
+        } else {
+            None
+        }
+    }
+}
+
+/// A stream of media delivered in segments, listed in an HLS or DASH manifest.
+/// Segments are downloaded one at a time, and read as if they were one file.
+#[derive(Debug)]
+pub struct SegmentedStream {
+    client: Client,
+    segments: VecDeque<String>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl SegmentedStream {
+    /// How many times fetching a segment is attempted
+    const MAX_ATTEMPTS: usize = 3;
+
+    pub fn try_new(url: &str) -> Result<Self, ManifestError> {
+        let client = Client::new();
+        let segments = fetch_segments(&client, url, true)?;
+
+        if segments.is_empty() {
+            return Err(ManifestError::NoSegments);
+        }
+
+        Ok(Self {
+            client,
+            segments: segments.into(),
+            current: Default::default(),
+        })
+    }
+
+    fn fetch_segment(&self, url: &str) -> Result<Vec<u8>, ManifestError> {
+        (0..Self::MAX_ATTEMPTS)
+            .find_map(|_| {
+                self.client
+                    .get(url)
+                    .send()
+                    .and_then(|r| r.error_for_status())
+                    .and_then(|r| r.bytes())
+                    .ok()
+            })
+            .map(|bytes| bytes.to_vec())
+            .ok_or_else(|| ManifestError::Segment(url.to_string()))
+    }
+}
+
+impl Read for SegmentedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let bytes_read = self.current.read(buf)?;
+
+            if bytes_read > 0 {
+                return Ok(bytes_read);
+            }
+
+            let Some(next) = self.segments.pop_front() else {
+                return Ok(0);
+            };
+
+            let segment = self.fetch_segment(&next).map_err(io::Error::other)?;
+
+            self.current = Cursor::new(segment);
+        }
+    }
+}
+
+/// Fetches a manifest and returns the absolute URLs of its segments, in order
+fn fetch_segments(
+    client: &Client,
+    url: &str,
+    follow_variants: bool,
+) -> Result<Vec<String>, ManifestError> {
+    let content = client
+        .get(url)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .and_then(|r| r.text())
+        .map_err(|_| ManifestError::Fetch)?;
+
+    match ManifestKind::from_content(&content) {
+        Some(ManifestKind::Hls) => {
+            let uris: Vec<_> = content
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| resolve_url(url, l))
+                .collect();
+
+            // A master playlist lists variants instead of segments, so use the first one
+            if content.contains("#EXT-X-STREAM-INF") {
+                let variant = uris.first().ok_or(ManifestError::NoSegments)?;
+
+                return match follow_variants {
+                    true => fetch_segments(client, variant, false),
+                    false => Err(ManifestError::NoSegments),
+                };
+            }
+
+            Ok(uris)
+        }
+        Some(ManifestKind::Dash) => {
+            let adaptation_set = AUDIO_ADAPTATION_SET
+                .find(&content)
+                .map(|m| m.as_str())
+                .unwrap_or(&content);
+
+            let representation = REPRESENTATION
+                .find(adaptation_set)
+                .map(|m| m.as_str())
+                .ok_or(ManifestError::NoSegments)?;
+
+            // The base URL can be specified on the representation, or for the entire document
+            let base = BASE_URL
+                .captures(representation)
+                .or_else(|| BASE_URL.captures(&content))
+                .map(|c| resolve_url(url, &unescape_xml(&c[1])))
+                .unwrap_or_else(|| url.to_string());
+
+            let segments: Vec<_> = INITIALIZATION
+                .captures_iter(representation)
+                .chain(SEGMENT_URL.captures_iter(representation))
+                .map(|c| resolve_url(&base, &unescape_xml(&c[1])))
+                .collect();
+
+            // Without a segment list, the representation is a single file
+            if segments.is_empty() {
+                return Ok(vec![base]);
+            }
+
+            Ok(segments)
+        }
+        None => Err(ManifestError::UnknownFormat),
+    }
+}
+
+/// Resolves a possibly relative URL against the URL of the document it appeared in
+fn resolve_url(base: &str, relative: &str) -> String {
+    if relative.starts_with("http://") || relative.starts_with("https://") {
+        return relative.to_string();
+    }
+
+    let base = base.split(['?', '#']).next().unwrap_or_default();
+
+    if relative.starts_with('/') {
+        let origin_end = base
+            .find("://")
+            .and_then(|i| base[i + 3..].find('/').map(|j| i + 3 + j))
+            .unwrap_or(base.len());
+
+        return format!("{}{}", &base[..origin_end], relative);
+    }
+
+    let directory_end = base.rfind('/').map(|i| i + 1).unwrap_or(base.len());
+    format!("{}{}", &base[..directory_end], relative)
+}
+
+fn unescape_xml(str: &str) -> String {
+    str.replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resolve_url, ManifestKind};
+
+    #[test]
+    fn detects_manifests() {
+        assert_eq!(
+            ManifestKind::detect("https://a.com/index.m3u8?token=1"),
+            Some(ManifestKind::Hls)
+        );
+        assert_eq!(
+            ManifestKind::detect("https://a.com/manifest.mpd"),
+            Some(ManifestKind::Dash)
+        );
+        assert_eq!(ManifestKind::detect("https://a.com/audio.webm"), None);
+    }
+
+    #[test]
+    fn resolves_urls() {
+        let base = "https://a.com/live/index.m3u8?token=1";
+
+        assert_eq!(resolve_url(base, "seg1.ts"), "https://a.com/live/seg1.ts");
+        assert_eq!(
+            resolve_url(base, "/other/seg1.ts"),
+            "https://a.com/other/seg1.ts"
+        );
+        assert_eq!(
+            resolve_url(base, "https://b.com/seg1.ts"),
+            "https://b.com/seg1.ts"
+        );
+    }
+}
@@ -19,7 +19,11 @@ impl ByteRangeStream {
     /// Tries to create a byte range stream from the provided URL,
     /// returning [None] if the endpoint does not support byte ranges.
     pub fn try_new(url: String) -> Option<Self> {
-        let client = Client::new();
+        Self::with_client(url, Client::new())
+    }
+
+    /// Like [ByteRangeStream::try_new], but makes every request with `client`
+    pub fn with_client(url: String, client: Client) -> Option<Self> {
         let response = client.head(&url).send();
 
         match response {
@@ -51,6 +55,11 @@ impl ByteRangeStream {
         }
     }
 
+    /// Moves ahead by `amount` bytes, so they are never fetched
+    pub fn skip(&mut self, amount: usize) {
+        self.offset.fetch_add(amount, Ordering::Relaxed);
+    }
+
     pub fn remaining(&self) -> usize {
         self.length
             .saturating_sub(self.offset.load(Ordering::Relaxed))
@@ -71,6 +80,7 @@ impl Read for ByteRangeStream {
             .get(&self.url)
             .header("Range", range)
             .send()
+            .and_then(|r| r.error_for_status())
             .map_err(|_| std::io::Error::from(ErrorKind::Other))?;
 
         response.read_exact(&mut buf[..requested_amount])?;
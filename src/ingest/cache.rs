@@ -0,0 +1,518 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+use crate::{audio::SAMPLES_PER_SEC, util::ID_COUNTER};
+
+use super::{
+    ffmpeg,
+    loading::{LoadResult, Loader, ProbeResult},
+    SinkLength,
+};
+
+/// Where downloaded audio is cached and how much of it is kept
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    /// In bytes
+    pub max_size: u64,
+}
+
+/// Returns the cache settings, set with `VINYL_CACHE_DIR` and `VINYL_CACHE_MAX_GB`.
+/// Caching is disabled unless the max size is above 0.
+pub fn cache_config() -> Option<CacheConfig> {
+    const BYTES_PER_GB: f64 = 1024. * 1024. * 1024.;
+
+    let max_gb = env::var("VINYL_CACHE_MAX_GB")
+        .map(|x| {
+            x.parse::<f64>()
+                .expect("Cache max size must be a number of GB")
+        })
+        .unwrap_or(0.);
+
+    assert!(
+        max_gb.is_finite() && max_gb >= 0.,
+        "Cache max size must be 0 or above"
+    );
+
+    let dir = env::var_os("VINYL_CACHE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("cache"));
+
+    (max_gb > 0.).then_some(CacheConfig {
+        dir,
+        max_size: (max_gb * BYTES_PER_GB) as u64,
+    })
+}
+
+/// Audio as it was downloaded, stored on disk by [Input::cache_key](super::Input::cache_key),
+/// so tracks that are played again are not fetched again.
+///
+/// The least recently used files are evicted by [AudioCache::prune] once it grows above its max size.
+#[derive(Debug)]
+pub struct AudioCache {
+    config: CacheConfig,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    size: u64,
+    last_used: SystemTime,
+}
+
+impl AudioCache {
+    const EXTENSION: &str = "audio";
+    const PARTIAL_EXTENSION: &str = "partial";
+
+    /// Opens the cache, picking up files from a previous run and removing unfinished ones
+    pub fn open(config: CacheConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+
+        let mut entries = HashMap::new();
+
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|x| x.to_str());
+
+            if extension == Some(Self::PARTIAL_EXTENSION) {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+
+            let Some(key) = path.file_stem().and_then(|x| x.to_str()) else {
+                continue;
+            };
+
+            if extension != Some(Self::EXTENSION) {
+                continue;
+            }
+
+            let metadata = fs::metadata(&path)?;
+
+            entries.insert(
+                key.to_string(),
+                CacheEntry {
+                    size: metadata.len(),
+                    last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                },
+            );
+        }
+
+        Ok(Self {
+            config,
+            entries: entries.into(),
+        })
+    }
+
+    /// Returns a loader reading the cached audio of `fingerprint`, or [None] if it is not cached
+    pub fn loader(&self, fingerprint: &str) -> Option<Box<dyn Loader>> {
+        let key = Self::key(fingerprint);
+        let path = self.path(&key);
+
+        let mut entries = self.entries.lock();
+        let entry = entries.get_mut(&key)?;
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => {
+                // Removed by someone else
+                entries.remove(&key);
+                return None;
+            }
+        };
+
+        entry.last_used = SystemTime::now();
+
+        // So the order survives a restart
+        let _ = file.set_modified(entry.last_used);
+
+        Some(Box::new(CachedLoader { file, path }))
+    }
+
+    /// Wraps a loader so what it loads is cached once it loaded everything.
+    /// Nothing is cached if the loader does not know how long the audio is, like for live streams.
+    pub fn caching(self: &Arc<Self>, fingerprint: &str, inner: Box<dyn Loader>) -> Box<dyn Loader> {
+        let writer = CacheWriter::create(self.clone(), Self::key(fingerprint))
+            .map_err(|err| warn!(target: "vinyl::audio", "Failed to create cache file: {}", err))
+            .ok();
+
+        Box::new(CachingLoader {
+            inner,
+            writer: writer.into(),
+        })
+    }
+
+    pub fn contains(&self, fingerprint: &str) -> bool {
+        self.entries.lock().contains_key(&Self::key(fingerprint))
+    }
+
+    /// Returns how many bytes are cached
+    pub fn size(&self) -> u64 {
+        self.entries.lock().values().map(|x| x.size).sum()
+    }
+
+    /// Removes the least recently used files until the cache fits in its max size,
+    /// returning how many were removed
+    pub fn prune(&self) -> usize {
+        let mut entries = self.entries.lock();
+        let mut size: u64 = entries.values().map(|x| x.size).sum();
+
+        if size <= self.config.max_size {
+            return 0;
+        }
+
+        let mut by_age: Vec<_> = entries.iter().map(|(k, e)| (k.clone(), *e)).collect();
+        by_age.sort_by_key(|(_, entry)| entry.last_used);
+
+        let mut removed = 0;
+
+        for (key, entry) in by_age {
+            if size <= self.config.max_size {
+                break;
+            }
+
+            if let Err(err) = fs::remove_file(self.path(&key)) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    warn!(target: "vinyl::audio", "Failed to evict {} from cache: {}", key, err);
+                    continue;
+                }
+            }
+
+            entries.remove(&key);
+            size -= entry.size;
+            removed += 1;
+        }
+
+        removed
+    }
+
+    /// Fingerprints contain characters that are not allowed in file names, so they are hashed
+    fn key(fingerprint: &str) -> String {
+        Sha256::digest(fingerprint.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.config.dir.join(format!("{}.{}", key, Self::EXTENSION))
+    }
+
+    fn insert(&self, key: String, size: u64) {
+        self.entries.lock().insert(
+            key,
+            CacheEntry {
+                size,
+                last_used: SystemTime::now(),
+            },
+        );
+    }
+}
+
+/// Writes audio to a temporary file, which is only added to the cache once it is complete.
+/// The file is removed if it is dropped before then.
+#[derive(Debug)]
+struct CacheWriter {
+    cache: Arc<AudioCache>,
+    key: String,
+    partial: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl CacheWriter {
+    fn create(cache: Arc<AudioCache>, key: String) -> io::Result<Self> {
+        // Unique, since the same track can be loaded in several rooms at once
+        let partial = cache.config.dir.join(format!(
+            "{}.{}.{}",
+            key,
+            ID_COUNTER.fetch_add(1),
+            AudioCache::PARTIAL_EXTENSION
+        ));
+
+        let file = BufWriter::new(File::create(&partial)?);
+
+        Ok(Self {
+            cache,
+            key,
+            partial,
+            file,
+            size: 0,
+        })
+    }
+
+    /// Fails once the file grows above the max size of the cache, since it would be evicted anyway
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.size + data.len() as u64 > self.cache.config.max_size {
+            return Err(io::Error::other("audio is larger than the cache"));
+        }
+
+        self.file.write_all(data)?;
+        self.size += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.file.flush()?;
+        fs::rename(&self.partial, self.cache.path(&self.key))?;
+
+        self.cache.insert(self.key.clone(), self.size);
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        // Already renamed if it was finished
+        let _ = fs::remove_file(&self.partial);
+    }
+}
+
+/// Loads audio from the cache instead of the network
+#[derive(Debug)]
+struct CachedLoader {
+    file: File,
+    path: PathBuf,
+}
+
+impl Loader for CachedLoader {
+    fn load(&mut self, amount: usize) -> LoadResult {
+        let mut buf = vec![0; amount];
+
+        match self.file.read(&mut buf) {
+            Ok(0) => LoadResult::Empty,
+            Ok(bytes_read) => {
+                buf.truncate(bytes_read);
+                LoadResult::Data(buf)
+            }
+            Err(err) => {
+                warn!(target: "vinyl::audio", "Failed to read from cache: {}", err);
+                LoadResult::Error
+            }
+        }
+    }
+
+    fn probe(&self) -> Option<ProbeResult> {
+        ffmpeg::probe(&self.path.to_string_lossy()).map(|probe| {
+            let length_in_samples = (probe.duration * SAMPLES_PER_SEC as f32).floor() as usize;
+
+            ProbeResult {
+                length: SinkLength::Exact(length_in_samples),
+            }
+        })
+    }
+}
+
+/// Passes through what another loader loads, writing it to the cache along the way
+#[derive(Debug)]
+struct CachingLoader {
+    inner: Box<dyn Loader>,
+    writer: Mutex<Option<CacheWriter>>,
+}
+
+impl Loader for CachingLoader {
+    fn load(&mut self, amount: usize) -> LoadResult {
+        let result = self.inner.load(amount);
+        let writer = self.writer.get_mut();
+
+        match &result {
+            LoadResult::Data(data) => {
+                if let Some(Err(err)) = writer.as_mut().map(|w| w.write(data)) {
+                    warn!(target: "vinyl::audio", "Failed to write to cache: {}", err);
+                    *writer = None;
+                }
+            }
+            LoadResult::Empty => {
+                if let Some(Err(err)) = writer.take().map(CacheWriter::finish) {
+                    warn!(target: "vinyl::audio", "Failed to add to cache: {}", err);
+                }
+            }
+            // Incomplete audio is never cached
+            LoadResult::Error => *writer = None,
+        }
+
+        result
+    }
+
+    fn probe(&self) -> Option<ProbeResult> {
+        let result = self.inner.probe();
+
+        // Audio without a known length may never end, so it would never finish caching
+        if let Some(ProbeResult {
+            length: SinkLength::Unknown,
+        }) = result
+        {
+            *self.writer.lock() = None;
+        }
+
+        result
+    }
+}
+
+/// Logs how big the cache is, and prunes it every now and then
+pub fn spawn_cache_prune_thread(cache: Arc<AudioCache>) {
+    const INTERVAL: Duration = Duration::from_secs(60);
+
+    let run = move || loop {
+        thread::sleep(INTERVAL);
+
+        let removed = cache.prune();
+
+        if removed > 0 {
+            info!(target: "vinyl::audio",
+                "Evicted {} files from the audio cache, which is now {} MB",
+                removed,
+                cache.size() / 1024 / 1024
+            );
+        }
+    };
+
+    thread::Builder::new()
+        .name("audio_cache_prune".to_string())
+        .spawn(run)
+        .unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, sync::Arc, thread, time::Duration};
+
+    use super::{AudioCache, CacheConfig};
+    use crate::ingest::{
+        loading::{LoadResult, Loader, ProbeResult},
+        SinkLength,
+    };
+
+    /// Loads the bytes it was given, one chunk per load
+    #[derive(Debug)]
+    struct MockLoader(Vec<Vec<u8>>);
+
+    impl Loader for MockLoader {
+        fn load(&mut self, _: usize) -> LoadResult {
+            match self.0.is_empty() {
+                true => LoadResult::Empty,
+                false => LoadResult::Data(self.0.remove(0)),
+            }
+        }
+
+        fn probe(&self) -> Option<ProbeResult> {
+            None
+        }
+    }
+
+    fn cache(name: &str, max_size: u64) -> Arc<AudioCache> {
+        let dir = env::temp_dir().join(format!("vinyl-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        Arc::new(AudioCache::open(CacheConfig { dir, max_size }).unwrap())
+    }
+
+    /// Loads forever without knowing how long it is, like a live stream
+    #[derive(Debug)]
+    struct LiveLoader;
+
+    impl Loader for LiveLoader {
+        fn load(&mut self, _: usize) -> LoadResult {
+            LoadResult::Data(vec![0])
+        }
+
+        fn probe(&self) -> Option<ProbeResult> {
+            Some(ProbeResult {
+                length: SinkLength::Unknown,
+            })
+        }
+    }
+
+    fn load_all(loader: &mut dyn Loader) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        while let LoadResult::Data(data) = loader.load(1024) {
+            bytes.extend(data);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn caches_complete_loads() {
+        let cache = cache("complete", 1000);
+        assert!(cache.loader("youtube:a").is_none());
+
+        let mut loader =
+            cache.caching("youtube:a", Box::new(MockLoader(vec![vec![1, 2], vec![3]])));
+        assert_eq!(load_all(loader.as_mut()), vec![1, 2, 3]);
+
+        let mut cached = cache.loader("youtube:a").expect("is cached");
+        assert_eq!(load_all(cached.as_mut()), vec![1, 2, 3]);
+        assert_eq!(cache.size(), 3);
+
+        // Unfinished loads are not
+        let mut loader = cache.caching("youtube:b", Box::new(MockLoader(vec![vec![1, 2]])));
+        loader.load(1024);
+        drop(loader);
+
+        assert!(cache.loader("youtube:b").is_none());
+        assert_eq!(fs::read_dir(&cache.config.dir).unwrap().count(), 1);
+
+        // Files are picked up again after a restart
+        let reopened = AudioCache::open(cache.config.clone()).unwrap();
+        assert!(reopened.loader("youtube:a").is_some());
+
+        fs::remove_dir_all(&cache.config.dir).unwrap();
+    }
+
+    #[test]
+    fn skips_unbounded_loads() {
+        let cache = cache("unbounded", 4);
+
+        // Too big to ever fit
+        let mut loader = cache.caching("a", Box::new(MockLoader(vec![vec![0; 3], vec![0; 3]])));
+        assert_eq!(load_all(loader.as_mut()).len(), 6);
+        assert!(cache.loader("a").is_none());
+
+        // Live, so it is not written at all
+        let mut loader = cache.caching("b", Box::new(LiveLoader));
+        loader.probe();
+        loader.load(1024);
+
+        assert_eq!(fs::read_dir(&cache.config.dir).unwrap().count(), 0);
+
+        drop(loader);
+        fs::remove_dir_all(&cache.config.dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache("evict", 4);
+
+        for fingerprint in ["a", "b", "c"] {
+            let mut loader = cache.caching(fingerprint, Box::new(MockLoader(vec![vec![0, 0]])));
+            load_all(loader.as_mut());
+
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // Makes "a" more recent than "b"
+        cache.loader("a");
+
+        assert_eq!(cache.prune(), 1);
+        assert_eq!(cache.size(), 4);
+        assert!(cache.loader("b").is_none());
+        assert!(cache.loader("a").is_some() && cache.loader("c").is_some());
+
+        fs::remove_dir_all(&cache.config.dir).unwrap();
+    }
+}
@@ -1,9 +1,11 @@
 use crate::{
+    auth::UserId,
     events::{Filter, IntoEvent},
+    queue::QueueId,
     VinylEvent,
 };
 
-use super::{SinkId, SinkLength};
+use super::{InputId, SinkId, SinkLength};
 
 #[derive(Debug, Clone)]
 pub enum IngestionEvent {
@@ -23,6 +25,29 @@ pub enum IngestionEvent {
     Cleared {
         amount: usize,
     },
+    /// A submitted input is being resolved, which can take a few seconds
+    Resolving {
+        queue: QueueId,
+        id: InputId,
+        user: UserId,
+        input: String,
+    },
+    /// A submitted input was resolved, and `added` tracks of it were queued
+    Ready {
+        queue: QueueId,
+        id: InputId,
+        added: usize,
+    },
+    /// An input could not be parsed or activated.
+    /// `id` is set if it failed while being resolved after it was submitted.
+    /// `status` is the HTTP status that describes why, like 451 for region blocked tracks.
+    Failed {
+        queue: QueueId,
+        id: Option<InputId>,
+        input: String,
+        reason: String,
+        status: u16,
+    },
 }
 
 impl IntoEvent<VinylEvent> for IngestionEvent {
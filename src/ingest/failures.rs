@@ -0,0 +1,81 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::{events::Handler, queue::QueueId, util::unix_millis, VinylEvent};
+
+use super::IngestionEvent;
+
+/// A failed attempt at ingesting an input
+#[derive(Debug, Clone, Serialize)]
+pub struct IngestionFailure {
+    pub input: String,
+    pub reason: String,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// Keeps the most recent failures for each queue
+#[derive(Debug, Default)]
+pub struct Failures {
+    entries: DashMap<QueueId, VecDeque<IngestionFailure>>,
+}
+
+pub struct FailureHandler {
+    failures: Arc<Failures>,
+}
+
+impl Failures {
+    const MAX_ENTRIES: usize = 50;
+
+    pub fn handler(self: &Arc<Self>) -> FailureHandler {
+        FailureHandler {
+            failures: self.clone(),
+        }
+    }
+
+    /// Returns the failures of a queue, newest first
+    pub fn get(&self, queue: QueueId) -> Vec<IngestionFailure> {
+        self.entries
+            .get(&queue)
+            .map(|x| x.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn clear(&self, queue: QueueId) {
+        self.entries.remove(&queue);
+    }
+
+    fn push(&self, queue: QueueId, input: String, reason: String) {
+        let timestamp = unix_millis();
+
+        let mut entries = self.entries.entry(queue).or_default();
+
+        entries.push_back(IngestionFailure {
+            input,
+            reason,
+            timestamp,
+        });
+
+        if entries.len() > Self::MAX_ENTRIES {
+            entries.pop_front();
+        }
+    }
+}
+
+impl Handler<VinylEvent> for FailureHandler {
+    type Incoming = IngestionEvent;
+
+    fn handle(&self, incoming: Self::Incoming) {
+        if let IngestionEvent::Failed {
+            queue,
+            input,
+            reason,
+            ..
+        } = incoming
+        {
+            self.failures.push(queue, input, reason)
+        }
+    }
+}
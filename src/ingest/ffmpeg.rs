@@ -1,5 +1,10 @@
 use serde_json::Value;
-use std::process::{Child, Command, Stdio};
+use std::{
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
 
 use crate::audio::{CHANNEL_COUNT, SAMPLE_RATE};
 
@@ -15,12 +20,7 @@ pub fn spawn() -> Child {
         .arg("-loglevel")
         .arg("error")
         .args(["-i", "pipe:"])
-        .args(["-c:a", "pcm_f32le"])
-        .args(["-f", "f32le"])
-        .args(["-fflags", "+discardcorrupt"])
-        .args(["-ar", &SAMPLE_RATE.to_string()])
-        .args(["-ac", &CHANNEL_COUNT.to_string()])
-        .args(["pipe:"])
+        .args(output_args())
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()
@@ -29,6 +29,48 @@ pub fn spawn() -> Child {
     child
 }
 
+/// Spawns ffmpeg reading directly from a remote stream.
+/// ffmpeg will try to reconnect by itself if the connection drops.
+pub fn spawn_relay(url: &str) -> std::io::Result<Child> {
+    Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .args(["-reconnect", "1"])
+        .args(["-reconnect_streamed", "1"])
+        .args(["-reconnect_delay_max", "5"])
+        // Read in real-time, so finite streams are not decoded all at once
+        .arg("-re")
+        .args(["-i", url])
+        .args(output_args())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+}
+
+/// Arguments to output raw samples to stdout
+fn output_args() -> Vec<String> {
+    [
+        "-c:a",
+        "pcm_f32le",
+        "-f",
+        "f32le",
+        "-fflags",
+        "+discardcorrupt",
+        "-ar",
+        &SAMPLE_RATE.to_string(),
+        "-ac",
+        &CHANNEL_COUNT.to_string(),
+        "pipe:",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// How long ffprobe may take before it is stopped, since remote files can stall forever
+const PROBE_TIMEOUT: Duration = Duration::from_secs(20);
+
 pub fn probe(path: &str) -> Option<Probe> {
     let mut child = Command::new("ffprobe")
         .arg("-v")
@@ -43,8 +85,21 @@ pub fn probe(path: &str) -> Option<Probe> {
         .expect("ffprobe spawned");
 
     let stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let value: Option<Value> = serde_json::from_reader(stdout).ok();
+        let _ = sender.send(value);
+    });
+
+    let value = match receiver.recv_timeout(PROBE_TIMEOUT) {
+        Ok(value) => value,
+        Err(_) => {
+            let _ = child.kill();
+            None
+        }
+    };
 
-    let value: Option<Value> = serde_json::from_reader(stdout).ok();
     let format = value
         .and_then(|v| v.get("format").cloned())
         .and_then(|f| f.as_object().cloned());
@@ -0,0 +1,128 @@
+use std::fmt::Display;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::{ingest::loading::Loader, track::Metadata};
+
+use super::{extracted::ExtractedTrack, AudioFormat, Extractor, InputError};
+
+lazy_static! {
+    static ref REGEX: Regex = Regex::new(
+        r"(?i)^(?:https?://)?(?P<artist>[a-z\d-]+)\.bandcamp\.com/track/(?P<track>[a-z\d_-]+)/?(?:[?#].*)?$"
+    )
+    .unwrap();
+}
+
+/// A single track on Bandcamp, extracted with yt-dlp
+#[derive(Debug, Clone)]
+pub struct BandcampTrack {
+    /// The artist and track from the url, like `rickastley/never-gonna-give-you-up`
+    path: String,
+    track: ExtractedTrack,
+}
+
+impl Extractor for BandcampTrack {
+    const SOURCE: &'static str = "bandcamp";
+
+    /// Only track urls are accepted, not albums or artist pages
+    fn key_from_url(url: &str) -> Option<String> {
+        let captures = REGEX.captures(url)?;
+        let artist = captures["artist"].to_lowercase();
+        let track = captures["track"].to_lowercase();
+
+        Some(format!("{}/{}", artist, track))
+    }
+
+    fn key(&self) -> String {
+        self.path.clone()
+    }
+
+    fn url(&self) -> String {
+        let (artist, track) = self.path.split_once('/').expect("path has an artist");
+        format!("https://{}.bandcamp.com/track/{}", artist, track)
+    }
+}
+
+impl BandcampTrack {
+    pub fn from_url(url: &str, format: &AudioFormat) -> Result<Self, InputError> {
+        let path = Self::key_from_url(url).ok_or(InputError::NoMatch)?;
+        let (artist, track) = path.split_once('/').expect("path has an artist");
+        let track = ExtractedTrack::extract(
+            &format!("https://{}.bandcamp.com/track/{}", artist, track),
+            format,
+        )?;
+
+        Ok(Self { path, track })
+    }
+
+    /// Fetches the track again to get a fresh stream url, since they expire
+    pub fn refresh(&self) -> Result<Self, InputError> {
+        Self::from_url(&self.url(), &self.track.format)
+    }
+
+    pub fn format(&self) -> &AudioFormat {
+        &self.track.format
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            title: self.track.title.clone(),
+            artist: self.track.artist.clone(),
+            canonical: self.url(),
+            source: Self::SOURCE.to_string(),
+            duration: self.track.duration,
+            artwork: self.track.artwork.clone(),
+            chapters: vec![],
+        }
+    }
+
+    pub fn loader(&self) -> Result<Box<dyn Loader>, InputError> {
+        self.track.loader()
+    }
+}
+
+impl Display for BandcampTrack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} by {}", self.track.title, self.track.artist)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BandcampTrack, Extractor};
+
+    #[test]
+    fn track_urls() {
+        for url in [
+            "https://rickastley.bandcamp.com/track/never-gonna-give-you-up",
+            "rickastley.bandcamp.com/track/Never-Gonna-Give-You-Up/",
+            "http://RickAstley.bandcamp.com/track/never-gonna-give-you-up?from=search",
+            "https://rickastley.bandcamp.com/track/never-gonna-give-you-up#lyrics",
+        ] {
+            assert_eq!(
+                BandcampTrack::key_from_url(url).as_deref(),
+                Some("rickastley/never-gonna-give-you-up"),
+                "{} is a track",
+                url
+            );
+        }
+
+        for url in [
+            "https://rickastley.bandcamp.com/album/whenever-you-need-somebody",
+            "https://rickastley.bandcamp.com/album/whenever-you-need-somebody/track/never",
+            "https://rickastley.bandcamp.com",
+            "https://rickastley.bandcamp.com/music",
+            "https://bandcamp.com/track/never-gonna-give-you-up",
+            "https://rickastley.bandcamp.com.example.com/track/never-gonna-give-you-up",
+            "https://soundcloud.com/rick-astley-official/never-gonna-give-you-up-4",
+        ] {
+            assert_eq!(
+                BandcampTrack::key_from_url(url),
+                None,
+                "{} is not a track",
+                url
+            );
+        }
+    }
+}
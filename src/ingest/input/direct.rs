@@ -0,0 +1,487 @@
+use std::{
+    fmt::Display,
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use hyper::{header::CONTENT_TYPE, StatusCode};
+use log::error;
+use parking_lot::Mutex;
+use reqwest::{
+    blocking::{Client, ClientBuilder, Response},
+    redirect::Policy,
+    Url,
+};
+
+use crate::{
+    audio::SAMPLES_PER_SEC,
+    http::stream::ByteRangeStream,
+    ingest::{
+        ffmpeg,
+        loading::{LoadResult, Loader, ProbeResult},
+        SinkLength,
+    },
+    track::Metadata,
+};
+
+use super::{Extractor, InputError};
+
+/// Files with these extensions are taken to be audio, unless the server says otherwise
+const EXTENSIONS: &[&str] = &[
+    "aac", "flac", "m4a", "mp3", "oga", "ogg", "opus", "wav", "weba", "webm",
+];
+
+/// How long a server may take to say what a url is
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Redirects past this are not followed, like the default policy of reqwest
+const MAX_REDIRECTS: usize = 10;
+
+/// An audio file on a server, streamed as it is without going through yt-dlp
+#[derive(Debug, Clone)]
+pub struct DirectUrl {
+    /// The normalized url, see [Extractor::key_from_url]
+    url: String,
+    title: String,
+    host: String,
+    /// In seconds, [None] if it could not be read from the file
+    duration: Option<f32>,
+}
+
+#[derive(Debug)]
+pub struct DirectUrlLoader {
+    url: String,
+    duration: Option<f32>,
+    stream: Mutex<DirectStream>,
+}
+
+/// Servers that support range requests are read in chunks, others in one request
+#[derive(Debug)]
+enum DirectStream {
+    Ranged(ByteRangeStream),
+    Plain(Response),
+}
+
+impl Read for DirectStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            DirectStream::Ranged(x) => x.read(buf),
+            DirectStream::Plain(x) => x.read(buf),
+        }
+    }
+}
+
+impl Extractor for DirectUrl {
+    const SOURCE: &'static str = "direct";
+
+    /// Normalized so the scheme and host are lowercase, and default ports and fragments are left out.
+    /// The query is kept, since some servers need it to give out the file.
+    fn key_from_url(url: &str) -> Option<String> {
+        let mut url = Url::parse(url).ok()?;
+
+        if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+            return None;
+        }
+
+        url.set_fragment(None);
+        Some(url.to_string())
+    }
+
+    fn key(&self) -> String {
+        self.url.clone()
+    }
+
+    fn url(&self) -> String {
+        self.url.clone()
+    }
+}
+
+impl DirectUrl {
+    /// Accepts urls ending in an audio extension, or that the server says are audio.
+    ///
+    /// Urls leading to the server itself or a private network are refused, see [check_public].
+    pub fn from_url(url: &str) -> Result<Self, InputError> {
+        let key = Self::key_from_url(url).ok_or(InputError::NoMatch)?;
+        let url = Url::parse(&key).expect("key is a valid url");
+        let known_extension = has_audio_extension(&url);
+
+        check_public(&url)?;
+
+        // Without an extension, the url is most likely a web page rather than a file
+        let (location, content_type) = match content_type(&key) {
+            Ok(result) => result,
+            Err(InputError::PrivateAddress) => return Err(InputError::PrivateAddress),
+            Err(_) if !known_extension => return Err(InputError::NoMatch),
+            Err(err) => return Err(err),
+        };
+
+        check_audio(known_extension, content_type.as_deref())?;
+
+        // Probed where the redirects that were checked lead, since ffprobe follows them by itself
+        let duration = ffmpeg::probe(location.as_str())
+            .map(|probe| probe.duration)
+            .filter(|d| d.is_finite() && *d > 0.);
+
+        Ok(Self {
+            title: file_name(&url),
+            host: url.host_str().unwrap_or_default().to_string(),
+            url: key,
+            duration,
+        })
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            title: self.title.clone(),
+            artist: self.host.clone(),
+            canonical: self.url.clone(),
+            source: Self::SOURCE.to_string(),
+            duration: self.duration,
+            artwork: None,
+            chapters: vec![],
+        }
+    }
+
+    pub fn loader(&self) -> Result<Box<dyn Loader>, InputError> {
+        let ranged = client()
+            .build()
+            .ok()
+            .and_then(|client| ByteRangeStream::with_client(self.url.clone(), client));
+
+        let stream = match ranged {
+            Some(stream) => DirectStream::Ranged(stream),
+            None => DirectStream::Plain(get(&self.url)?),
+        };
+
+        Ok(Box::new(DirectUrlLoader {
+            url: self.url.clone(),
+            duration: self.duration,
+            stream: stream.into(),
+        }))
+    }
+}
+
+impl Loader for DirectUrlLoader {
+    fn load(&mut self, amount: usize) -> LoadResult {
+        let mut buf = vec![0; amount];
+
+        let bytes_read = match self.stream.lock().read(&mut buf) {
+            Ok(bytes_read) => bytes_read,
+            Err(err) => {
+                error!("Failed to load {}: {}", self.url, err);
+                return LoadResult::Error;
+            }
+        };
+
+        if bytes_read > 0 {
+            LoadResult::Data(buf[..bytes_read].to_vec())
+        } else {
+            LoadResult::Empty
+        }
+    }
+
+    /// Files without a readable duration are played until they end.
+    /// The duration was probed when the url was accepted, so it is not probed again.
+    fn probe(&self) -> Option<ProbeResult> {
+        let length = self
+            .duration
+            .map(|duration| {
+                let length_in_samples = (duration * SAMPLES_PER_SEC as f32).floor() as usize;
+                SinkLength::Exact(length_in_samples)
+            })
+            .unwrap_or(SinkLength::Unknown);
+
+        Some(ProbeResult { length })
+    }
+}
+
+impl Display for DirectUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} from {}", self.title, self.host)
+    }
+}
+
+/// Returns where a url leads after redirects, along with the content type the server reports
+/// for it without downloading it, or [None] if it does not say or does not answer HEAD requests
+fn content_type(url: &str) -> Result<(Url, Option<String>), InputError> {
+    let response = client()
+        .timeout(HEAD_TIMEOUT)
+        .build()
+        .and_then(|client| client.head(url).send())
+        .map_err(|err| {
+            if err.is_redirect() {
+                InputError::PrivateAddress
+            } else {
+                InputError::NetworkFailed
+            }
+        })?;
+
+    let location = response.url().clone();
+
+    match response.status() {
+        StatusCode::NOT_FOUND | StatusCode::GONE => return Err(InputError::NotFound),
+        status if !status.is_success() => return Ok((location, None)),
+        _ => {}
+    }
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_lowercase());
+
+    Ok((location, content_type))
+}
+
+/// Requests the whole file at once, for servers that do not support range requests
+fn get(url: &str) -> Result<Response, InputError> {
+    // The response is read for as long as the file plays
+    let client = client()
+        .timeout(None)
+        .connect_timeout(HEAD_TIMEOUT)
+        .build()
+        .map_err(|err| InputError::Other(Box::new(err)))?;
+
+    client
+        .get(url)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .map_err(|err| match err.status() {
+            Some(StatusCode::NOT_FOUND) => InputError::NotFound,
+            _ => InputError::NetworkFailed,
+        })
+}
+
+/// A client that only follows redirects to public addresses, see [check_public]
+fn client() -> ClientBuilder {
+    let policy = Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.stop()
+        } else if check_public(attempt.url()).is_err() {
+            attempt.error("Redirected to a private address")
+        } else {
+            attempt.follow()
+        }
+    });
+
+    Client::builder().redirect(policy)
+}
+
+/// Makes sure a url does not lead to the server itself or a private network,
+/// checking every address its host resolves to
+fn check_public(url: &Url) -> Result<(), InputError> {
+    let addresses = url
+        .socket_addrs(|| None)
+        .map_err(|_| InputError::NetworkFailed)?;
+
+    if addresses.iter().all(|a| is_public(a.ip())) {
+        Ok(())
+    } else {
+        Err(InputError::PrivateAddress)
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+
+    // 0.0.0.0/8 is "this network", and 100.64.0.0/10 is shared by carrier-grade NAT
+    let reserved = first == 0 || (first == 100 && (64..128).contains(&second));
+
+    !(reserved
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation())
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    // fc00::/7 is for unique local addresses, and fe80::/10 for link-local ones
+    let local = (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80;
+
+    !(local || ip.is_loopback() || ip.is_unspecified() || ip.is_multicast())
+}
+
+/// Decides if a url points to audio, from its extension and the content type the server reported
+fn check_audio(known_extension: bool, content_type: Option<&str>) -> Result<(), InputError> {
+    match content_type {
+        Some(t) if t.starts_with("audio/") || t == "application/ogg" => Ok(()),
+        // Files are often served without saying what they are,
+        // and audio-only containers like webm are sometimes served as video
+        None | Some("application/octet-stream" | "binary/octet-stream") if known_extension => {
+            Ok(())
+        }
+        Some(t) if known_extension && t.starts_with("video/") => Ok(()),
+        Some(t) if known_extension => Err(InputError::NotAudio(t.to_string())),
+        _ => Err(InputError::NoMatch),
+    }
+}
+
+fn has_audio_extension(url: &Url) -> bool {
+    url.path()
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .is_some_and(|extension| EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// Returns the name of the file without its extension, to show as the title
+fn file_name(url: &Url) -> String {
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .map(percent_decode);
+
+    let Some(name) = name else {
+        return url.to_string();
+    };
+
+    match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem.to_string(),
+        _ => name,
+    }
+}
+
+fn percent_decode(str: &str) -> String {
+    let bytes = str.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::Url;
+
+    use super::{
+        check_audio, check_public, file_name, has_audio_extension, is_public, DirectUrl, Extractor,
+        InputError,
+    };
+
+    #[test]
+    fn normalizes_urls() {
+        assert_eq!(
+            DirectUrl::key_from_url("HTTPS://CDN.Example.com:443/Music/Song.mp3?token=a#t=10")
+                .as_deref(),
+            Some("https://cdn.example.com/Music/Song.mp3?token=a")
+        );
+
+        for url in [
+            "ftp://example.com/song.mp3",
+            "example.com/song.mp3",
+            "never gonna give you up",
+        ] {
+            assert_eq!(DirectUrl::key_from_url(url), None, "{} is not a url", url);
+        }
+    }
+
+    #[test]
+    fn recognizes_audio() {
+        let url = |url: &str| Url::parse(url).unwrap();
+
+        assert!(has_audio_extension(&url("https://example.com/a/song.MP3")));
+        assert!(has_audio_extension(&url(
+            "https://example.com/song.flac?x=y.html"
+        )));
+        assert!(!has_audio_extension(&url("https://example.com/song.html")));
+        assert!(!has_audio_extension(&url("https://example.com/")));
+
+        assert!(check_audio(false, Some("audio/mpeg")).is_ok());
+        assert!(check_audio(true, None).is_ok());
+        assert!(check_audio(true, Some("application/octet-stream")).is_ok());
+
+        assert!(matches!(
+            check_audio(true, Some("text/html")),
+            Err(InputError::NotAudio(t)) if t == "text/html"
+        ));
+        assert!(matches!(
+            check_audio(false, Some("text/html")),
+            Err(InputError::NoMatch)
+        ));
+        assert!(matches!(check_audio(false, None), Err(InputError::NoMatch)));
+    }
+
+    #[test]
+    fn refuses_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{} is private", ip);
+        }
+
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{} is public", ip);
+        }
+
+        for url in [
+            "http://localhost/song.mp3",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]:8080/song.mp3",
+        ] {
+            assert!(
+                matches!(
+                    check_public(&Url::parse(url).unwrap()),
+                    Err(InputError::PrivateAddress)
+                ),
+                "{} is refused",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn titles_from_file_names() {
+        let title = |url: &str| file_name(&Url::parse(url).unwrap());
+
+        assert_eq!(
+            title("https://example.com/music/Deep%20Blue.flac"),
+            "Deep Blue"
+        );
+        assert_eq!(title("https://example.com/music/.mp3"), ".mp3");
+        assert_eq!(title("https://example.com/"), "https://example.com/");
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::Read;
+
+use log::error;
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::{
+    audio::SAMPLES_PER_SEC,
+    http::segmented::ManifestKind,
+    ingest::{
+        ffmpeg,
+        loading::{LoadResult, Loader, ProbeResult},
+        SinkLength,
+    },
+};
+
+use super::{
+    youtube::{extract_json, manifest_kind, AudioStream, RawFormat},
+    AudioFormat, InputError,
+};
+
+/// A track extracted with yt-dlp from a site other than YouTube, such as SoundCloud.
+/// Sources wrap this with their own urls and keys.
+#[derive(Debug, Clone)]
+pub struct ExtractedTrack {
+    pub title: String,
+    pub artist: String,
+    /// In seconds
+    pub duration: Option<f32>,
+    pub artwork: Option<String>,
+    audio_stream_url: String,
+
+    /// Set if the stream url points to a manifest of segments
+    manifest: Option<ManifestKind>,
+
+    /// What the audio was chosen with, so it is chosen the same way when refreshed
+    pub format: AudioFormat,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawExtractedTrack {
+    title: String,
+
+    /// Sites set either of these, or both
+    #[serde(default)]
+    artist: Option<String>,
+    #[serde(default)]
+    uploader: Option<String>,
+
+    #[serde(default)]
+    thumbnail: Option<String>,
+    #[serde(default)]
+    duration: Option<f32>,
+    #[serde(default)]
+    format_id: Option<String>,
+    #[serde(default)]
+    formats: Vec<RawFormat>,
+}
+
+#[derive(Debug)]
+pub struct ExtractedTrackLoader {
+    stream_url: String,
+    stream: Mutex<AudioStream>,
+}
+
+impl ExtractedTrack {
+    pub fn extract(url: &str, format: &AudioFormat) -> Result<Self, InputError> {
+        Self::from_slice(&extract_json(url, format)?, format)
+    }
+
+    /// Creates a track from what yt-dlp printed, where `requested` is what the format was chosen with
+    pub fn from_slice(json: &[u8], requested: &AudioFormat) -> Result<Self, InputError> {
+        let raw: RawExtractedTrack = serde_json::from_slice(json).map_err(|err| {
+            error!("Failed to extract track: {}", err);
+            InputError::Malformed(err.to_string())
+        })?;
+
+        if raw.duration.is_some_and(|d| !d.is_finite() || d < 0.) {
+            return Err(InputError::Malformed("duration is invalid".to_string()));
+        }
+
+        let artist = raw
+            .artist
+            .or(raw.uploader)
+            .ok_or_else(|| InputError::Malformed("artist is missing".to_string()))?;
+
+        let format = raw
+            .formats
+            .iter()
+            .find(|f| Some(&f.format_id) == raw.format_id.as_ref())
+            .ok_or(InputError::NotFound)?;
+
+        Ok(Self {
+            audio_stream_url: format.url.clone(),
+            manifest: manifest_kind(format),
+            title: raw.title,
+            artist,
+            duration: raw.duration,
+            artwork: raw.thumbnail.filter(|url| !url.trim().is_empty()),
+            format: requested.clone(),
+        })
+    }
+
+    pub fn loader(&self) -> Result<Box<dyn Loader>, InputError> {
+        let stream = AudioStream::open(&self.audio_stream_url, self.manifest)?;
+
+        Ok(Box::new(ExtractedTrackLoader {
+            stream_url: self.audio_stream_url.clone(),
+            stream: stream.into(),
+        }))
+    }
+}
+
+impl Loader for ExtractedTrackLoader {
+    fn load(&mut self, amount: usize) -> LoadResult {
+        let mut buf = vec![0; amount];
+
+        let bytes_read = match self.stream.lock().read(&mut buf) {
+            Ok(bytes_read) => bytes_read,
+            Err(err) => {
+                error!("Failed to load extracted track: {}", err);
+                return LoadResult::Error;
+            }
+        };
+
+        if bytes_read > 0 {
+            LoadResult::Data(buf[..bytes_read].to_vec())
+        } else {
+            LoadResult::Empty
+        }
+    }
+
+    fn probe(&self) -> Option<ProbeResult> {
+        ffmpeg::probe(&self.stream_url).map(|probe| {
+            let length_in_samples = (probe.duration * SAMPLES_PER_SEC as f32).floor() as usize;
+
+            ProbeResult {
+                length: SinkLength::Exact(length_in_samples),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{AudioFormat, ExtractedTrack, InputError};
+
+    #[test]
+    fn from_json() {
+        let format = AudioFormat::configured();
+        let json = json!({
+            "title": "Never Gonna Give You Up",
+            "uploader": "Rick Astley",
+            "thumbnail": "https://i1.sndcdn.com/artworks-original.jpg",
+            "duration": 213.0,
+            "format_id": "hls_opus_64",
+            "formats": [
+                { "format_id": "http_mp3_128", "url": "https://example.com/mp3" },
+                { "format_id": "hls_opus_64", "url": "https://example.com/playlist.m3u8", "protocol": "m3u8_native" }
+            ]
+        });
+
+        let track = ExtractedTrack::from_slice(json.to_string().as_bytes(), &format).unwrap();
+
+        assert_eq!(track.audio_stream_url, "https://example.com/playlist.m3u8");
+        assert!(track.manifest.is_some());
+        assert_eq!(track.artist, "Rick Astley");
+        assert_eq!(track.duration, Some(213.));
+
+        // The artist is preferred over who uploaded it
+        let mut with_artist = json.clone();
+        with_artist["artist"] = json!("Astley");
+
+        let track =
+            ExtractedTrack::from_slice(with_artist.to_string().as_bytes(), &format).unwrap();
+        assert_eq!(track.artist, "Astley");
+
+        let mut without_artist = json;
+        without_artist.as_object_mut().unwrap().remove("uploader");
+
+        for json in [without_artist.to_string(), "{}".to_string()] {
+            assert!(matches!(
+                ExtractedTrack::from_slice(json.as_bytes(), &format),
+                Err(InputError::Malformed(_))
+            ));
+        }
+    }
+}
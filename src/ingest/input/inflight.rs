@@ -0,0 +1,191 @@
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::{Condvar, Mutex};
+
+use super::InputError;
+
+/// Resolutions that are running right now, keyed by [Input::fingerprint](super::Input::fingerprint),
+/// so the same input submitted several times at once is only resolved once.
+///
+/// Results are only shared with the callers that were waiting while it ran.
+/// Once a resolution finishes it is forgotten, so a failed one can be tried again.
+#[derive(Debug)]
+pub(super) struct InFlight<T> {
+    flights: Mutex<HashMap<String, Arc<Flight<T>>>>,
+}
+
+#[derive(Debug)]
+struct Flight<T> {
+    result: Mutex<Option<Result<T, InputError>>>,
+    landed: Condvar,
+}
+
+/// Removes a flight once it is done, and wakes up everyone waiting for it.
+/// This also happens if resolving panics, so nobody waits forever.
+struct Landing<'a, T> {
+    in_flight: &'a InFlight<T>,
+    key: &'a str,
+    flight: Arc<Flight<T>>,
+}
+
+impl<T: Clone> InFlight<T> {
+    /// Calls `resolve` unless another caller is already resolving `key`,
+    /// in which case its result is waited for instead.
+    pub fn resolve<F>(&self, key: &str, resolve: F) -> Result<T, InputError>
+    where
+        F: FnOnce() -> Result<T, InputError>,
+    {
+        let landing = {
+            let mut flights = self.flights.lock();
+
+            if let Some(flight) = flights.get(key) {
+                let flight = flight.clone();
+                drop(flights);
+
+                return flight.wait();
+            }
+
+            let flight = Arc::new(Flight {
+                result: Default::default(),
+                landed: Default::default(),
+            });
+
+            flights.insert(key.to_string(), flight.clone());
+
+            Landing {
+                in_flight: self,
+                key,
+                flight,
+            }
+        };
+
+        let result = resolve();
+        *landing.flight.result.lock() = Some(duplicate(&result));
+
+        result
+    }
+}
+
+impl<T> Default for InFlight<T> {
+    fn default() -> Self {
+        Self {
+            flights: Default::default(),
+        }
+    }
+}
+
+impl<T: Clone> Flight<T> {
+    fn wait(&self) -> Result<T, InputError> {
+        let mut result = self.result.lock();
+
+        while result.is_none() {
+            self.landed.wait(&mut result);
+        }
+
+        duplicate(result.as_ref().expect("flight has landed"))
+    }
+}
+
+impl<T> Drop for Landing<'_, T> {
+    fn drop(&mut self) {
+        self.in_flight.flights.lock().remove(self.key);
+
+        self.flight
+            .result
+            .lock()
+            .get_or_insert(Err(InputError::Unknown));
+
+        self.flight.landed.notify_all();
+    }
+}
+
+fn duplicate<T: Clone>(result: &Result<T, InputError>) -> Result<T, InputError> {
+    match result {
+        Ok(x) => Ok(x.clone()),
+        Err(err) => Err(err.duplicate()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Barrier,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::{InFlight, InputError};
+
+    /// Resolves `key` from two threads at the same time
+    fn resolve_twice(
+        in_flight: &Arc<InFlight<String>>,
+        calls: &Arc<AtomicUsize>,
+        result: Result<String, ()>,
+    ) -> Vec<Result<String, InputError>> {
+        let barrier = Arc::new(Barrier::new(2));
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let in_flight = in_flight.clone();
+                let calls = calls.clone();
+                let barrier = barrier.clone();
+                let result = result.clone();
+
+                thread::spawn(move || {
+                    barrier.wait();
+
+                    in_flight.resolve("youtube:dQw4w9WgXcQ", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+
+                        result.map_err(|_| InputError::NetworkFailed)
+                    })
+                })
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn shares_concurrent_resolutions() {
+        let in_flight = Arc::new(InFlight::default());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let results = resolve_twice(&in_flight, &calls, Ok("Never Gonna Give You Up".into()));
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(in_flight.flights.lock().len(), 0);
+
+        for result in results {
+            assert_eq!(result.unwrap(), "Never Gonna Give You Up");
+        }
+    }
+
+    #[test]
+    fn forgets_failures() {
+        let in_flight = Arc::new(InFlight::default());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let results = resolve_twice(&in_flight, &calls, Err(()));
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(in_flight.flights.lock().len(), 0);
+
+        for result in results {
+            assert!(matches!(result, Err(InputError::NetworkFailed)));
+        }
+
+        // The next attempt resolves again instead of getting the failure
+        let result = in_flight.resolve("youtube:dQw4w9WgXcQ", || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok("Never Gonna Give You Up".to_string())
+        });
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(result.unwrap(), "Never Gonna Give You Up");
+    }
+}
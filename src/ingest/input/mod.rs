@@ -1,18 +1,156 @@
-use crate::track::Metadata;
+use crate::{store::Id, track::Metadata, util::ApiError};
 
 use super::loading::Loader;
 use axum::response::IntoResponse;
 use hyper::StatusCode;
-use std::fmt::Display;
+use inflight::InFlight;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{env, fmt::Display, io, time::Duration};
 use thiserror::Error;
 
+mod bandcamp;
+mod direct;
+mod extracted;
+mod inflight;
+mod soundcloud;
 mod wavedistrict;
 mod youtube;
 
+pub use youtube::{
+    check_ytdlp, init_stream_url_max_age, init_ytdlp_attempts, init_ytdlp_cookies, init_ytdlp_jobs,
+    init_ytdlp_path, outdated_ytdlp, ytdlp_jobs, ytdlp_jobs_full, ytdlp_jobs_waiting, YtDlpError,
+};
+
+lazy_static! {
+    /// How many entries of a playlist are queued at most, see [Input::parse_many]
+    static ref PLAYLIST_LIMIT: usize = {
+        let limit = env::var("VINYL_PLAYLIST_LIMIT")
+            .map(|x| x.parse::<usize>().expect("Playlist limit must be a number"))
+            .unwrap_or(100);
+
+        assert!(limit > 0, "Playlist limit must be at least 1");
+        limit
+    };
+
+    /// Urls being parsed by [Input::parse], so the same track is not resolved twice at once
+    static ref PARSING: InFlight<Input> = InFlight::default();
+
+    /// The format used unless a room picks its own, set with `VINYL_YTDLP_FORMAT`
+    static ref AUDIO_FORMAT: AudioFormat = env::var("VINYL_YTDLP_FORMAT")
+        .map(|x| AudioFormat::parse(&x).expect("yt-dlp format must be a format selector, like bestaudio"))
+        .unwrap_or_else(|_| AudioFormat(AudioFormat::FALLBACK.to_string()));
+}
+
+/// Reads and validates the playlist limit, so mistakes are caught on startup
+pub fn init_playlist_limit() -> &'static impl std::fmt::Debug {
+    &*PLAYLIST_LIMIT
+}
+
+/// Reads and validates the default yt-dlp format, so mistakes are caught on startup
+pub fn init_audio_format() -> &'static impl std::fmt::Debug {
+    &*AUDIO_FORMAT
+}
+
+/// Which audio yt-dlp picks, like `bestaudio[abr<=128]` for a lower bitrate.
+/// See "Format Selection" in the yt-dlp documentation.
+///
+/// Rooms can pick their own, otherwise the one set with `VINYL_YTDLP_FORMAT` is used.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct AudioFormat(String);
+
+impl AudioFormat {
+    /// Used when nothing matches the chosen format
+    pub const FALLBACK: &'static str = "bestaudio/best";
+
+    const MAX_LENGTH: usize = 200;
+
+    /// Returns [None] if the format does not look like a yt-dlp format selector.
+    /// Whether anything matches it is only known once yt-dlp runs.
+    pub fn parse(format: &str) -> Option<Self> {
+        let format = format.trim();
+
+        let is_allowed =
+            |c: char| c.is_ascii_alphanumeric() || "[]<>=!^$*~?+-_/.,:()'\"".contains(c);
+
+        let mut depth = 0_i32;
+        let balanced = format.chars().all(|c| {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                _ => {}
+            }
+
+            depth >= 0
+        }) && depth == 0;
+
+        let valid = !format.is_empty()
+            && format.len() <= Self::MAX_LENGTH
+            && !format.starts_with('-')
+            && format.chars().all(is_allowed)
+            && balanced;
+
+        valid.then(|| Self(format.to_string()))
+    }
+
+    /// Returns the format set with `VINYL_YTDLP_FORMAT`, or [AudioFormat::FALLBACK]
+    pub fn configured() -> Self {
+        AUDIO_FORMAT.clone()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_fallback(&self) -> bool {
+        self.0 == Self::FALLBACK
+    }
+}
+
+impl Display for AudioFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A source inputs can come from, such as YouTube
+trait Extractor {
+    /// Name of the source, see [Input::SOURCES]
+    const SOURCE: &'static str;
+
+    /// Returns the key of the track a url points to, or [None] if the url is not from this source.
+    ///
+    /// Every url pointing to the same track must give the same key,
+    /// which must also be what [Extractor::key] returns once the track is extracted.
+    fn key_from_url(url: &str) -> Option<String>;
+
+    /// Returns the key identifying the track within the source
+    fn key(&self) -> String;
+
+    /// Returns a url pointing to the track, which parses back into the same key
+    fn url(&self) -> String;
+
+    /// Namespaces a key as `<source>:<key>`, so keys from different sources never collide
+    fn fingerprint_from_key(key: &str) -> String {
+        format!("{}:{}", Self::SOURCE, key)
+    }
+
+    fn fingerprint(&self) -> String {
+        Self::fingerprint_from_key(&self.key())
+    }
+}
+
+/// Identifies an input that was submitted while it is resolved, before it is in a queue
+pub type InputId = Id<Input>;
+
 #[derive(Debug, Clone)]
 pub enum Input {
     WaveDistrict(wavedistrict::Track),
     YouTube(youtube::YouTubeVideo),
+    SoundCloud(soundcloud::SoundCloudTrack),
+    Bandcamp(bandcamp::BandcampTrack),
+    DirectUrl(direct::DirectUrl),
     Empty(Metadata),
 }
 
@@ -30,9 +168,40 @@ pub enum InputError {
     #[error("Failed to fetch resource")]
     NetworkFailed,
 
+    /// Too many inputs were being resolved to start another in time
+    #[error("Too many tracks are being resolved, try again later")]
+    Busy,
+
+    /// The source refused to give out the track, and will keep refusing
+    #[error("Track is unavailable: {0}")]
+    Unavailable(String),
+
+    /// The track is private, or only for members of a channel
+    #[error("Track is private: {0}")]
+    Private(String),
+
+    /// The source wants a session old enough to watch it, see `VINYL_YTDLP_COOKIES`
+    #[error("Track is age restricted: {0}")]
+    AgeRestricted(String),
+
+    /// The source does not offer the track in the region the server is in
+    #[error("Track is blocked in this region: {0}")]
+    RegionBlocked(String),
+
+    /// A url looked like an audio file, but the server says it is something else
+    #[error("Not an audio file, the server says it is {0}")]
+    NotAudio(String),
+
+    /// A url leads to the server itself or a private network, which users may not reach through it
+    #[error("Url points to a private address")]
+    PrivateAddress,
+
     #[error("Resource is invalid")]
     Invalid,
 
+    #[error("Metadata is malformed: {0}")]
+    Malformed(String),
+
     #[error(transparent)]
     Other(Box<dyn std::error::Error + Send>),
 
@@ -40,26 +209,160 @@ pub enum InputError {
     Unknown,
 }
 
+impl InputError {
+    /// Returns a copy of the error, for when several callers get the same one.
+    /// Errors from elsewhere can't be copied, so only their message is kept.
+    fn duplicate(&self) -> Self {
+        match self {
+            Self::NotFound => Self::NotFound,
+            Self::NoMatch => Self::NoMatch,
+            Self::UnsupportedType => Self::UnsupportedType,
+            Self::NetworkFailed => Self::NetworkFailed,
+            Self::Busy => Self::Busy,
+            Self::Unavailable(x) => Self::Unavailable(x.clone()),
+            Self::Private(x) => Self::Private(x.clone()),
+            Self::AgeRestricted(x) => Self::AgeRestricted(x.clone()),
+            Self::RegionBlocked(x) => Self::RegionBlocked(x.clone()),
+            Self::NotAudio(x) => Self::NotAudio(x.clone()),
+            Self::PrivateAddress => Self::PrivateAddress,
+            Self::Invalid => Self::Invalid,
+            Self::Malformed(x) => Self::Malformed(x.clone()),
+            Self::Other(err) => Self::Other(Box::new(io::Error::other(err.to_string()))),
+            Self::Unknown => Self::Unknown,
+        }
+    }
+
+    /// Returns the status a request gets when it fails with this error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            InputError::NotFound => StatusCode::NOT_FOUND,
+            InputError::NoMatch => StatusCode::BAD_REQUEST,
+            InputError::UnsupportedType => StatusCode::BAD_REQUEST,
+            InputError::Invalid => StatusCode::BAD_REQUEST,
+            InputError::Malformed(_) => StatusCode::BAD_REQUEST,
+            InputError::NetworkFailed => StatusCode::BAD_GATEWAY,
+            InputError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            InputError::Unavailable(_) => StatusCode::NOT_FOUND,
+            InputError::Private(_) => StatusCode::FORBIDDEN,
+            InputError::AgeRestricted(_) => StatusCode::FORBIDDEN,
+            InputError::RegionBlocked(_) => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            InputError::NotAudio(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            InputError::PrivateAddress => StatusCode::FORBIDDEN,
+            InputError::Other(_) | InputError::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 impl Input {
-    /// Returns the fingerprint used to check
-    /// if this is already in cache
+    /// Every source inputs can come from
+    pub const SOURCES: &'static [&'static str] = &[
+        youtube::YouTubeVideo::SOURCE,
+        soundcloud::SoundCloudTrack::SOURCE,
+        bandcamp::BandcampTrack::SOURCE,
+        wavedistrict::Track::SOURCE,
+        direct::DirectUrl::SOURCE,
+    ];
+
+    /// Returns the source this input comes from, one of [Input::SOURCES]
+    pub fn source(&self) -> &'static str {
+        match self {
+            Input::WaveDistrict(_) => wavedistrict::Track::SOURCE,
+            Input::YouTube(_) => youtube::YouTubeVideo::SOURCE,
+            Input::SoundCloud(_) => soundcloud::SoundCloudTrack::SOURCE,
+            Input::Bandcamp(_) => bandcamp::BandcampTrack::SOURCE,
+            Input::DirectUrl(_) => direct::DirectUrl::SOURCE,
+            Input::Empty(_) => "empty",
+        }
+    }
+
+    /// Returns the fingerprint used to check if this is already in cache,
+    /// which is the same for every url pointing to the same track
     pub fn fingerprint(&self) -> String {
         match self {
             Input::WaveDistrict(t) => t.fingerprint(),
             Input::YouTube(v) => v.fingerprint(),
+            Input::SoundCloud(t) => t.fingerprint(),
+            Input::Bandcamp(t) => t.fingerprint(),
+            Input::DirectUrl(u) => u.fingerprint(),
             Input::Empty(_) => "".to_string(),
         }
     }
 
-    pub fn parse(str: &str) -> Result<Self, InputError> {
-        let predicates = [
-            |url| youtube::YouTubeVideo::from_url(url).map(Self::YouTube),
-            |url| wavedistrict::Track::from_url(url).map(Self::WaveDistrict),
+    /// Returns the key the audio of this input is cached under, which is empty if it cannot be.
+    /// The same track in another format is different audio, so the format is part of it.
+    pub fn cache_key(&self) -> String {
+        let fingerprint = self.fingerprint();
+
+        match self.format() {
+            Some(format) if !fingerprint.is_empty() => format!("{} {}", fingerprint, format),
+            _ => fingerprint,
+        }
+    }
+
+    /// Returns what the audio was chosen with, for sources extracted with yt-dlp
+    pub fn format(&self) -> Option<&AudioFormat> {
+        match self {
+            Input::YouTube(v) => Some(v.format()),
+            Input::SoundCloud(t) => Some(t.format()),
+            Input::Bandcamp(t) => Some(t.format()),
+            _ => None,
+        }
+    }
+
+    /// Returns a url that parses back into this input, so it can be queued again elsewhere
+    pub fn url(&self) -> Option<String> {
+        match self {
+            Input::WaveDistrict(t) => Some(t.url()),
+            Input::YouTube(v) => Some(v.url()),
+            Input::SoundCloud(t) => Some(t.url()),
+            Input::Bandcamp(t) => Some(t.url()),
+            Input::DirectUrl(u) => Some(u.url()),
+            Input::Empty(_) => None,
+        }
+    }
+
+    /// Parses a url from one of the [Input::SOURCES],
+    /// or searches YouTube if the string is not a url.
+    /// Sources extracted with yt-dlp pick their audio with `format`.
+    ///
+    /// If the same track is already being parsed, its result is waited for instead.
+    pub fn parse(str: &str, format: &AudioFormat) -> Result<Self, InputError> {
+        let str = str.trim();
+
+        if str.is_empty() {
+            return Err(InputError::Invalid);
+        }
+
+        if is_search(str) {
+            return youtube::YouTubeVideo::from_search(str, format).map(Self::YouTube);
+        }
+
+        // The same track in another format is a different resolution
+        match fingerprint_from_url(str) {
+            Some(fingerprint) => PARSING
+                .resolve(&format!("{} {}", fingerprint, format), || {
+                    Self::parse_url(str, format)
+                })
+                .map(|input| input.with_start_from(str)),
+            None => Self::parse_url(str, format),
+        }
+    }
+
+    fn parse_url(str: &str, format: &AudioFormat) -> Result<Self, InputError> {
+        type Predicate = fn(&str, &AudioFormat) -> Result<Input, InputError>;
+
+        let predicates: [Predicate; 5] = [
+            |url, format| youtube::YouTubeVideo::from_url(url, format).map(Self::YouTube),
+            |url, format| soundcloud::SoundCloudTrack::from_url(url, format).map(Self::SoundCloud),
+            |url, format| bandcamp::BandcampTrack::from_url(url, format).map(Self::Bandcamp),
+            |url, _| wavedistrict::Track::from_url(url).map(Self::WaveDistrict),
+            // Last, since it asks the server what any other url is
+            |url, _| direct::DirectUrl::from_url(url).map(Self::DirectUrl),
         ];
 
         predicates
             .into_iter()
-            .map(|f| f(str))
+            .map(|f| f(str, format))
             .find_map(|r| match r {
                 Err(InputError::NoMatch) => None,
                 x => Some(x),
@@ -67,10 +370,76 @@ impl Input {
             .unwrap_or(Err(InputError::UnsupportedType))
     }
 
+    /// Parses an input that may point to several tracks, such as a playlist, in the order they play.
+    /// At most `VINYL_PLAYLIST_LIMIT` tracks are returned, and the ones that fail to resolve are skipped.
+    pub fn parse_many(str: &str, format: &AudioFormat) -> Result<Vec<Self>, InputError> {
+        match youtube::YouTubeVideo::from_playlist_url(str, *PLAYLIST_LIMIT, format) {
+            Err(InputError::NoMatch) => Self::parse(str, format).map(|input| vec![input]),
+            result => result.map(|videos| videos.into_iter().map(Self::YouTube).collect()),
+        }
+    }
+
+    /// Creates an input from youtube-dl JSON the caller already has, skipping extraction.
+    ///
+    /// The stream url is taken from the JSON if the chosen format is in it,
+    /// otherwise it is resolved again.
+    pub fn from_json(json: &str) -> Result<Self, InputError> {
+        youtube::YouTubeVideo::from_json(json).map(Self::YouTube)
+    }
+
+    /// Returns when resources used by this input expire, in seconds since the unix epoch.
+    /// This is [None] if the source does not say.
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Input::YouTube(video) => video.expires_at(),
+            _ => None,
+        }
+    }
+
+    /// Begins playback where `url` says instead, since inputs shared between urls
+    /// of the same track may have come from a different one
+    fn with_start_from(self, url: &str) -> Self {
+        match self {
+            Input::YouTube(video) => Input::YouTube(video.with_start_from(url)),
+            x => x,
+        }
+    }
+
+    /// Returns where playback should begin, if the url it was parsed from says,
+    /// see [youtube::start_from_url]
+    pub fn start(&self) -> Option<Duration> {
+        match self {
+            Input::YouTube(video) => video.start(),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the input should be resolved again before it is loaded,
+    /// see [youtube::YouTubeVideo::needs_refresh]
+    pub fn needs_refresh(&self) -> bool {
+        match self {
+            Input::YouTube(video) => video.needs_refresh(),
+            _ => false,
+        }
+    }
+
+    /// Resolves the input again, so it can be loaded after it expired
+    pub fn refresh(&self) -> Result<Self, InputError> {
+        match self {
+            Input::YouTube(video) => video.refresh().map(Self::YouTube),
+            Input::SoundCloud(track) => track.refresh().map(Self::SoundCloud),
+            Input::Bandcamp(track) => track.refresh().map(Self::Bandcamp),
+            x => Ok(x.clone()),
+        }
+    }
+
     pub fn loader(&self) -> Result<Box<dyn Loader>, InputError> {
         match self {
             Input::YouTube(video) => video.loader(),
             Input::WaveDistrict(track) => track.loader(),
+            Input::SoundCloud(track) => track.loader(),
+            Input::Bandcamp(track) => track.loader(),
+            Input::DirectUrl(url) => url.loader(),
             Input::Empty(_) => Err(InputError::UnsupportedType),
         }
     }
@@ -79,6 +448,9 @@ impl Input {
         match self {
             Input::WaveDistrict(x) => x.metatada(),
             Input::YouTube(x) => x.metadata(),
+            Input::SoundCloud(x) => x.metadata(),
+            Input::Bandcamp(x) => x.metadata(),
+            Input::DirectUrl(x) => x.metadata(),
             Input::Empty(x) => x.clone(),
         }
     }
@@ -89,6 +461,9 @@ impl Display for Input {
         match &self {
             Input::WaveDistrict(x) => x.fmt(f),
             Input::YouTube(x) => x.fmt(f),
+            Input::SoundCloud(x) => x.fmt(f),
+            Input::Bandcamp(x) => x.fmt(f),
+            Input::DirectUrl(x) => x.fmt(f),
             Input::Empty(_) => write!(f, "Empty"),
         }
     }
@@ -100,16 +475,187 @@ impl From<&Input> for String {
     }
 }
 
+/// Returns the fingerprint of the track a url points to without resolving it,
+/// trying sources in the same order as [Input::parse]
+fn fingerprint_from_url(url: &str) -> Option<String> {
+    fn from<E: Extractor>(url: &str) -> Option<String> {
+        E::key_from_url(url).map(|k| E::fingerprint_from_key(&k))
+    }
+
+    from::<youtube::YouTubeVideo>(url)
+        .or_else(|| from::<soundcloud::SoundCloudTrack>(url))
+        .or_else(|| from::<bandcamp::BandcampTrack>(url))
+        .or_else(|| from::<wavedistrict::Track>(url))
+        .or_else(|| from::<direct::DirectUrl>(url))
+}
+
+/// Returns true if a string is a search term rather than a url,
+/// meaning it has spaces in it or does not point to a path on a domain.
+fn is_search(str: &str) -> bool {
+    if str.contains("://") {
+        return false;
+    }
+
+    let looks_like_url = match str.split_once('/') {
+        Some((domain, _)) => domain.contains('.'),
+        None => false,
+    };
+
+    str.contains(char::is_whitespace) || !looks_like_url
+}
+
+impl From<InputError> for ApiError {
+    fn from(err: InputError) -> Self {
+        match err {
+            InputError::NotFound => ApiError::NotFound("Track"),
+            InputError::NetworkFailed => ApiError::Unavailable("Source"),
+            InputError::Busy => ApiError::Busy("Track resolving"),
+            InputError::Other(_) | InputError::Unknown => ApiError::Other(Box::new(err)),
+            err => ApiError::Input(err),
+        }
+    }
+}
+
 impl IntoResponse for InputError {
     fn into_response(self) -> axum::response::Response {
-        let status = match self {
-            InputError::NotFound => StatusCode::NOT_FOUND,
-            InputError::NoMatch => StatusCode::BAD_REQUEST,
-            InputError::UnsupportedType => StatusCode::BAD_REQUEST,
-            InputError::Invalid => StatusCode::BAD_REQUEST,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+        (self.status(), self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::{bandcamp, is_search, soundcloud, wavedistrict, youtube, AudioFormat, Extractor};
+
+    fn fingerprint<E: Extractor>(url: &str) -> String {
+        E::key_from_url(url)
+            .map(|k| E::fingerprint_from_key(&k))
+            .expect("url matches")
+    }
+
+    #[test]
+    fn equivalent_urls_share_fingerprint() {
+        let youtube = [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "youtube.com/watch?v=dQw4w9WgXcQ&t=43s",
+            "https://m.youtube.com/watch?feature=share&v=dQw4w9WgXcQ",
+            "http://youtube.com/v/dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?si=abc",
+        ];
+
+        let wavedistrict = [
+            "https://wavedistrict.com/@enitoni/tracks/saturn",
+            "wavedistrict.com/@Enitoni/tracks/Saturn/",
+        ];
+
+        let soundcloud = [
+            "https://soundcloud.com/rick-astley-official/never-gonna-give-you-up-4",
+            "m.soundcloud.com/Rick-Astley-Official/never-gonna-give-you-up-4?in=x",
+        ];
+
+        let bandcamp = [
+            "https://rickastley.bandcamp.com/track/never-gonna-give-you-up",
+            "RickAstley.bandcamp.com/track/never-gonna-give-you-up?from=search",
+        ];
+
+        let soundcloud: HashSet<_> = soundcloud
+            .iter()
+            .map(|u| fingerprint::<soundcloud::SoundCloudTrack>(u))
+            .collect();
+
+        let bandcamp: HashSet<_> = bandcamp
+            .iter()
+            .map(|u| fingerprint::<bandcamp::BandcampTrack>(u))
+            .collect();
+
+        let youtube: HashSet<_> = youtube
+            .iter()
+            .map(|u| fingerprint::<youtube::YouTubeVideo>(u))
+            .collect();
+
+        let wavedistrict: HashSet<_> = wavedistrict
+            .iter()
+            .map(|u| fingerprint::<wavedistrict::Track>(u))
+            .collect();
+
+        assert_eq!(youtube.len(), 1);
+        assert_eq!(soundcloud.len(), 1);
+        assert_eq!(bandcamp.len(), 1);
+        assert_eq!(wavedistrict.len(), 1);
+    }
+
+    #[test]
+    fn distinct_tracks_have_distinct_fingerprints() {
+        let fingerprints = [
+            fingerprint::<youtube::YouTubeVideo>("https://youtube.com/watch?v=dQw4w9WgXcQ"),
+            fingerprint::<youtube::YouTubeVideo>("https://youtube.com/watch?v=dQw4w9WgXcR"),
+            fingerprint::<youtube::YouTubeVideo>("https://youtube.com/watch?v=enitoni"),
+            fingerprint::<soundcloud::SoundCloudTrack>("https://soundcloud.com/enitoni/saturn"),
+            fingerprint::<soundcloud::SoundCloudTrack>("https://soundcloud.com/enitoni/jupiter"),
+            fingerprint::<bandcamp::BandcampTrack>("https://enitoni.bandcamp.com/track/saturn"),
+            fingerprint::<bandcamp::BandcampTrack>("https://saturn.bandcamp.com/track/enitoni"),
+            fingerprint::<wavedistrict::Track>("https://wavedistrict.com/@enitoni/tracks/saturn"),
+            fingerprint::<wavedistrict::Track>("https://wavedistrict.com/@enitoni/tracks/jupiter"),
+            fingerprint::<wavedistrict::Track>("https://wavedistrict.com/@saturn/tracks/enitoni"),
+        ];
+
+        let unique: HashSet<_> = fingerprints.iter().collect();
+        assert_eq!(unique.len(), fingerprints.len());
+    }
+
+    #[test]
+    fn validates_audio_formats() {
+        for format in [
+            "bestaudio",
+            "bestaudio[abr<=128]/bestaudio",
+            " bestaudio[ext=m4a][abr<=96] ",
+            "251/140/bestaudio*",
+        ] {
+            assert!(AudioFormat::parse(format).is_some(), "{} is valid", format);
+        }
+
+        for format in [
+            "",
+            "   ",
+            "-x",
+            "bestaudio[abr<=128",
+            "bestaudio]",
+            "best audio",
+            "bestaudio;rm",
+        ] {
+            assert!(
+                AudioFormat::parse(format).is_none(),
+                "{} is invalid",
+                format
+            );
+        }
 
-        (status, self.to_string()).into_response()
+        assert_eq!(
+            AudioFormat::parse(" bestaudio ").unwrap().as_str(),
+            "bestaudio"
+        );
+    }
+
+    #[test]
+    fn plain_text_is_searched() {
+        for query in [
+            "never gonna give you up",
+            "rick astley",
+            "AC/DC - Thunderstruck",
+            "dQw4w9WgXcQ",
+        ] {
+            assert!(is_search(query), "{} is searched", query);
+        }
+
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "youtu.be/dQw4w9WgXcQ",
+            "https://soundcloud.com/rick-astley/never-gonna-give-you-up",
+            "wavedistrict.com/@enitoni/tracks/saturn",
+            "enitoni.bandcamp.com/track/saturn",
+        ] {
+            assert!(!is_search(url), "{} is not searched", url);
+        }
     }
 }
@@ -0,0 +1,141 @@
+use std::fmt::Display;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::{ingest::loading::Loader, track::Metadata};
+
+use super::{extracted::ExtractedTrack, AudioFormat, Extractor, InputError};
+
+lazy_static! {
+    static ref REGEX: Regex = Regex::new(
+        r"(?i)^(?:https?://)?(?:(?:www|m)\.)?soundcloud\.com/(?P<user>[a-z\d_-]+)/(?P<track>[a-z\d_-]+)/?(?:[?#].*)?$"
+    )
+    .unwrap();
+}
+
+/// Paths that look like tracks, but are pages of SoundCloud or of a user
+const RESERVED_USERS: &[&str] = &["discover", "search", "stream", "you", "charts", "upload"];
+const RESERVED_TRACKS: &[&str] = &[
+    "albums",
+    "comments",
+    "followers",
+    "following",
+    "likes",
+    "popular-tracks",
+    "reposts",
+    "sets",
+    "tracks",
+];
+
+/// A track on SoundCloud, extracted with yt-dlp
+#[derive(Debug, Clone)]
+pub struct SoundCloudTrack {
+    /// The user and track from the url, like `rick-astley/never-gonna-give-you-up`
+    permalink: String,
+    track: ExtractedTrack,
+}
+
+impl Extractor for SoundCloudTrack {
+    const SOURCE: &'static str = "soundcloud";
+
+    /// Permalinks are case insensitive, so they are lowercased
+    fn key_from_url(url: &str) -> Option<String> {
+        let captures = REGEX.captures(url)?;
+        let user = captures["user"].to_lowercase();
+        let track = captures["track"].to_lowercase();
+
+        if RESERVED_USERS.contains(&user.as_str()) || RESERVED_TRACKS.contains(&track.as_str()) {
+            return None;
+        }
+
+        Some(format!("{}/{}", user, track))
+    }
+
+    fn key(&self) -> String {
+        self.permalink.clone()
+    }
+
+    fn url(&self) -> String {
+        format!("https://soundcloud.com/{}", self.permalink)
+    }
+}
+
+impl SoundCloudTrack {
+    pub fn from_url(url: &str, format: &AudioFormat) -> Result<Self, InputError> {
+        let permalink = Self::key_from_url(url).ok_or(InputError::NoMatch)?;
+        let track =
+            ExtractedTrack::extract(&format!("https://soundcloud.com/{}", permalink), format)?;
+
+        Ok(Self { permalink, track })
+    }
+
+    /// Fetches the track again to get a fresh stream url, since they expire
+    pub fn refresh(&self) -> Result<Self, InputError> {
+        Self::from_url(&self.url(), &self.track.format)
+    }
+
+    pub fn format(&self) -> &AudioFormat {
+        &self.track.format
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            title: self.track.title.clone(),
+            artist: self.track.artist.clone(),
+            canonical: self.url(),
+            source: Self::SOURCE.to_string(),
+            duration: self.track.duration,
+            artwork: self.track.artwork.clone(),
+            chapters: vec![],
+        }
+    }
+
+    pub fn loader(&self) -> Result<Box<dyn Loader>, InputError> {
+        self.track.loader()
+    }
+}
+
+impl Display for SoundCloudTrack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} by {}", self.track.title, self.track.artist)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Extractor, SoundCloudTrack};
+
+    #[test]
+    fn track_urls() {
+        for url in [
+            "https://soundcloud.com/rick-astley-official/never-gonna-give-you-up-4",
+            "soundcloud.com/Rick-Astley-Official/Never-Gonna-Give-You-Up-4/",
+            "https://m.soundcloud.com/rick-astley-official/never-gonna-give-you-up-4?in=x",
+            "http://www.soundcloud.com/rick-astley-official/never-gonna-give-you-up-4#t=1:00",
+        ] {
+            assert_eq!(
+                SoundCloudTrack::key_from_url(url).as_deref(),
+                Some("rick-astley-official/never-gonna-give-you-up-4"),
+                "{} is a track",
+                url
+            );
+        }
+
+        for url in [
+            "https://soundcloud.com/rick-astley-official",
+            "https://soundcloud.com/rick-astley-official/sets/whenever-you-need-somebody",
+            "https://soundcloud.com/rick-astley-official/likes",
+            "https://soundcloud.com/discover/sets",
+            "https://soundcloud.com.example.com/rick-astley-official/never-gonna-give-you-up-4",
+            "https://youtube.com/watch?v=dQw4w9WgXcQ",
+        ] {
+            assert_eq!(
+                SoundCloudTrack::key_from_url(url),
+                None,
+                "{} is not a track",
+                url
+            );
+        }
+    }
+}
@@ -17,12 +17,12 @@ use crate::{
     track::Metadata,
 };
 
-use super::InputError;
+use super::{Extractor, InputError};
 use reqwest::blocking::Client;
 
 lazy_static! {
     static ref REGEX: Regex = Regex::new(
-        r"^(?:https?://)?wavedistrict\.com/@(?P<username>[a-z0-9-]+)/tracks/(?P<slug>[a-z0-9-]+)/?$"
+        r"(?i)^(?:https?://)?wavedistrict\.com/@(?P<username>[a-z0-9-]+)/tracks/(?P<slug>[a-z0-9-]+)/?$"
     )
     .unwrap();
 }
@@ -50,9 +50,12 @@ struct AudioMetadata {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Track {
-    id: u32,
     title: String,
     audio: Media,
+
+    /// Set from the url the track was extracted from, see [Extractor::key]
+    #[serde(skip)]
+    key: String,
 }
 
 #[derive(Debug)]
@@ -62,20 +65,37 @@ pub struct TrackLoader {
     stream: Mutex<ByteRangeStream>,
 }
 
+impl Extractor for Track {
+    const SOURCE: &'static str = "wavedistrict";
+
+    /// Usernames and slugs are case insensitive, so they are lowercased
+    fn key_from_url(url: &str) -> Option<String> {
+        REGEX
+            .captures(url)
+            .map(|c| format!("{}/{}", &c["username"], &c["slug"]).to_lowercase())
+    }
+
+    fn key(&self) -> String {
+        self.key.clone()
+    }
+
+    fn url(&self) -> String {
+        let (username, slug) = self.key.split_once('/').expect("key has a slash");
+        format!("https://wavedistrict.com/@{}/tracks/{}", username, slug)
+    }
+}
+
 impl Track {
     pub fn from_url(url: &str) -> Result<Self, InputError> {
-        let (username, slug) = REGEX
-            .captures(url)
-            .and_then(|c| c.name("username").zip(c.name("slug")))
-            .map(|(u, s)| (u.as_str(), s.as_str()))
-            .ok_or(InputError::NoMatch)?;
+        let key = Self::key_from_url(url).ok_or(InputError::NoMatch)?;
+        let (username, slug) = key.split_once('/').expect("key has a slash");
 
         let api_url = format!(
             "https://api.wavedistrict.com/users/{}/tracks/{}",
             username, slug
         );
 
-        let track: Track = Client::new()
+        let mut track: Track = Client::new()
             .get(api_url)
             .send()
             .map_err(|err| match err.status() {
@@ -84,15 +104,10 @@ impl Track {
             })
             .and_then(|r| r.json().map_err(|x| InputError::Other(Box::new(x))))?;
 
+        track.key = key;
         Ok(track)
     }
 
-    pub fn fingerprint(&self) -> String {
-        self.audio
-            .best_source_url()
-            .unwrap_or_else(|| format!("wd:{}", self.id))
-    }
-
     pub fn loader(&self) -> Result<Box<dyn Loader>, InputError> {
         let audio_url = self.audio.best_source_url().ok_or(InputError::Invalid)?;
         let stream = ByteRangeStream::try_new(audio_url.clone()).ok_or(InputError::Unknown)?;
@@ -110,8 +125,9 @@ impl Track {
             artist: "I am too lazy to implement this rn".to_string(),
             canonical: "This too".to_string(),
             source: "WaveDistrict".to_string(),
-            duration: self.audio.metadata.duration,
+            duration: Some(self.audio.metadata.duration),
             artwork: None,
+            chapters: vec![],
         }
     }
 }
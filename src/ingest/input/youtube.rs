@@ -1,31 +1,466 @@
 use std::{
+    env,
     fmt::Display,
-    io::Read,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
     process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
 
+use crossbeam::atomic::AtomicCell;
 use lazy_static::lazy_static;
-use log::error;
-use parking_lot::Mutex;
+use log::{error, info, warn};
+use parking_lot::{Condvar, Mutex};
 use regex::Regex;
+use reqwest::Url;
 use serde::Deserialize;
+use thiserror::Error;
 
 use crate::{
     audio::SAMPLES_PER_SEC,
-    http::stream::ByteRangeStream,
+    http::{
+        segmented::{ManifestKind, SegmentedStream},
+        stream::ByteRangeStream,
+    },
     ingest::{
         ffmpeg,
         loading::{LoadResult, Loader, ProbeResult},
         SinkLength,
     },
-    track::Metadata,
+    track::{Chapter, Metadata},
+    util::unix_millis,
 };
 
-use super::InputError;
+use super::{AudioFormat, Extractor, InputError};
 
 lazy_static! {
-    static ref REGEX: Regex =
-        Regex::new(r"^(?:https?://)?(?:.+\.)?youtube\.com/(?:watch\?v=|v/)[A-Za-z\d_-]+").unwrap();
+    /// Where yt-dlp is, looked up on `PATH` unless `VINYL_YTDLP_PATH` is set
+    static ref YTDLP_PATH: PathBuf = env::var_os("VINYL_YTDLP_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| "yt-dlp".into());
+
+    /// A cookies file passed to yt-dlp, set with `VINYL_YTDLP_COOKIES`,
+    /// so videos that need a logged in session resolve, like age restricted ones.
+    ///
+    /// The path is never logged, since it leads to the session.
+    static ref YTDLP_COOKIES: Option<PathBuf> = {
+        let path = PathBuf::from(env::var_os("VINYL_YTDLP_COOKIES")?);
+
+        if !path.is_file() {
+            error!(target: "vinyl",
+                "The yt-dlp cookies file does not exist, so videos that need a session will fail"
+            );
+            return None;
+        }
+
+        if let Err(err) = File::open(&path) {
+            warn!(target: "vinyl",
+                "The yt-dlp cookies file can't be read, so videos that need a session will fail: {}",
+                err.kind()
+            );
+            return None;
+        }
+
+        Some(path)
+    };
+    static ref PLAYLIST_REGEX: Regex = Regex::new(
+        r"^(?:https?://)?(?:[^/]+\.)?youtube\.com/playlist\?(?:[^#]*&)?list=(?P<list>[A-Za-z\d_-]+)"
+    )
+    .unwrap();
+    static ref EXPIRE_REGEX: Regex = Regex::new(r"[?&]expire=(\d+)").unwrap();
+    static ref ID_REGEX: Regex = Regex::new(r"^[A-Za-z\d_-]+$").unwrap();
+    static ref TIMESTAMP_REGEX: Regex =
+        Regex::new(r"^(?:(?P<h>\d+)h)?(?:(?P<m>\d+)m)?(?:(?P<s>\d+)s?)?$").unwrap();
+    static ref ERROR_REGEX: Regex = Regex::new(r"^ERROR: (?:\[[^\]]+\] [^:]+: )?(?P<reason>.+)$").unwrap();
+
+    /// How many times yt-dlp is run before giving up, set with `VINYL_YTDLP_ATTEMPTS`
+    static ref YTDLP_ATTEMPTS: u32 = {
+        let attempts = env::var("VINYL_YTDLP_ATTEMPTS")
+            .map(|x| x.parse::<u32>().expect("yt-dlp attempts must be a number"))
+            .unwrap_or(3);
+
+        assert!(attempts > 0, "yt-dlp attempts must be at least 1");
+        attempts
+    };
+
+    /// How many yt-dlp processes can run at once, set with `VINYL_YTDLP_MAX_JOBS`,
+    /// and how long to wait for one to finish when that many are running,
+    /// set with `VINYL_YTDLP_QUEUE_SECS`
+    static ref YTDLP_JOB_LIMIT: JobLimit = {
+        let max = env::var("VINYL_YTDLP_MAX_JOBS")
+            .map(|x| x.parse::<usize>().expect("yt-dlp max jobs must be a number"))
+            .unwrap_or(4);
+
+        let timeout = env::var("VINYL_YTDLP_QUEUE_SECS")
+            .map(|x| x.parse::<u64>().expect("yt-dlp queue time must be a number of seconds"))
+            .unwrap_or(30);
+
+        assert!(max > 0, "yt-dlp max jobs must be at least 1");
+
+        JobLimit {
+            max,
+            timeout: Duration::from_secs(timeout),
+        }
+    };
+
+    /// How old the stream url of a queued video can get before it is resolved again,
+    /// set with `VINYL_STREAM_URL_MAX_AGE_SECS`. 0 resolves it every time urls are refreshed.
+    static ref STREAM_URL_MAX_AGE: Duration = {
+        let secs = env::var("VINYL_STREAM_URL_MAX_AGE_SECS")
+            .map(|x| x.parse::<u64>().expect("Stream url max age must be a number of seconds"))
+            .unwrap_or(60 * 60 * 2);
+
+        Duration::from_secs(secs)
+    };
+}
+
+/// Reads where yt-dlp is, logging an error if the configured path does not exist,
+/// so it is noticed on startup instead of when something is queued.
+pub fn init_ytdlp_path() -> &'static impl std::fmt::Debug {
+    if env::var_os("VINYL_YTDLP_PATH").is_some() && !YTDLP_PATH.is_file() {
+        error!(target: "vinyl",
+            "yt-dlp does not exist at {}, so nothing can be queued from YouTube",
+            YTDLP_PATH.display()
+        );
+    }
+
+    &*YTDLP_PATH
+}
+
+/// Reads and checks the cookies file, so mistakes are caught on startup.
+/// Returns true if cookies are passed to yt-dlp.
+pub fn init_ytdlp_cookies() -> bool {
+    YTDLP_COOKIES.is_some()
+}
+
+/// The oldest yt-dlp known to work. Older ones fail to resolve videos in confusing ways,
+/// since YouTube changes often.
+const MIN_YTDLP_VERSION: &str = "2023.03.04";
+
+/// What happens on startup when yt-dlp is older than [MIN_YTDLP_VERSION].
+///
+/// Set with `VINYL_YTDLP_OUTDATED` to `warn` or `refuse`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutdatedYtDlp {
+    /// Logs a warning and starts anyway
+    #[default]
+    Warn,
+    /// Refuses to start
+    Refuse,
+}
+
+/// Why yt-dlp can't be used, see [check_ytdlp]
+#[derive(Debug, Error)]
+pub enum YtDlpError {
+    #[error("yt-dlp could not be run at {path}: {source}")]
+    Missing { path: String, source: io::Error },
+
+    #[error("yt-dlp did not print a version it knows, instead it printed \"{0}\"")]
+    UnknownVersion(String),
+
+    #[error("yt-dlp {0} is older than {MIN_YTDLP_VERSION}, the oldest version known to work")]
+    Outdated(String),
+}
+
+/// Reads what happens when yt-dlp is outdated, so mistakes are caught on startup
+pub fn outdated_ytdlp() -> OutdatedYtDlp {
+    match env::var("VINYL_YTDLP_OUTDATED").as_deref() {
+        Ok("warn") | Err(_) => OutdatedYtDlp::Warn,
+        Ok("refuse") => OutdatedYtDlp::Refuse,
+        Ok(other) => panic!(
+            "Unknown outdated yt-dlp policy {}, must be warn or refuse",
+            other
+        ),
+    }
+}
+
+/// Runs `yt-dlp --version` and logs it, returning an error if it is missing,
+/// or outdated while `VINYL_YTDLP_OUTDATED` is `refuse`.
+///
+/// This way a broken install is noticed on startup, instead of whenever something is queued.
+pub fn check_ytdlp() -> Result<String, YtDlpError> {
+    let version = ytdlp_version(&YTDLP_PATH)?;
+
+    if !is_outdated(&version) {
+        info!(target: "vinyl", "Using yt-dlp {}", version);
+        return Ok(version);
+    }
+
+    match outdated_ytdlp() {
+        OutdatedYtDlp::Refuse => Err(YtDlpError::Outdated(version)),
+        OutdatedYtDlp::Warn => {
+            warn!(target: "vinyl",
+                "yt-dlp {} is older than {}, so some videos may fail to resolve",
+                version, MIN_YTDLP_VERSION
+            );
+            Ok(version)
+        }
+    }
+}
+
+fn ytdlp_version(path: &Path) -> Result<String, YtDlpError> {
+    let output = Command::new(path)
+        .arg("--version")
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|source| YtDlpError::Missing {
+            path: path.display().to_string(),
+            source,
+        })?;
+
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if !output.status.success() || parse_version(&version).is_none() {
+        return Err(YtDlpError::UnknownVersion(version));
+    }
+
+    Ok(version)
+}
+
+/// Versions are dates like `2023.07.06`, sometimes with a build number after
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    version.split('.').map(|x| x.parse().ok()).collect()
+}
+
+fn is_outdated(version: &str) -> bool {
+    let minimum = parse_version(MIN_YTDLP_VERSION).expect("minimum version is valid");
+    parse_version(version).is_none_or(|v| v < minimum)
+}
+
+/// The yt-dlp processes that are running, see [ytdlp_jobs]
+static YTDLP_JOBS: Jobs = Jobs::new();
+
+/// Returns how many yt-dlp processes are running
+pub fn ytdlp_jobs() -> usize {
+    *YTDLP_JOBS.running.lock()
+}
+
+/// Returns how many yt-dlp processes are waiting for others to finish before they can start
+pub fn ytdlp_jobs_waiting() -> usize {
+    YTDLP_JOBS.waiting.load()
+}
+
+/// Returns true if new yt-dlp processes would most likely give up waiting, see [Jobs::is_full]
+pub fn ytdlp_jobs_full() -> bool {
+    YTDLP_JOBS.is_full(*YTDLP_JOB_LIMIT)
+}
+
+/// Reads and validates how many yt-dlp processes can run, so mistakes are caught on startup
+pub fn init_ytdlp_jobs() -> &'static impl std::fmt::Debug {
+    &*YTDLP_JOB_LIMIT
+}
+
+/// How many jobs can run at once, and how long to wait for a slot
+#[derive(Debug, Clone, Copy)]
+struct JobLimit {
+    max: usize,
+    timeout: Duration,
+}
+
+/// Counts running jobs, so there are never more than a [JobLimit] allows
+struct Jobs {
+    running: Mutex<usize>,
+    waiting: AtomicCell<usize>,
+    finished: Condvar,
+}
+
+/// Counts a job as running until this is dropped
+struct Job<'a>(&'a Jobs);
+
+/// A yt-dlp process, so that not too many run at once on small servers
+type YtDlpJob = Job<'static>;
+
+impl Jobs {
+    const fn new() -> Self {
+        Self {
+            running: Mutex::new(0),
+            waiting: AtomicCell::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Waits until less than `limit.max` jobs are running, then counts one more.
+    /// Gives up with [InputError::Busy] if none finish within `limit.timeout`.
+    fn start(&self, limit: JobLimit) -> Result<Job<'_>, InputError> {
+        let deadline = Instant::now() + limit.timeout;
+        let mut running = self.running.lock();
+
+        self.waiting.fetch_add(1);
+
+        while *running >= limit.max {
+            if self.finished.wait_until(&mut running, deadline).timed_out() {
+                break;
+            }
+        }
+
+        self.waiting.fetch_sub(1);
+
+        if *running >= limit.max {
+            return Err(InputError::Busy);
+        }
+
+        *running += 1;
+        Ok(Job(self))
+    }
+
+    /// Returns true if every slot is taken and as many jobs are already waiting,
+    /// so a new one would have to wait for two rounds of jobs to finish
+    fn is_full(&self, limit: JobLimit) -> bool {
+        *self.running.lock() >= limit.max && self.waiting.load() >= limit.max
+    }
+}
+
+impl YtDlpJob {
+    /// Waits for a slot to run yt-dlp in, see `VINYL_YTDLP_MAX_JOBS`
+    fn start() -> Result<Self, InputError> {
+        YTDLP_JOBS.start(*YTDLP_JOB_LIMIT)
+    }
+}
+
+impl Drop for Job<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+/// Reads and validates how many times yt-dlp is tried, so mistakes are caught on startup
+pub fn init_ytdlp_attempts() -> &'static impl std::fmt::Debug {
+    &*YTDLP_ATTEMPTS
+}
+
+/// Reads and validates how old stream urls can get, so mistakes are caught on startup
+pub fn init_stream_url_max_age() -> &'static impl std::fmt::Debug {
+    &*STREAM_URL_MAX_AGE
+}
+
+fn yt_dlp() -> Command {
+    let mut command = Command::new(&*YTDLP_PATH);
+
+    if let Some(cookies) = YTDLP_COOKIES.as_ref() {
+        command.arg("--cookies").arg(cookies);
+    }
+
+    command
+}
+
+/// Why running yt-dlp failed
+#[derive(Debug)]
+enum Failure {
+    /// Trying again could work, like after a timeout or a server error
+    Transient(String),
+    /// Trying again will fail the same way, like when a video is private
+    Permanent(InputError),
+}
+
+impl Failure {
+    /// Decides what went wrong from what yt-dlp printed to stderr
+    fn from_stderr(stderr: &str) -> Self {
+        // Checked in order, since yt-dlp often prefixes the specific reason with "Video unavailable"
+        type Classified = fn(String) -> InputError;
+
+        const PERMANENT: &[(&[&str], Classified)] = &[
+            (
+                &[
+                    "not available in your country",
+                    "blocked it in your country",
+                    "not made this video available in your country",
+                    "geo restriction",
+                    "geo-restricted",
+                ],
+                InputError::RegionBlocked,
+            ),
+            (
+                &[
+                    "sign in to confirm your age",
+                    "age-restricted",
+                    "inappropriate for some users",
+                ],
+                InputError::AgeRestricted,
+            ),
+            (
+                &[
+                    "private video",
+                    "video is private",
+                    "members-only",
+                    "join this channel",
+                ],
+                InputError::Private,
+            ),
+            (
+                &[
+                    "video unavailable",
+                    "has been removed",
+                    "account associated with this video has been terminated",
+                    "this live event will begin",
+                    "premieres in",
+                    "requested format is not available",
+                ],
+                InputError::Unavailable,
+            ),
+        ];
+
+        let reason = stderr
+            .lines()
+            .rev()
+            .find_map(|line| ERROR_REGEX.captures(line.trim()))
+            .map(|c| c["reason"].trim().to_string())
+            .unwrap_or_else(|| "yt-dlp failed without a reason".to_string());
+
+        let lowercase = reason.to_lowercase();
+
+        for (patterns, error) in PERMANENT {
+            if patterns.iter().any(|p| lowercase.contains(p)) {
+                return Self::Permanent(error(reason));
+            }
+        }
+
+        Self::Transient(reason)
+    }
+}
+
+/// How long to wait before trying again after `attempt` failed, doubling every time
+fn backoff(attempt: u32) -> Duration {
+    const BASE: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(30);
+
+    BASE.saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX)
+}
+
+/// Runs `attempt` until it succeeds, fails permanently, or `VINYL_YTDLP_ATTEMPTS` is reached
+fn with_retries<T>(
+    what: &str,
+    mut attempt: impl FnMut() -> Result<T, Failure>,
+) -> Result<T, InputError> {
+    let mut tries = 0;
+
+    loop {
+        tries += 1;
+
+        match attempt() {
+            Ok(x) => return Ok(x),
+            Err(Failure::Permanent(err)) => return Err(err),
+            Err(Failure::Transient(reason)) if tries >= *YTDLP_ATTEMPTS => {
+                error!(
+                    "Gave up fetching {} after {} attempts: {}",
+                    what, tries, reason
+                );
+                return Err(InputError::NetworkFailed);
+            }
+            Err(Failure::Transient(reason)) => {
+                let delay = backoff(tries);
+
+                warn!(
+                    "Fetching {} failed ({}), retrying in {:?}",
+                    what, reason, delay
+                );
+                thread::sleep(delay);
+            }
+        }
+    }
 }
 
 /// Parsed from youtube-dl
@@ -33,16 +468,55 @@ lazy_static! {
 pub struct YouTubeVideo {
     id: String,
     title: String,
-    duration: f32,
-    thumbnail: String,
+    /// In seconds, [None] for live streams
+    duration: Option<f32>,
     channel: String,
+    chapters: Vec<Chapter>,
     audio_stream_url: String,
+
+    /// The largest thumbnail, if there is one
+    thumbnail: Option<String>,
+
+    /// Set if the stream url points to a manifest of segments
+    manifest: Option<ManifestKind>,
+
+    /// When the stream url stops working, in seconds since the unix epoch
+    expires_at: Option<u64>,
+
+    /// When the stream url was resolved, in seconds since the unix epoch
+    resolved_at: u64,
+
+    /// Where playback begins, from the timestamp in the url it was queued with.
+    /// This is not part of the key, so the same video is cached once.
+    start: Option<Duration>,
+
+    /// What the audio was chosen with, see [AudioFormat]
+    format: AudioFormat,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct RawFormat {
+    pub format_id: String,
+    pub url: String,
+    pub protocol: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
-struct RawFormat {
-    format_id: String,
+struct RawThumbnail {
     url: String,
+    #[serde(default)]
+    preference: Option<i32>,
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    height: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawChapter {
+    title: String,
+    start_time: f32,
+    end_time: f32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -50,23 +524,126 @@ struct RawYouTubeVideo {
     id: String,
     title: String,
     channel: String,
-    thumbnail: String,
-    format_id: String,
+
+    /// The thumbnail yt-dlp picked, used if `thumbnails` is left out
+    #[serde(default)]
+    thumbnail: Option<String>,
+    #[serde(default)]
+    thumbnails: Vec<RawThumbnail>,
+
+    /// Live streams have no duration, so this is null or left out
+    #[serde(default)]
+    duration: Option<f32>,
+
+    /// These can be left out of pre-fetched JSON, in which case the video is resolved again
+    #[serde(default)]
+    format_id: Option<String>,
+    #[serde(default)]
     formats: Vec<RawFormat>,
-    duration: f32,
+
+    /// Videos without chapters have this set to null
+    #[serde(default)]
+    chapters: Option<Vec<RawChapter>>,
+}
+
+/// An entry of a playlist, as listed by youtube-dl without resolving it
+#[derive(Debug, Deserialize)]
+struct RawPlaylistEntry {
+    id: String,
 }
 
 #[derive(Debug)]
 pub struct YouTubeVideoLoader {
     video: Mutex<YouTubeVideo>,
-    stream: Mutex<ByteRangeStream>,
+    stream: Mutex<AudioStream>,
+
+    /// How many bytes were read, so the stream can continue from there after reconnecting
+    position: u64,
+    reconnects: usize,
 }
 
-impl YouTubeVideo {
-    pub fn fingerprint(&self) -> String {
-        self.title.to_owned()
+/// The audio of a video can either be one file, or split up into segments
+#[derive(Debug)]
+pub(super) enum AudioStream {
+    Progressive(ByteRangeStream),
+    Segmented(SegmentedStream),
+}
+
+impl AudioStream {
+    /// Connects to a stream url, which points to a manifest if `manifest` is set
+    pub fn open(url: &str, manifest: Option<ManifestKind>) -> Result<Self, InputError> {
+        let stream = match manifest {
+            Some(_) => SegmentedStream::try_new(url)
+                .map(AudioStream::Segmented)
+                .map_err(|err| InputError::Other(Box::new(err)))?,
+            None => ByteRangeStream::try_new(url.to_string())
+                .map(AudioStream::Progressive)
+                .ok_or(InputError::Unknown)?,
+        };
+
+        Ok(stream)
+    }
+
+    /// Moves ahead by `amount` bytes without returning them
+    fn skip(&mut self, amount: u64) -> std::io::Result<()> {
+        match self {
+            AudioStream::Progressive(x) => {
+                x.skip(amount as usize);
+                Ok(())
+            }
+            // Segments have no byte offsets, so they are read and thrown away
+            AudioStream::Segmented(x) => {
+                std::io::copy(&mut x.take(amount), &mut std::io::sink()).map(|_| ())
+            }
+        }
+    }
+}
+
+impl Read for AudioStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            AudioStream::Progressive(x) => x.read(buf),
+            AudioStream::Segmented(x) => x.read(buf),
+        }
+    }
+}
+
+impl Extractor for YouTubeVideo {
+    const SOURCE: &'static str = "youtube";
+
+    /// Accepts watch, shorts, embed and live urls on any YouTube subdomain, as well as youtu.be links.
+    /// Other parameters, such as the playlist or timestamp, are left out of the key.
+    fn key_from_url(url: &str) -> Option<String> {
+        let url = parse_url(url)?;
+        let host = url.host_str()?;
+        let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+
+        let id = if host == "youtu.be" {
+            segments.next()?.to_string()
+        } else if host == "youtube.com" || host.ends_with(".youtube.com") {
+            match segments.next()? {
+                "watch" => url.query_pairs().find(|(k, _)| k == "v")?.1.into_owned(),
+                "v" | "shorts" | "embed" | "live" => segments.next()?.to_string(),
+                _ => return None,
+            }
+        } else {
+            return None;
+        };
+
+        ID_REGEX.is_match(&id).then_some(id)
+    }
+
+    /// Titles are not unique, so the video id is used
+    fn key(&self) -> String {
+        self.id.clone()
     }
 
+    fn url(&self) -> String {
+        format!("https://www.youtube.com/watch?v={}", self.id)
+    }
+}
+
+impl YouTubeVideo {
     pub fn metadata(&self) -> Metadata {
         Metadata {
             title: self.title.clone(),
@@ -74,26 +651,182 @@ impl YouTubeVideo {
             canonical: format!("https://youtube.com/v/{}", self.id),
             source: "youtube".to_string(),
             duration: self.duration,
-            artwork: Some(self.thumbnail.clone()),
+            artwork: self.thumbnail.clone(),
+            chapters: self.chapters.clone(),
+        }
+    }
+
+    pub fn from_url(url: &str, format: &AudioFormat) -> Result<Self, InputError> {
+        let id = Self::key_from_url(url).ok_or(InputError::NoMatch)?;
+        parse_from_url(&watch_url(&id), format).map(|video| video.with_start_from(url))
+    }
+
+    /// Returns the same video, beginning where `url` says
+    pub fn with_start_from(self, url: &str) -> Self {
+        Self {
+            start: start_from_url(url),
+            ..self
         }
     }
 
-    pub fn from_url(url: &str) -> Result<Self, InputError> {
-        let url = REGEX
-            .find(url)
-            .map(|m| m.as_str())
+    /// Returns the first `limit` videos of a playlist in order, skipping the ones that fail to resolve
+    pub fn from_playlist_url(
+        url: &str,
+        limit: usize,
+        format: &AudioFormat,
+    ) -> Result<Vec<Self>, InputError> {
+        // Resolving a video takes a while, so a few are resolved at once
+        const PARALLEL_RESOLVES: usize = 8;
+
+        let list = PLAYLIST_REGEX
+            .captures(url)
+            .map(|c| c["list"].to_string())
             .ok_or(InputError::NoMatch)?;
 
-        parse_from_url(url).ok_or(InputError::NotFound)
+        let ids = playlist_ids(&list, limit)?;
+
+        let results: Vec<_> = ids
+            .chunks(PARALLEL_RESOLVES)
+            .flat_map(|chunk| {
+                thread::scope(|scope| {
+                    let handles: Vec<_> = chunk
+                        .iter()
+                        .map(|id| scope.spawn(|| parse_from_url(&watch_url(id), format)))
+                        .collect();
+
+                    handles
+                        .into_iter()
+                        .filter_map(|h| h.join().ok())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let busy = results.iter().any(|r| matches!(r, Err(InputError::Busy)));
+
+        let videos: Vec<_> = results.into_iter().filter_map(Result::ok).collect();
+
+        if videos.is_empty() && busy {
+            return Err(InputError::Busy);
+        }
+
+        if videos.is_empty() {
+            return Err(InputError::NotFound);
+        }
+
+        Ok(videos)
+    }
+
+    /// Returns the top result of searching YouTube for `query`
+    pub fn from_search(query: &str, format: &AudioFormat) -> Result<Self, InputError> {
+        let stdout = with_retries(&format!("search results for \"{}\"", query), || {
+            extract(&format!("ytsearch1:{}", query), format)
+        })?;
+
+        // Nothing is printed if there are no results
+        if stdout.iter().all(u8::is_ascii_whitespace) {
+            return Err(InputError::NotFound);
+        }
+
+        let raw: RawYouTubeVideo = serde_json::from_slice(&stdout)
+            .map_err(|err| InputError::Malformed(err.to_string()))?;
+
+        raw.validate()?;
+        raw.into_video(format).ok_or(InputError::NotFound)
+    }
+
+    /// Creates a video from youtube-dl JSON, resolving it again if no usable format is in it.
+    /// The format is the one set with `VINYL_YTDLP_FORMAT`, since the JSON does not say.
+    pub fn from_json(json: &str) -> Result<Self, InputError> {
+        let raw: RawYouTubeVideo =
+            serde_json::from_str(json).map_err(|err| InputError::Malformed(err.to_string()))?;
+
+        raw.validate()?;
+
+        let url = watch_url(&raw.id);
+        let format = AudioFormat::configured();
+
+        match raw.into_video(&format) {
+            Some(video) => Ok(video),
+            None => parse_from_url(&url, &format),
+        }
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    pub fn start(&self) -> Option<Duration> {
+        self.start
+    }
+
+    pub fn format(&self) -> &AudioFormat {
+        &self.format
+    }
+
+    /// Fetches the video again to get a fresh stream url, in the format it was first parsed in.
+    /// Only the stream is replaced, the metadata stays as it was first parsed.
+    pub fn refresh(&self) -> Result<Self, InputError> {
+        let fresh = parse_from_url(&watch_url(&self.id), &self.format)?;
+
+        Ok(Self {
+            audio_stream_url: fresh.audio_stream_url,
+            manifest: fresh.manifest,
+            expires_at: fresh.expires_at,
+            resolved_at: fresh.resolved_at,
+            ..self.clone()
+        })
+    }
+
+    /// Returns true if the stream url should be resolved again before loading,
+    /// because it is older than `VINYL_STREAM_URL_MAX_AGE_SECS` or about to expire
+    pub fn needs_refresh(&self) -> bool {
+        self.needs_refresh_at(unix_millis() / 1000, *STREAM_URL_MAX_AGE)
+    }
+
+    fn needs_refresh_at(&self, now: u64, max_age: Duration) -> bool {
+        /// Loading can take a while, so urls expiring this soon are not worth trying
+        const EXPIRY_MARGIN: u64 = 60 * 5;
+
+        let too_old = now.saturating_sub(self.resolved_at) >= max_age.as_secs();
+        let expiring = self
+            .expires_at
+            .is_some_and(|expires_at| now + EXPIRY_MARGIN >= expires_at);
+
+        too_old || expiring
+    }
+
+    fn stream(&self) -> Result<AudioStream, InputError> {
+        AudioStream::open(&self.audio_stream_url, self.manifest)
     }
 
     pub fn loader(&self) -> Result<Box<dyn Loader>, InputError> {
-        let stream = ByteRangeStream::try_new(self.audio_stream_url.clone())
-            .ok_or(InputError::Unknown)?
-            .into();
+        Ok(Box::new(YouTubeVideoLoader {
+            video: self.clone().into(),
+            stream: self.stream()?.into(),
+            position: 0,
+            reconnects: 0,
+        }))
+    }
+}
+
+impl YouTubeVideoLoader {
+    /// Reconnecting more than this means something other than the url is wrong
+    const MAX_RECONNECTS: usize = 2;
+
+    /// Resolves the stream url again and continues where reading stopped
+    fn reconnect(&mut self) -> Result<(), InputError> {
+        let video = self.video.lock().refresh()?;
+
+        let mut stream = video.stream()?;
+        stream
+            .skip(self.position)
+            .map_err(|err| InputError::Other(Box::new(err)))?;
+
+        *self.stream.lock() = stream;
+        *self.video.lock() = video;
 
-        let video = self.clone().into();
-        Ok(Box::new(YouTubeVideoLoader { video, stream }))
+        Ok(())
     }
 }
 
@@ -101,7 +834,30 @@ impl Loader for YouTubeVideoLoader {
     fn load(&mut self, amount: usize) -> LoadResult {
         let mut buf = vec![0; amount];
 
-        let bytes_read = self.stream.lock().read(&mut buf).unwrap_or_default();
+        let result = self.stream.lock().read(&mut buf);
+
+        let bytes_read = match result {
+            Ok(bytes_read) => bytes_read,
+            // The stream url may have expired while the video was loading
+            Err(err) if self.reconnects < Self::MAX_RECONNECTS => {
+                self.reconnects += 1;
+                warn!("Failed to load YouTube video, reconnecting: {}", err);
+
+                return match self.reconnect() {
+                    Ok(_) => self.load(amount),
+                    Err(err) => {
+                        error!("Failed to reconnect to YouTube video: {}", err);
+                        LoadResult::Error
+                    }
+                };
+            }
+            Err(err) => {
+                error!("Failed to load YouTube video: {}", err);
+                return LoadResult::Error;
+            }
+        };
+        self.position += bytes_read as u64;
+
         let bytes: Vec<_> = buf[..bytes_read].to_vec();
 
         if bytes_read > 0 {
@@ -128,42 +884,660 @@ impl Display for YouTubeVideo {
     }
 }
 
-/// Tries to fetch the video via youtube-dl, returning None if important
-/// fields are missing or the fetch failed.
-pub fn parse_from_url(url: &str) -> Option<YouTubeVideo> {
-    let mut child = Command::new("yt-dlp")
-        .arg("-f")
-        .arg("bestaudio/best")
+/// Fetches the video via yt-dlp, trying again if it fails for reasons that might go away
+pub fn parse_from_url(url: &str, format: &AudioFormat) -> Result<YouTubeVideo, InputError> {
+    let json = extract_json(url, format)?;
+
+    let raw: RawYouTubeVideo = serde_json::from_slice(&json).map_err(|err| {
+        error!("Failed to fetch YouTube video: {}", err);
+        InputError::Malformed(err.to_string())
+    })?;
+
+    raw.into_video(format).ok_or(InputError::NotFound)
+}
+
+/// Extracts the audio of a url in `format` with yt-dlp, returning what it printed as JSON.
+/// This works for any site yt-dlp supports, and is tried again if it fails for reasons that might go away.
+pub(super) fn extract_json(url: &str, format: &AudioFormat) -> Result<Vec<u8>, InputError> {
+    with_retries(url, || extract(url, format))
+}
+
+/// Runs yt-dlp once to extract `target` in `format`.
+/// If nothing matches the format, [AudioFormat::FALLBACK] is tried instead.
+fn extract(target: &str, format: &AudioFormat) -> Result<Vec<u8>, Failure> {
+    let _job = YtDlpJob::start().map_err(Failure::Permanent)?;
+
+    let run = |format: &str| {
+        yt_dlp()
+            .arg("-f")
+            .arg(format)
+            .arg("-j")
+            .arg("--")
+            .arg(target)
+            .output()
+            .expect("yt-dlp failed to spawn")
+    };
+
+    let mut output = run(format.as_str());
+    let mut stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    if !output.status.success() && !format.is_fallback() && is_format_unavailable(&stderr) {
+        warn!(
+            "No audio of {} matches the format {}, using {} instead",
+            target,
+            format,
+            AudioFormat::FALLBACK
+        );
+
+        output = run(AudioFormat::FALLBACK);
+        stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    }
+
+    if !output.status.success() {
+        return Err(Failure::from_stderr(&stderr));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Returns true if yt-dlp failed because no format of the audio matched the one asked for
+fn is_format_unavailable(stderr: &str) -> bool {
+    stderr
+        .to_lowercase()
+        .contains("requested format is not available")
+}
+
+/// Lists the ids of the first `limit` videos in a playlist, without resolving them
+fn playlist_ids(list: &str, limit: usize) -> Result<Vec<String>, InputError> {
+    let _job = YtDlpJob::start()?;
+
+    let output = yt_dlp()
+        .arg("--flat-playlist")
+        .arg("--playlist-end")
+        .arg(limit.to_string())
         .arg("-j")
         .arg("--")
-        .arg(url)
-        .stdout(Stdio::piped())
+        .arg(format!("https://youtube.com/playlist?list={}", list))
         .stderr(Stdio::null())
-        .spawn()
+        .output()
         .expect("yt-dlp failed to spawn");
 
-    let stdout = child.stdout.take().unwrap();
-    let video: Result<RawYouTubeVideo, serde_json::Error> = serde_json::from_reader(stdout);
+    // One entry is printed per line
+    let ids = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<RawPlaylistEntry>(line).ok())
+        .map(|entry| entry.id)
+        .filter(|id| ID_REGEX.is_match(id))
+        .take(limit)
+        .collect();
+
+    Ok(ids)
+}
+
+/// Parses a url that may be missing its scheme, accepting only http and https
+fn parse_url(url: &str) -> Option<Url> {
+    let url = match url.contains("://") {
+        true => Url::parse(url),
+        false => Url::parse(&format!("https://{}", url)),
+    }
+    .ok()?;
+
+    matches!(url.scheme(), "http" | "https").then_some(url)
+}
+
+/// Returns where playback should begin from the `t` or `start` parameter of a url,
+/// which can be in the query or the fragment, like `t=90`, `t=90s` or `#t=1m30s`
+pub fn start_from_url(url: &str) -> Option<Duration> {
+    let url = parse_url(url)?;
+
+    let fragment = url
+        .fragment()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+
+    url.query_pairs()
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .chain(fragment)
+        .filter(|(k, _)| k == "t" || k == "start")
+        .find_map(|(_, v)| parse_timestamp(&v))
+        .filter(|start| !start.is_zero())
+}
 
-    child.wait().expect("yt-dlp exits");
+/// Parses a timestamp in seconds, or with units like `1h2m3s`
+fn parse_timestamp(str: &str) -> Option<Duration> {
+    let captures = TIMESTAMP_REGEX.captures(str).filter(|_| !str.is_empty())?;
 
-    video
-        .map_err(|err| {
-            error!("Failed to fetch YouTube video: {}", err.to_string());
+    let seconds = [("h", 60 * 60), ("m", 60), ("s", 1)]
+        .into_iter()
+        .map(|(unit, seconds)| match captures.name(unit) {
+            Some(x) => x.as_str().parse::<u64>().ok()?.checked_mul(seconds),
+            None => Some(0),
         })
-        .ok()
-        .and_then(|raw_video| {
-            raw_video
-                .formats
-                .iter()
-                .find(|f| f.format_id == raw_video.format_id)
-                .map(|format| YouTubeVideo {
-                    id: raw_video.id,
-                    title: raw_video.title,
-                    channel: raw_video.channel,
-                    thumbnail: raw_video.thumbnail,
-                    duration: raw_video.duration,
-                    audio_stream_url: format.url.to_owned(),
+        .try_fold(0u64, |total, x| total.checked_add(x?))?;
+
+    Some(Duration::from_secs(seconds))
+}
+
+fn watch_url(id: &str) -> String {
+    format!("https://youtube.com/watch?v={}", id)
+}
+
+impl RawYouTubeVideo {
+    /// Checks fields that would otherwise cause confusing failures later
+    fn validate(&self) -> Result<(), InputError> {
+        if !ID_REGEX.is_match(&self.id) {
+            return Err(InputError::Malformed(
+                "id is not a YouTube video id".to_string(),
+            ));
+        }
+
+        if self.title.trim().is_empty() {
+            return Err(InputError::Malformed("title is empty".to_string()));
+        }
+
+        if self.duration.is_some_and(|d| !d.is_finite() || d < 0.) {
+            return Err(InputError::Malformed("duration is invalid".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the url of the thumbnail with the highest preference, then the highest resolution
+    fn best_thumbnail(&self) -> Option<String> {
+        self.thumbnails
+            .iter()
+            .max_by_key(|t| {
+                let pixels = t.width.unwrap_or_default() * t.height.unwrap_or_default();
+                (t.preference.unwrap_or(i32::MIN), pixels)
+            })
+            .map(|t| t.url.clone())
+            .or_else(|| self.thumbnail.clone())
+            .filter(|url| !url.trim().is_empty())
+    }
+
+    /// Returns [None] if the chosen format is missing.
+    /// `requested` is what the format was chosen with, so it is chosen the same way when refreshed.
+    fn into_video(self, requested: &AudioFormat) -> Option<YouTubeVideo> {
+        let format = self
+            .formats
+            .iter()
+            .find(|f| Some(&f.format_id) == self.format_id.as_ref())?;
+
+        let thumbnail = self.best_thumbnail();
+
+        Some(YouTubeVideo {
+            expires_at: EXPIRE_REGEX
+                .captures(&format.url)
+                .and_then(|c| c[1].parse().ok()),
+            audio_stream_url: format.url.to_owned(),
+            manifest: manifest_kind(format),
+            resolved_at: unix_millis() / 1000,
+            start: None,
+            format: requested.clone(),
+            id: self.id,
+            title: self.title,
+            channel: self.channel,
+            thumbnail,
+            duration: self.duration,
+            chapters: self
+                .chapters
+                .unwrap_or_default()
+                .into_iter()
+                .map(|c| Chapter {
+                    title: c.title,
+                    start: c.start_time,
+                    end: c.end_time,
                 })
+                .collect(),
+        })
+    }
+}
+
+/// Detects if a format is delivered as a manifest, using the protocol youtube-dl reports,
+/// or the url if the protocol is missing.
+pub(super) fn manifest_kind(format: &RawFormat) -> Option<ManifestKind> {
+    match format.protocol.as_deref() {
+        Some(p) if p.starts_with("m3u8") => Some(ManifestKind::Hls),
+        Some("http_dash_segments") => Some(ManifestKind::Dash),
+        _ => ManifestKind::detect(&format.url),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::util::ApiError;
+
+    use super::{
+        backoff, is_outdated, parse_timestamp, start_from_url, ytdlp_version, AudioFormat,
+        Extractor, Failure, InputError, JobLimit, Jobs, YouTubeVideo, YtDlpError, PLAYLIST_REGEX,
+    };
+
+    #[test]
+    fn compares_versions() {
+        assert!(!is_outdated("2023.03.04"));
+        assert!(!is_outdated("2023.11.16"));
+        assert!(!is_outdated("2024.01.01.232323"));
+        assert!(is_outdated("2023.02.17"));
+        assert!(is_outdated("2021.12.27"));
+        assert!(is_outdated("youtube-dl"));
+    }
+
+    #[test]
+    fn reports_missing_ytdlp() {
+        let result = ytdlp_version("/nonexistent/yt-dlp".as_ref());
+        assert!(matches!(result, Err(YtDlpError::Missing { .. })));
+    }
+
+    #[test]
+    fn limits_jobs() {
+        let jobs = Jobs::new();
+        let limit = JobLimit {
+            max: 2,
+            timeout: Duration::from_millis(50),
+        };
+
+        let first = jobs.start(limit).unwrap();
+        let _second = jobs.start(limit).unwrap();
+
+        // Waits for the timeout, then gives up
+        let started = Instant::now();
+        assert!(matches!(jobs.start(limit), Err(InputError::Busy)));
+        assert!(started.elapsed() >= limit.timeout);
+        assert_eq!(jobs.waiting.load(), 0);
+
+        // Waits for a job to finish instead of failing
+        let limit = JobLimit {
+            timeout: Duration::from_secs(5),
+            ..limit
+        };
+
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| jobs.start(limit).map(|_| ()));
+
+            while jobs.waiting.load() == 0 {
+                thread::yield_now();
+            }
+
+            drop(first);
+            assert!(waiting.join().unwrap().is_ok());
+        });
+
+        assert_eq!(*jobs.running.lock(), 1);
+    }
+
+    #[test]
+    fn reports_full_jobs() {
+        let jobs = Jobs::new();
+        let limit = JobLimit {
+            max: 1,
+            timeout: Duration::from_secs(5),
+        };
+
+        let first = jobs.start(limit).unwrap();
+        assert!(!jobs.is_full(limit));
+
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| jobs.start(limit).map(|_| ()));
+
+            while jobs.waiting.load() == 0 {
+                thread::yield_now();
+            }
+
+            assert!(jobs.is_full(limit));
+
+            drop(first);
+            assert!(waiting.join().unwrap().is_ok());
+        });
+
+        assert!(!jobs.is_full(limit));
+    }
+
+    fn video_json() -> serde_json::Value {
+        json!({
+            "id": "dQw4w9WgXcQ",
+            "title": "Never Gonna Give You Up",
+            "channel": "Rick Astley",
+            "thumbnail": "https://i.ytimg.com/vi/dQw4w9WgXcQ/maxresdefault.jpg",
+            "duration": 212.0,
+            "format_id": "251",
+            "formats": [
+                { "format_id": "140", "url": "https://example.com/140" },
+                { "format_id": "251", "url": "https://example.com/251?expire=1700000000" }
+            ]
         })
+    }
+
+    #[test]
+    fn from_json() {
+        let video = YouTubeVideo::from_json(&video_json().to_string()).unwrap();
+
+        assert_eq!(video.fingerprint(), "youtube:dQw4w9WgXcQ");
+        assert_eq!(
+            video.audio_stream_url,
+            "https://example.com/251?expire=1700000000"
+        );
+        assert_eq!(video.expires_at(), Some(1700000000));
+        assert!(video.chapters.is_empty());
+    }
+
+    #[test]
+    fn picks_best_thumbnail() {
+        let artwork = |json: serde_json::Value| {
+            YouTubeVideo::from_json(&json.to_string())
+                .unwrap()
+                .metadata()
+                .artwork
+        };
+
+        let mut json = video_json();
+        assert_eq!(
+            artwork(json.clone()).as_deref(),
+            Some("https://i.ytimg.com/vi/dQw4w9WgXcQ/maxresdefault.jpg")
+        );
+
+        json["thumbnails"] = json!([
+            { "url": "https://example.com/small.jpg", "preference": -10, "width": 120, "height": 90 },
+            { "url": "https://example.com/large.jpg", "preference": -1, "width": 1280, "height": 720 },
+            { "url": "https://example.com/medium.jpg", "preference": -1, "width": 640, "height": 480 }
+        ]);
+        assert_eq!(
+            artwork(json.clone()).as_deref(),
+            Some("https://example.com/large.jpg")
+        );
+
+        json["thumbnails"] = json!([]);
+        json["thumbnail"] = json!("");
+        assert_eq!(artwork(json), None);
+    }
+
+    #[test]
+    fn live_streams_have_no_duration() {
+        let mut json = video_json();
+        json["duration"] = json!(null);
+
+        let video = YouTubeVideo::from_json(&json.to_string()).unwrap();
+        assert_eq!(video.metadata().duration, None);
+
+        json["duration"] = json!(-1.0);
+        assert!(YouTubeVideo::from_json(&json.to_string()).is_err());
+    }
+
+    #[test]
+    fn needs_refresh_when_old_or_expiring() {
+        let mut video = YouTubeVideo::from_json(&video_json().to_string()).unwrap();
+        let max_age = Duration::from_secs(60 * 60);
+
+        video.resolved_at = 1000;
+        video.expires_at = None;
+
+        assert!(!video.needs_refresh_at(1000, max_age));
+        assert!(!video.needs_refresh_at(1000 + 60 * 59, max_age));
+        assert!(video.needs_refresh_at(1000 + 60 * 60, max_age));
+
+        // Even if it is young, it should not expire while loading
+        video.expires_at = Some(1000 + 60 * 10);
+
+        assert!(!video.needs_refresh_at(1000 + 60, max_age));
+        assert!(video.needs_refresh_at(1000 + 60 * 6, max_age));
+
+        // A max age of 0 always refreshes
+        assert!(video.needs_refresh_at(1000, Duration::ZERO));
+    }
+
+    #[test]
+    fn fingerprint_uses_id() {
+        let video = |id: &str, title: &str| {
+            let mut json = video_json();
+            json["id"] = json!(id);
+            json["title"] = json!(title);
+
+            YouTubeVideo::from_json(&json.to_string()).unwrap()
+        };
+
+        assert_ne!(
+            video("dQw4w9WgXcQ", "Never Gonna Give You Up").fingerprint(),
+            video("yPYZpwSpKmA", "Never Gonna Give You Up").fingerprint()
+        );
+
+        assert_eq!(
+            video("dQw4w9WgXcQ", "Never Gonna Give You Up").fingerprint(),
+            video(
+                "dQw4w9WgXcQ",
+                "Rick Astley - Never Gonna Give You Up (Official Video)"
+            )
+            .fingerprint()
+        );
+    }
+
+    #[test]
+    fn video_urls() {
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "youtube.com/watch?v=dQw4w9WgXcQ&list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI&t=30s",
+            "https://youtube.com/watch?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI&index=2&v=dQw4w9WgXcQ",
+            "https://m.youtube.com/watch?v=dQw4w9WgXcQ&feature=share#comments",
+            "https://music.youtube.com/watch?v=dQw4w9WgXcQ&si=abc",
+            "HTTPS://WWW.YOUTUBE.COM/watch?v=dQw4w9WgXcQ",
+            "https://youtube.com/shorts/dQw4w9WgXcQ?feature=share",
+            "m.youtube.com/shorts/dQw4w9WgXcQ/",
+            "https://www.youtube.com/embed/dQw4w9WgXcQ?start=30",
+            "https://youtube.com/live/dQw4w9WgXcQ",
+            "http://youtube.com/v/dQw4w9WgXcQ",
+            "youtu.be/dQw4w9WgXcQ?t=30",
+        ] {
+            assert_eq!(
+                YouTubeVideo::key_from_url(url).as_deref(),
+                Some("dQw4w9WgXcQ"),
+                "{} is a video",
+                url
+            );
+        }
+
+        for url in [
+            "https://youtube.com/playlist?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI",
+            "https://youtube.com/@RickAstleyYT",
+            "https://youtube.com/watch?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI",
+            "https://youtube.com/watch?v=../../etc",
+            "https://youtube.com/shorts",
+            "https://notyoutube.com/watch?v=dQw4w9WgXcQ",
+            "https://youtube.com.example.com/watch?v=dQw4w9WgXcQ",
+            "ftp://youtube.com/watch?v=dQw4w9WgXcQ",
+        ] {
+            assert_eq!(
+                YouTubeVideo::key_from_url(url),
+                None,
+                "{} is not a video",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        for timestamp in ["90", "90s", "1m30s", "1m30", "0h1m30s"] {
+            assert_eq!(
+                parse_timestamp(timestamp),
+                Some(Duration::from_secs(90)),
+                "{} is 90 seconds",
+                timestamp
+            );
+        }
+
+        assert_eq!(parse_timestamp("1h"), Some(Duration::from_secs(60 * 60)));
+
+        for timestamp in ["", "-90", "1m30sec", "90.5", "s", "99999999999999999999"] {
+            assert_eq!(parse_timestamp(timestamp), None, "{} is invalid", timestamp);
+        }
+    }
+
+    #[test]
+    fn start_from_urls() {
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=90s",
+            "youtube.com/watch?v=dQw4w9WgXcQ&list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI&t=90",
+            "https://youtu.be/dQw4w9WgXcQ?t=1m30s",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ#t=1m30s",
+            "https://www.youtube.com/embed/dQw4w9WgXcQ?start=90",
+        ] {
+            assert_eq!(
+                start_from_url(url),
+                Some(Duration::from_secs(90)),
+                "{} starts 90 seconds in",
+                url
+            );
+        }
+
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=0s",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=soon",
+        ] {
+            assert_eq!(start_from_url(url), None, "{} starts at the beginning", url);
+        }
+
+        // The timestamp does not change which video it is
+        assert_eq!(
+            YouTubeVideo::key_from_url("https://youtu.be/dQw4w9WgXcQ?t=90"),
+            YouTubeVideo::key_from_url("https://youtu.be/dQw4w9WgXcQ")
+        );
+    }
+
+    #[test]
+    fn playlist_urls() {
+        for url in [
+            "https://www.youtube.com/playlist?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI",
+            "youtube.com/playlist?feature=share&list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI",
+        ] {
+            let list = PLAYLIST_REGEX.captures(url).map(|c| c["list"].to_string());
+            assert_eq!(list.as_deref(), Some("PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI"));
+        }
+
+        // Videos in a playlist are queued on their own
+        assert!(!PLAYLIST_REGEX.is_match("https://youtube.com/watch?v=dQw4w9WgXcQ&list=PLabc"));
+        assert!(matches!(
+            YouTubeVideo::from_playlist_url(
+                "https://youtube.com/watch?v=dQw4w9WgXcQ",
+                100,
+                &AudioFormat::configured()
+            ),
+            Err(InputError::NoMatch)
+        ));
+    }
+
+    #[test]
+    fn from_malformed_json() {
+        let mut missing_title = video_json();
+        missing_title.as_object_mut().unwrap().remove("title");
+
+        let mut bad_id = video_json();
+        bad_id["id"] = json!("../../etc");
+
+        for json in [
+            missing_title.to_string(),
+            bad_id.to_string(),
+            "{".to_string(),
+        ] {
+            assert!(matches!(
+                YouTubeVideo::from_json(&json),
+                Err(InputError::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn classifies_failures() {
+        let private = "WARNING: something\nERROR: [youtube] dQw4w9WgXcQ: Private video. Sign in if you've been granted access";
+        let server = "ERROR: [youtube] dQw4w9WgXcQ: Unable to download API page: HTTP Error 503: Service Unavailable";
+
+        assert!(matches!(
+            Failure::from_stderr(private),
+            Failure::Permanent(InputError::Private(reason))
+                if reason == "Private video. Sign in if you've been granted access"
+        ));
+        assert!(matches!(
+            Failure::from_stderr(server),
+            Failure::Transient(reason) if reason.contains("HTTP Error 503")
+        ));
+        assert!(matches!(Failure::from_stderr(""), Failure::Transient(_)));
+    }
+
+    #[test]
+    fn classifies_permanent_failures() {
+        let classify = |reason: &str| match Failure::from_stderr(&format!(
+            "ERROR: [youtube] dQw4w9WgXcQ: {reason}"
+        )) {
+            Failure::Permanent(err) => err,
+            Failure::Transient(reason) => panic!("{reason} should be permanent"),
+        };
+
+        assert!(matches!(
+            classify("Video unavailable. This video has been removed by the uploader"),
+            InputError::Unavailable(_)
+        ));
+        assert!(matches!(
+            classify("Video unavailable. This video is no longer available because the YouTube account associated with this video has been terminated."),
+            InputError::Unavailable(_)
+        ));
+        assert!(matches!(
+            classify("Video unavailable. This video is private"),
+            InputError::Private(_)
+        ));
+        assert!(matches!(
+            classify("Join this channel to get access to members-only content like this video, and other exclusive perks."),
+            InputError::Private(_)
+        ));
+        assert!(matches!(
+            classify(
+                "Sign in to confirm your age. This video may be inappropriate for some users."
+            ),
+            InputError::AgeRestricted(_)
+        ));
+        assert!(matches!(
+            classify(
+                "Video unavailable. The uploader has not made this video available in your country"
+            ),
+            InputError::RegionBlocked(_)
+        ));
+        assert!(matches!(
+            classify("Video unavailable. This video contains content from SME, who has blocked it in your country on copyright grounds"),
+            InputError::RegionBlocked(_)
+        ));
+    }
+
+    #[test]
+    fn maps_failures_to_statuses() {
+        use hyper::StatusCode;
+
+        let cases = [
+            (InputError::Unavailable("".into()), StatusCode::NOT_FOUND),
+            (InputError::Private("".into()), StatusCode::FORBIDDEN),
+            (InputError::AgeRestricted("".into()), StatusCode::FORBIDDEN),
+            (
+                InputError::RegionBlocked("".into()),
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            ),
+            (InputError::NetworkFailed, StatusCode::BAD_GATEWAY),
+        ];
+
+        for (err, status) in cases {
+            assert_eq!(ApiError::from(err).status(), status);
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(3), Duration::from_secs(4));
+        assert_eq!(backoff(100), Duration::from_secs(30));
+    }
 }
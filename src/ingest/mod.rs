@@ -11,30 +11,44 @@ use crossbeam::{
     channel::{unbounded, Receiver, Sender},
 };
 use dashmap::DashMap;
+use log::{error, info};
 use parking_lot::Mutex;
 
 use crate::{
     audio::{raw_samples_from_bytes, Sample, SAMPLES_PER_SEC},
+    util::shutdown,
     EventEmitter,
 };
 
 use self::loading::{LoadResult, Loader};
 
+mod cache;
 mod events;
+mod failures;
 mod ffmpeg;
 mod input;
 mod loading;
+mod relay;
 mod sink;
 
+pub use cache::{cache_config, AudioCache};
 pub use events::*;
+pub use failures::*;
 pub use input::*;
 pub use loading::*;
+pub use relay::Relay;
 pub use sink::*;
 
 #[derive(Debug)]
 pub struct Ingestion {
     emitter: EventEmitter,
 
+    /// Recent failures, used to show users what did not work
+    pub failures: Arc<Failures>,
+
+    /// Downloaded audio, [None] if caching is disabled, see [cache_config]
+    pub cache: Option<Arc<AudioCache>>,
+
     current_sink_id: AtomicCell<SinkId>,
     child: Mutex<Option<Child>>,
 
@@ -73,6 +87,8 @@ impl Ingestion {
 
         Self {
             emitter,
+            failures: Default::default(),
+            cache: open_cache(),
 
             current_sink_id: SinkId::none().into(),
             child: None.into(),
@@ -124,8 +140,16 @@ impl Ingestion {
         }
     }
 
-    pub fn add(&self, probe_result: ProbeResult, loader: Box<dyn Loader>) -> SinkId {
-        let sink = Arc::new(InternalSink::new(probe_result.length));
+    /// Adds a sink to ingest into, sharing its gain with the track, see [InternalSink::with_gain]
+    /// Playback of the sink begins `start` samples in.
+    pub fn add(
+        &self,
+        probe_result: ProbeResult,
+        loader: Box<dyn Loader>,
+        gain: Arc<AtomicCell<Option<f32>>>,
+        start: usize,
+    ) -> SinkId {
+        let sink = Arc::new(InternalSink::with_gain(probe_result.length, gain).starting_at(start));
         let sink_id = sink.id();
 
         self.sinks.insert(sink_id, sink.clone());
@@ -145,6 +169,28 @@ impl Ingestion {
             .unwrap();
     }
 
+    /// Returns true if the input is cached, so loading it needs nothing from its source
+    pub fn is_cached(&self, input: &Input) -> bool {
+        self.cache
+            .as_ref()
+            .is_some_and(|cache| cache.contains(&input.cache_key()))
+    }
+
+    /// Returns a loader for the input, reading it from the cache if it was loaded before
+    pub fn loader(&self, input: &Input) -> Result<Box<dyn Loader>, InputError> {
+        let key = input.cache_key();
+
+        let Some(cache) = self.cache.as_ref().filter(|_| !key.is_empty()) else {
+            return input.loader();
+        };
+
+        if let Some(loader) = cache.loader(&key) {
+            return Ok(loader);
+        }
+
+        Ok(cache.caching(&key, input.loader()?))
+    }
+
     pub fn current_sink(&self) -> Option<Sink> {
         self.sinks
             .get(&self.current_sink_id.load())
@@ -160,8 +206,8 @@ pub fn spawn_loading_thread(ingestion: Arc<Ingestion>) {
         let receiver = ingestion.loading_receiver.clone();
         let emitter = ingestion.emitter.clone();
 
-        loop {
-            match receiver.recv() {
+        while !shutdown::requested() {
+            match receiver.recv_timeout(Duration::from_millis(100)) {
                 Ok(LoadingMessage::Respawn(new_stdin, new_sink_id)) => {
                     stdin = Some(new_stdin);
                     sink_id = new_sink_id;
@@ -196,7 +242,15 @@ pub fn spawn_loading_thread(ingestion: Arc<Ingestion>) {
                                 total: sink.available(),
                             });
                         }
-                        LoadResult::Error => todo!(),
+                        // Play what was loaded instead of waiting for data that will never come
+                        LoadResult::Error => {
+                            sink.seal();
+
+                            emitter.dispatch(IngestionEvent::Finished {
+                                sink: sink.id(),
+                                total: sink.available(),
+                            });
+                        }
                     }
                 }
                 _ => {}
@@ -217,7 +271,7 @@ pub fn spawn_processing_thread(ingestion: Arc<Ingestion>) {
         let receiver = ingestion.processing_receiver.clone();
         let sender = ingestion.check_channel.0.clone();
 
-        loop {
+        while !shutdown::requested() {
             if let Ok(ProcessingMessage::Respawn(new_stdout)) = receiver.try_recv() {
                 stdout = Some(new_stdout);
             }
@@ -249,7 +303,7 @@ pub fn spawn_load_write_thread(ingestion: Arc<Ingestion>) {
 
         let mut data = vec![];
 
-        loop {
+        while !shutdown::requested() {
             if let Some(sink) = ingestion.current_sink() {
                 // We wait for the ffmpeg thread to block before proceeding
                 if let Ok(message) = receiver.recv_timeout(Duration::from_millis(50)) {
@@ -320,7 +374,38 @@ pub fn spawn_cleanup_thread(ingestion: Arc<Ingestion>) {
         .unwrap();
 }
 
+/// Opens the cache if it is enabled, logging an error and going without it if that fails
+fn open_cache() -> Option<Arc<AudioCache>> {
+    let config = cache_config()?;
+    let dir = config.dir.clone();
+
+    match AudioCache::open(config) {
+        Ok(cache) => {
+            info!(target: "vinyl::audio",
+                "Audio cache in {} holds {} MB",
+                dir.display(),
+                cache.size() / 1024 / 1024
+            );
+
+            Some(cache.into())
+        }
+        Err(err) => {
+            error!(target: "vinyl::audio",
+                "Could not open audio cache in {}, so nothing is cached: {}",
+                dir.display(),
+                err
+            );
+
+            None
+        }
+    }
+}
+
 pub fn run_ingestion(ingestion: Arc<Ingestion>) {
+    if let Some(cache) = ingestion.cache.clone() {
+        cache::spawn_cache_prune_thread(cache);
+    }
+
     spawn_loading_thread(ingestion.clone());
     spawn_processing_thread(ingestion.clone());
     spawn_load_write_thread(ingestion.clone());
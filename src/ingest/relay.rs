@@ -0,0 +1,139 @@
+use std::{io::Read, process::Child, sync::Arc, thread, time::Duration};
+
+use crossbeam::atomic::AtomicCell;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+
+use crate::audio::{raw_samples_from_bytes, SAMPLES_PER_SEC, SAMPLE_IN_BYTES};
+
+use super::{ffmpeg, InternalSink, Sink};
+
+/// Continuously decodes an external stream, such as an Icecast station
+/// or another Vinyl room, into an endless [Sink].
+///
+/// If the upstream drops, the relay will keep reconnecting until it is stopped,
+/// backing off longer after each consecutive failure.
+#[derive(Debug)]
+pub struct Relay {
+    url: String,
+    sink: Sink,
+    stopped: AtomicCell<bool>,
+    child: Mutex<Option<Child>>,
+}
+
+impl Relay {
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    pub fn spawn(url: String) -> Arc<Self> {
+        let relay = Arc::new(Self {
+            url,
+            sink: InternalSink::new_relayed().into(),
+            stopped: false.into(),
+            child: None.into(),
+        });
+
+        let cloned = relay.clone();
+
+        thread::Builder::new()
+            .name("audio_relay".to_string())
+            .spawn(move || cloned.run())
+            .unwrap();
+
+        relay
+    }
+
+    pub fn sink(&self) -> Sink {
+        self.sink.clone()
+    }
+
+    /// Stops relaying. This cannot be undone.
+    pub fn stop(&self) {
+        self.stopped.store(true);
+
+        if let Some(mut child) = self.child.lock().take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    fn run(&self) {
+        let mut failures = 0;
+
+        while !self.stopped.load() {
+            info!(target: "vinyl::audio", "Relaying {}", self.url);
+            let received = self.receive();
+
+            if self.stopped.load() {
+                break;
+            }
+
+            // A connection that received audio starts the backoff over
+            if received > 0 {
+                failures = 0;
+            } else {
+                failures += 1;
+            }
+
+            let backoff =
+                Duration::from_secs(2_u64.saturating_pow(failures)).min(Self::MAX_BACKOFF);
+
+            warn!(target: "vinyl::audio",
+                "Relay of {} dropped, reconnecting in {} seconds",
+                self.url,
+                backoff.as_secs()
+            );
+
+            thread::sleep(backoff);
+        }
+    }
+
+    /// Decodes the stream into the sink until it ends, returning the amount of samples received.
+    fn receive(&self) -> usize {
+        let mut child = match ffmpeg::spawn_relay(&self.url) {
+            Ok(child) => child,
+            Err(err) => {
+                error!(target: "vinyl::audio", "Failed to spawn ffmpeg for relay: {}", err);
+                return 0;
+            }
+        };
+
+        let mut stdout = child.stdout.take().expect("ffmpeg has stdout");
+        *self.child.lock() = Some(child);
+
+        // The relay may have been stopped before ffmpeg was stored
+        if self.stopped.load() {
+            self.stop();
+        }
+
+        let mut received = 0;
+        let mut buf = vec![0; SAMPLES_PER_SEC * SAMPLE_IN_BYTES / 10];
+
+        // Reads are not guaranteed to be aligned to a sample
+        let mut pending: Vec<u8> = vec![];
+
+        loop {
+            let bytes_read = stdout.read(&mut buf).unwrap_or_default();
+
+            if bytes_read == 0 {
+                break;
+            }
+
+            pending.extend_from_slice(&buf[..bytes_read]);
+
+            let aligned = pending.len() - pending.len() % SAMPLE_IN_BYTES;
+            let samples = raw_samples_from_bytes(&pending[..aligned]);
+
+            pending.drain(..aligned);
+
+            self.sink.write(&samples);
+            received += samples.len();
+        }
+
+        if let Some(mut child) = self.child.lock().take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+
+        received
+    }
+}
@@ -1,9 +1,10 @@
 use crate::{
-    audio::{util::Buffer, Sample},
+    audio::{util::Buffer, LoudnessMeter, Sample},
     store::{FromId, Id},
     util::sync::Wait,
 };
 use crossbeam::atomic::AtomicCell;
+use parking_lot::Mutex;
 use std::{fmt::Display, sync::Arc};
 
 pub type SinkId = Id<InternalSink>;
@@ -25,6 +26,20 @@ pub struct InternalSink {
 
     /// This is true when the sink should be cleared and deleted
     pub(super) consumed: AtomicCell<bool>,
+
+    /// This is true when samples are written by a [Relay](super::Relay) instead of ingestion
+    relayed: bool,
+
+    /// Measures loudness as samples are written, see [InternalSink::gain]
+    meter: Mutex<LoudnessMeter>,
+    /// The gain measured so far, used until the sink is sealed
+    provisional_gain: AtomicCell<Option<f32>>,
+    /// The gain of the whole sink, shared with the track it belongs to
+    gain: Arc<AtomicCell<Option<f32>>>,
+
+    /// Where playback of the sink begins, in samples.
+    /// Everything is still loaded, so the sink can be cached as a whole.
+    start: usize,
 }
 
 /// A length in [Sample]
@@ -62,10 +77,45 @@ impl InternalSink {
             consumed: false.into(),
             pending: false.into(),
             wait: Wait::default(),
+            relayed: false,
+            meter: LoudnessMeter::new().into(),
+            provisional_gain: None.into(),
+            gain: Default::default(),
+            start: 0,
+        }
+    }
+
+    /// Makes playback begin `start` samples into the sink, see [InternalSink::start]
+    pub fn starting_at(self, start: usize) -> Self {
+        Self { start, ..self }
+    }
+
+    /// Creates a sink sharing its gain with a track.
+    /// If the gain is already known, the sink is not measured again.
+    pub fn with_gain(length: SinkLength, gain: Arc<AtomicCell<Option<f32>>>) -> Self {
+        Self {
+            gain,
+            ..Self::new(length)
+        }
+    }
+
+    /// Creates an endless sink that is fed by a [Relay](super::Relay)
+    pub fn new_relayed() -> Self {
+        Self {
+            relayed: true,
+            ..Self::new(SinkLength::Unknown)
         }
     }
 
     pub fn write(&self, samples: &[Sample]) {
+        // Relays never end, so their loudness is never known
+        if !self.relayed && self.gain.load().is_none() {
+            let mut meter = self.meter.lock();
+
+            meter.process(samples);
+            self.provisional_gain.store(meter.gain());
+        }
+
         self.samples.write_at_end(samples);
         self.status
             .store(SinkStatus::Partial(self.samples.length()));
@@ -78,16 +128,31 @@ impl InternalSink {
     }
 
     pub fn seal(&self) {
+        if !self.relayed && self.gain.load().is_none() {
+            self.gain.store(self.meter.lock().gain());
+        }
+
         self.status
             .store(SinkStatus::Completed(self.samples.length()));
 
         self.wait.notify();
     }
 
+    /// Returns the gain in dB that brings the sink to the same loudness as others.
+    /// While it is being ingested, this is based on what was measured so far.
+    pub fn gain(&self) -> Option<f32> {
+        self.gain.load().or_else(|| self.provisional_gain.load())
+    }
+
     pub fn id(&self) -> SinkId {
         self.id
     }
 
+    /// Returns where playback of the sink begins, in samples
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
     pub fn available(&self) -> usize {
         self.status.load().amount()
     }
@@ -122,10 +187,20 @@ impl InternalSink {
         self.pending.load()
     }
 
+    pub fn is_relayed(&self) -> bool {
+        self.relayed
+    }
+
     pub fn consume(&self) {
         self.consumed.store(true)
     }
 
+    /// Frees samples that are behind the offset.
+    /// This is used for endless sinks, which would otherwise grow forever.
+    pub fn discard(&self, offset: usize) {
+        self.samples.discard(offset)
+    }
+
     pub(super) fn clear(&self) {
         if !self.consumed.load() {
             panic!("Attempt to clear sink before consumption")
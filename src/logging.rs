@@ -1,48 +1,45 @@
-use std::fmt::{Debug, Display};
+use std::{
+    env,
+    fmt::{Arguments, Debug, Display},
+};
 
+use chrono::{DateTime, SecondsFormat, Utc};
 use colored::{Color, Colorize};
-use log::{trace, Level};
+use fern::FormatCallback;
+use log::{trace, Level, Record};
+use serde_json::json;
 
 use crate::{events::Handler, ingest::IngestionEvent, VinylEvent};
 
-pub fn init_logger() {
-    fern::Dispatch::new()
-        .format(move |out, message, record| {
-            let target = Target::from_str(record.target());
-            let now = chrono::Local::now();
-
-            let level = match record.level() {
-                log::Level::Error => "ERR".color(LogColor::Black).on_color(LogColor::Red).bold(),
-                log::Level::Warn => "WRN"
-                    .color(LogColor::Black)
-                    .on_color(LogColor::Orange)
-                    .bold(),
-                log::Level::Info => "INF".color(LogColor::Black).on_color(LogColor::Blue).bold(),
-                log::Level::Debug => {
-                    let index = (now.timestamp_subsec_micros() as usize) % DEBUG_WORDS.len();
-                    let word = DEBUG_WORDS[index];
-
-                    word.color(LogColor::Black).on_color(LogColor::Teal).bold()
-                }
-                log::Level::Trace => "TRC".bold(),
-            };
+/// How log records are written, set with `VINYL_LOG_FORMAT`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Colored and aligned for reading in a terminal, which is the default
+    Pretty,
+    /// A JSON object on each line, for log aggregators
+    Json,
+}
 
-            let message_color = if target.is_important() && record.level() != Level::Trace {
-                LogColor::White.into()
-            } else {
-                LogColor::White.dimmed()
-            };
+pub fn log_format() -> LogFormat {
+    match env::var("VINYL_LOG_FORMAT").as_deref() {
+        Err(_) | Ok("pretty") => LogFormat::Pretty,
+        Ok("json") => LogFormat::Json,
+        Ok(_) => panic!("Log format must be pretty or json"),
+    }
+}
 
-            out.finish(format_args!(
-                "{:^5} {} {:<7} {}",
-                level,
-                now.format("%H:%M:%S")
-                    .to_string()
-                    .color(LogColor::White.dimmed()),
-                target,
-                message.to_string().color(message_color)
-            ))
-        })
+pub fn init_logger() {
+    let dispatch = match log_format() {
+        LogFormat::Pretty => fern::Dispatch::new().format(format_pretty),
+        LogFormat::Json => {
+            // Colors are escape codes that only make sense in a terminal,
+            // and messages are colored in many places
+            colored::control::set_override(false);
+            fern::Dispatch::new().format(format_json)
+        }
+    };
+
+    dispatch
         .filter(|meta| {
             let is_important = Target::from_str(meta.target()).is_important();
             let is_severe = ALLOWED_LEVELS.contains(&meta.level());
@@ -54,6 +51,60 @@ pub fn init_logger() {
         .unwrap()
 }
 
+fn format_pretty(out: FormatCallback, message: &Arguments, record: &Record) {
+    let target = Target::from_str(record.target());
+    let now = chrono::Local::now();
+
+    let level = match record.level() {
+        log::Level::Error => "ERR".color(LogColor::Black).on_color(LogColor::Red).bold(),
+        log::Level::Warn => "WRN"
+            .color(LogColor::Black)
+            .on_color(LogColor::Orange)
+            .bold(),
+        log::Level::Info => "INF".color(LogColor::Black).on_color(LogColor::Blue).bold(),
+        log::Level::Debug => {
+            let index = (now.timestamp_subsec_micros() as usize) % DEBUG_WORDS.len();
+            let word = DEBUG_WORDS[index];
+
+            word.color(LogColor::Black).on_color(LogColor::Teal).bold()
+        }
+        log::Level::Trace => "TRC".bold(),
+    };
+
+    let message_color = if target.is_important() && record.level() != Level::Trace {
+        LogColor::White.into()
+    } else {
+        LogColor::White.dimmed()
+    };
+
+    out.finish(format_args!(
+        "{:^5} {} {:<7} {}",
+        level,
+        now.format("%H:%M:%S")
+            .to_string()
+            .color(LogColor::White.dimmed()),
+        target,
+        message.to_string().color(message_color)
+    ))
+}
+
+fn format_json(out: FormatCallback, message: &Arguments, record: &Record) {
+    out.finish(format_args!(
+        "{}",
+        json_record(Utc::now(), record, &message.to_string())
+    ))
+}
+
+fn json_record(time: DateTime<Utc>, record: &Record, message: &str) -> String {
+    json!({
+        "timestamp": time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": message,
+    })
+    .to_string()
+}
+
 // Programmers are very peaceful creatures.
 const DEBUG_WORDS: [&str; 7] = ["FCK", "SHT", "ASS", "WHY", "WTF", "NOO", "AGH"];
 
@@ -209,6 +260,21 @@ impl EventLogger {
             IngestionEvent::Cleared { amount } => {
                 trace!(target: "vinyl::audio", "Cleared {} samples.", amount)
             }
+            IngestionEvent::Resolving { id, input, .. } => trace!(target: "vinyl::audio",
+                "{}: {}",
+                input,
+                format!("Resolving as {}", id).color(LogColor::White),
+            ),
+            IngestionEvent::Ready { id, added, .. } => trace!(target: "vinyl::audio",
+                "{}: {}",
+                id,
+                format!("Resolved, queued {} tracks", added).color(LogColor::Success),
+            ),
+            IngestionEvent::Failed { input, reason, .. } => trace!(target: "vinyl::audio",
+                "{}: {}",
+                input,
+                format!("Failed: {}", reason).color(LogColor::Red),
+            ),
         }
     }
 }
@@ -223,3 +289,35 @@ impl Handler<VinylEvent> for EventLogger {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+    use log::{Level, Record};
+    use serde_json::{json, Value};
+
+    use super::json_record;
+
+    #[test]
+    fn json_records() {
+        let time = Utc.with_ymd_and_hms(2023, 5, 1, 12, 30, 0).unwrap();
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("vinyl::server")
+            .build();
+
+        let line = json_record(time, &record, "Something \"odd\"\nhappened");
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+
+        assert!(!line.contains('\n'));
+        assert_eq!(
+            parsed,
+            json!({
+                "timestamp": "2023-05-01T12:30:00.000Z",
+                "level": "WARN",
+                "target": "vinyl::server",
+                "message": "Something \"odd\"\nhappened",
+            })
+        );
+    }
+}
@@ -1,6 +1,7 @@
-use std::{sync::Arc, thread};
+use std::{env, process, sync::Arc, thread, time::Duration};
 
 use audio::AudioEvent;
+use auth::RotatedTokens;
 use colored::Colorize;
 use db::Database;
 use events::{Bus, Channel, Emitter};
@@ -8,17 +9,21 @@ use ingest::IngestionEvent;
 use log::{error, info};
 use queue::QueueEvent;
 use rooms::RoomEvent;
-use server::sse::SseManager;
+use server::{sse::SseManager, Limits, Metrics, ServerEvent};
 use store::Store;
 use thiserror::Error;
 use tokio::runtime::{self, Runtime};
+use util::shutdown;
 
 use crate::logging::{EventLogger, LogColor};
 
+mod aliases;
 mod audio;
 mod auth;
+mod config;
 mod db;
 mod events;
+mod favorites;
 mod http;
 mod ingest;
 mod logging;
@@ -34,6 +39,9 @@ pub struct Vinyl {
     store: Arc<Store>,
     event_bus: Arc<EventBus>,
     sse: Arc<SseManager>,
+    limits: Arc<Limits>,
+    metrics: Arc<Metrics>,
+    rotated_tokens: Arc<RotatedTokens>,
     runtime: Runtime,
 }
 
@@ -43,6 +51,7 @@ pub enum VinylEvent {
     Audio(AudioEvent),
     Queue(QueueEvent),
     Ingestion(IngestionEvent),
+    Server(ServerEvent),
 }
 
 pub type EventEmitter = Emitter<Channel<VinylEvent>, VinylEvent>;
@@ -53,6 +62,10 @@ pub struct VinylContext {
     pub db: Arc<Database>,
     pub store: Arc<Store>,
     pub sse: Arc<SseManager>,
+    pub limits: Arc<Limits>,
+    pub metrics: Arc<Metrics>,
+    pub rotated_tokens: Arc<RotatedTokens>,
+    pub emitter: EventEmitter,
 }
 
 #[derive(Debug, Error)]
@@ -60,6 +73,9 @@ enum VinylError {
     #[error("Could not initialize database: {0}")]
     Database(#[from] surrealdb::Error),
 
+    #[error("{0}")]
+    YtDlp(#[from] ingest::YtDlpError),
+
     #[error("Fatal error: {0}")]
     Fatal(String),
 }
@@ -73,6 +89,9 @@ impl Vinyl {
             .build()
             .map_err(|e| VinylError::Fatal(e.to_string()))?;
 
+        info!("Checking yt-dlp...");
+        ingest::check_ytdlp()?;
+
         info!("Connecting to database...");
 
         let channel = Channel::new();
@@ -80,12 +99,16 @@ impl Vinyl {
 
         let store = Store::new(event_bus.emitter());
         let sse = SseManager::new(Arc::downgrade(&store));
+        let metrics = Arc::new(Metrics::default());
 
         let database = main_runtime.block_on(db::connect())?;
 
         event_bus.register(EventLogger);
         event_bus.register(store.queue_store.handler());
+        event_bus.register(store.room_store.handler());
+        event_bus.register(store.ingestion.failures.handler());
         event_bus.register(sse.handler());
+        event_bus.register(metrics.handler());
 
         main_runtime
             .block_on(store.room_store.init(&database))
@@ -95,22 +118,51 @@ impl Vinyl {
             sse,
             store,
             event_bus,
+            limits: Default::default(),
+            metrics,
+            rotated_tokens: Default::default(),
             db: database.into(),
             runtime: main_runtime,
         })
     }
 
     fn run(&self) {
+        rooms::init_output_config();
+        rooms::init_connection_policy();
+        audio::init_output_format();
+        audio::init_normalization_config();
+        audio::init_ducking_config();
+        ingest::init_playlist_limit();
+        queue::init_history_limit();
+        ingest::init_ytdlp_path();
+        ingest::init_ytdlp_cookies();
+        ingest::init_ytdlp_attempts();
+        ingest::init_ytdlp_jobs();
+        ingest::init_audio_format();
+        ingest::init_stream_url_max_age();
+
         audio::run_playback(self.store.playback.clone());
         ingest::run_ingestion(self.store.ingestion.clone());
+        track::spawn_refresh_thread(Arc::downgrade(&self.store));
+        rooms::spawn_schedule_thread(Arc::downgrade(&self.store));
+        rooms::spawn_stale_item_thread(Arc::downgrade(&self.store));
 
         let event_bus = self.event_bus.clone();
-        thread::spawn(move || loop {
-            event_bus.tick()
+        let events = thread::spawn(move || {
+            // Also handles what happened while shutting down, like streams closing
+            event_bus.run(Duration::from_millis(100), shutdown::requested);
         });
 
         self.runtime
             .block_on(async move { server::run_server(self.context()).await });
+
+        // The server only returns once every stream was closed, so nothing needs playback anymore
+        shutdown::request();
+        let _ = events.join();
+
+        self.runtime
+            .block_on(self.store.room_store.save_queues(&self.db));
+        info!("Shut down.");
     }
 
     fn context(&self) -> VinylContext {
@@ -118,6 +170,10 @@ impl Vinyl {
             db: self.db.clone(),
             sse: self.sse.clone(),
             store: self.store.clone(),
+            limits: self.limits.clone(),
+            metrics: self.metrics.clone(),
+            rotated_tokens: self.rotated_tokens.clone(),
+            emitter: self.event_bus.emitter(),
         }
     }
 }
@@ -126,6 +182,8 @@ impl VinylError {
     fn hint(&self) -> String {
         match self {
             VinylError::Database(_) => "This is a database error. Make sure the SurrealDB instance is properly installed and running, then try again.".to_string(),
+            VinylError::YtDlp(ingest::YtDlpError::Outdated(_)) => "Update yt-dlp with `yt-dlp -U` or your package manager, or set VINYL_YTDLP_OUTDATED=warn to start anyway.".to_string(),
+            VinylError::YtDlp(_) => "yt-dlp is needed to queue tracks. Install it from https://github.com/yt-dlp/yt-dlp, then make sure it is on PATH or set VINYL_YTDLP_PATH to where it is.".to_string(),
             VinylError::Fatal(_) => "This error is fatal, and should not happen.".to_string(),
         }
     }
@@ -134,6 +192,16 @@ impl VinylError {
 fn main() {
     logging::init_logger();
 
+    let args: Vec<_> = env::args().collect();
+
+    // Checks the configuration for deployments, without starting anything
+    if args.iter().any(|a| a == "--check-config") {
+        let check_db = args.iter().any(|a| a == "--check-db");
+        let valid = config::check(check_db);
+
+        process::exit(if valid { 0 } else { 1 });
+    }
+
     match Vinyl::new() {
         Ok(vinyl) => {
             info!("Initialized successfully.");
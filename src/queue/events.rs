@@ -4,13 +4,15 @@ use crate::{
     VinylEvent,
 };
 
-use super::{QueueId, QueueItem};
+use super::{QueueId, QueueItem, QueueItemId, QueuePatch, RepeatMode};
 
 #[derive(Debug, Clone)]
 pub enum QueueEvent {
     Update {
         queue: QueueId,
         new_items: Vec<QueueItem>,
+        /// What changed since the previous update
+        patch: QueuePatch,
     },
     Advance {
         queue: QueueId,
@@ -20,6 +22,24 @@ pub enum QueueEvent {
         queue: QueueId,
         track: TrackId,
     },
+    RepeatChanged {
+        queue: QueueId,
+        mode: RepeatMode,
+    },
+    /// Every item was removed at once, except the current one if it was kept.
+    /// This is sent instead of an update.
+    Cleared {
+        queue: QueueId,
+        removed: Vec<QueueItemId>,
+        kept: Option<QueueItemId>,
+        /// What changed since the previous update
+        patch: QueuePatch,
+    },
+    Shuffled {
+        queue: QueueId,
+        /// The items after the current one, in their new order
+        order: Vec<QueueItemId>,
+    },
 }
 
 impl IntoEvent<VinylEvent> for QueueEvent {
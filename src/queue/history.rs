@@ -0,0 +1,133 @@
+use std::{collections::VecDeque, env};
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::util::unix_millis;
+
+use super::{QueueId, QueueItem};
+
+lazy_static! {
+    /// How many played items are remembered per queue, set with `VINYL_HISTORY_LIMIT`.
+    /// Operators can lower it to save memory on busy servers.
+    static ref HISTORY_LIMIT: usize = {
+        let limit = env::var("VINYL_HISTORY_LIMIT")
+            .map(|x| x.parse::<usize>().expect("History limit must be a number"))
+            .unwrap_or(500);
+
+        assert!(limit > 0, "History limit must be at least 1");
+        limit
+    };
+}
+
+/// Reads and validates the history limit, so mistakes are caught on startup
+pub fn init_history_limit() -> &'static impl std::fmt::Debug {
+    &*HISTORY_LIMIT
+}
+
+/// A queue item that started playing
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayedItem {
+    pub item: QueueItem,
+    /// When the item started playing, in milliseconds since the unix epoch
+    pub played_at: u64,
+}
+
+/// What has been played in each queue, oldest first
+#[derive(Debug)]
+pub struct PlayHistory {
+    entries: DashMap<QueueId, VecDeque<PlayedItem>>,
+    /// How many items to remember per queue, the oldest are forgotten first
+    limit: usize,
+}
+
+impl PlayHistory {
+    fn with_limit(limit: usize) -> Self {
+        Self {
+            entries: Default::default(),
+            limit,
+        }
+    }
+
+    pub fn push(&self, queue: QueueId, item: QueueItem) {
+        let mut entries = self.entries.entry(queue).or_default();
+
+        entries.push_back(PlayedItem {
+            item,
+            played_at: unix_millis(),
+        });
+
+        if entries.len() > self.limit {
+            entries.pop_front();
+        }
+    }
+
+    /// Forgets everything played in a queue
+    pub fn remove(&self, queue: QueueId) {
+        self.entries.remove(&queue);
+    }
+
+    /// Returns everything remembered of a queue, most recently played first
+    pub fn recent(&self, queue: QueueId) -> Vec<PlayedItem> {
+        self.entries
+            .get(&queue)
+            .map(|entries| entries.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the items that started playing at or after `since`, oldest first
+    pub fn since(&self, queue: QueueId, since: u64) -> Vec<PlayedItem> {
+        self.entries
+            .get(&queue)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|e| e.played_at >= since)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Default for PlayHistory {
+    fn default() -> Self {
+        Self::with_limit(*HISTORY_LIMIT)
+    }
+}
+
+/// The outcome of adding played items back to the queue
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Replay {
+    pub requeued: usize,
+    /// Items that could not be resolved again, or are no longer allowed in the room
+    pub skipped: usize,
+}
+
+#[cfg(test)]
+mod test {
+    use super::PlayHistory;
+    use crate::{queue::QueueItem, store::Id};
+
+    #[test]
+    fn forgets_the_oldest_items() {
+        let history = PlayHistory::with_limit(2);
+        let queue = Id::new();
+
+        let items: Vec<_> = ["apples", "bananas", "oranges"]
+            .into_iter()
+            .map(QueueItem::mock)
+            .collect();
+
+        for item in items.iter() {
+            history.push(queue, item.clone());
+        }
+
+        let recent: Vec<_> = history.recent(queue).iter().map(|e| e.item.id()).collect();
+        assert_eq!(recent, vec![items[2].id(), items[1].id()]);
+
+        assert!(history.recent(Id::new()).is_empty());
+    }
+}
@@ -1,17 +1,22 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, time::Duration};
 
 use crossbeam::atomic::AtomicCell;
 use parking_lot::Mutex;
-use serde::Serialize;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
 use surrealdb::sql::Thing;
 
 use crate::{
     auth::{User, UserId},
     store::Id,
     track::Track,
+    util::unix_millis,
 };
 
 mod events;
+mod history;
+mod patch;
+mod router;
 mod store;
 
 pub type QueueId = Id<Queue>;
@@ -19,6 +24,9 @@ pub type SubQueueId = Id<SubQueue>;
 pub type QueueItemId = Id<QueueItem>;
 
 pub use events::*;
+pub use history::*;
+pub use patch::*;
+pub use router::router;
 pub use store::*;
 
 /// A queue, belonging to a room
@@ -32,14 +40,80 @@ pub struct Queue {
 
     /// The calculated list of queue items
     items: Mutex<Vec<QueueItem>>,
+
+    /// Voice tracks playing on top of the music, in the order they play
+    voices: Mutex<Vec<QueueItem>>,
+
+    /// What happens when the current item ends
+    repeat: AtomicCell<RepeatMode>,
+
+    /// True if the last item ended without repeating, so nothing is playing
+    ended: AtomicCell<bool>,
+
+    /// True if the current item is ending because it was skipped, see [Queue::mark_skipped]
+    skipped: AtomicCell<bool>,
+}
+
+/// Describes what happens when the current item ends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    /// Playback stops after the last item
+    #[default]
+    Off,
+    /// The current item plays again until it is skipped
+    One,
+    /// Playback starts over from the first item after the last one
+    All,
 }
 
 /// An item  in the queue
 #[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct QueueItem {
     id: QueueItemId,
     submitter: UserId,
     track: Track,
+    kind: ItemKind,
+
+    /// When this was added, in milliseconds since the unix epoch
+    added_at: u64,
+    added_by: AddedBy,
+}
+
+/// Who added a queue item, so clients can show it without looking the user up
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddedBy {
+    pub id: UserId,
+    pub display_name: String,
+}
+
+/// Describes how a queue item is played
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ItemKind {
+    /// Played one after another in the queue
+    Music,
+    /// Played on top of the music, which is lowered until it ends
+    Voice,
+}
+
+/// An estimate of when a queue item will start playing
+#[derive(Debug, Clone, Serialize)]
+pub struct Eta {
+    /// Seconds until the item plays, [None] if it might never play
+    pub seconds: Option<f32>,
+    /// True if some durations ahead of the item are unknown
+    pub approximate: bool,
+}
+
+impl Eta {
+    /// For items that are not certain to play
+    const UNKNOWN: Self = Self {
+        seconds: None,
+        approximate: false,
+    };
 }
 
 /// A sub queue allows a queue to be non-destructive and dynamic
@@ -60,10 +134,11 @@ pub enum OrderStrategy {
     Fallback,
 }
 
+/// One or more tracks added at once, along with when they were added
 #[derive(Debug)]
 pub enum Entry {
-    Single(Track, QueueItemId),
-    Multiple(Vec<(Track, QueueItemId)>),
+    Single(Track, QueueItemId, u64),
+    Multiple(Vec<(Track, QueueItemId)>, u64),
 }
 
 impl Queue {
@@ -73,10 +148,18 @@ impl Queue {
             current_item: Id::none().into(),
             robin: RoundRobin::new(),
             items: Default::default(),
+            voices: Default::default(),
+            repeat: Default::default(),
+            ended: Default::default(),
+            skipped: Default::default(),
         }
     }
 
     pub(self) fn tracks_to_play(&self) -> Vec<Track> {
+        if self.ended.load() {
+            return vec![];
+        }
+
         let current_index = self.current_index();
 
         self.items
@@ -89,10 +172,100 @@ impl Queue {
             .collect()
     }
 
+    pub fn item(&self, id: QueueItemId) -> Option<QueueItem> {
+        self.items.lock().iter().find(|i| i.id == id).cloned()
+    }
+
+    /// Estimates when an item starts playing, given how far into the current item playback is
+    pub fn eta(&self, id: QueueItemId, position: f32) -> Option<Eta> {
+        let items = self.items.lock().clone();
+        let current_index = self.current_index();
+        let target_index = items.iter().position(|i| i.id == id)?;
+
+        if self.ended.load() {
+            return Some(Eta::UNKNOWN);
+        }
+
+        if target_index == current_index {
+            return Some(Eta {
+                seconds: Some(0.),
+                approximate: false,
+            });
+        }
+
+        let wraps = match self.repeat.load() {
+            // The current item plays until it is skipped, so nothing after it is certain to play
+            RepeatMode::One => return Some(Eta::UNKNOWN),
+            RepeatMode::Off => false,
+            RepeatMode::All => true,
+        };
+
+        // Items that were played only play again if the queue wraps around
+        if target_index < current_index && !wraps {
+            return Some(Eta::UNKNOWN);
+        }
+
+        let ahead = items
+            .iter()
+            .cycle()
+            .skip(current_index)
+            .take_while(|i| i.id != id)
+            .filter(|i| i.track.suitable());
+
+        let mut seconds = -position;
+        let mut approximate = false;
+
+        for item in ahead {
+            match item.track.metadata.duration {
+                Some(duration) if duration > 0. => seconds += duration,
+                _ => approximate = true,
+            }
+        }
+
+        Some(Eta {
+            seconds: Some(seconds.max(0.)),
+            approximate,
+        })
+    }
+
     pub fn add(&self, submitter: &User, tracks: Vec<Track>) {
         self.robin.add(submitter, tracks);
+        self.after_add();
+    }
+
+    /// Adds tracks that play before the rest of the queue, right after the current item
+    pub fn add_priority(&self, submitter: &User, tracks: Vec<Track>) {
+        self.robin.add_priority(submitter, tracks);
+        self.after_add();
+    }
+
+    /// Adds a voice track that plays on top of the music, returning the new item
+    pub fn add_voice(&self, submitter: &User, track: Track) -> QueueItem {
+        let item = QueueItem::new(Id::new(), submitter, track, ItemKind::Voice, unix_millis());
+
+        self.voices.lock().push(item.clone());
+        item
+    }
+
+    /// Removes the voice track that was playing, returning it
+    pub fn end_voice(&self) -> Option<QueueItem> {
+        let mut voices = self.voices.lock();
+        (!voices.is_empty()).then(|| voices.remove(0))
+    }
 
-        if self.current_item.load() == Id::none() {
+    pub fn voices(&self) -> Vec<QueueItem> {
+        self.voices.lock().clone()
+    }
+
+    fn after_add(&self) {
+        if self.ended.swap(false) {
+            // Playback continues with what was added
+            self.robin.next_available();
+            self.update();
+
+            let added = self.robin.history.lock().last().map(|i| i.id);
+            self.current_item.store(added.unwrap_or_default());
+        } else if self.current_item.load() == Id::none() {
             self.advance_index(0);
 
             // To ensure the current track is always in the robin history
@@ -102,18 +275,180 @@ impl Queue {
         self.update();
     }
 
-    pub fn next(&self) -> Option<QueueItem> {
-        self.robin.next();
-        self.advance_index(1);
+    /// Removes items that have not been played yet and were added before `max_age`,
+    /// returning the ids of the removed items.
+    pub fn remove_older_than(&self, max_age: Duration) -> Vec<QueueItemId> {
+        let cutoff = unix_millis().saturating_sub(max_age.as_millis() as u64);
+        let removed = self.robin.remove_where(|item| item.added_at < cutoff);
+
+        if !removed.is_empty() {
+            self.update();
+        }
+
+        removed
+    }
+
+    /// Removes an item, whether it was played or not, returning false if it is not in the queue.
+    /// If it is the current item, nothing is current until something is added.
+    pub fn remove(&self, id: QueueItemId) -> bool {
+        let removed_upcoming = !self.robin.remove_where(|item| item.id == id).is_empty();
+        let removed_played = self.robin.remove_played(id);
+
+        if self.current_item.load() == id {
+            self.current_item.store(Id::none());
+        }
+
         self.update();
-        self.current_item()
+        removed_upcoming || removed_played
+    }
+
+    /// Removes every item, except the current one if `keep_current` is true,
+    /// returning the removed items. Without a current item, nothing plays until something is added.
+    pub fn clear(&self, keep_current: bool) -> Vec<QueueItem> {
+        let kept = self.current_item().filter(|_| keep_current).map(|i| i.id);
+
+        let removed: Vec<_> = self
+            .items()
+            .into_iter()
+            .filter(|i| Some(i.id) != kept)
+            .collect();
+
+        self.robin.remove_where(|_| true);
+        self.robin.retain_played(|item| Some(item.id) == kept);
+
+        if kept.is_none() {
+            self.current_item.store(Id::none());
+            self.ended.store(false);
+        }
+
+        self.update();
+        removed
+    }
+
+    /// Moves an item after the current one to `position` in [Queue::items],
+    /// which is clamped to the positions after the current item.
+    ///
+    /// Returns the position it was moved to, or [None] if the item is not after the current one.
+    pub fn move_item(&self, id: QueueItemId, position: usize) -> Option<usize> {
+        let first = self.current_index() + 1;
+        let mut upcoming: Vec<_> = self.items.lock().iter().skip(first).cloned().collect();
+
+        let index = upcoming.iter().position(|i| i.id == id)?;
+        let position = position.clamp(first, first + upcoming.len() - 1);
+
+        let item = upcoming.remove(index);
+        upcoming.insert(position - first, item);
+
+        self.robin.pin(upcoming);
+        self.update();
+
+        Some(position)
+    }
+
+    /// Randomly reorders the items after the current one, returning their new order.
+    /// Items are only shuffled among those of the same submitter, so turns are kept.
+    /// Returns [None] if there are less than two, since there is nothing to shuffle.
+    pub fn shuffle(&self, rng: &mut impl Rng) -> Option<Vec<QueueItemId>> {
+        if self.upcoming().len() < 3 {
+            return None;
+        }
+
+        self.robin.shuffle(rng);
+        self.update();
+
+        Some(self.upcoming().into_iter().skip(1).map(|i| i.id).collect())
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat.load()
+    }
+
+    /// Sets the repeat mode, returning false if it was already set
+    pub fn set_repeat(&self, mode: RepeatMode) -> bool {
+        self.repeat.swap(mode) != mode
+    }
+
+    /// Marks the current item as skipped, so [RepeatMode::One] does not repeat it
+    pub fn mark_skipped(&self) {
+        self.skipped.store(true);
+    }
+
+    /// Moves on from the current item after it ended, following the [RepeatMode].
+    /// Returns the item that plays next, or [None] if playback stopped.
+    pub fn next(&self) -> Option<QueueItem> {
+        let skipped = self.skipped.swap(false);
+
+        if self.ended.load() {
+            return None;
+        }
+
+        let mode = self.repeat.load();
+        let current = self.current_item.load();
+
+        // Tracks that failed to load would only fail again
+        let repeats = mode == RepeatMode::One
+            && !skipped
+            && self.current_item().is_some_and(|i| i.track.suitable());
+
+        // Played tracks cannot be played again, so they are replaced with fresh ones
+        if repeats {
+            self.robin.replay_where(|item| item.id == current);
+            self.update();
+
+            return self.current_item();
+        }
+
+        let is_last = self.current_index() + 1 >= self.items.lock().len();
+
+        if !is_last {
+            self.robin.next();
+            self.advance_index(1);
+            self.update();
+
+            return self.current_item();
+        }
+
+        if mode == RepeatMode::All {
+            self.robin.replay_where(|_| true);
+            self.update();
+
+            self.current_item.store(self.item_at(0).unwrap_or_default());
+            return self.current_item();
+        }
+
+        self.ended.store(true);
+        None
     }
 
     pub fn items(&self) -> Vec<QueueItem> {
         self.items.lock().clone()
     }
 
+    /// Returns everyone who has added something to the queue
+    pub fn submitters(&self) -> Vec<User> {
+        self.robin.submitters()
+    }
+
+    /// Returns the current item and the ones after it
+    pub fn upcoming(&self) -> Vec<QueueItem> {
+        if self.ended.load() {
+            return vec![];
+        }
+
+        let current_index = self.current_index();
+        self.items
+            .lock()
+            .iter()
+            .skip(current_index)
+            .cloned()
+            .collect()
+    }
+
     pub fn current_item(&self) -> Option<QueueItem> {
+        if self.ended.load() {
+            return None;
+        }
+
         let current_index = self.current_index();
         self.items.lock().get(current_index).cloned()
     }
@@ -154,6 +489,45 @@ impl Queue {
     }
 }
 
+impl QueueItem {
+    fn new(id: QueueItemId, submitter: &User, track: Track, kind: ItemKind, added_at: u64) -> Self {
+        Self {
+            id,
+            submitter: submitter.id.clone(),
+            track,
+            kind,
+            added_at,
+            added_by: AddedBy {
+                id: submitter.id.clone(),
+                display_name: submitter.display_name.clone(),
+            },
+        }
+    }
+
+    pub fn id(&self) -> QueueItemId {
+        self.id
+    }
+
+    pub fn track(&self) -> &Track {
+        &self.track
+    }
+
+    pub fn submitter(&self) -> &UserId {
+        &self.submitter
+    }
+
+    #[cfg(test)]
+    pub fn mock(title: &str) -> QueueItem {
+        QueueItem::new(
+            Id::new(),
+            &User::mock("submitter"),
+            crate::track::InternalTrack::mock(title),
+            ItemKind::Music,
+            0,
+        )
+    }
+}
+
 impl SubQueue {
     fn new(owner: User, ordering: OrderStrategy) -> Self {
         Self {
@@ -176,10 +550,47 @@ impl SubQueue {
         self.entries
             .lock()
             .iter()
-            .map(|x| x.to_items(self.owner.id.clone()))
+            .map(|x| x.to_items(&self.owner))
             .collect()
     }
 
+    /// Removes items matching the predicate, returning their ids
+    fn remove_where(&self, predicate: impl Fn(&QueueItem) -> bool) -> Vec<QueueItemId> {
+        let mut entries = self.entries.lock();
+        let mut removed = vec![];
+
+        let remaining: Vec<_> = entries
+            .drain(..)
+            .filter_map(|entry| {
+                let (kept, mut removed_from_entry): (Vec<_>, Vec<_>) = entry
+                    .to_items(&self.owner)
+                    .into_iter()
+                    .partition(|item| !predicate(item));
+
+                removed.append(&mut removed_from_entry);
+                Entry::from_items(kept)
+            })
+            .collect();
+
+        *entries = remaining;
+        removed.into_iter().map(|item| item.id).collect()
+    }
+
+    /// Randomly reorders the items, splitting multiple tracks into their own entries
+    fn shuffle(&self, rng: &mut impl Rng) {
+        let mut entries = self.entries.lock();
+        let mut items: Vec<_> = entries
+            .drain(..)
+            .flat_map(|entry| entry.to_items(&self.owner))
+            .collect();
+
+        items.shuffle(rng);
+        *entries = items
+            .into_iter()
+            .filter_map(|item| Entry::from_items(vec![item]))
+            .collect();
+    }
+
     fn next(&self) -> Option<QueueItem> {
         let mut entries = self.entries.lock();
 
@@ -190,7 +601,7 @@ impl SubQueue {
         let next = entries.drain(..1).next();
 
         if let Some(next) = next {
-            let (item, entry) = next.consume_one(self.owner.id.clone());
+            let (item, entry) = next.consume_one(&self.owner);
 
             if let Some(entry) = entry {
                 entries.push(entry);
@@ -206,60 +617,67 @@ impl SubQueue {
 impl Entry {
     fn new(tracks: Vec<Track>) -> Self {
         let with_ids: Vec<_> = tracks.into_iter().map(|t| (t, Id::new())).collect();
+        let added_at = unix_millis();
 
         if with_ids.len() == 1 {
             let (track, id) = with_ids.into_iter().next().unwrap();
-            Self::Single(track, id)
+            Self::Single(track, id, added_at)
+        } else {
+            Self::Multiple(with_ids, added_at)
+        }
+    }
+
+    /// Creates an entry from items of another entry, returning [None] if there are none
+    fn from_items(items: Vec<QueueItem>) -> Option<Self> {
+        let added_at = items.first()?.added_at;
+        let mut pairs: Vec<_> = items.into_iter().map(|i| (i.track, i.id)).collect();
+
+        if pairs.len() == 1 {
+            let (track, id) = pairs.remove(0);
+            Some(Self::Single(track, id, added_at))
         } else {
-            Self::Multiple(with_ids)
+            Some(Self::Multiple(pairs, added_at))
         }
     }
 
-    fn to_items(&self, submitter: UserId) -> Vec<QueueItem> {
+    fn to_items(&self, submitter: &User) -> Vec<QueueItem> {
         match self {
-            Entry::Single(track, id) => vec![QueueItem {
-                id: *id,
+            Entry::Single(track, id, added_at) => vec![QueueItem::new(
+                *id,
                 submitter,
-                track: track.clone(),
-            }],
-            Entry::Multiple(x) => x
+                track.clone(),
+                ItemKind::Music,
+                *added_at,
+            )],
+            Entry::Multiple(x, added_at) => x
                 .clone()
                 .into_iter()
-                .map(|(track, id)| QueueItem {
-                    id,
-                    track,
-                    submitter: submitter.clone(),
-                })
+                .map(|(track, id)| QueueItem::new(id, submitter, track, ItemKind::Music, *added_at))
                 .collect(),
         }
     }
 
     /// Consumes one item from the entry, returning the item and entry if the entry has more items
-    fn consume_one(self, submitter: UserId) -> (QueueItem, Option<Entry>) {
+    fn consume_one(self, submitter: &User) -> (QueueItem, Option<Entry>) {
         match self {
-            Entry::Single(track, id) => (
-                QueueItem {
-                    id,
-                    submitter,
-                    track: track.clone(),
-                },
+            Entry::Single(track, id, added_at) => (
+                QueueItem::new(id, submitter, track, ItemKind::Music, added_at),
                 None,
             ),
-            Entry::Multiple(mut items) => {
+            Entry::Multiple(mut items, added_at) => {
                 let item = items.drain(1..).next().expect("items is not empty");
                 let new_length = items.len();
 
-                let item = QueueItem {
-                    id: item.1,
-                    submitter,
-                    track: item.0,
-                };
+                let item = QueueItem::new(item.1, submitter, item.0, ItemKind::Music, added_at);
 
                 if new_length > 1 {
-                    (item, Some(Entry::Multiple(items)))
+                    (item, Some(Entry::Multiple(items, added_at)))
                 } else {
                     let last_item = items.into_iter().next().expect("items is not empty");
-                    (item, Some(Entry::Single(last_item.0, last_item.1)))
+                    (
+                        item,
+                        Some(Entry::Single(last_item.0, last_item.1, added_at)),
+                    )
                 }
             }
         }
@@ -271,26 +689,56 @@ impl Entry {
 pub struct SerializedQueue {
     id: QueueId,
     items: Vec<QueueItem>,
+    voices: Vec<QueueItem>,
     current_item: QueueItemId,
     submitters: Vec<User>,
+    repeat: RepeatMode,
+
+    /// How long every item takes to play in seconds, [None] if the duration of any is unknown
+    total_duration: Option<f32>,
+
+    /// The sequence of the last [QueuePatch] this includes
+    sequence: u64,
 }
 
 impl SerializedQueue {
-    pub fn new(queue: &Queue) -> Self {
+    #[cfg(test)]
+    pub fn mock() -> Self {
+        Self::new(&Queue::new(), 0)
+    }
+
+    pub fn new(queue: &Queue, sequence: u64) -> Self {
+        let items = queue.items();
+
         Self {
+            sequence,
             id: queue.id,
-            current_item: queue.current_item.load(),
-            items: queue.items(),
+            current_item: match queue.ended.load() {
+                true => Id::none(),
+                false => queue.current_item.load(),
+            },
+            repeat: queue.repeat(),
+            total_duration: total_duration(&items),
+            items,
+            voices: queue.voices(),
             submitters: queue.robin.submitters(),
         }
     }
 }
 
+/// Returns how long the items take to play in seconds, or [None] if any of them has no duration
+fn total_duration(items: &[QueueItem]) -> Option<f32> {
+    items.iter().map(|i| i.track.metadata.duration).sum()
+}
+
 #[derive(Debug)]
 pub struct RoundRobin {
     current_submitter: Mutex<UserId>,
     history: Mutex<Vec<QueueItem>>,
     queues: Mutex<Vec<SubQueue>>,
+
+    /// Items that skip the round robin, played in the order they were added
+    priority: Mutex<Vec<QueueItem>>,
 }
 
 impl RoundRobin {
@@ -303,10 +751,21 @@ impl RoundRobin {
             .into(),
             history: Default::default(),
             queues: Default::default(),
+            priority: Default::default(),
         }
     }
 
     fn next(&self) {
+        let mut priority = self.priority.lock();
+
+        // The turn of the current submitter is kept for after the priority items
+        if !priority.is_empty() {
+            self.history.lock().push(priority.remove(0));
+            return;
+        }
+
+        drop(priority);
+
         let next_submitter_index = self.next_submitter_index();
         let current_submitter_index = self.current_submitter_index();
 
@@ -323,6 +782,20 @@ impl RoundRobin {
         *self.current_submitter.lock() = next_submitter.owner.id.clone();
     }
 
+    /// Like [RoundRobin::next], but passes over submitters that have nothing left
+    fn next_available(&self) {
+        let played = self.history.lock().len();
+        let submitters = self.queues.lock().len();
+
+        for _ in 0..submitters.max(1) {
+            self.next();
+
+            if self.history.lock().len() > played {
+                break;
+            }
+        }
+    }
+
     fn calculate(&self) -> Vec<QueueItem> {
         let current_submitter_index = self.current_submitter_index();
         let queues = self.queues.lock();
@@ -336,7 +809,8 @@ impl RoundRobin {
 
         iterators.rotate_left(current_submitter_index);
 
-        let mut result = vec![];
+        let mut result = self.priority.lock().clone();
+        let iterations = iterations + result.len();
         let mut current_iteration = 0usize;
 
         while result.len() < iterations {
@@ -379,6 +853,79 @@ impl RoundRobin {
         queue.add(Entry::new(tracks));
     }
 
+    fn add_priority(&self, user: &User, tracks: Vec<Track>) {
+        // So the user is still listed as a submitter
+        self.ensure_sub_queue(user);
+
+        let items = Entry::new(tracks).to_items(user);
+        self.priority.lock().extend(items);
+    }
+
+    /// Removes items that have not been played yet, matching the predicate
+    fn remove_where(&self, predicate: impl Fn(&QueueItem) -> bool) -> Vec<QueueItemId> {
+        let mut removed: Vec<_> = self
+            .queues
+            .lock()
+            .iter()
+            .flat_map(|q| q.remove_where(&predicate))
+            .collect();
+
+        self.priority.lock().retain(|item| {
+            let matches = predicate(item);
+
+            if matches {
+                removed.push(item.id);
+            }
+
+            !matches
+        });
+
+        removed
+    }
+
+    /// Randomly reorders the items of each sub-queue and the priority items
+    fn shuffle(&self, rng: &mut impl Rng) {
+        for queue in self.queues.lock().iter() {
+            queue.shuffle(rng);
+        }
+
+        self.priority.lock().shuffle(rng);
+    }
+
+    /// Replaces the order of the items after the current one.
+    /// They are kept as priority items, so tracks added later play after them.
+    fn pin(&self, order: Vec<QueueItem>) {
+        self.remove_where(|_| true);
+
+        // Items after the current one can already be in the history
+        self.history
+            .lock()
+            .retain(|item| !order.iter().any(|i| i.id == item.id));
+
+        *self.priority.lock() = order;
+    }
+
+    /// Replaces the tracks of played items matching the predicate, so they can play again
+    fn replay_where(&self, predicate: impl Fn(&QueueItem) -> bool) {
+        for item in self.history.lock().iter_mut().filter(|i| predicate(i)) {
+            item.track = item.track.replay().into();
+        }
+    }
+
+    /// Keeps the played items matching the predicate, removing the rest
+    fn retain_played(&self, predicate: impl Fn(&QueueItem) -> bool) {
+        self.history.lock().retain(predicate);
+    }
+
+    /// Removes an item that was already played, returning false if there is none
+    fn remove_played(&self, id: QueueItemId) -> bool {
+        let mut history = self.history.lock();
+        let len = history.len();
+
+        history.retain(|item| item.id != id);
+        history.len() != len
+    }
+
     fn ensure_sub_queue(&self, user: &User) {
         let mut queues = self.queues.lock();
         let queue_exists = queues.iter().any(|q| q.owner.id == user.id);
@@ -414,9 +961,58 @@ impl RoundRobin {
 
 #[cfg(test)]
 mod test {
-    use crate::{auth::User, queue::QueueItem, track::InternalTrack};
+    use crate::{
+        auth::User,
+        events::Channel,
+        ingest::{Ingestion, Input},
+        queue::QueueItem,
+        track::{InternalTrack, Track},
+        EventBus,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{thread, time::Duration};
+
+    use super::{total_duration, Queue, RepeatMode, RoundRobin};
+
+    fn queue_of(titles: &[&str]) -> Queue {
+        let queue = Queue::new();
+        let john = User::mock("john");
 
-    use super::RoundRobin;
+        for title in titles {
+            queue.add(&john, vec![InternalTrack::mock(title)]);
+        }
+
+        queue
+    }
+
+    fn title(item: Option<QueueItem>) -> Option<String> {
+        item.map(|i| i.track.metadata.title.clone())
+    }
+
+    fn track_with_duration(duration: Option<f32>) -> Track {
+        let mut metadata = InternalTrack::mock("bananas").metadata.clone();
+        metadata.duration = duration;
+
+        InternalTrack::new(Input::Empty(metadata)).into()
+    }
+
+    #[test]
+    fn sums_durations() {
+        let item = |duration| QueueItem {
+            track: track_with_duration(duration),
+            ..QueueItem::mock("bananas")
+        };
+
+        assert_eq!(total_duration(&[]), Some(0.));
+        assert_eq!(
+            total_duration(&[item(Some(60.)), item(Some(30.5))]),
+            Some(90.5)
+        );
+
+        // Live streams have no end
+        assert_eq!(total_duration(&[item(Some(60.)), item(None)]), None);
+    }
 
     #[test]
     fn round_robin() {
@@ -481,4 +1077,414 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn priority() {
+        let robin = RoundRobin::new();
+
+        let john = User::mock("john");
+        let mary = User::mock("mary");
+
+        let titles = |robin: &RoundRobin| -> Vec<String> {
+            robin
+                .items()
+                .into_iter()
+                .map(|q| q.track.metadata.title.clone())
+                .collect()
+        };
+
+        robin.add(&john, vec![InternalTrack::mock("strawberries")]);
+        robin.add(&john, vec![InternalTrack::mock("bananas")]);
+        robin.add(&mary, vec![InternalTrack::mock("windows")]);
+        robin.next();
+
+        robin.add_priority(&mary, vec![InternalTrack::mock("cake")]);
+        robin.add_priority(&mary, vec![InternalTrack::mock("candles")]);
+
+        assert_eq!(
+            titles(&robin),
+            vec!["strawberries", "cake", "candles", "windows", "bananas"]
+        );
+
+        robin.next();
+        robin.next();
+
+        // The round robin continues where it left off
+        assert_eq!(
+            titles(&robin),
+            vec!["strawberries", "cake", "candles", "windows", "bananas"]
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let queue = Queue::new();
+        let john = User::mock("john");
+
+        queue.add(&john, vec![InternalTrack::mock("strawberries")]);
+        queue.add(&john, vec![InternalTrack::mock("bananas")]);
+        queue.add(&john, vec![InternalTrack::mock("apples")]);
+
+        let titles = |queue: &Queue| -> Vec<String> {
+            queue
+                .items()
+                .into_iter()
+                .map(|q| q.track.metadata.title.clone())
+                .collect()
+        };
+
+        let bananas = queue.items()[1].id;
+        assert!(queue.remove(bananas));
+        assert!(!queue.remove(bananas));
+        assert_eq!(titles(&queue), vec!["strawberries", "apples"]);
+
+        // Played items can be removed too
+        let strawberries = queue.current_item().unwrap().id;
+        queue.next();
+        assert!(queue.remove(strawberries));
+
+        assert_eq!(titles(&queue), vec!["apples"]);
+        assert_eq!(
+            queue.current_item().map(|i| i.track.metadata.title.clone()),
+            Some("apples".to_string())
+        );
+    }
+
+    #[test]
+    fn remove_older_than() {
+        let queue = queue_of(&["strawberries", "bananas", "apples"]);
+        let current = queue.current_item().unwrap();
+        let apples = queue.items()[2].id;
+        assert!(queue.remove_older_than(Duration::from_secs(60)).is_empty());
+
+        thread::sleep(Duration::from_millis(5));
+
+        // Every item is older now, but the current one keeps playing
+        let removed = queue.remove_older_than(Duration::ZERO);
+
+        assert!(removed.contains(&apples));
+        assert!(!removed.contains(&current.id));
+        assert_eq!(queue.current_item().map(|i| i.id), Some(current.id));
+    }
+
+    #[test]
+    fn move_item() {
+        let queue = Queue::new();
+
+        let john = User::mock("john");
+        let mary = User::mock("mary");
+
+        for (user, title) in [
+            (&john, "strawberries"),
+            (&john, "bananas"),
+            (&mary, "windows"),
+            (&john, "apples"),
+            (&mary, "linux"),
+        ] {
+            queue.add(user, vec![InternalTrack::mock(title)]);
+        }
+
+        let titles = |queue: &Queue| -> Vec<String> {
+            queue
+                .items()
+                .into_iter()
+                .map(|q| q.track.metadata.title.clone())
+                .collect()
+        };
+
+        let id_of = |queue: &Queue, title: &str| {
+            queue
+                .items()
+                .into_iter()
+                .find(|i| i.track.metadata.title == title)
+                .map(|i| i.id)
+                .unwrap()
+        };
+
+        assert_eq!(
+            titles(&queue),
+            vec!["strawberries", "bananas", "apples", "windows", "linux"]
+        );
+
+        // Forward
+        assert_eq!(queue.move_item(id_of(&queue, "linux"), 1), Some(1));
+        assert_eq!(
+            titles(&queue),
+            vec!["strawberries", "linux", "bananas", "apples", "windows"]
+        );
+
+        // Backward
+        assert_eq!(queue.move_item(id_of(&queue, "linux"), 3), Some(3));
+        assert_eq!(
+            titles(&queue),
+            vec!["strawberries", "bananas", "apples", "linux", "windows"]
+        );
+
+        // To the ends, clamped to the items after the current one
+        assert_eq!(queue.move_item(id_of(&queue, "apples"), 0), Some(1));
+        assert_eq!(queue.move_item(id_of(&queue, "bananas"), 100), Some(4));
+        assert_eq!(
+            titles(&queue),
+            vec!["strawberries", "apples", "linux", "windows", "bananas"]
+        );
+
+        // The current item stays where it is
+        assert_eq!(queue.move_item(id_of(&queue, "strawberries"), 2), None);
+
+        // The order is kept as items are played and added
+        queue.next();
+        queue.add(&mary, vec![InternalTrack::mock("osx")]);
+        assert_eq!(
+            titles(&queue),
+            vec![
+                "strawberries",
+                "apples",
+                "linux",
+                "windows",
+                "bananas",
+                "osx"
+            ]
+        );
+    }
+
+    #[test]
+    fn repeat_off() {
+        let queue = queue_of(&["strawberries", "bananas"]);
+
+        assert_eq!(title(queue.next()), Some("bananas".to_string()));
+        assert_eq!(title(queue.next()), None);
+        assert_eq!(title(queue.current_item()), None);
+        assert!(queue.tracks_to_play().is_empty());
+
+        // Playback continues with what is added after it stopped
+        queue.add(&User::mock("mary"), vec![InternalTrack::mock("apples")]);
+        assert_eq!(title(queue.current_item()), Some("apples".to_string()));
+    }
+
+    #[test]
+    fn repeat_one() {
+        let queue = queue_of(&["strawberries", "bananas"]);
+        assert!(queue.set_repeat(RepeatMode::One));
+        assert!(!queue.set_repeat(RepeatMode::One));
+
+        let played = queue.current_item().unwrap();
+        let replayed = queue.next().unwrap();
+
+        // The same item plays again, with a track that was not consumed
+        assert_eq!(replayed.id, played.id);
+        assert_ne!(replayed.track.id, played.track.id);
+        assert_eq!(queue.items().len(), 2);
+
+        // Skipping moves on
+        queue.mark_skipped();
+        assert_eq!(title(queue.next()), Some("bananas".to_string()));
+        assert_eq!(title(queue.next()), Some("bananas".to_string()));
+    }
+
+    #[test]
+    fn repeat_all() {
+        let queue = queue_of(&["strawberries", "bananas"]);
+        queue.set_repeat(RepeatMode::All);
+
+        let first = queue.current_item().unwrap();
+
+        assert_eq!(title(queue.next()), Some("bananas".to_string()));
+
+        let wrapped = queue.next().unwrap();
+        assert_eq!(wrapped.id, first.id);
+        assert_ne!(wrapped.track.id, first.track.id);
+
+        assert_eq!(title(queue.next()), Some("bananas".to_string()));
+        assert_eq!(queue.items().len(), 2);
+    }
+
+    fn queue_with_durations(durations: &[f32]) -> Queue {
+        let queue = Queue::new();
+        let john = User::mock("john");
+
+        for &duration in durations {
+            queue.add(&john, vec![track_with_duration(Some(duration))]);
+        }
+
+        queue
+    }
+
+    fn etas(queue: &Queue, position: f32) -> Vec<Option<f32>> {
+        queue
+            .items()
+            .iter()
+            .map(|i| queue.eta(i.id, position).unwrap().seconds)
+            .collect()
+    }
+
+    #[test]
+    fn eta_repeat_off() {
+        let queue = queue_with_durations(&[10., 20., 30.]);
+        assert_eq!(etas(&queue, 4.), vec![Some(0.), Some(6.), Some(26.)]);
+
+        // Played items do not play again
+        queue.next();
+        assert_eq!(etas(&queue, 5.), vec![None, Some(0.), Some(15.)]);
+    }
+
+    #[test]
+    fn eta_repeat_one() {
+        let queue = queue_with_durations(&[10., 20., 30.]);
+        queue.next();
+        queue.set_repeat(RepeatMode::One);
+
+        // Nothing after the current item plays until it is skipped
+        assert_eq!(etas(&queue, 5.), vec![None, Some(0.), None]);
+    }
+
+    #[test]
+    fn repeat_one_skips_failed_items() {
+        let event_bus = EventBus::new(Channel::new());
+        let ingestion = Ingestion::new(event_bus.emitter());
+
+        let queue = queue_of(&["apples", "pears"]);
+        queue.set_repeat(RepeatMode::One);
+
+        // Mock tracks cannot be loaded
+        let current = queue.current_item().unwrap();
+        assert!(current.track.ensure_activation(&ingestion).is_err());
+
+        let next = queue.next().unwrap();
+        assert_ne!(next.id, current.id);
+        assert_eq!(next.track.metadata.title, "pears");
+    }
+
+    #[test]
+    fn eta_repeat_all() {
+        let queue = queue_with_durations(&[10., 20., 30.]);
+        queue.next();
+        queue.set_repeat(RepeatMode::All);
+
+        // Played items play again after the last one
+        assert_eq!(etas(&queue, 5.), vec![Some(45.), Some(0.), Some(15.)]);
+    }
+
+    #[test]
+    fn eta_ended() {
+        let queue = queue_with_durations(&[10., 20.]);
+        queue.next();
+        queue.next();
+
+        assert_eq!(etas(&queue, 0.), vec![None, None]);
+    }
+
+    #[test]
+    fn shuffle() {
+        let titles = ["strawberries", "bananas", "apples", "windows", "linux"];
+        let queue = queue_of(&titles);
+        let current = queue.current_item().unwrap();
+
+        let order = queue.shuffle(&mut StdRng::seed_from_u64(3)).unwrap();
+        let items = queue.items();
+
+        assert_eq!(items[0].id, current.id);
+        assert_eq!(queue.current_item().unwrap().id, current.id);
+        assert_eq!(order, items[1..].iter().map(|i| i.id).collect::<Vec<_>>());
+
+        let mut shuffled: Vec<_> = items
+            .iter()
+            .map(|i| i.track.metadata.title.clone())
+            .collect();
+        shuffled.sort();
+
+        let mut expected = titles.map(String::from).to_vec();
+        expected.sort();
+
+        assert_eq!(shuffled, expected);
+
+        // The same seed gives the same order
+        let other = queue_of(&titles);
+        other.shuffle(&mut StdRng::seed_from_u64(3));
+
+        let titles_of = |queue: &Queue| -> Vec<String> {
+            queue
+                .items()
+                .into_iter()
+                .map(|q| q.track.metadata.title.clone())
+                .collect()
+        };
+
+        assert_eq!(titles_of(&queue), titles_of(&other));
+
+        // There is nothing to shuffle with one item after the current one
+        let short = queue_of(&["strawberries", "bananas"]);
+        assert_eq!(short.shuffle(&mut StdRng::seed_from_u64(3)), None);
+        assert_eq!(titles_of(&short), vec!["strawberries", "bananas"]);
+        assert_eq!(queue_of(&[]).shuffle(&mut StdRng::seed_from_u64(3)), None);
+    }
+
+    #[test]
+    fn shuffle_keeps_turns() {
+        let queue = Queue::new();
+        let john = User::mock("john");
+        let mary = User::mock("mary");
+
+        for (a, b) in [
+            ("strawberries", "windows"),
+            ("bananas", "linux"),
+            ("apples", "macos"),
+        ] {
+            queue.add(&john, vec![InternalTrack::mock(a)]);
+            queue.add(&mary, vec![InternalTrack::mock(b)]);
+        }
+
+        let submitters = |queue: &Queue| -> Vec<String> {
+            queue
+                .items()
+                .into_iter()
+                .map(|i| i.submitter().id.to_string())
+                .collect()
+        };
+
+        let before = submitters(&queue);
+        queue.shuffle(&mut StdRng::seed_from_u64(3)).unwrap();
+
+        assert_eq!(submitters(&queue), before);
+    }
+
+    #[test]
+    fn clear() {
+        let queue = queue_of(&["strawberries", "bananas", "apples", "windows"]);
+        queue.next();
+
+        let removed = queue.clear(true);
+        let titles: Vec<_> = removed
+            .iter()
+            .map(|i| i.track.metadata.title.clone())
+            .collect();
+
+        // Played items are removed too
+        assert_eq!(titles, vec!["strawberries", "apples", "windows"]);
+        assert_eq!(queue.items().len(), 1);
+        assert_eq!(title(queue.current_item()), Some("bananas".to_string()));
+
+        queue.add(&User::mock("mary"), vec![InternalTrack::mock("linux")]);
+        assert_eq!(title(queue.next()), Some("linux".to_string()));
+
+        assert_eq!(queue.clear(false).len(), 2);
+        assert!(queue.items().is_empty());
+        assert_eq!(title(queue.current_item()), None);
+
+        queue.add(&User::mock("mary"), vec![InternalTrack::mock("osx")]);
+        assert_eq!(title(queue.current_item()), Some("osx".to_string()));
+    }
+
+    #[test]
+    fn attributes_items() {
+        let queue = queue_of(&["strawberries"]);
+        queue.add(&User::mock("mary"), vec![InternalTrack::mock("bananas")]);
+
+        let added_by: Vec<_> = queue
+            .items()
+            .into_iter()
+            .map(|i| serde_json::to_value(i).unwrap()["addedBy"]["displayName"].clone())
+            .collect();
+
+        assert_eq!(added_by, vec!["john", "mary"]);
+    }
 }
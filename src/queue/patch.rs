@@ -0,0 +1,184 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use super::QueueId;
+
+/// The changes between two versions of a queue, as a JSON Patch (RFC 6902)
+/// to apply to the previous [super::SerializedQueue].
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuePatch {
+    pub queue: QueueId,
+    /// Increases by one for every change, so a missed patch can be detected
+    pub sequence: u64,
+    pub operations: Vec<Operation>,
+}
+
+/// A single JSON Patch operation
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+/// Returns the operations that turn `old` into `new`.
+///
+/// Arrays are compared by trimming what is the same at the start and end,
+/// so adding or removing a single item results in a single operation.
+pub fn diff(old: &Value, new: &Value) -> Vec<Operation> {
+    let mut operations = vec![];
+    diff_at("", old, new, &mut operations);
+
+    operations
+}
+
+fn diff_at(path: &str, old: &Value, new: &Value, operations: &mut Vec<Operation>) {
+    match (old, new) {
+        _ if old == new => {}
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                let path = format!("{}/{}", path, escape(key));
+
+                match new.get(key) {
+                    Some(new_value) => diff_at(&path, old_value, new_value, operations),
+                    None => operations.push(Operation::Remove { path }),
+                }
+            }
+
+            for (key, new_value) in new.iter().filter(|(k, _)| !old.contains_key(*k)) {
+                operations.push(Operation::Add {
+                    path: format!("{}/{}", path, escape(key)),
+                    value: new_value.clone(),
+                });
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => diff_array(path, old, new, operations),
+        _ => operations.push(Operation::Replace {
+            path: path.to_string(),
+            value: new.clone(),
+        }),
+    }
+}
+
+fn diff_array(path: &str, old: &[Value], new: &[Value], operations: &mut Vec<Operation>) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let removed = &old[prefix..old.len() - suffix];
+    let added = &new[prefix..new.len() - suffix];
+
+    // A single changed item is patched in place
+    if removed.len() == 1 && added.len() == 1 {
+        return diff_at(
+            &format!("{}/{}", path, prefix),
+            &removed[0],
+            &added[0],
+            operations,
+        );
+    }
+
+    // Removed from the end first, so the indices of the others stay the same
+    for index in (prefix..prefix + removed.len()).rev() {
+        operations.push(Operation::Remove {
+            path: format!("{}/{}", path, index),
+        });
+    }
+
+    for (offset, value) in added.iter().enumerate() {
+        operations.push(Operation::Add {
+            path: format!("{}/{}", path, prefix + offset),
+            value: value.clone(),
+        });
+    }
+}
+
+/// Escapes a key to be used in a JSON Pointer
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{diff, Operation};
+
+    #[test]
+    fn array_changes() {
+        let old = json!({ "items": ["a", "b", "c"], "playing": "a" });
+
+        assert_eq!(
+            diff(
+                &old,
+                &json!({ "items": ["a", "x", "b", "c"], "playing": "a" })
+            ),
+            vec![Operation::Add {
+                path: "/items/1".to_string(),
+                value: json!("x")
+            }]
+        );
+
+        assert_eq!(
+            diff(&old, &json!({ "items": ["a", "c"], "playing": "b" })),
+            vec![
+                Operation::Remove {
+                    path: "/items/1".to_string()
+                },
+                Operation::Replace {
+                    path: "/playing".to_string(),
+                    value: json!("b")
+                }
+            ]
+        );
+
+        // Reordering removes and adds the items that moved
+        assert_eq!(
+            diff(&old, &json!({ "items": ["a", "c", "b"], "playing": "a" })),
+            vec![
+                Operation::Remove {
+                    path: "/items/2".to_string()
+                },
+                Operation::Remove {
+                    path: "/items/1".to_string()
+                },
+                Operation::Add {
+                    path: "/items/1".to_string(),
+                    value: json!("c")
+                },
+                Operation::Add {
+                    path: "/items/2".to_string(),
+                    value: json!("b")
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn object_changes() {
+        let old = json!({ "a/b": 1, "gone": true, "nested": { "x": 1 } });
+        let new = json!({ "a/b": 2, "nested": { "x": 1, "y": 2 } });
+
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                Operation::Replace {
+                    path: "/a~1b".to_string(),
+                    value: json!(2)
+                },
+                Operation::Remove {
+                    path: "/gone".to_string()
+                },
+                Operation::Add {
+                    path: "/nested/y".to_string(),
+                    value: json!(2)
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use axum::{extract::State, routing::post, Json};
+use log::trace;
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    aliases::Alias,
+    auth::Session,
+    ingest::{AudioFormat, Input},
+    rooms::RoomRole,
+    server::{Context, Router},
+    util::ApiError,
+};
+
+pub fn router() -> Router {
+    Router::new().route("/broadcast", post(broadcast_input))
+}
+
+#[derive(Deserialize)]
+struct BroadcastBody {
+    input: String,
+    rooms: Vec<String>,
+}
+
+/// Describes if an input was added to a room
+#[derive(Serialize)]
+struct BroadcastResult {
+    room: String,
+    added: bool,
+    error: Option<String>,
+}
+
+/// Resolves an input once, and adds it to each room it is allowed in
+async fn broadcast_input(
+    session: Session,
+    State(context): Context,
+    Json(body): Json<BroadcastBody>,
+) -> Result<Json<Vec<BroadcastResult>>, ApiError> {
+    if let Some(limiter) = &context.limits.adds {
+        limiter
+            .check(session.user.id.clone())
+            .map_err(ApiError::TooManyRequests)?;
+    }
+
+    let query = Alias::expand(&context.db, body.input).await?;
+    // Rooms may pick different formats, but the input is only resolved once for all of them
+    let input = spawn_blocking(move || Input::parse(&query, &AudioFormat::configured()))
+        .await
+        .unwrap()
+        .map_err(ApiError::from)?;
+
+    if let Some(cooldown) = &context.limits.duplicate_adds {
+        cooldown
+            .check((session.user.id.clone(), input.fingerprint()))
+            .map_err(ApiError::TooManyRequests)?;
+    }
+
+    let mut results = vec![];
+
+    for id in body.rooms {
+        let room_store = &context.store.room_store;
+
+        let result = room_store
+            .find_room(&id)
+            .and_then(|room| room_store.check_access(&room, &session).map(|_| room))
+            .and_then(|room| {
+                room_store
+                    .check_role(
+                        &room,
+                        &session.user,
+                        RoomRole::Member,
+                        "Queueing in this room",
+                    )
+                    .map(|_| room)
+            })
+            .and_then(|room| room_store.check_can_queue(&room, &input).map(|_| room))
+            .map_err(|err| err.to_string());
+
+        match result {
+            Ok(room) => {
+                let context = context.clone();
+                let user = session.user.clone();
+                let input = input.clone();
+
+                let merge_duplicates = context.limits.duplicate_adds.is_some();
+
+                let added = spawn_blocking(move || {
+                    context
+                        .store
+                        .room_store
+                        .add_input(user, &room, input, merge_duplicates)
+                })
+                .await
+                .unwrap();
+
+                // Someone else added the same track to this room at the same time
+                let error = (!added).then(|| ApiError::Conflict("Track in the queue").to_string());
+
+                results.push(BroadcastResult {
+                    room: id,
+                    added,
+                    error,
+                });
+            }
+            Err(err) => results.push(BroadcastResult {
+                room: id,
+                added: false,
+                error: Some(err),
+            }),
+        }
+    }
+
+    trace!(target: "vinyl::server", "Broadcasted {} to {} rooms", input,
+        results.iter().filter(|r| r.added).count()
+    );
+
+    Ok(Json(results))
+}
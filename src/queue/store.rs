@@ -1,14 +1,23 @@
-use super::{OrderStrategy, Queue, QueueEvent, QueueId, QueueItem, SerializedQueue, SubQueueId};
+use super::{
+    diff, Eta, OrderStrategy, PlayHistory, Queue, QueueEvent, QueueId, QueueItem, QueueItemId,
+    QueuePatch, RepeatMode, SerializedQueue, SubQueueId,
+};
 use crate::{
     audio::{AudioEvent, PlayerId},
     auth::User,
     events::Handler,
+    ingest::IngestionEvent,
     store::Store,
     track::Track,
     EventEmitter, VinylEvent,
 };
-use dashmap::DashMap;
-use std::sync::{Arc, Weak};
+use dashmap::{DashMap, DashSet};
+use rand::{rngs::StdRng, SeedableRng};
+use serde_json::Value;
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
 
 #[derive(Debug)]
 pub struct QueueStore {
@@ -17,6 +26,18 @@ pub struct QueueStore {
 
     queues: DashMap<QueueId, Queue>,
     players: DashMap<QueueId, PlayerId>,
+
+    /// The sequence and state of the last update of each queue, to compute patches from
+    published: DashMap<QueueId, (u64, Value)>,
+
+    /// Adds that are in progress, keyed by queue and fingerprint, see [QueueStore::add_once]
+    pending_adds: DashSet<(QueueId, String)>,
+
+    /// Current items that were removed, to take out of the queue once the player skipped them
+    pending_removals: DashMap<QueueId, QueueItemId>,
+
+    /// Items that started playing, to replay them later
+    pub history: PlayHistory,
 }
 
 impl QueueStore {
@@ -26,6 +47,10 @@ impl QueueStore {
             emitter,
             queues: Default::default(),
             players: Default::default(),
+            published: Default::default(),
+            pending_adds: Default::default(),
+            pending_removals: Default::default(),
+            history: Default::default(),
         }
     }
 
@@ -40,28 +65,316 @@ impl QueueStore {
         id
     }
 
+    /// Removes a queue along with everything about it,
+    /// letting ingestion clean up the tracks that were loaded for it
+    pub fn remove_queue(&self, queue_id: QueueId) {
+        let store = self.store();
+
+        let Some((_, queue)) = self.queues.remove(&queue_id) else {
+            return;
+        };
+
+        for sink in queue.items().iter().flat_map(|i| i.track.sink()) {
+            if let Some(sink) = sink.try_upgrade(&store) {
+                sink.consume();
+            }
+        }
+
+        self.players.remove(&queue_id);
+        self.published.remove(&queue_id);
+        self.pending_adds.retain(|(queue, _)| *queue != queue_id);
+        self.history.remove(queue_id);
+    }
+
+    /// Returns false if the queue was removed, see [QueueStore::remove_queue]
+    pub fn contains(&self, queue: QueueId) -> bool {
+        self.queues.contains_key(&queue)
+    }
+
     pub fn add(&self, queue: &QueueId, submitter: User, tracks: Vec<Track>) {
         let queue = self.queues.get(queue).expect("queue exists");
+        let was_empty = queue.current_item().is_none();
 
         queue.add(&submitter, tracks);
 
+        // The first item starts playing without advancing
+        if let Some(item) = queue.current_item().filter(|_| was_empty) {
+            self.history.push(queue.id, item);
+        }
+
         self.apply_to_player(queue.id);
-        self.emitter.dispatch(QueueEvent::Update {
-            queue: queue.id,
-            new_items: queue.items(),
-        });
+        self.publish(queue.id);
+    }
+
+    /// Runs `add` unless a track with the same fingerprint is already waiting to play in the queue,
+    /// returning false if it was merged into that one instead.
+    ///
+    /// Only one add per fingerprint can be in progress at a time,
+    /// so identical adds racing each other cannot both pass.
+    pub fn add_once(&self, queue: QueueId, fingerprint: String, add: impl FnOnce()) -> bool {
+        let key = (queue, fingerprint);
+
+        if !self.pending_adds.insert(key.clone()) {
+            return false;
+        }
+
+        let queued = self
+            .queues
+            .get(&queue)
+            .expect("queue exists")
+            .upcoming()
+            .iter()
+            .any(|i| i.track.fingerprint() == key.1);
+
+        if !queued {
+            add();
+        }
+
+        self.pending_adds.remove(&key);
+        !queued
+    }
+
+    /// Adds tracks that play right after the current item
+    pub fn add_priority(&self, queue: &QueueId, submitter: User, tracks: Vec<Track>) {
+        let queue = self.queues.get(queue).expect("queue exists");
+
+        queue.add_priority(&submitter, tracks);
+
+        self.apply_to_player(queue.id);
+        self.publish(queue.id);
+    }
+
+    /// Plays a track on top of the music, lowering the music while it plays
+    pub fn add_voice(&self, queue_id: QueueId, submitter: User, track: Track) {
+        let store = self.store();
+        let queue = self.queues.get(&queue_id).expect("queue exists");
+
+        let player = self
+            .players
+            .get(&queue_id)
+            .expect("player is assigned")
+            .upgrade(&store);
+
+        if let Err(err) = track.ensure_activation(&store.ingestion) {
+            self.emitter.dispatch(QueueEvent::ActivationError {
+                queue: queue_id,
+                track: track.id,
+            });
+
+            self.emitter.dispatch(IngestionEvent::Failed {
+                queue: queue_id,
+                id: None,
+                input: track.metadata.canonical.clone(),
+                reason: err.to_string(),
+                status: err.status().as_u16(),
+            });
+
+            return;
+        }
+
+        let sink = track
+            .sink()
+            .expect("track is active after activation")
+            .upgrade(&store);
+
+        queue.add_voice(&submitter, track);
+        player.duck(sink);
+
+        self.publish(queue_id);
     }
 
     pub fn next(&self, queue: QueueId) {
         let item = self.queues.get(&queue).expect("queue exists").next();
 
+        if let Some((_, removed)) = self.pending_removals.remove(&queue) {
+            self.queues
+                .get(&queue)
+                .expect("queue exists")
+                .remove(removed);
+        }
+
         self.apply_to_player(queue);
 
+        // Repeated tracks are replaced, and the current item is cleared once playback stops
+        self.publish(queue);
+
         if let Some(item) = item {
+            self.history.push(queue, item.clone());
             self.emitter.dispatch(QueueEvent::Advance { queue, item });
         }
     }
 
+    /// Marks the current item as skipped, so it is not repeated when it ends
+    pub fn mark_skipped(&self, queue: QueueId) {
+        self.queues
+            .get(&queue)
+            .expect("queue exists")
+            .mark_skipped();
+    }
+
+    /// Sets what happens when the current item ends, returning false if it was already set
+    pub fn set_repeat(&self, queue: QueueId, mode: RepeatMode) -> bool {
+        let changed = self
+            .queues
+            .get(&queue)
+            .expect("queue exists")
+            .set_repeat(mode);
+
+        if changed {
+            self.emitter
+                .dispatch(QueueEvent::RepeatChanged { queue, mode });
+            self.apply_to_player(queue);
+            self.publish(queue);
+        }
+
+        changed
+    }
+
+    /// Removes an item from the queue, returning false if it is not in it.
+    ///
+    /// The current item is skipped instead, and removed once the queue advances past it,
+    /// so the next one plays from the start.
+    pub fn remove(&self, queue_id: QueueId, item: QueueItemId) -> bool {
+        let store = self.store();
+        let queue = self.queues.get(&queue_id).expect("queue exists");
+
+        if queue.current_item().map(|i| i.id) == Some(item) {
+            queue.mark_skipped();
+            drop(queue);
+
+            self.pending_removals.insert(queue_id, item);
+
+            self.players
+                .get(&queue_id)
+                .expect("player is assigned")
+                .upgrade(&store)
+                .skip();
+
+            return true;
+        }
+
+        let track = queue
+            .items()
+            .into_iter()
+            .find(|i| i.id == item)
+            .map(|i| i.track);
+        let removed = queue.remove(item);
+
+        drop(queue);
+
+        if !removed {
+            return false;
+        }
+
+        self.apply_to_player(queue_id);
+
+        // Nothing plays the removed track anymore, so ingestion can clean it up
+        if let Some(sink) = track.and_then(|t| t.sink()) {
+            if let Some(sink) = sink.try_upgrade(&store) {
+                sink.consume();
+            }
+        }
+
+        self.publish(queue_id);
+        true
+    }
+
+    /// Moves an item after the current one to a new position, see [Queue::move_item]
+    pub fn move_item(
+        &self,
+        queue_id: QueueId,
+        item: QueueItemId,
+        position: usize,
+    ) -> Option<usize> {
+        let position = self
+            .queues
+            .get(&queue_id)
+            .expect("queue exists")
+            .move_item(item, position)?;
+
+        self.apply_to_player(queue_id);
+        self.publish(queue_id);
+
+        Some(position)
+    }
+
+    /// Removes every item from the queue, except the current one if `keep_current` is true,
+    /// returning how many were removed. Playback stops if the current item is removed.
+    pub fn clear(&self, queue_id: QueueId, keep_current: bool) -> usize {
+        let store = self.store();
+        let queue = self.queues.get(&queue_id).expect("queue exists");
+
+        let removed = queue.clear(keep_current);
+        let kept = queue.current_item().map(|i| i.id);
+
+        drop(queue);
+
+        if removed.is_empty() {
+            return 0;
+        }
+
+        if kept.is_none() {
+            self.players
+                .get(&queue_id)
+                .expect("player is assigned")
+                .upgrade(&store)
+                .stop();
+        }
+
+        self.apply_to_player(queue_id);
+
+        // Nothing plays the removed tracks anymore, so ingestion can clean them up
+        for sink in removed.iter().flat_map(|i| i.track.sink()) {
+            if let Some(sink) = sink.try_upgrade(&store) {
+                sink.consume();
+            }
+        }
+
+        // The clear also stands for the update, so it is the only event
+        self.emitter.dispatch(QueueEvent::Cleared {
+            queue: queue_id,
+            removed: removed.iter().map(|i| i.id).collect(),
+            kept,
+            patch: self.patch(queue_id),
+        });
+
+        removed.len()
+    }
+
+    /// Randomly reorders the items after the current one, using `seed` so the order can be reproduced.
+    /// Returns false if there was nothing to shuffle.
+    pub fn shuffle(&self, queue_id: QueueId, seed: u64) -> bool {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let Some(order) = self
+            .queues
+            .get(&queue_id)
+            .expect("queue exists")
+            .shuffle(&mut rng)
+        else {
+            return false;
+        };
+
+        self.emitter.dispatch(QueueEvent::Shuffled {
+            queue: queue_id,
+            order,
+        });
+
+        self.apply_to_player(queue_id);
+        self.publish(queue_id);
+
+        true
+    }
+
+    /// Removes the voice track that finished playing, at which point the music is back to full
+    fn end_voice(&self, queue_id: QueueId) {
+        let queue = self.queues.get(&queue_id).expect("queue exists");
+
+        if queue.end_voice().is_some() {
+            self.publish(queue_id);
+        }
+    }
+
     pub fn current_item(&self, queue: QueueId) -> Option<QueueItem> {
         self.queues
             .get(&queue)
@@ -69,8 +382,118 @@ impl QueueStore {
             .current_item()
     }
 
-    pub fn serialized(&self, queue: QueueId) -> SerializedQueue {
-        SerializedQueue::new(&self.queues.get(&queue).expect("queue exists"))
+    /// Estimates when an item starts playing, returning [None] if it is not in the queue
+    pub fn eta(&self, queue: QueueId, item: QueueItemId) -> Option<Eta> {
+        let store = self.store();
+        let player = self
+            .players
+            .get(&queue)
+            .expect("player is assigned")
+            .upgrade(&store);
+
+        let eta = self
+            .queues
+            .get(&queue)
+            .expect("queue exists")
+            .eta(item, player.position().as_secs_f32())?;
+
+        // Nothing plays until the player is released
+        if player.is_held() {
+            return Some(Eta {
+                seconds: None,
+                ..eta
+            });
+        }
+
+        Some(eta)
+    }
+
+    /// Removes items waiting to be played that were added more than `max_age` ago
+    pub fn remove_older_than(&self, queue_id: QueueId, max_age: Duration) {
+        let queue = self.queues.get(&queue_id).expect("queue exists");
+        let removed = queue.remove_older_than(max_age);
+
+        if removed.is_empty() {
+            return;
+        }
+
+        self.apply_to_player(queue_id);
+        self.publish(queue_id);
+    }
+
+    /// Returns the tracks of every queue
+    pub fn tracks(&self) -> Vec<Track> {
+        self.queues
+            .iter()
+            .flat_map(|q| q.items())
+            .map(|i| i.track)
+            .collect()
+    }
+
+    /// Returns the current item and the ones after it
+    pub fn upcoming(&self, queue: QueueId) -> Vec<QueueItem> {
+        self.queues.get(&queue).expect("queue exists").upcoming()
+    }
+
+    /// Returns everyone who has added something to the queue
+    pub fn submitters(&self, queue: QueueId) -> Vec<User> {
+        self.queues.get(&queue).expect("queue exists").submitters()
+    }
+
+    /// Returns an item in the queue, including ones that were already played
+    pub fn item(&self, queue: QueueId, item: QueueItemId) -> Option<QueueItem> {
+        self.queues.get(&queue).expect("queue exists").item(item)
+    }
+
+    /// Returns the queue as clients see it, along with the sequence of the last patch.
+    ///
+    /// This is done under the same guard as [QueueStore::publish],
+    /// so a patch can't be published in between reading the sequence and the queue.
+    pub fn serialized(&self, queue_id: QueueId) -> SerializedQueue {
+        let queue = self.queues.get(&queue_id).expect("queue exists");
+        let published = self.published.entry(queue_id).or_default();
+
+        SerializedQueue::new(&queue, published.0)
+    }
+
+    /// Returns every queue, see [QueueStore::serialized]
+    pub fn serialized_all(&self) -> Vec<SerializedQueue> {
+        let ids: Vec<_> = self.queues.iter().map(|q| *q.key()).collect();
+        ids.into_iter().map(|id| self.serialized(id)).collect()
+    }
+
+    /// Notifies about a change to the queue, with a patch from the previous update
+    fn publish(&self, queue_id: QueueId) {
+        let patch = self.patch(queue_id);
+        let new_items = self.queues.get(&queue_id).expect("queue exists").items();
+
+        self.emitter.dispatch(QueueEvent::Update {
+            queue: queue_id,
+            new_items,
+            patch,
+        });
+    }
+
+    /// Advances the sequence of the queue, returning what changed since the previous update
+    fn patch(&self, queue_id: QueueId) -> QueuePatch {
+        let queue = self.queues.get(&queue_id).expect("queue exists");
+        let mut published = self.published.entry(queue_id).or_default();
+
+        let (sequence, previous) = &mut *published;
+        *sequence += 1;
+
+        let current = serde_json::to_value(SerializedQueue::new(&queue, *sequence))
+            .expect("queue serializes properly");
+
+        // The sequence always changes, and is left out so clients do not have to patch it
+        let operations = diff(&without_sequence(previous), &without_sequence(&current));
+        *previous = current;
+
+        QueuePatch {
+            queue: queue_id,
+            sequence: *sequence,
+            operations,
+        }
     }
 
     /// Applies the queue to the player, ensuring tracks are activated
@@ -89,11 +512,19 @@ impl QueueStore {
         for track in tracks.iter() {
             let result = track.ensure_activation(&store.ingestion);
 
-            if result.is_err() {
+            if let Err(err) = result {
                 self.emitter.dispatch(QueueEvent::ActivationError {
                     queue: queue_id,
                     track: track.id,
-                })
+                });
+
+                self.emitter.dispatch(IngestionEvent::Failed {
+                    queue: queue_id,
+                    id: None,
+                    input: track.metadata.canonical.clone(),
+                    reason: err.to_string(),
+                    status: err.status().as_u16(),
+                });
             }
         }
 
@@ -103,6 +534,7 @@ impl QueueStore {
             .map(|x| x.upgrade(&store))
             .collect();
 
+        player.set_repeating(queue.repeat() == RepeatMode::One);
         player.set_sinks(sinks);
     }
 
@@ -117,6 +549,16 @@ impl QueueStore {
     }
 }
 
+fn without_sequence(value: &Value) -> Value {
+    let mut value = value.clone();
+
+    if let Some(object) = value.as_object_mut() {
+        object.remove("sequence");
+    }
+
+    value
+}
+
 pub struct QueueHandler {
     store: Weak<Store>,
 }
@@ -127,15 +569,129 @@ impl Handler<VinylEvent> for QueueHandler {
     fn handle(&self, incoming: Self::Incoming) {
         let store = self.store.upgrade().unwrap();
 
-        if let AudioEvent::Next { player } = incoming {
-            let queue = store
+        // The queue is gone if its room was deleted while the event was dispatched
+        let find_queue = |player: PlayerId| {
+            store
                 .queue_store
                 .players
                 .iter()
                 .find_map(|x| (x.value() == &player).then_some(*x.key()))
-                .expect("queue exists");
+        };
 
-            store.queue_store.next(queue)
+        match incoming {
+            AudioEvent::Next { player } => {
+                if let Some(queue) = find_queue(player) {
+                    store.queue_store.next(queue)
+                }
+            }
+            AudioEvent::VoiceEnded { player } => {
+                if let Some(queue) = find_queue(player) {
+                    store.queue_store.end_voice(queue)
+                }
+            }
+            _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Barrier, Weak},
+        thread,
+    };
+
+    use super::QueueStore;
+    use crate::{
+        auth::User,
+        events::Channel,
+        store::{Id, Store},
+        track::InternalTrack,
+        EventBus,
+    };
+
+    #[test]
+    fn removing_current_item_skips_it() {
+        let event_bus = EventBus::new(Channel::new());
+        let store = Store::new(event_bus.emitter());
+
+        let player = store.playback.create_player().unwrap();
+        let queue = store.queue_store.create_queue(player);
+
+        let tracks = vec![InternalTrack::mock("apples"), InternalTrack::mock("pears")];
+        store.queue_store.add(&queue, User::mock("john"), tracks);
+
+        let current = store.queue_store.current_item(queue).unwrap();
+        assert!(store.queue_store.remove(queue, current.id));
+
+        // It keeps playing until the player skipped it, which is reported like it ended
+        assert_eq!(
+            store.queue_store.current_item(queue).unwrap().id,
+            current.id
+        );
+        assert_eq!(player.upgrade(&store).process().consumed_sinks, 1);
+
+        store.queue_store.next(queue);
+
+        let items = store.queue_store.queues.get(&queue).unwrap().items();
+        let next = store.queue_store.current_item(queue).unwrap();
+
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, next.id);
+        assert_ne!(next.id, current.id);
+    }
+
+    #[test]
+    fn clearing_sends_one_event() {
+        let event_bus = EventBus::new(Channel::new());
+        let store = Store::new(event_bus.emitter());
+
+        let player = store.playback.create_player().unwrap();
+        let queue = store.queue_store.create_queue(player);
+
+        let tracks = vec![InternalTrack::mock("apples"), InternalTrack::mock("pears")];
+        store.queue_store.add(&queue, User::mock("john"), tracks);
+
+        let backlog = event_bus.backlog();
+        assert_eq!(store.queue_store.clear(queue, true), 1);
+        assert_eq!(event_bus.backlog(), backlog + 1);
+    }
+
+    #[test]
+    fn merges_racing_adds() {
+        let store = Arc::new(QueueStore::new(
+            Weak::new(),
+            EventBus::new(Channel::new()).emitter(),
+        ));
+
+        let queue = store.create_queue(Id::new());
+        let barrier = Arc::new(Barrier::new(2));
+
+        let handles: Vec<_> = ["john", "mary"]
+            .into_iter()
+            .map(|name| {
+                let (store, barrier) = (store.clone(), barrier.clone());
+
+                thread::spawn(move || {
+                    let user = User::mock(name);
+                    barrier.wait();
+
+                    let track = InternalTrack::mock("strawberries");
+
+                    store.add_once(queue, track.fingerprint(), || {
+                        store.queues.get(&queue).unwrap().add(&user, vec![track])
+                    })
+                })
+            })
+            .collect();
+
+        let added: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(added.iter().filter(|a| **a).count(), 1);
+        assert_eq!(store.queues.get(&queue).unwrap().items().len(), 1);
+
+        // The track is still waiting to play, so adding it again later is merged too
+        let track = InternalTrack::mock("strawberries");
+        assert!(!store.add_once(queue, track.fingerprint(), || unreachable!()));
+    }
+}
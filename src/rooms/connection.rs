@@ -1,30 +1,196 @@
+use crossbeam::atomic::AtomicCell;
 use futures_util::{FutureExt, Stream};
+use lazy_static::lazy_static;
+use log::trace;
 use parking_lot::Mutex;
 use tokio::runtime;
+use tokio::sync::mpsc::{self, error::TrySendError};
 use tokio::task;
 
 use super::RoomId;
 use crate::store::Store;
-use crate::{audio::WaveStream, auth::User, util::ID_COUNTER};
+use crate::{
+    audio::{output_format, EncodedStream, Encoding, SAMPLE_RATE},
+    auth::User,
+    util::ID_COUNTER,
+};
 use std::{
     convert::Infallible,
+    env,
+    fmt::Debug,
     io::Read,
+    mem,
     pin::Pin,
     sync::{Arc, Weak},
     task::{Context, Poll},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
+lazy_static! {
+    static ref OUTPUT_CONFIG: OutputConfig = OutputConfig::from_env();
+    pub(super) static ref CONNECTION_POLICY: ConnectionPolicy = ConnectionPolicy::from_env();
+}
+
+/// Reads and validates the output config, so mistakes are caught on startup
+pub fn init_output_config() -> &'static impl Debug {
+    &*OUTPUT_CONFIG
+}
+
+/// Reads and validates the connection policy, so mistakes are caught on startup
+pub fn init_connection_policy() -> &'static impl Debug {
+    &*CONNECTION_POLICY
+}
+
+/// What happens when a user opens more than one stream in the same room, such as in several tabs.
+///
+/// Set with `VINYL_STREAM_CONNECTION_POLICY` to `allow`, `replace-oldest` or `limit`,
+/// and the amount of streams per user with `VINYL_STREAM_CONNECTIONS_PER_USER`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) enum ConnectionPolicy {
+    /// Every connection is accepted
+    #[default]
+    Allow,
+    /// New connections close the oldest ones of the user when there are too many
+    ReplaceOldest(usize),
+    /// New connections are refused when there are too many
+    Limit(usize),
+}
+
+impl ConnectionPolicy {
+    const DEFAULT_CONNECTIONS_PER_USER: usize = 1;
+
+    fn from_env() -> Self {
+        let per_user = env::var("VINYL_STREAM_CONNECTIONS_PER_USER")
+            .map(|x| {
+                x.parse::<usize>()
+                    .expect("Connections per user must be a number")
+            })
+            .unwrap_or(Self::DEFAULT_CONNECTIONS_PER_USER);
+
+        assert!(per_user > 0, "Connections per user must be above 0");
+
+        match env::var("VINYL_STREAM_CONNECTION_POLICY").as_deref() {
+            Ok("allow") | Err(_) => Self::Allow,
+            Ok("replace-oldest") => Self::ReplaceOldest(per_user),
+            Ok("limit") => Self::Limit(per_user),
+            Ok(other) => panic!(
+                "Unknown connection policy {}, must be allow, replace-oldest or limit",
+                other
+            ),
+        }
+    }
+}
+
+/// Controls how the stream is split up when it is sent to clients.
+///
+/// Every chunk adds per-chunk overhead, while waiting to flush adds latency and jitter.
+/// The flush interval is added on top of the preloaded second new listeners start with,
+/// and in sync mode it should stay well below the sync latency, since clients can only
+/// align samples they have received.
+#[derive(Debug, Clone, Copy)]
+struct OutputConfig {
+    /// How many frames to read at a time, set with `VINYL_STREAM_CHUNK_FRAMES`
+    chunk_frames: usize,
+    /// How long to collect chunks before sending them, set with `VINYL_STREAM_FLUSH_MS`.
+    /// When this is 0, every chunk is sent as soon as it is read.
+    flush_interval: Duration,
+}
+
+impl OutputConfig {
+    const DEFAULT_CHUNK_FRAMES: usize = 512;
+    const MIN_CHUNK_FRAMES: usize = 64;
+    const MAX_CHUNK_FRAMES: usize = SAMPLE_RATE;
+    const MAX_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+    fn from_env() -> Self {
+        let chunk_frames = env::var("VINYL_STREAM_CHUNK_FRAMES")
+            .map(|x| x.parse::<usize>().expect("Chunk size must be a number"))
+            .unwrap_or(Self::DEFAULT_CHUNK_FRAMES);
+
+        let flush_interval = env::var("VINYL_STREAM_FLUSH_MS")
+            .map(|x| x.parse::<u64>().expect("Flush interval must be a number"))
+            .map(Duration::from_millis)
+            .unwrap_or_default();
+
+        assert!(
+            (Self::MIN_CHUNK_FRAMES..=Self::MAX_CHUNK_FRAMES).contains(&chunk_frames),
+            "Chunk size must be between {} and {} frames",
+            Self::MIN_CHUNK_FRAMES,
+            Self::MAX_CHUNK_FRAMES
+        );
+
+        assert!(
+            flush_interval <= Self::MAX_FLUSH_INTERVAL,
+            "Flush interval must be at most {}ms",
+            Self::MAX_FLUSH_INTERVAL.as_millis()
+        );
+
+        Self {
+            chunk_frames,
+            flush_interval,
+        }
+    }
+
+    /// The size of a chunk in bytes, as encoded by [WaveStream](crate::audio::WaveStream)
+    fn chunk_bytes(&self) -> usize {
+        self.chunk_frames * output_format().frame_bytes()
+    }
+}
+
 pub type ConnectionHandleId = u64;
 
+/// How the audio of a connection is delivered to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// A chunked response in the given encoding, preloaded so it survives hiccups in the network.
+    /// Compressed encodings use the bit rate the listener asked for, as capped by the room.
+    Http(Encoding, Option<u32>),
+    /// Raw samples over a WebSocket, kept as close to live as possible,
+    /// see [ConnectionHandle::into_frames]
+    WebSocket,
+}
+
+impl Transport {
+    /// How far behind live a WebSocket connection starts, just enough to not run dry immediately
+    pub const WEBSOCKET_PRELOAD: Duration = Duration::from_millis(100);
+
+    /// How many chunks may wait for a slow WebSocket client before new ones are dropped
+    pub const WEBSOCKET_BACKLOG: usize = 8;
+}
+
 /// A handle to a connection containing its stream.
 /// When this is dropped, it will notify the connection manager and remove the connection.
+///
+/// Every connection reads from its own bounded buffer, so a client that reads slower than
+/// real-time never holds up playback or other listeners. Once the buffer overflows, the client
+/// skips ahead to as far behind live as it started, with its preload or sync latency, and the
+/// audio in between is lost, since keeping a radio in sync matters more than hearing every sample.
+/// Clients are not disconnected for being slow.
 #[derive(Debug)]
 pub struct ConnectionHandle {
     pub id: ConnectionHandleId,
-    stream: Arc<Mutex<WaveStream>>,
+    /// Set if the room is in sync mode
+    pub sync: Option<SyncReference>,
+    stream: Arc<Mutex<EncodedStream>>,
     store: Weak<Store>,
     rt: runtime::Handle,
     fut: Mutex<Option<task::JoinHandle<Vec<u8>>>>,
+    /// Ends the stream when set, see [Connection::close]
+    closed: Arc<AtomicCell<bool>>,
+}
+
+/// Describes how a client should align a synchronized stream.
+///
+/// The first sample of the stream was live on the server at `timestamp`,
+/// and every listener is expected to play it exactly `latency` later.
+/// Clients should estimate the offset between their clock and the server clock,
+/// then play sample `n` at `timestamp + latency + n / sample rate`,
+/// skipping samples or padding with silence to stay aligned.
+#[derive(Debug, Clone, Copy)]
+pub struct SyncReference {
+    pub timestamp: SystemTime,
+    pub latency: Duration,
 }
 
 /// A connection to a room.
@@ -36,18 +202,157 @@ pub struct Connection {
     pub room: RoomId,
     /// The user this connection was made by
     pub user: User,
+    closed: Arc<AtomicCell<bool>>,
 }
 
 impl ConnectionHandle {
-    pub fn new(store: Weak<Store>, stream: WaveStream) -> Self {
+    pub fn new(store: Weak<Store>, stream: EncodedStream, sync: Option<SyncReference>) -> Self {
         Self {
             id: ID_COUNTER.fetch_add(1),
+            sync,
             rt: runtime::Handle::current(),
             stream: Arc::new(stream.into()),
             fut: None.into(),
+            closed: Default::default(),
             store,
         }
     }
+
+    /// Reads the stream in chunks of `VINYL_STREAM_CHUNK_FRAMES` frames as soon as they are live,
+    /// sending them to the returned receiver without ever waiting for it.
+    /// The connections of a room are all read by the same thread, see [Fanout].
+    ///
+    /// If `capacity` chunks are already waiting, new ones are dropped, so a slow client
+    /// skips audio instead of falling further and further behind.
+    /// The connection ends when the receiver is dropped or the connection is closed.
+    pub fn into_frames(self, capacity: usize) -> mpsc::Receiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let store = self.store.upgrade().expect("store is never none");
+
+        let room = store
+            .room_store
+            .connections
+            .get(&self.id)
+            .map(|c| c.room.clone());
+
+        // Already closed, so dropping the sender ends it right away
+        if let Some(room) = room {
+            store.room_store.fanout(&room).subscribe(Subscriber {
+                handle: self,
+                sender,
+                dropped: 0,
+            });
+        }
+
+        receiver
+    }
+}
+
+/// The connections of a room sent in frames, see [ConnectionHandle::into_frames].
+///
+/// One thread takes turns reading a chunk for each of them while there are any,
+/// rather than every connection blocking a thread of its own.
+#[derive(Debug, Default)]
+pub struct Fanout {
+    state: Mutex<FanoutState>,
+}
+
+#[derive(Debug, Default)]
+struct FanoutState {
+    subscribers: Vec<Subscriber>,
+    /// True while the thread reading for the subscribers runs
+    running: bool,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    handle: ConnectionHandle,
+    sender: mpsc::Sender<Vec<u8>>,
+    /// How many chunks the client was too slow to take
+    dropped: usize,
+}
+
+impl Fanout {
+    fn subscribe(self: &Arc<Self>, subscriber: Subscriber) {
+        let mut state = self.state.lock();
+        state.subscribers.push(subscriber);
+
+        if !state.running {
+            state.running = true;
+
+            let fanout = self.clone();
+
+            thread::Builder::new()
+                .name("room_fanout".to_string())
+                .spawn(move || fanout.run())
+                .unwrap();
+        }
+    }
+
+    /// Sends a chunk to every subscriber in turn, until none are left
+    fn run(&self) {
+        loop {
+            let subscribers = {
+                let mut state = self.state.lock();
+
+                if state.subscribers.is_empty() {
+                    state.running = false;
+                    return;
+                }
+
+                mem::take(&mut state.subscribers)
+            };
+
+            let kept: Vec<_> = subscribers
+                .into_iter()
+                .filter_map(|mut s| s.send_chunk().then_some(s))
+                .collect();
+
+            // Subscribers that joined in the meantime take their turn after the others
+            let mut state = self.state.lock();
+            let joined = mem::replace(&mut state.subscribers, kept);
+
+            state.subscribers.extend(joined);
+        }
+    }
+}
+
+impl Subscriber {
+    /// Reads a chunk and sends it, returning false once the connection ended
+    fn send_chunk(&mut self) -> bool {
+        if self.handle.closed.load() {
+            return false;
+        }
+
+        let mut buf = vec![0; OUTPUT_CONFIG.chunk_bytes()];
+        let bytes_read = self.handle.stream.lock().read(&mut buf).unwrap_or_default();
+
+        // Nothing is read once the stream was closed
+        if bytes_read == 0 {
+            return false;
+        }
+
+        buf.truncate(bytes_read);
+
+        match self.sender.try_send(buf) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            trace!(target: "vinyl::server",
+                "Dropped {} chunks for slow connection {}", self.dropped, self.handle.id
+            );
+        }
+    }
 }
 
 impl Drop for ConnectionHandle {
@@ -64,16 +369,32 @@ impl Stream for ConnectionHandle {
     type Item = Result<Vec<u8>, Infallible>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.closed.load() {
+            return Poll::Ready(None);
+        }
+
         let mut fut_guard = self.fut.lock();
 
         let fut = fut_guard.get_or_insert_with(|| {
             let stream = Arc::clone(&self.stream);
 
             self.rt.spawn_blocking(move || {
-                let mut buf = vec![0; 2048];
-                stream.lock().read(&mut buf).ok();
+                let config = *OUTPUT_CONFIG;
+                let started = Instant::now();
+
+                let mut stream = stream.lock();
+                let mut result = vec![];
+
+                loop {
+                    let mut buf = vec![0; config.chunk_bytes()];
+                    let bytes_read = stream.read(&mut buf).unwrap_or_default();
 
-                buf
+                    result.extend_from_slice(&buf[..bytes_read]);
+
+                    if bytes_read == 0 || started.elapsed() >= config.flush_interval {
+                        break result;
+                    }
+                }
             })
         });
 
@@ -88,7 +409,84 @@ impl Stream for ConnectionHandle {
 }
 
 impl Connection {
-    pub fn new(handle: ConnectionHandleId, room: RoomId, user: User) -> Self {
-        Self { handle, room, user }
+    pub fn new(handle: &ConnectionHandle, room: RoomId, user: User) -> Self {
+        Self {
+            handle: handle.id,
+            room,
+            user,
+            closed: handle.closed.clone(),
+        }
+    }
+
+    /// Ends the stream after the chunk being sent, so the client sees a clean end
+    /// instead of an error. The connection is removed once the handle is dropped.
+    pub fn close(&self) {
+        self.closed.store(true);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use tokio::runtime;
+
+    use super::Transport;
+    use crate::{auth::User, events::Channel, store::Store, EventBus};
+
+    #[test]
+    fn fans_out_frames_on_one_thread() {
+        let runtime = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let event_bus = EventBus::new(Channel::new());
+            let store = Store::new(event_bus.emitter());
+            let room = store.room_store.mock_room(User::mock("owner"));
+
+            let player = store.room_store.players.get(&room).unwrap().upgrade(&store);
+
+            let mut receivers: Vec<_> = ["john", "mary"]
+                .into_iter()
+                .map(|name| {
+                    store
+                        .room_store
+                        .connect(User::mock(name), &room, Transport::WebSocket)
+                        .unwrap()
+                        .into_frames(Transport::WEBSOCKET_BACKLOG)
+                })
+                .collect();
+
+            for _ in 0..5 {
+                player.process();
+            }
+
+            for receiver in receivers.iter_mut() {
+                assert!(receiver.recv().await.is_some());
+            }
+
+            let fanout = store.room_store.fanout(&room);
+            assert!(fanout.state.lock().running);
+
+            // The thread stops once every connection is gone
+            drop(receivers);
+
+            let started = Instant::now();
+
+            while fanout.state.lock().running {
+                assert!(started.elapsed() < Duration::from_secs(5));
+
+                player.process();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            assert_eq!(store.room_store.connection_count(), 0);
+        });
     }
 }
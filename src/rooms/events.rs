@@ -1,4 +1,4 @@
-use super::RoomId;
+use super::{NowPlayingOverride, RoomId};
 use crate::{
     auth::{User, UserId},
     events::{Filter, IntoEvent},
@@ -11,6 +11,27 @@ pub enum RoomEvent {
     UserEnteredRoom { user: User, room: RoomId },
     /// A user disconnected from the room stream
     UserLeftRoom { user: UserId, room: RoomId },
+    /// Playback started after waiting for enough listeners
+    PlaybackStarted { room: RoomId },
+    /// A stream connection to the room was opened or closed
+    ListenersChanged { room: RoomId, count: usize },
+    /// The owner gave the room a new name
+    Renamed { room: RoomId, name: String },
+    /// The room was deleted by its owner
+    Deleted { room: RoomId },
+    /// The volume of the room changed
+    VolumeChanged { room: RoomId, volume: f32 },
+    /// Someone voted to skip the current item, which is skipped once `votes` reaches `required`
+    SkipVoted {
+        room: RoomId,
+        votes: usize,
+        required: usize,
+    },
+    /// What is shown as playing was overridden, or the override was cleared
+    NowPlayingChanged {
+        room: RoomId,
+        label: Option<NowPlayingOverride>,
+    },
 }
 
 impl IntoEvent<VinylEvent> for RoomEvent {
@@ -0,0 +1,80 @@
+use dashmap::DashMap;
+
+use super::RoomId;
+
+/// How many stream connections are open in each room
+#[derive(Debug, Default)]
+pub struct ListenerCounts {
+    counts: DashMap<RoomId, usize>,
+}
+
+impl ListenerCounts {
+    /// Counts a new connection, returning the new count
+    pub fn connect(&self, room: &RoomId) -> usize {
+        let mut count = self.counts.entry(room.clone()).or_default();
+        *count += 1;
+
+        *count
+    }
+
+    /// Counts a closed connection, returning the new count,
+    /// or [None] if the room has no connections or was removed
+    pub fn disconnect(&self, room: &RoomId) -> Option<usize> {
+        let mut count = self.counts.get_mut(room)?;
+        *count = count.saturating_sub(1);
+
+        let result = *count;
+        drop(count);
+
+        if result == 0 {
+            self.counts.remove_if(room, |_, c| *c == 0);
+        }
+
+        Some(result)
+    }
+
+    pub fn get(&self, room: &RoomId) -> usize {
+        self.counts.get(room).map(|c| *c).unwrap_or_default()
+    }
+
+    /// Forgets a room, so connections closing after it was removed are not counted
+    pub fn remove(&self, room: &RoomId) {
+        self.counts.remove(room);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ListenerCounts;
+    use crate::auth::User;
+
+    #[test]
+    fn counts_connections() {
+        let counts = ListenerCounts::default();
+        let room = User::mock("room").id;
+
+        assert_eq!(counts.connect(&room), 1);
+        assert_eq!(counts.connect(&room), 2);
+        assert_eq!(counts.disconnect(&room), Some(1));
+        assert_eq!(counts.get(&room), 1);
+
+        assert_eq!(counts.disconnect(&room), Some(0));
+        assert_eq!(counts.get(&room), 0);
+
+        // Never goes below zero
+        assert_eq!(counts.disconnect(&room), None);
+        assert_eq!(counts.get(&room), 0);
+    }
+
+    #[test]
+    fn ignores_removed_rooms() {
+        let counts = ListenerCounts::default();
+        let room = User::mock("room").id;
+
+        counts.connect(&room);
+        counts.remove(&room);
+
+        assert_eq!(counts.disconnect(&room), None);
+        assert_eq!(counts.get(&room), 0);
+    }
+}
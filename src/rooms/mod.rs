@@ -1,10 +1,21 @@
 mod connection;
 mod events;
+mod listeners;
+mod priority;
 mod room;
 mod router;
+mod saves;
+mod snapshot;
 mod store;
+mod votes;
 
+pub use connection::{init_connection_policy, init_output_config, Transport};
 pub use events::*;
+pub use listeners::*;
+pub use priority::*;
 pub use room::*;
 pub use router::router;
+pub use saves::*;
+pub use snapshot::*;
 pub use store::*;
+pub use votes::*;
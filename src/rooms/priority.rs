@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::{
+    auth::{User, UserId},
+    util::unix_millis,
+};
+
+use super::RoomId;
+
+/// Lets a user's next adds play before the rest of the queue, for a while or a number of tracks
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PriorityGrant {
+    pub user: User,
+
+    /// When the grant stops applying, in milliseconds since the unix epoch
+    pub expires_at: Option<u64>,
+
+    /// How many more tracks can be added with priority
+    pub remaining_tracks: Option<u32>,
+}
+
+impl PriorityGrant {
+    pub fn new(user: User, duration: Option<Duration>, tracks: Option<u32>) -> Self {
+        Self {
+            user,
+            expires_at: duration.map(|d| unix_millis() + d.as_millis() as u64),
+            remaining_tracks: tracks,
+        }
+    }
+
+    fn is_active(&self, now: u64) -> bool {
+        let expired = self.expires_at.is_some_and(|e| e <= now);
+        let used_up = self.remaining_tracks == Some(0);
+
+        !expired && !used_up
+    }
+}
+
+/// Priority grants given out by room owners
+#[derive(Debug, Default)]
+pub struct PriorityGrants {
+    grants: DashMap<RoomId, Vec<PriorityGrant>>,
+}
+
+impl PriorityGrants {
+    /// Gives a user priority, replacing any grant they already have
+    pub fn grant(&self, room: &RoomId, grant: PriorityGrant) {
+        let mut grants = self.grants.entry(room.clone()).or_default();
+
+        grants.retain(|g| g.user.id != grant.user.id);
+        grants.push(grant);
+    }
+
+    /// Removes the grant of a user, returning false if they did not have one
+    pub fn revoke(&self, room: &RoomId, user: &UserId) -> bool {
+        let Some(mut grants) = self.grants.get_mut(room) else {
+            return false;
+        };
+
+        let before = grants.len();
+        grants.retain(|g| g.user.id != *user);
+
+        grants.len() != before
+    }
+
+    /// Removes every grant in a room
+    pub fn clear(&self, room: &RoomId) {
+        self.grants.remove(room);
+    }
+
+    /// Returns the grants that still apply in a room
+    pub fn active(&self, room: &RoomId) -> Vec<PriorityGrant> {
+        self.prune(room);
+
+        self.grants.get(room).map(|g| g.clone()).unwrap_or_default()
+    }
+
+    /// Uses up one track of a user's grant, returning true if they had priority
+    pub fn take(&self, room: &RoomId, user: &UserId) -> bool {
+        self.prune(room);
+
+        let Some(mut grants) = self.grants.get_mut(room) else {
+            return false;
+        };
+
+        let Some(grant) = grants.iter_mut().find(|g| g.user.id == *user) else {
+            return false;
+        };
+
+        if let Some(remaining) = grant.remaining_tracks.as_mut() {
+            *remaining -= 1;
+        }
+
+        true
+    }
+
+    fn prune(&self, room: &RoomId) {
+        let now = unix_millis();
+
+        if let Some(mut grants) = self.grants.get_mut(room) {
+            grants.retain(|g| g.is_active(now));
+        }
+    }
+}
@@ -1,28 +1,203 @@
 use crate::{
-    auth::User,
+    audio::{Input, OpusStream},
+    auth::{verify_password, User, UserId},
     db::{Database, Record},
+    ingest::AudioFormat,
     queue::QueueItem,
-    util::ApiError,
+    util::{unix_millis, ApiError},
 };
 
+use super::SnapshotItem;
+
 use serde::{Deserialize, Serialize};
 use surrealdb::sql::Thing;
 
 pub type RoomId = Thing;
 
+/// The queue of a room as it was last saved, see [RoomData::update_queue]
+#[derive(Debug, Deserialize)]
+pub struct SavedQueue {
+    pub id: RoomId,
+
+    /// The current item first, so playback resumes with it
+    #[serde(default)]
+    pub queue: Vec<SnapshotItem>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct RoomData {
     pub id: RoomId,
     pub name: String,
     pub owner: User,
+
+    /// URL of an external stream this room relays instead of playing a queue
+    #[serde(default)]
+    pub relay: Option<String>,
+
+    #[serde(default)]
+    pub settings: RoomSettings,
+
+    /// When playback should start, in milliseconds since the unix epoch
+    #[serde(default)]
+    pub scheduled_start: Option<u64>,
+
+    /// Hash of the password others need to join the room with, if it is private
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// Users the owner lets add to the queue, see [RoomRole]
+    #[serde(default)]
+    pub members: Vec<UserId>,
+
+    /// Milliseconds since the unix epoch, or 0 if the room was created before this was stored
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+/// What a user can do in a room, ordered from least to most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomRole {
+    /// Can listen, unless the stream is for members only
+    Listener,
+    /// Can also add to the queue and control playback
+    Member,
+    /// Can also change, clear, and delete the room
+    Owner,
+}
+
+/// Settings the owner of a room can change
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RoomSettings {
+    /// How many listeners need to be connected before playback starts
+    pub start_when_listeners: usize,
+
+    /// Delays every listener to this latency in milliseconds,
+    /// so playback can be aligned across devices. 0 means disabled.
+    pub sync_latency: u32,
+
+    /// Sources users can queue from, all of them if this is not set
+    pub allowed_sources: Option<Vec<String>>,
+
+    /// Items waiting to be played are removed after this many seconds. 0 means disabled.
+    pub max_item_age: u64,
+
+    /// Keeps loudness steady within long tracks, such as DJ mixes
+    pub dynamic_normalization: bool,
+
+    /// Lets anyone listen to the stream without a session, such as from an embedded player.
+    /// Everything else in the room still requires one. This has no effect in private rooms.
+    pub public_stream: bool,
+
+    /// What the room is scaled by, between 0 and 1
+    pub volume: f32,
+
+    /// Plays every track at the same loudness, measured when it is ingested
+    pub loudness_normalization: bool,
+
+    /// Whether skipping happens right away, or once enough listeners voted for it
+    pub skip_mode: SkipMode,
+
+    /// The fraction of listeners that need to vote to skip, between 0 and 1
+    pub skip_vote_fraction: f32,
+
+    /// How long the end of a track is mixed with the start of the next in milliseconds.
+    /// The last track fades to silence. 0 means disabled.
+    pub crossfade: u32,
+
+    /// Only lets members and the owner listen, see [RoomRole]
+    pub members_only_stream: bool,
+
+    /// Which audio yt-dlp picks for tracks queued here, such as a lower bitrate to save bandwidth.
+    /// The one set with `VINYL_YTDLP_FORMAT` is used if this is not set.
+    pub audio_format: Option<AudioFormat>,
+
+    /// Caps the bit rate of compressed streams in kbps, whatever listeners ask for.
+    /// Listeners get the one they ask for or [OpusStream::DEFAULT_BIT_RATE] if this is not set.
+    pub max_bit_rate: Option<u32>,
+}
+
+/// Describes what happens when someone skips the current item
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SkipMode {
+    /// The item is skipped right away
+    #[default]
+    Instant,
+    /// Skipping counts as a vote, and the item is skipped once enough listeners voted
+    Vote,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            start_when_listeners: 0,
+            sync_latency: 0,
+            allowed_sources: None,
+            max_item_age: 0,
+            dynamic_normalization: false,
+            public_stream: false,
+            volume: 1.,
+            loudness_normalization: true,
+            skip_mode: SkipMode::Instant,
+            skip_vote_fraction: 0.5,
+            crossfade: 0,
+            members_only_stream: false,
+            audio_format: None,
+            max_bit_rate: None,
+        }
+    }
+}
+
+impl RoomSettings {
+    pub fn audio_format(&self) -> AudioFormat {
+        self.audio_format
+            .clone()
+            .unwrap_or_else(AudioFormat::configured)
+    }
+
+    /// The bit rate a compressed stream is encoded at, given what the listener asked for
+    pub fn bit_rate(&self, requested: Option<u32>) -> u32 {
+        let requested = requested.unwrap_or(OpusStream::DEFAULT_BIT_RATE);
+
+        match self.max_bit_rate {
+            Some(max) => requested.min(max),
+            None => requested,
+        }
+    }
+
+    pub fn allowed_sources(&self) -> Vec<String> {
+        self.allowed_sources.clone().unwrap_or_else(|| {
+            Input::SOURCES
+                .iter()
+                .map(|source| source.to_string())
+                .collect()
+        })
+    }
+
+    pub fn allows_source(&self, source: &str) -> bool {
+        self.allowed_sources().iter().any(|s| s == source)
+    }
 }
 
 impl RoomData {
-    pub async fn create(db: &Database, user: &User, name: String) -> Result<Self, ApiError> {
+    pub async fn create(
+        db: &Database,
+        user: &User,
+        name: String,
+        relay: Option<String>,
+        password: Option<String>,
+    ) -> Result<Self, ApiError> {
         #[derive(Serialize)]
         struct NewRoom {
             name: String,
             owner: Thing,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            relay: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            password: Option<String>,
+            created_at: u64,
         }
 
         let raw: Record = db
@@ -30,6 +205,9 @@ impl RoomData {
             .content(NewRoom {
                 owner: user.id.clone(),
                 name,
+                relay,
+                password,
+                created_at: unix_millis(),
             })
             .await
             .map_err(ApiError::from_db)?;
@@ -55,6 +233,148 @@ impl RoomData {
             .take::<Option<RoomData>>(0)?
             .ok_or(ApiError::NotFound("Room"))
     }
+
+    pub async fn update_settings(
+        db: &Database,
+        id: &RoomId,
+        settings: &RoomSettings,
+    ) -> Result<(), ApiError> {
+        db.query("UPDATE type::thing($tb, $id) SET settings = $settings")
+            .bind(("tb", "room"))
+            .bind(("id", id.id.to_string()))
+            .bind(("settings", settings))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    pub async fn update_name(db: &Database, id: &RoomId, name: &str) -> Result<(), ApiError> {
+        db.query("UPDATE type::thing($tb, $id) SET name = $name")
+            .bind(("tb", "room"))
+            .bind(("id", id.id.to_string()))
+            .bind(("name", name))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    pub async fn update_members(
+        db: &Database,
+        id: &RoomId,
+        members: &[UserId],
+    ) -> Result<(), ApiError> {
+        db.query("UPDATE type::thing($tb, $id) SET members = $members")
+            .bind(("tb", "room"))
+            .bind(("id", id.id.to_string()))
+            .bind(("members", members))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    pub async fn update_schedule(
+        db: &Database,
+        id: &RoomId,
+        scheduled_start: Option<u64>,
+    ) -> Result<(), ApiError> {
+        db.query("UPDATE type::thing($tb, $id) SET scheduled_start = $scheduled_start")
+            .bind(("tb", "room"))
+            .bind(("id", id.id.to_string()))
+            .bind(("scheduled_start", scheduled_start))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    pub async fn update_queue(
+        db: &Database,
+        id: &RoomId,
+        queue: &[SnapshotItem],
+    ) -> Result<(), ApiError> {
+        // Saves happen in the background, so this must not recreate a room deleted meanwhile
+        db.query("UPDATE type::thing($tb, $id) SET queue = $queue WHERE owner != NONE")
+            .bind(("tb", "room"))
+            .bind(("id", id.id.to_string()))
+            .bind(("queue", queue))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    /// Returns the queue of every room as it was last saved
+    pub async fn saved_queues(db: &Database) -> Result<Vec<SavedQueue>, ApiError> {
+        let queues = db
+            .query("SELECT id, queue FROM room")
+            .await?
+            .take::<Vec<SavedQueue>>(0)?;
+
+        Ok(queues)
+    }
+
+    pub async fn delete(db: &Database, id: &RoomId) -> Result<(), ApiError> {
+        db.query("DELETE type::thing($tb, $id)")
+            .bind(("tb", "room"))
+            .bind(("id", id.id.to_string()))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    /// Returns true if playback is scheduled to start later
+    pub fn is_scheduled(&self) -> bool {
+        let now = unix_millis();
+
+        self.scheduled_start.filter(|&start| start > now).is_some()
+    }
+
+    /// Returns true if the name contains the search query, ignoring case.
+    /// Every room matches an empty query.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        query.is_empty() || self.name.to_lowercase().contains(&query)
+    }
+
+    pub fn role(&self, user: &User) -> RoomRole {
+        if self.owner.id == user.id {
+            RoomRole::Owner
+        } else if self.members.contains(&user.id) {
+            RoomRole::Member
+        } else {
+            RoomRole::Listener
+        }
+    }
+
+    /// Returns true if the user has at least `role`. Superusers can do anything.
+    pub fn allows(&self, user: &User, role: RoomRole) -> bool {
+        user.superuser || self.role(user) >= role
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.password.is_some()
+    }
+
+    /// Returns true if the password lets someone into the room
+    pub fn check_password(&self, incoming: &str) -> bool {
+        self.password
+            .as_ref()
+            .is_none_or(|hash| verify_password(hash, incoming))
+    }
+
+    /// Returns true if a user can listen to the room and see what is in it.
+    /// Everyone can in public rooms, and in private ones the owner, superusers,
+    /// and sessions that joined with the password can.
+    pub fn admits(&self, user: &User, joined_rooms: &[RoomId]) -> bool {
+        !self.is_private()
+            || self.owner.id == user.id
+            || user.superuser
+            || joined_rooms.contains(&self.id)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -72,6 +392,36 @@ impl Room {
     }
 }
 
+/// Shown as playing instead of the current track, such as during a live announcement.
+/// This does not change what is heard.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NowPlayingOverride {
+    pub title: String,
+    pub artist: Option<String>,
+    pub set_by: User,
+    /// When the override was set, in milliseconds since the unix epoch
+    pub set_at: u64,
+}
+
+/// What clients should show as playing in a room
+#[derive(Debug, Serialize)]
+pub struct NowPlaying {
+    pub item: Option<QueueItem>,
+    /// Takes precedence over the item when set
+    #[serde(rename = "override")]
+    pub label: Option<NowPlayingOverride>,
+}
+
+/// The item playing in a room, and how far into it playback is
+#[derive(Debug, Serialize)]
+pub struct CurrentTrack {
+    pub item: QueueItem,
+    /// Milliseconds played of the item, as produced by the player.
+    /// Listeners hear it slightly later, depending on how much their stream is buffered.
+    pub position: u64,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SerializedRoom {
@@ -79,5 +429,141 @@ pub struct SerializedRoom {
     pub name: String,
     pub owner: User,
     pub connections: Vec<User>,
+    /// How many streams are open, which can be more than users
+    pub listener_count: usize,
     pub current_queue_item: Option<QueueItem>,
+    pub relay: Option<String>,
+    pub allowed_sources: Vec<String>,
+    pub settings: RoomSettings,
+    pub scheduled_start: Option<u64>,
+    pub now_playing_override: Option<NowPlayingOverride>,
+    pub paused: bool,
+    /// Private rooms have to be joined with a password, see [RoomData::admits]
+    pub private: bool,
+    pub members: Vec<UserId>,
+    pub created_at: u64,
+}
+
+impl SerializedRoom {
+    /// Leaves out what is playing and who is listening, for those not let into a private room
+    pub fn redacted(self) -> Self {
+        Self {
+            connections: vec![],
+            members: vec![],
+            current_queue_item: None,
+            now_playing_override: None,
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auth::{hash_password, User};
+
+    use super::{RoomData, RoomRole, RoomSettings};
+
+    fn room(password: Option<&str>) -> RoomData {
+        RoomData {
+            id: User::mock("room").id,
+            name: "Room".to_string(),
+            owner: User::mock("owner"),
+            relay: None,
+            settings: RoomSettings::default(),
+            scheduled_start: None,
+            password: password.map(|p| hash_password(p).unwrap()),
+            members: vec![User::mock("member").id],
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn checks_passwords() {
+        let private = room(Some("hunter2"));
+
+        assert!(private.is_private());
+        assert!(private.check_password("hunter2"));
+        assert!(!private.check_password("hunter3"));
+        assert!(!private.check_password(""));
+
+        // The password is never stored as it is
+        assert_ne!(private.password.as_deref(), Some("hunter2"));
+    }
+
+    #[test]
+    fn admits_joined_sessions() {
+        let private = room(Some("hunter2"));
+        let public = room(None);
+
+        let owner = User::mock("owner");
+        let stranger = User::mock("stranger");
+        let superuser = User {
+            superuser: true,
+            ..User::mock("admin")
+        };
+
+        assert!(public.admits(&stranger, &[]));
+
+        assert!(private.admits(&owner, &[]));
+        assert!(private.admits(&superuser, &[]));
+        assert!(!private.admits(&stranger, &[]));
+        assert!(!private.admits(&stranger, &[User::mock("other room").id]));
+        assert!(private.admits(&stranger, std::slice::from_ref(&private.id)));
+    }
+
+    #[test]
+    fn roles() {
+        let room = room(None);
+        let superuser = User {
+            superuser: true,
+            ..User::mock("admin")
+        };
+
+        assert_eq!(room.role(&User::mock("owner")), RoomRole::Owner);
+        assert_eq!(room.role(&User::mock("member")), RoomRole::Member);
+        assert_eq!(room.role(&User::mock("stranger")), RoomRole::Listener);
+        assert_eq!(room.role(&superuser), RoomRole::Listener);
+
+        assert!(room.allows(&User::mock("member"), RoomRole::Member));
+        assert!(!room.allows(&User::mock("member"), RoomRole::Owner));
+        assert!(!room.allows(&User::mock("stranger"), RoomRole::Member));
+        assert!(room.allows(&User::mock("stranger"), RoomRole::Listener));
+        assert!(room.allows(&superuser, RoomRole::Owner));
+    }
+
+    #[test]
+    fn matches_names() {
+        let named = |name: &str| RoomData {
+            name: name.to_string(),
+            ..room(None)
+        };
+
+        let rooms = [named("Chill Beats"), named("Techno"), named("chillstep")];
+        let matching = |query: &str| -> Vec<_> {
+            rooms
+                .iter()
+                .filter(|r| r.matches(query))
+                .map(|r| r.name.as_str())
+                .collect()
+        };
+
+        assert_eq!(matching("chill"), vec!["Chill Beats", "chillstep"]);
+        assert_eq!(matching("  BEATS "), vec!["Chill Beats"]);
+        assert_eq!(matching("ambient"), Vec::<&str>::new());
+        assert_eq!(matching(""), vec!["Chill Beats", "Techno", "chillstep"]);
+    }
+
+    #[test]
+    fn caps_bit_rates() {
+        let mut settings = RoomSettings::default();
+
+        assert_eq!(settings.bit_rate(None), 96);
+        assert_eq!(settings.bit_rate(Some(192)), 192);
+
+        settings.max_bit_rate = Some(64);
+
+        assert_eq!(settings.bit_rate(None), 64);
+        assert_eq!(settings.bit_rate(Some(192)), 64);
+        assert_eq!(settings.bit_rate(Some(32)), 32);
+    }
 }
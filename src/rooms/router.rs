@@ -1,33 +1,74 @@
 use axum::{
     debug_handler,
-    extract::{Path, State},
-    response::Response,
-    routing::{get, post},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put},
     Json,
 };
-use hyper::StatusCode;
-use log::trace;
-use serde::Deserialize;
+use hyper::{header::ACCEPT, HeaderMap, StatusCode};
+use log::{info, trace};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
 use tokio::task::spawn_blocking;
 
 use crate::{
-    audio::WaveStream,
-    auth::Session,
-    ingest::Input,
-    queue::SerializedQueue,
+    aliases::Alias,
+    audio::{output_format, Encoding, OpusStream},
+    auth::{hash_password, Session, StreamSession, User},
+    ingest::{ytdlp_jobs_full, AudioFormat, IngestionFailure, Input, InputId},
+    queue::{Eta, PlayedItem, QueueItemId, RepeatMode, Replay, SerializedQueue},
     server::{Context, Router},
-    util::ApiError,
+    util::{unix_millis, unix_millis_at, ApiError},
     VinylContext,
 };
 
-use super::SerializedRoom;
+use super::{
+    connection::ConnectionHandle, NowPlaying, NowPlayingOverride, PriorityGrant, RoomData, RoomId,
+    RoomRole, SerializedRoom, SkipMode, Transport,
+};
 
 pub fn router() -> Router {
     Router::new()
         .route("/:id/stream", get(get_room_stream))
+        .route("/:id/stream/info", get(get_room_stream_info))
+        .route("/:id/stream/report", post(report_stream_error))
+        .route("/:id/ws-audio", get(get_room_ws_audio))
+        .route("/:id/sync", get(get_room_sync))
         .route("/:id/queue", post(add_input))
         .route("/:id/queue", get(get_room_queue))
+        .route("/:id/history", get(get_room_history))
+        .route("/:id/history/replay", post(replay_history))
+        .route("/:id/queue/failures", get(get_queue_failures))
+        .route("/:id/queue/skip", post(skip_current_item))
+        .route("/:id/queue/repeat", put(update_queue_repeat))
+        .route("/:id/queue/shuffle", post(shuffle_queue))
+        .route("/:id/queue/clear", post(clear_queue))
+        .route("/:id/queue/:item_id/eta", get(get_queue_item_eta))
+        .route("/:id/queue/:item_id", delete(remove_queue_item))
+        .route("/:id/queue/:item_id", patch(move_queue_item))
+        .route("/:id/queue/failures", delete(clear_queue_failures))
+        .route("/:id/settings", patch(update_room_settings))
+        .route("/:id/schedule", put(update_room_schedule))
+        .route("/:id/playback/pause", post(pause_playback))
+        .route("/:id/playback/resume", post(resume_playback))
+        .route("/:id/playback/seek", post(seek_playback))
+        .route("/:id/playback/volume", put(update_room_volume))
+        .route("/:id/now-playing", get(get_now_playing))
+        .route("/:id/current", get(get_current_track))
+        .route("/:id/now-playing", put(override_now_playing))
+        .route("/:id/now-playing", delete(clear_now_playing_override))
+        .route("/:id/priority", get(get_priority_grants))
+        .route("/:id/priority", put(grant_priority))
+        .route("/:id/priority/:username", delete(revoke_priority))
+        .route("/:id/members/:username", put(add_member))
+        .route("/:id/members/:username", delete(remove_member))
+        .route("/:id/join", post(join_room))
         .route("/:id", get(get_room))
+        .route("/:id", patch(rename_room))
+        .route("/:id", delete(delete_room))
         .route("/", post(create_room))
         .route("/", get(get_rooms))
 }
@@ -35,6 +76,9 @@ pub fn router() -> Router {
 #[derive(Deserialize)]
 struct CreateRoomBody {
     name: String,
+    relay: Option<String>,
+    /// Makes the room private, so others have to join it with this password
+    password: Option<String>,
 }
 
 #[debug_handler(state = VinylContext)]
@@ -43,30 +87,167 @@ async fn create_room(
     State(context): Context,
     Json(body): Json<CreateRoomBody>,
 ) -> Result<(StatusCode, Json<SerializedRoom>), ApiError> {
+    let is_valid_relay = body
+        .relay
+        .as_ref()
+        .map(|url| url.starts_with("http://") || url.starts_with("https://"))
+        .unwrap_or(true);
+
+    if !is_valid_relay {
+        return Err(ApiError::Invalid("Relay URL"));
+    }
+
+    let name = room_name(&body.name)?;
+
+    let password = match body.password {
+        Some(password) if password.is_empty() => return Err(ApiError::Invalid("Room password")),
+        Some(password) => Some(hash_password(&password)?),
+        None => None,
+    };
+
     let room = context
         .store
         .room_store
-        .create_room(&context.db, &session.user, body.name)
+        .create_room(&context.db, &session.user, name, body.relay, password)
         .await?;
 
     Ok((StatusCode::CREATED, Json(room)))
 }
 
-async fn get_rooms(_: Session, State(context): Context) -> Json<Vec<SerializedRoom>> {
-    let rooms: Vec<_> = context.store.room_store.rooms();
+const MAX_ROOM_NAME_LENGTH: usize = 100;
+
+/// Returns the name trimmed, if it is valid for a room
+fn room_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+
+    if name.is_empty() || name.chars().count() > MAX_ROOM_NAME_LENGTH {
+        return Err(ApiError::Invalid("Room name"));
+    }
+
+    Ok(name.to_string())
+}
+
+#[derive(Deserialize)]
+struct RenameRoomBody {
+    name: String,
+}
+
+async fn rename_room(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<RenameRoomBody>,
+) -> Result<Json<SerializedRoom>, ApiError> {
+    let room = owned_room(&context, &session, &id, "Renaming this room")?;
+    let name = room_name(&body.name)?;
+
+    let room = context
+        .store
+        .room_store
+        .rename(&context.db, &room.id, name)
+        .await?;
+
+    Ok(Json(room))
+}
+
+#[derive(Deserialize)]
+struct RoomsQuery {
+    /// Only returns rooms with this in their name
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// A page of rooms, see [get_rooms]
+#[derive(Serialize)]
+struct RoomsPage {
+    rooms: Vec<SerializedRoom>,
+    /// How many rooms there are across every page
+    total: usize,
+}
+
+const DEFAULT_ROOMS_PAGE_SIZE: usize = 50;
+const MAX_ROOMS_PAGE_SIZE: usize = 200;
 
-    Json(rooms)
+/// Returns a page of rooms, oldest first, optionally searching by name.
+/// Only whether a room is private is shown to those who have not joined it.
+async fn get_rooms(
+    session: Session,
+    State(context): Context,
+    Query(query): Query<RoomsQuery>,
+) -> Result<Json<RoomsPage>, ApiError> {
+    let limit = page_size(query.limit)?;
+    let (rooms, total) = context
+        .store
+        .room_store
+        .page_for(&session, &query.q, query.offset, limit);
+
+    Ok(Json(RoomsPage { rooms, total }))
+}
+
+fn page_size(limit: Option<usize>) -> Result<usize, ApiError> {
+    match limit {
+        None => Ok(DEFAULT_ROOMS_PAGE_SIZE),
+        Some(limit) if limit == 0 || limit > MAX_ROOMS_PAGE_SIZE => {
+            Err(ApiError::Invalid("Page size"))
+        }
+        Some(limit) => Ok(limit),
+    }
 }
 
 async fn get_room(
-    _: Session,
+    session: Session,
     State(context): Context,
     Path(id): Path<String>,
 ) -> Result<Json<SerializedRoom>, ApiError> {
     let room = context
         .store
         .room_store
-        .rooms()
+        .rooms_for(&session)
+        .into_iter()
+        .find(|r| r.id == id)
+        .ok_or(ApiError::NotFound("Room"))?;
+
+    Ok(Json(room))
+}
+
+#[derive(Deserialize)]
+struct JoinRoomBody {
+    password: String,
+}
+
+/// Lets the session into a private room, so it can listen and queue there until it is logged out.
+/// Joining a room that is not private does nothing.
+async fn join_room(
+    mut session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<JoinRoomBody>,
+) -> Result<Json<SerializedRoom>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    if !room.admits(&session.user, &session.joined_rooms) {
+        context
+            .limits
+            .room_joins
+            .check(session.user.id.clone())
+            .map_err(ApiError::TooManyRequests)?;
+
+        if !room.check_password(&body.password) {
+            return Err(ApiError::Unauthorized);
+        }
+
+        session.join_room(&context.db, &room.id).await?;
+
+        info!(target: "vinyl::server", "{} joined private room {}", session.user.username, room.name);
+    }
+
+    let room = context
+        .store
+        .room_store
+        .rooms_for(&session)
         .into_iter()
         .find(|r| r.id == id)
         .ok_or(ApiError::NotFound("Room"))?;
@@ -74,81 +255,513 @@ async fn get_room(
     Ok(Json(room))
 }
 
+/// Deletes a room, closing every stream of it. Only the owner can do this.
+async fn delete_room(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    context.store.room_store.check_role(
+        &room.id,
+        &session.user,
+        RoomRole::Owner,
+        "Deleting this room",
+    )?;
+
+    context
+        .store
+        .room_store
+        .delete_room(&context.db, &room.id)
+        .await?;
+
+    info!(target: "vinyl::server", "{} deleted room {}", session.user.username, room.name);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct AddInputQuery {
+    /// Plays the input on top of the music instead of queueing it
+    #[serde(default)]
+    voice: bool,
+}
+
+/// Returned as soon as an input is submitted, see [add_input]
+#[derive(Serialize)]
+struct PendingInput {
+    /// Identifies the input in `input.*` events until it is resolved
+    id: InputId,
+}
+
+/// Submits an input to the queue, returning right away while it is resolved in the background.
+/// Clients follow what happens to it through `input.resolving`, `input.ready` and `input.failed` events.
+///
+/// Responds with 503 if too many inputs are already waiting to be resolved.
 async fn add_input(
     session: Session,
     State(context): Context,
     Path(id): Path<String>,
+    Query(add_query): Query<AddInputQuery>,
     query: String,
-) -> Result<String, ApiError> {
-    let room = context
+) -> Result<(StatusCode, Json<PendingInput>), ApiError> {
+    let room = context.store.room_store.find_room(&id)?;
+
+    context.store.room_store.check_access(&room, &session)?;
+    context.store.room_store.check_role(
+        &room,
+        &session.user,
+        RoomRole::Member,
+        "Queueing in this room",
+    )?;
+
+    if context.store.room_store.relays.contains_key(&room) {
+        return Err(ApiError::NotAllowed("Queueing in a relay room"));
+    }
+
+    if let Some(limiter) = &context.limits.adds {
+        limiter
+            .check(session.user.id.clone())
+            .map_err(ApiError::TooManyRequests)?;
+    }
+
+    // Resolving waits for yt-dlp in the background, so it is refused now rather than failing later
+    if ytdlp_jobs_full() {
+        return Err(ApiError::Busy("Track resolving"));
+    }
+
+    let query = Alias::expand(&context.db, query).await?;
+    let id = InputId::new();
+
+    context
         .store
         .room_store
-        .rooms
-        .iter()
-        .find(|r| r.id.id.to_string() == id)
-        .map(|r| r.id.clone())
-        .ok_or(ApiError::NotFound("Room"))?;
+        .report_resolving(&room, id, &session.user, query.clone());
 
-    let input = spawn_blocking(move || Input::parse(&query))
+    tokio::spawn(async move {
+        let room_store = &context.store.room_store;
+
+        match resolve_input(&context, session.user, &room, &query, add_query.voice).await {
+            Ok(added) => room_store.report_ready(&room, id, added),
+            Err(err) => room_store.report_failure(&room, Some(id), query, &err),
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(PendingInput { id })))
+}
+
+/// Parses an input and adds it to the room, returning how many tracks were added
+async fn resolve_input(
+    context: &VinylContext,
+    user: User,
+    room: &RoomId,
+    query: &str,
+    voice: bool,
+) -> Result<usize, ApiError> {
+    let parsed_query = query.to_string();
+    let format = context.store.room_store.audio_format(room);
+    let mut inputs = spawn_blocking(move || Input::parse_many(&parsed_query, &format))
         .await
-        .unwrap()
-        .map_err(|x| ApiError::Other(Box::new(x)))?;
+        .unwrap()?;
+
+    // The room may have been deleted while the input was resolved
+    if !context.store.room_store.rooms.contains_key(room) {
+        return Err(ApiError::NotFound("Room"));
+    }
+
+    if inputs.len() > 1 {
+        if voice {
+            return Err(ApiError::NotAllowed("Playing a playlist as a voice track"));
+        }
+
+        return add_playlist(context.clone(), user, room.clone(), inputs).await;
+    }
+
+    let input = inputs.pop().expect("at least one input is parsed");
+
+    context.store.room_store.check_can_queue(room, &input)?;
+
+    if let Some(cooldown) = &context.limits.duplicate_adds {
+        cooldown
+            .check((user.id.clone(), input.fingerprint()))
+            .map_err(ApiError::TooManyRequests)?;
+    }
 
     let name = input.to_string();
-    let response = format!("Added {} to the queue", name);
+    let context = context.clone();
+    let room = room.clone();
 
-    trace!(target: "vinyl::server", "Added {} to the queue", name);
-    let _ = spawn_blocking(move || {
+    if voice {
+        trace!(target: "vinyl::server", "Playing {} as a voice track", name);
+        let _ =
+            spawn_blocking(move || context.store.room_store.add_voice(user, &room, input)).await;
+
+        return Ok(1);
+    }
+
+    let merge_duplicates = context.limits.duplicate_adds.is_some();
+
+    let added = spawn_blocking(move || {
         context
             .store
             .room_store
-            .add_input(session.user, &room, input)
+            .add_input(user, &room, input, merge_duplicates)
     })
-    .await;
+    .await
+    .unwrap();
 
-    Ok(response)
+    // Someone else added the same track at the same time
+    if !added {
+        return Err(ApiError::Conflict("Track in the queue"));
+    }
+
+    trace!(target: "vinyl::server", "Added {} to the queue", name);
+    Ok(1)
+}
+
+/// Adds the tracks of a playlist in order, skipping the ones that can't be added
+async fn add_playlist(
+    context: VinylContext,
+    user: User,
+    room: RoomId,
+    inputs: Vec<Input>,
+) -> Result<usize, ApiError> {
+    let added = spawn_blocking(move || {
+        let room_store = &context.store.room_store;
+        let merge_duplicates = context.limits.duplicate_adds.is_some();
+
+        inputs
+            .into_iter()
+            .filter(|input| room_store.check_can_queue(&room, input).is_ok())
+            .filter(|input| match &context.limits.duplicate_adds {
+                Some(cooldown) => cooldown
+                    .check((user.id.clone(), input.fingerprint()))
+                    .is_ok(),
+                None => true,
+            })
+            .filter(|input| {
+                room_store.add_input(user.clone(), &room, input.clone(), merge_duplicates)
+            })
+            .count()
+    })
+    .await
+    .unwrap();
+
+    if added == 0 {
+        return Err(ApiError::NotAllowed("Queueing any track of this playlist"));
+    }
+
+    trace!(target: "vinyl::server", "Added {} tracks of a playlist to the queue", added);
+    Ok(added)
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Disposition {
+    /// Lets browsers play the stream directly
+    #[default]
+    Inline,
+    /// Makes browsers download the stream as a file
+    Attachment,
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    #[serde(default)]
+    disposition: Disposition,
+    /// The bit rate in kbps for compressed streams, capped by the room
+    bit_rate: Option<u32>,
+}
+
+impl StreamQuery {
+    fn bit_rate(&self) -> Result<Option<u32>, ApiError> {
+        match self.bit_rate {
+            Some(x) if !OpusStream::BIT_RATES.contains(&x) => Err(ApiError::Invalid("Bit rate")),
+            x => Ok(x),
+        }
+    }
 }
 
 async fn get_room_stream(
-    session: Session,
+    session: Option<StreamSession>,
     State(context): Context,
     Path(id): Path<String>,
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
 ) -> Result<Response<hyper::Body>, ApiError> {
-    let room = context
-        .store
-        .room_store
-        .rooms
-        .iter()
-        .find(|r| r.id.id.to_string() == id)
-        .map(|r| r.id.clone())
-        .ok_or(ApiError::NotFound("Room"))?;
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    let user = listener(session, &room)?;
+    let bit_rate = query.bit_rate()?;
 
-    let connection = context.store.room_store.connect(session.user, &room);
+    // Clients that don't ask for Opus get .wav, like before it was supported
+    let encoding = Encoding::from_accept(headers.get(ACCEPT).and_then(|h| h.to_str().ok()));
+
+    let disposition = match query.disposition {
+        Disposition::Inline => "inline",
+        Disposition::Attachment => "attachment",
+    };
+
+    let content_disposition = format!(
+        "{}; filename=\"{}.{}\"",
+        disposition,
+        sanitize_filename(&room.name),
+        encoding.extension()
+    );
+
+    let connection =
+        context
+            .store
+            .room_store
+            .connect(user, &room.id, Transport::Http(encoding, bit_rate))?;
+    let sync = connection.sync;
     let body = hyper::Body::wrap_stream(connection);
 
-    Ok(Response::builder()
+    let mut response = Response::builder()
         .status(200)
         .header("Transfer-Encoding", "chunked")
-        .header("Content-Type", WaveStream::MIME)
+        .header("Content-Type", encoding.mime())
         .header("Cache-Control", "no-store")
-        .header("Content-Disposition", "inline; filename=\"stream.wav\"")
-        .body(body)
-        .unwrap())
+        .header("Content-Disposition", content_disposition);
+
+    // See SyncReference for how clients should use these
+    if let Some(sync) = sync {
+        response = response
+            .header(
+                "Vinyl-Sync-Timestamp",
+                unix_millis_at(sync.timestamp).to_string(),
+            )
+            .header("Vinyl-Sync-Latency", sync.latency.as_millis().to_string());
+    }
+
+    Ok(response.body(body).unwrap())
 }
 
-async fn get_room_queue(
-    _: Session,
+/// Describes the audio a listener gets from [get_room_stream]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StreamInfo {
+    sample_rate: usize,
+    channels: usize,
+    /// The bit rate the stream is encoded at in kbps, for the one asked for.
+    /// This is [None] for uncompressed streams.
+    bit_rate: Option<u32>,
+    /// The cap set for the room, if any
+    max_bit_rate: Option<u32>,
+}
+
+/// Describes the stream, negotiating the encoding from the `Accept` header like [get_room_stream]
+async fn get_room_stream_info(
+    session: Option<StreamSession>,
     State(context): Context,
     Path(id): Path<String>,
-) -> Result<Json<SerializedQueue>, ApiError> {
-    let room = context
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+) -> Result<Json<StreamInfo>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    listener(session, &room)?;
+
+    let output = output_format();
+    let bit_rate = room.settings.bit_rate(query.bit_rate()?);
+
+    let encoding = Encoding::from_accept(headers.get(ACCEPT).and_then(|h| h.to_str().ok()));
+
+    Ok(Json(StreamInfo {
+        sample_rate: output.sample_rate,
+        channels: output.channels,
+        bit_rate: (encoding == Encoding::Opus).then_some(bit_rate),
+        max_bit_rate: room.settings.max_bit_rate,
+    }))
+}
+
+/// Sent as the first message of a WebSocket stream, before any audio
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WebSocketFormat {
+    sample_rate: usize,
+    channels: usize,
+    /// Always "s16le", interleaved signed 16-bit little endian samples
+    encoding: &'static str,
+    /// Set if the room is in sync mode, see SyncReference
+    sync_timestamp: Option<u64>,
+    sync_latency: Option<u128>,
+}
+
+/// Streams a room over a WebSocket, with as little buffering as possible.
+///
+/// The first message is text containing a [WebSocketFormat].
+/// Every message after it is binary, containing a chunk of samples in that format,
+/// with no header. A client that can't keep up has chunks dropped,
+/// so it skips ahead instead of falling further behind.
+async fn get_room_ws_audio(
+    session: Option<StreamSession>,
+    State(context): Context,
+    Path(id): Path<String>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    let user = listener(session, &room)?;
+
+    let connection = context
         .store
         .room_store
-        .rooms
-        .iter()
-        .find(|r| r.id.id.to_string() == id)
-        .map(|r| r.id.clone())
-        .ok_or(ApiError::NotFound("Room"))?;
+        .connect(user, &room.id, Transport::WebSocket)?;
+
+    let output = output_format();
+
+    let format = WebSocketFormat {
+        sample_rate: output.sample_rate,
+        channels: output.channels,
+        encoding: "s16le",
+        sync_timestamp: connection.sync.map(|s| unix_millis_at(s.timestamp)),
+        sync_latency: connection.sync.map(|s| s.latency.as_millis()),
+    };
+
+    Ok(upgrade.on_upgrade(move |socket| send_audio(socket, connection, format)))
+}
+
+async fn send_audio(mut socket: WebSocket, connection: ConnectionHandle, format: WebSocketFormat) {
+    let format = serde_json::to_string(&format).expect("format serializes properly");
+
+    if socket.send(Message::Text(format)).await.is_err() {
+        return;
+    }
+
+    let mut frames = connection.into_frames(Transport::WEBSOCKET_BACKLOG);
+
+    while let Some(frame) = frames.recv().await {
+        if socket.send(Message::Binary(frame)).await.is_err() {
+            return;
+        }
+    }
+
+    // The connection was closed on our end
+    let _ = socket.close().await;
+}
+
+/// Returns who is listening to a room.
+/// Public streams can be listened to without a session, as a guest,
+/// private rooms only by those they admit, see [RoomData::admits],
+/// and members only streams only by members.
+fn listener(session: Option<StreamSession>, room: &RoomData) -> Result<User, ApiError> {
+    let members_only = room.settings.members_only_stream;
+
+    match session {
+        Some(StreamSession(session)) if !room.admits(&session.user, &session.joined_rooms) => {
+            Err(ApiError::NotAllowed("Listening to this private room"))
+        }
+        Some(StreamSession(session))
+            if members_only && !room.allows(&session.user, RoomRole::Member) =>
+        {
+            Err(ApiError::NotAllowed("Listening to this room"))
+        }
+        Some(StreamSession(session)) => Ok(session.user),
+        None if room.settings.public_stream && !room.is_private() && !members_only => {
+            Ok(User::guest())
+        }
+        None => Err(ApiError::Unauthorized),
+    }
+}
+
+/// Makes a room name safe to use as a filename in a header
+fn sanitize_filename(name: &str) -> String {
+    const MAX_FILENAME_LENGTH: usize = 64;
+
+    let sanitized: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ' ' => c,
+            _ => '_',
+        })
+        .take(MAX_FILENAME_LENGTH)
+        .collect();
+
+    let trimmed = sanitized.trim();
+
+    if trimmed.is_empty() {
+        "stream".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SyncResponse {
+    /// Current time on the server in milliseconds since the unix epoch
+    server_time: u64,
+    /// Latency every listener is delayed to in milliseconds, 0 if sync mode is disabled
+    latency: u32,
+}
+
+/// Lets clients estimate the offset between their clock and the server clock,
+/// which is needed to align a synchronized stream.
+async fn get_room_sync(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<SyncResponse>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    context.store.room_store.check_access(&room.id, &session)?;
+
+    Ok(Json(SyncResponse {
+        server_time: unix_millis(),
+        latency: room.settings.sync_latency,
+    }))
+}
+
+#[derive(Deserialize)]
+struct StreamReportBody {
+    code: String,
+    description: String,
+    format: Option<String>,
+}
+
+/// Lets clients report playback problems. This is only used for diagnostics.
+async fn report_stream_error(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<StreamReportBody>,
+) -> Result<StatusCode, ApiError> {
+    const MAX_FIELD_LENGTH: usize = 500;
+
+    let room = context.store.room_store.find_room(&id)?;
+
+    context
+        .limits
+        .stream_reports
+        .check(session.user.id.clone())
+        .map_err(ApiError::TooManyRequests)?;
+
+    let truncate = |x: &str| x.chars().take(MAX_FIELD_LENGTH).collect::<String>();
+
+    info!(
+        target: "vinyl::server",
+        "{} reported a playback error in {} ({}): {} - {}",
+        session.user.username,
+        room,
+        body.format.as_deref().map(truncate).unwrap_or_else(|| "wav".to_string()),
+        truncate(&body.code),
+        truncate(&body.description)
+    );
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn get_room_queue(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<SerializedQueue>, ApiError> {
+    let room = context.store.room_store.find_room(&id)?;
+
+    context.store.room_store.check_access(&room, &session)?;
 
     let queue_id = context
         .store
@@ -161,3 +774,921 @@ async fn get_room_queue(
 
     Ok(Json(queue))
 }
+
+#[derive(Deserialize)]
+struct RoomSettingsBody {
+    start_when_listeners: Option<usize>,
+    sync_latency: Option<u32>,
+    allowed_sources: Option<Vec<String>>,
+    max_item_age: Option<u64>,
+    dynamic_normalization: Option<bool>,
+    public_stream: Option<bool>,
+    loudness_normalization: Option<bool>,
+    skip_mode: Option<SkipMode>,
+    skip_vote_fraction: Option<f32>,
+    crossfade: Option<u32>,
+    members_only_stream: Option<bool>,
+    /// An empty string uses the one set for the server
+    audio_format: Option<String>,
+    /// In kbps, 0 removes the cap
+    max_bit_rate: Option<u32>,
+}
+
+/// Keeping more history than this per room would use too much memory
+const MAX_SYNC_LATENCY: u32 = 10_000;
+
+/// Longer fades would take up most of a short track
+const MAX_CROSSFADE: u32 = 12_000;
+
+async fn update_room_settings(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<RoomSettingsBody>,
+) -> Result<Json<SerializedRoom>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+    let mut settings = room.settings.clone();
+
+    context.store.room_store.check_role(
+        &room.id,
+        &session.user,
+        RoomRole::Owner,
+        "Changing settings of this room",
+    )?;
+
+    if let Some(start_when_listeners) = body.start_when_listeners {
+        settings.start_when_listeners = start_when_listeners;
+    }
+
+    if let Some(sync_latency) = body.sync_latency {
+        if sync_latency > MAX_SYNC_LATENCY {
+            return Err(ApiError::Invalid("Sync latency"));
+        }
+
+        settings.sync_latency = sync_latency;
+    }
+
+    if let Some(allowed_sources) = body.allowed_sources {
+        let is_known = |source: &String| Input::SOURCES.contains(&source.as_str());
+
+        if !allowed_sources.iter().all(is_known) {
+            return Err(ApiError::Invalid("Allowed sources"));
+        }
+
+        settings.allowed_sources = Some(allowed_sources);
+    }
+
+    if let Some(max_item_age) = body.max_item_age {
+        settings.max_item_age = max_item_age;
+    }
+
+    if let Some(dynamic_normalization) = body.dynamic_normalization {
+        settings.dynamic_normalization = dynamic_normalization;
+    }
+
+    if let Some(public_stream) = body.public_stream {
+        settings.public_stream = public_stream;
+    }
+
+    if let Some(loudness_normalization) = body.loudness_normalization {
+        settings.loudness_normalization = loudness_normalization;
+    }
+
+    if let Some(skip_mode) = body.skip_mode {
+        settings.skip_mode = skip_mode;
+    }
+
+    if let Some(skip_vote_fraction) = body.skip_vote_fraction {
+        if !(skip_vote_fraction > 0. && skip_vote_fraction <= 1.) {
+            return Err(ApiError::Invalid("Skip vote fraction"));
+        }
+
+        settings.skip_vote_fraction = skip_vote_fraction;
+    }
+
+    if let Some(crossfade) = body.crossfade {
+        if crossfade > MAX_CROSSFADE {
+            return Err(ApiError::Invalid("Crossfade"));
+        }
+
+        settings.crossfade = crossfade;
+    }
+
+    if let Some(members_only_stream) = body.members_only_stream {
+        settings.members_only_stream = members_only_stream;
+    }
+
+    if let Some(audio_format) = body.audio_format {
+        settings.audio_format = match audio_format.trim() {
+            "" => None,
+            format => Some(AudioFormat::parse(format).ok_or(ApiError::Invalid("Audio format"))?),
+        };
+    }
+
+    if let Some(max_bit_rate) = body.max_bit_rate {
+        settings.max_bit_rate = match max_bit_rate {
+            0 => None,
+            x if OpusStream::BIT_RATES.contains(&x) => Some(x),
+            _ => return Err(ApiError::Invalid("Max bit rate")),
+        };
+    }
+
+    let room = context
+        .store
+        .room_store
+        .update_settings(&context.db, &room.id, settings)
+        .await?;
+
+    Ok(Json(room))
+}
+
+#[derive(Deserialize)]
+struct ScheduleBody {
+    /// Milliseconds since the unix epoch, or null to clear the schedule
+    start: Option<u64>,
+}
+
+/// Schedules when playback starts. Times in the past start playback immediately.
+async fn update_room_schedule(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<ScheduleBody>,
+) -> Result<Json<SerializedRoom>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    context.store.room_store.check_role(
+        &room.id,
+        &session.user,
+        RoomRole::Owner,
+        "Scheduling this room",
+    )?;
+
+    let room = context
+        .store
+        .room_store
+        .update_schedule(&context.db, &room.id, body.start)
+        .await?;
+
+    Ok(Json(room))
+}
+
+/// Returns the room if the user owns it
+fn owned_room(
+    context: &VinylContext,
+    session: &Session,
+    id: &str,
+    action: &'static str,
+) -> Result<RoomData, ApiError> {
+    let room = context.store.room_store.find_room_data(id)?;
+
+    context
+        .store
+        .room_store
+        .check_role(&room.id, &session.user, RoomRole::Owner, action)?;
+
+    Ok(room)
+}
+
+/// Lets a user add to the queue of a room. Only the owner can do this.
+async fn add_member(
+    session: Session,
+    State(context): Context,
+    Path((id, username)): Path<(String, String)>,
+) -> Result<Json<SerializedRoom>, ApiError> {
+    let room = owned_room(&context, &session, &id, "Adding members to this room")?;
+    let user = User::get(&context.db, &username).await?;
+
+    if room.role(&user) != RoomRole::Listener {
+        return Err(ApiError::Conflict("Member"));
+    }
+
+    let room_store = &context.store.room_store;
+    room_store.add_member(&context.db, &room.id, &user).await?;
+
+    info!(target: "vinyl::server", "{} made {} a member of {}", session.user.username, user.username, room.name);
+
+    let room = room_store
+        .rooms_for(&session)
+        .into_iter()
+        .find(|r| r.id == id)
+        .ok_or(ApiError::NotFound("Room"))?;
+
+    Ok(Json(room))
+}
+
+/// Stops a member from adding to the queue of a room. Only the owner can do this.
+async fn remove_member(
+    session: Session,
+    State(context): Context,
+    Path((id, username)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    let room = owned_room(&context, &session, &id, "Removing members of this room")?;
+    let user = User::get(&context.db, &username).await?;
+
+    let removed = context
+        .store
+        .room_store
+        .remove_member(&context.db, &room.id, &user.id)
+        .await?;
+
+    if !removed {
+        return Err(ApiError::NotFound("Member"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns what was recently played in the room, most recent first, including the current item.
+/// How much is remembered is limited by `VINYL_HISTORY_LIMIT`.
+async fn get_room_history(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<PlayedItem>>, ApiError> {
+    let room = context.store.room_store.find_room(&id)?;
+
+    context.store.room_store.check_access(&room, &session)?;
+
+    Ok(Json(context.store.room_store.history(&room)))
+}
+
+#[derive(Deserialize)]
+struct ReplayQuery {
+    /// Milliseconds since the unix epoch
+    since: u64,
+}
+
+/// How far back a replay can go
+const MAX_REPLAY_RANGE: Duration = Duration::from_secs(60 * 60 * 12);
+/// How many items a replay can add at once
+const MAX_REPLAY_ITEMS: usize = 100;
+
+/// Adds everything played since a time back to the queue, to run a set again
+async fn replay_history(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Query(query): Query<ReplayQuery>,
+) -> Result<Json<Replay>, ApiError> {
+    let room = owned_room(&context, &session, &id, "Replaying history in this room")?;
+
+    let earliest = unix_millis_at(SystemTime::now() - MAX_REPLAY_RANGE);
+
+    if query.since < earliest {
+        return Err(ApiError::Invalid("Replay range"));
+    }
+
+    if context.store.room_store.relays.contains_key(&room.id) {
+        return Err(ApiError::NotAllowed("Queueing in a relay room"));
+    }
+
+    let replay = spawn_blocking(move || {
+        context.store.room_store.replay_history(
+            session.user,
+            &room.id,
+            query.since,
+            MAX_REPLAY_ITEMS,
+        )
+    })
+    .await
+    .unwrap();
+
+    Ok(Json(replay))
+}
+
+async fn get_now_playing(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<NowPlaying>, ApiError> {
+    let room = context.store.room_store.find_room(&id)?;
+
+    context.store.room_store.check_access(&room, &session)?;
+
+    Ok(Json(context.store.room_store.now_playing(&room)))
+}
+
+/// Returns what is playing and how far into it, so clients can show progress.
+/// Responds with no content if nothing is playing.
+async fn get_current_track(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let room = context.store.room_store.find_room(&id)?;
+
+    context.store.room_store.check_access(&room, &session)?;
+
+    let response = match context.store.room_store.current_track(&room) {
+        Some(current) => Json(current).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    };
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct NowPlayingBody {
+    title: String,
+    artist: Option<String>,
+}
+
+const MAX_NOW_PLAYING_LENGTH: usize = 200;
+
+/// Shows something else as playing until it is cleared or the track changes
+async fn override_now_playing(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<NowPlayingBody>,
+) -> Result<Json<NowPlaying>, ApiError> {
+    let room = owned_room(
+        &context,
+        &session,
+        &id,
+        "Overriding now playing in this room",
+    )?;
+
+    let title = body.title.trim().to_string();
+    let artist = body.artist.map(|a| a.trim().to_string());
+
+    if title.is_empty() || title.chars().count() > MAX_NOW_PLAYING_LENGTH {
+        return Err(ApiError::Invalid("Title"));
+    }
+
+    if artist
+        .as_ref()
+        .is_some_and(|a| a.chars().count() > MAX_NOW_PLAYING_LENGTH)
+    {
+        return Err(ApiError::Invalid("Artist"));
+    }
+
+    let label = NowPlayingOverride {
+        title,
+        artist: artist.filter(|a| !a.is_empty()),
+        set_by: session.user,
+        set_at: unix_millis(),
+    };
+
+    let room_store = &context.store.room_store;
+    room_store.set_now_playing(&room.id, Some(label));
+
+    Ok(Json(room_store.now_playing(&room.id)))
+}
+
+async fn clear_now_playing_override(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<NowPlaying>, ApiError> {
+    let room = owned_room(
+        &context,
+        &session,
+        &id,
+        "Overriding now playing in this room",
+    )?;
+
+    let room_store = &context.store.room_store;
+    room_store.set_now_playing(&room.id, None);
+
+    Ok(Json(room_store.now_playing(&room.id)))
+}
+
+async fn get_priority_grants(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<PriorityGrant>>, ApiError> {
+    let room = owned_room(&context, &session, &id, "Viewing priority in this room")?;
+    let grants = context.store.room_store.priority.active(&room.id);
+
+    Ok(Json(grants))
+}
+
+#[derive(Deserialize)]
+struct PriorityBody {
+    username: String,
+    /// How long the grant lasts
+    seconds: Option<u64>,
+    /// How many tracks can be added with priority
+    tracks: Option<u32>,
+}
+
+/// Grants should not outlast a party
+const MAX_PRIORITY_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+const MAX_PRIORITY_TRACKS: u32 = 50;
+
+/// Lets a user's next adds play before everyone else's, until the grant runs out
+async fn grant_priority(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<PriorityBody>,
+) -> Result<Json<PriorityGrant>, ApiError> {
+    let room = owned_room(&context, &session, &id, "Granting priority in this room")?;
+
+    let duration = body.seconds.map(Duration::from_secs);
+
+    // Grants must always run out eventually
+    if duration.is_none() && body.tracks.is_none() {
+        return Err(ApiError::Invalid("Priority grant without a limit"));
+    }
+
+    if duration.is_some_and(|d| d.is_zero() || d > MAX_PRIORITY_DURATION) {
+        return Err(ApiError::Invalid("Priority duration"));
+    }
+
+    if body
+        .tracks
+        .is_some_and(|t| t == 0 || t > MAX_PRIORITY_TRACKS)
+    {
+        return Err(ApiError::Invalid("Priority track amount"));
+    }
+
+    let user = User::get(&context.db, &body.username).await?;
+    let grant = PriorityGrant::new(user, duration, body.tracks);
+
+    context
+        .store
+        .room_store
+        .priority
+        .grant(&room.id, grant.clone());
+
+    Ok(Json(grant))
+}
+
+async fn revoke_priority(
+    session: Session,
+    State(context): Context,
+    Path((id, username)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    let room = owned_room(&context, &session, &id, "Revoking priority in this room")?;
+    let user = User::get(&context.db, &username).await?;
+
+    if !context.store.room_store.priority.revoke(&room.id, &user.id) {
+        return Err(ApiError::NotFound("Priority grant"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_queue_item_eta(
+    session: Session,
+    State(context): Context,
+    Path((id, item_id)): Path<(String, QueueItemId)>,
+) -> Result<Json<Eta>, ApiError> {
+    let room = context.store.room_store.find_room(&id)?;
+
+    context.store.room_store.check_access(&room, &session)?;
+
+    let eta = context
+        .store
+        .room_store
+        .queue_eta(&room, item_id)
+        .ok_or(ApiError::NotFound("Queue item"))?;
+
+    Ok(Json(eta))
+}
+
+/// Pauses playback for everyone in the room, see [set_paused]
+async fn pause_playback(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<SerializedRoom>, ApiError> {
+    set_paused(&context, &session.user, &id, true)
+}
+
+async fn resume_playback(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<SerializedRoom>, ApiError> {
+    set_paused(&context, &session.user, &id, false)
+}
+
+/// Pauses or resumes a room. Members of the room can do this, along with the owner and superusers.
+fn set_paused(
+    context: &VinylContext,
+    user: &User,
+    id: &str,
+    paused: bool,
+) -> Result<Json<SerializedRoom>, ApiError> {
+    let room_store = &context.store.room_store;
+
+    let room = room_store.find_room_data(id)?;
+
+    room_store.check_role(&room.id, user, RoomRole::Member, "Pausing this room")?;
+
+    room_store.set_paused(&room.id, paused);
+
+    let room = room_store
+        .rooms()
+        .into_iter()
+        .find(|r| r.id == id)
+        .ok_or(ApiError::NotFound("Room"))?;
+
+    Ok(Json(room))
+}
+
+#[derive(Deserialize)]
+struct SeekBody {
+    position_ms: u64,
+}
+
+#[derive(Serialize)]
+struct Seeked {
+    /// Where playback moved to, after it was clamped to the length of the track
+    position_ms: u64,
+}
+
+/// Moves playback within the current track for everyone in the room.
+/// Members of the room can do this, along with the owner and superusers.
+///
+/// Remote tracks are loaded from the start, so seeking ahead of what was loaded
+/// waits until ingestion gets there, which can take as long as loading the whole track.
+async fn seek_playback(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<SeekBody>,
+) -> Result<Json<Seeked>, ApiError> {
+    let room_store = &context.store.room_store;
+
+    let room = room_store.find_room_data(&id)?;
+
+    let user = &session.user;
+
+    room_store.check_role(&room.id, user, RoomRole::Member, "Seeking in this room")?;
+
+    let position = room_store
+        .seek(&room.id, Duration::from_millis(body.position_ms))
+        .ok_or_else(|| ApiError::Rejected("Nothing that can be seeked is playing".to_string()))?;
+
+    Ok(Json(Seeked {
+        position_ms: position.as_millis() as u64,
+    }))
+}
+
+#[derive(Deserialize)]
+struct VolumeBody {
+    volume: f32,
+}
+
+/// Changes the volume of everything the room plays, clamped between 0 and 1
+async fn update_room_volume(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<VolumeBody>,
+) -> Result<Json<SerializedRoom>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    context.store.room_store.check_role(
+        &room.id,
+        &session.user,
+        RoomRole::Owner,
+        "Changing the volume of this room",
+    )?;
+
+    if !body.volume.is_finite() {
+        return Err(ApiError::Invalid("Volume"));
+    }
+
+    let room = context
+        .store
+        .room_store
+        .set_volume(&context.db, &room.id, body.volume)
+        .await?;
+
+    Ok(Json(room))
+}
+
+/// Skips the item that is playing.
+/// Members of the room can do this, along with the owner and superusers.
+///
+/// If the room uses [SkipMode::Vote], this votes to skip instead, and returns where the vote stands.
+/// Members and anyone listening to the room can vote.
+async fn skip_current_item(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    let user = &session.user;
+    let room_store = &context.store.room_store;
+
+    if room.settings.skip_mode == SkipMode::Vote {
+        if !room.allows(user, RoomRole::Member) && !room_store.is_listening(&room.id, &user.id) {
+            return Err(ApiError::NotAllowed("Voting to skip in this room"));
+        }
+
+        let vote = room_store
+            .vote_to_skip(&room.id, user.id.clone())
+            .ok_or(ApiError::NotFound("Current item"))?;
+
+        trace!(target: "vinyl::server", "{} voted to skip in {}", user.username, room.name);
+        return Ok(Json(vote).into_response());
+    }
+
+    room_store.check_role(&room.id, user, RoomRole::Member, "Skipping in this room")?;
+
+    if !room_store.skip(&room.id) {
+        return Err(ApiError::NotFound("Current item"));
+    }
+
+    trace!(target: "vinyl::server", "{} skipped the current item in {}", user.username, room.name);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Deserialize)]
+struct RepeatBody {
+    mode: RepeatMode,
+}
+
+/// Sets what happens when the current item ends.
+/// Members of the room can do this, along with the owner and superusers.
+async fn update_queue_repeat(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    Json(body): Json<RepeatBody>,
+) -> Result<Json<SerializedQueue>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    let user = &session.user;
+    let room_store = &context.store.room_store;
+
+    room_store.check_role(
+        &room.id,
+        user,
+        RoomRole::Member,
+        "Changing the repeat mode of this room",
+    )?;
+
+    room_store.set_repeat(&room.id, body.mode);
+
+    let queue = *room_store
+        .queues
+        .get(&room.id)
+        .expect("queue exists if room exists");
+
+    Ok(Json(context.store.queue_store.serialized(queue)))
+}
+
+/// Removes an item from the queue, skipping it if it is playing.
+/// Only the submitter, the room owner, or a superuser can do this.
+async fn remove_queue_item(
+    session: Session,
+    State(context): Context,
+    Path((id, item_id)): Path<(String, QueueItemId)>,
+) -> Result<StatusCode, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    let item = context
+        .store
+        .room_store
+        .queue_item(&room.id, item_id)
+        .ok_or(ApiError::NotFound("Queue item"))?;
+
+    let user = &session.user;
+
+    if *item.submitter() != user.id && !room.allows(user, RoomRole::Owner) {
+        return Err(ApiError::NotAllowed("Removing this item"));
+    }
+
+    let removed = spawn_blocking(move || {
+        context
+            .store
+            .room_store
+            .remove_queue_item(&room.id, item_id)
+    })
+    .await
+    .unwrap();
+
+    if !removed {
+        return Err(ApiError::NotFound("Queue item"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct MoveQueueItemBody {
+    /// Where the item should be in the queue, clamped to the positions after the current item
+    position: usize,
+}
+
+/// Moves an item to a new position in the queue. Only the room owner or a superuser can do this.
+async fn move_queue_item(
+    session: Session,
+    State(context): Context,
+    Path((id, item_id)): Path<(String, QueueItemId)>,
+    Json(body): Json<MoveQueueItemBody>,
+) -> Result<Json<SerializedQueue>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    context.store.room_store.check_role(
+        &room.id,
+        &session.user,
+        RoomRole::Owner,
+        "Reordering this queue",
+    )?;
+
+    context
+        .store
+        .room_store
+        .queue_item(&room.id, item_id)
+        .ok_or(ApiError::NotFound("Queue item"))?;
+
+    let moving_context = context.clone();
+    let moving_room = room.id.clone();
+
+    // The current item, and the ones before it, stay where they are
+    spawn_blocking(move || {
+        moving_context
+            .store
+            .room_store
+            .move_queue_item(&moving_room, item_id, body.position)
+    })
+    .await
+    .unwrap()
+    .ok_or(ApiError::NotAllowed("Moving this item"))?;
+
+    let queue_id = *context
+        .store
+        .room_store
+        .queues
+        .get(&room.id)
+        .expect("queue exists if room exists");
+
+    Ok(Json(context.store.queue_store.serialized(queue_id)))
+}
+
+#[derive(Deserialize, Default)]
+struct ClearQueueBody {
+    /// Keeps the item that is playing, otherwise playback stops
+    #[serde(default)]
+    keep_current: bool,
+}
+
+#[derive(Serialize)]
+struct ClearedQueue {
+    removed: usize,
+}
+
+/// Removes every item from the queue at once, the body can be left out to remove all of them.
+/// Only the room owner or a superuser can do this.
+async fn clear_queue(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+    body: Option<Json<ClearQueueBody>>,
+) -> Result<Json<ClearedQueue>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    let user = &session.user;
+
+    context.store.room_store.check_role(
+        &room.id,
+        user,
+        RoomRole::Owner,
+        "Clearing the queue of this room",
+    )?;
+
+    let keep_current = body.map(|Json(b)| b).unwrap_or_default().keep_current;
+    let clearing_context = context.clone();
+    let clearing_room = room.id.clone();
+
+    let removed = spawn_blocking(move || {
+        clearing_context
+            .store
+            .room_store
+            .clear_queue(&clearing_room, keep_current)
+    })
+    .await
+    .unwrap();
+
+    info!(
+        target: "vinyl::server",
+        "{} cleared {} items from the queue of {}",
+        user.username,
+        removed,
+        room.name
+    );
+
+    Ok(Json(ClearedQueue { removed }))
+}
+
+/// Randomly reorders the items after the current one, keeping the turns of submitters.
+/// Members of the room can do this, along with the owner and superusers.
+async fn shuffle_queue(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<SerializedQueue>, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    context.store.room_store.check_role(
+        &room.id,
+        &session.user,
+        RoomRole::Member,
+        "Reordering this queue",
+    )?;
+
+    let shuffling_context = context.clone();
+    let shuffling_room = room.id.clone();
+
+    // Shuffling fewer than two items does nothing
+    spawn_blocking(move || {
+        shuffling_context
+            .store
+            .room_store
+            .shuffle_queue(&shuffling_room)
+    })
+    .await
+    .unwrap();
+
+    let queue_id = *context
+        .store
+        .room_store
+        .queues
+        .get(&room.id)
+        .expect("queue exists if room exists");
+
+    Ok(Json(context.store.queue_store.serialized(queue_id)))
+}
+
+async fn get_queue_failures(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<IngestionFailure>>, ApiError> {
+    let room = context.store.room_store.find_room(&id)?;
+
+    context.store.room_store.check_access(&room, &session)?;
+
+    let queue_id = *context
+        .store
+        .room_store
+        .queues
+        .get(&room)
+        .expect("queue exists if room exists");
+
+    Ok(Json(context.store.ingestion.failures.get(queue_id)))
+}
+
+async fn clear_queue_failures(
+    session: Session,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let room = context.store.room_store.find_room_data(&id)?;
+
+    context.store.room_store.check_role(
+        &room.id,
+        &session.user,
+        RoomRole::Owner,
+        "Clearing failures of this room",
+    )?;
+
+    let queue_id = *context
+        .store
+        .room_store
+        .queues
+        .get(&room.id)
+        .expect("queue exists if room exists");
+
+    context.store.ingestion.failures.clear(queue_id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{page_size, sanitize_filename, DEFAULT_ROOMS_PAGE_SIZE, MAX_ROOMS_PAGE_SIZE};
+
+    #[test]
+    fn sanitizes_filenames() {
+        assert_eq!(sanitize_filename("Chill beats"), "Chill beats");
+        assert_eq!(sanitize_filename("a\"; b/../c"), "a__ b____c");
+        assert_eq!(sanitize_filename("Ünïcode 🎵"), "_n_code _");
+        assert_eq!(sanitize_filename("  \r\n "), "stream");
+        assert_eq!(sanitize_filename(&"a".repeat(100)).len(), 64);
+    }
+
+    #[test]
+    fn page_sizes() {
+        assert_eq!(page_size(None).unwrap(), DEFAULT_ROOMS_PAGE_SIZE);
+        assert_eq!(page_size(Some(10)).unwrap(), 10);
+        assert_eq!(
+            page_size(Some(MAX_ROOMS_PAGE_SIZE)).unwrap(),
+            MAX_ROOMS_PAGE_SIZE
+        );
+
+        assert!(page_size(Some(0)).is_err());
+        assert!(page_size(Some(MAX_ROOMS_PAGE_SIZE + 1)).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashSet;
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::RoomId;
+
+/// Rooms whose queue changed and needs to be saved, see [RoomStore::save_queue](super::RoomStore::save_queue)
+#[derive(Debug)]
+pub struct QueueSaves {
+    sender: UnboundedSender<RoomId>,
+    receiver: Mutex<Option<UnboundedReceiver<RoomId>>>,
+}
+
+impl QueueSaves {
+    pub fn request(&self, room: RoomId) {
+        let _ = self.sender.send(room);
+    }
+
+    /// Takes the receiving end, which can only be done once
+    pub fn take_receiver(&self) -> Option<UnboundedReceiver<RoomId>> {
+        self.receiver.lock().take()
+    }
+
+    /// Waits for a request, returning it along with every other one that is waiting,
+    /// so a room that changed several times is only saved once.
+    /// Returns [None] once every sender is dropped.
+    pub async fn next_batch(receiver: &mut UnboundedReceiver<RoomId>) -> Option<HashSet<RoomId>> {
+        let mut rooms = HashSet::from([receiver.recv().await?]);
+
+        while let Ok(room) = receiver.try_recv() {
+            rooms.insert(room);
+        }
+
+        Some(rooms)
+    }
+}
+
+impl Default for QueueSaves {
+    fn default() -> Self {
+        let (sender, receiver) = unbounded_channel();
+
+        Self {
+            sender,
+            receiver: Some(receiver).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+
+    use super::QueueSaves;
+    use crate::auth::User;
+
+    #[test]
+    fn batches_requests() {
+        let saves = QueueSaves::default();
+        let mut receiver = saves.take_receiver().unwrap();
+        assert!(saves.take_receiver().is_none());
+
+        let room = User::mock("room").id;
+        let other = User::mock("other").id;
+
+        saves.request(room.clone());
+        saves.request(other.clone());
+        saves.request(room.clone());
+
+        let batch = block_on(QueueSaves::next_batch(&mut receiver)).unwrap();
+
+        assert_eq!(batch.len(), 2);
+        assert!(batch.contains(&room) && batch.contains(&other));
+    }
+}
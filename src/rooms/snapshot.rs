@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::util::ApiError;
+
+use super::RoomSettings;
+
+/// Everything needed to recreate a room on another instance.
+///
+/// Users are referred to by username, since ids differ between instances.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSnapshot {
+    /// Increased whenever the format changes, see [RoomSnapshot::from_value]
+    pub version: u32,
+
+    pub name: String,
+    pub owner: String,
+    pub relay: Option<String>,
+    pub settings: RoomSettings,
+    pub scheduled_start: Option<u64>,
+
+    /// Hash of the password, so a private room stays private when it is imported
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// Usernames of the members of the room
+    #[serde(default)]
+    pub members: Vec<String>,
+
+    /// The current item and the ones after it, in the order they play
+    pub queue: Vec<SnapshotItem>,
+    pub priority: Vec<SnapshotGrant>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotItem {
+    /// A url the track is resolved from again
+    pub input: String,
+    pub submitter: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotGrant {
+    pub user: String,
+    pub expires_at: Option<u64>,
+    pub remaining_tracks: Option<u32>,
+}
+
+/// Describes how a room was imported
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomImport {
+    pub room: String,
+    pub requeued: usize,
+    /// Items that could not be resolved again, or are not allowed in the room
+    pub skipped: usize,
+}
+
+impl RoomSnapshot {
+    pub const VERSION: u32 = 2;
+
+    /// Reads a snapshot of any version, migrating older ones to the current format
+    pub fn from_value(value: Value) -> Result<Self, ApiError> {
+        let version = value
+            .get("version")
+            .and_then(Value::as_u64)
+            .ok_or(ApiError::Invalid("Snapshot version"))?;
+
+        // Migrations from older versions go here, before the current one is read
+        match version {
+            // Version 1 left out the password and members, so those rooms are public without members
+            1 | 2 => serde_json::from_value(value).map_err(|_| ApiError::Invalid("Snapshot")),
+            _ => Err(ApiError::Invalid("Snapshot version")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::RoomSnapshot;
+
+    #[test]
+    fn reads_versions() {
+        let snapshot = json!({
+            "version": 1,
+            "name": "Lounge",
+            "owner": "john",
+            "relay": null,
+            "settings": { "max_item_age": 60 },
+            "scheduledStart": null,
+            "queue": [{ "input": "https://youtu.be/dQw4w9WgXcQ", "submitter": "mary" }],
+            "priority": [],
+        });
+
+        let read = RoomSnapshot::from_value(snapshot.clone()).unwrap();
+        assert_eq!(read.queue.len(), 1);
+        assert_eq!(read.password, None);
+        assert!(read.members.is_empty());
+
+        let mut private = snapshot.clone();
+        private["version"] = json!(2);
+        private["password"] = json!("$scrypt$hash");
+        private["members"] = json!(["mary"]);
+
+        let read = RoomSnapshot::from_value(private).unwrap();
+        assert_eq!(read.password.as_deref(), Some("$scrypt$hash"));
+        assert_eq!(read.members, vec!["mary"]);
+
+        let mut future = snapshot;
+        future["version"] = json!(RoomSnapshot::VERSION + 1);
+        assert!(RoomSnapshot::from_value(future).is_err());
+
+        assert!(RoomSnapshot::from_value(json!({ "name": "Lounge" })).is_err());
+    }
+}
@@ -1,21 +1,38 @@
-use std::sync::{Arc, Weak};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
+};
 
 use dashmap::DashMap;
+use log::{info, warn};
+use tokio::task::spawn_blocking;
 
 use crate::{
-    audio::{Input, PlayerId, WaveStream},
-    auth::{User, UserId},
+    audio::{duration_to_samples, AudioEvent, EncodedStream, Input, PlayerId, WaveStream},
+    auth::{Session, User, UserId},
     db::Database,
-    queue::{QueueId, SubQueueId},
+    events::Handler,
+    ingest::{AudioFormat, IngestionEvent, InputId, Relay},
+    queue::{
+        Eta, PlayedItem, QueueEvent, QueueId, QueueItem, QueueItemId, RepeatMode, Replay,
+        SubQueueId,
+    },
     store::{FromId, Store},
-    track::InternalTrack,
+    track::{InternalTrack, Track},
     util::ApiError,
-    EventEmitter,
+    EventEmitter, VinylEvent,
 };
 
 use super::{
-    connection::{Connection, ConnectionHandle, ConnectionHandleId},
-    RoomData, RoomEvent, RoomId, SerializedRoom,
+    connection::{
+        Connection, ConnectionHandle, ConnectionHandleId, ConnectionPolicy, Fanout, SyncReference,
+        Transport, CONNECTION_POLICY,
+    },
+    required_votes, CurrentTrack, ListenerCounts, NowPlaying, NowPlayingOverride, PriorityGrant,
+    PriorityGrants, QueueSaves, RoomData, RoomEvent, RoomId, RoomImport, RoomRole, RoomSettings,
+    RoomSnapshot, SerializedRoom, SkipVote, SkipVotes, SnapshotGrant, SnapshotItem,
 };
 
 #[derive(Debug)]
@@ -26,7 +43,14 @@ pub struct RoomStore {
     pub(super) rooms: DashMap<RoomId, RoomData>,
     pub(super) queues: DashMap<RoomId, QueueId>,
     pub(super) players: DashMap<RoomId, PlayerId>,
+    pub(super) relays: DashMap<RoomId, Arc<Relay>>,
     pub(super) connections: DashMap<ConnectionHandleId, Connection>,
+    pub(super) fanouts: DashMap<RoomId, Arc<Fanout>>,
+    pub(super) now_playing: DashMap<RoomId, NowPlayingOverride>,
+    pub priority: PriorityGrants,
+    pub listeners: ListenerCounts,
+    pub skip_votes: SkipVotes,
+    saves: QueueSaves,
 }
 
 impl RoomStore {
@@ -37,7 +61,14 @@ impl RoomStore {
             rooms: Default::default(),
             queues: Default::default(),
             players: Default::default(),
+            relays: Default::default(),
             connections: Default::default(),
+            fanouts: Default::default(),
+            now_playing: Default::default(),
+            priority: Default::default(),
+            listeners: Default::default(),
+            skip_votes: Default::default(),
+            saves: Default::default(),
         }
     }
 
@@ -46,21 +77,206 @@ impl RoomStore {
             self.set_up_room(r);
         });
 
+        self.spawn_queue_saver(db.clone());
+        self.restore_saved_queues(db).await
+    }
+
+    /// Saves queues as they change, see [RoomHandler]
+    fn spawn_queue_saver(&self, db: Database) {
+        let Some(mut receiver) = self.saves.take_receiver() else {
+            return;
+        };
+
+        let store = self.store.clone();
+
+        tokio::spawn(async move {
+            while let Some(rooms) = QueueSaves::next_batch(&mut receiver).await {
+                let Some(store) = store.upgrade() else {
+                    break;
+                };
+
+                for room in rooms {
+                    store.room_store.save_queue(&db, &room).await;
+                }
+            }
+        });
+    }
+
+    /// Queues what was in each room when the server stopped.
+    /// Inputs are resolved again in the background, and the ones that fail are skipped.
+    async fn restore_saved_queues(&self, db: &Database) -> Result<(), ApiError> {
+        let mut users: HashMap<String, Option<User>> = HashMap::new();
+
+        for saved in RoomData::saved_queues(db).await? {
+            let Some(owner) = self.rooms.get(&saved.id).map(|r| r.owner.clone()) else {
+                continue;
+            };
+
+            if saved.queue.is_empty() || self.relays.contains_key(&saved.id) {
+                continue;
+            }
+
+            let mut items = vec![];
+
+            for item in saved.queue {
+                if !users.contains_key(&item.submitter) {
+                    let user = User::get(db, &item.submitter).await.ok();
+                    users.insert(item.submitter.clone(), user);
+                }
+
+                let user = users
+                    .get(&item.submitter)
+                    .cloned()
+                    .flatten()
+                    .unwrap_or_else(|| owner.clone());
+
+                items.push((user, item.input));
+            }
+
+            let store = self.store();
+            let room = saved.id;
+
+            spawn_blocking(move || {
+                let replay = store.room_store.restore_queue(&room, items);
+
+                info!(target: "vinyl::server",
+                    "Restored the queue of room {}, skipping {} of {} items",
+                    room.id, replay.skipped, replay.requeued + replay.skipped
+                );
+            });
+        }
+
         Ok(())
     }
 
+    /// Saves the queue of every room, so nothing is lost when the server stops
+    pub async fn save_queues(&self, db: &Database) {
+        let rooms: Vec<_> = self.rooms.iter().map(|r| r.key().clone()).collect();
+
+        for room in rooms {
+            self.save_queue(db, &room).await;
+        }
+    }
+
+    /// Saves the queue of a room, see [RoomStore::restore_saved_queues]
+    pub async fn save_queue(&self, db: &Database, room: &RoomId) {
+        // The room was deleted along with its queue
+        let Some(queue) = self.saved_queue(room) else {
+            return;
+        };
+
+        if let Err(err) = RoomData::update_queue(db, room, &queue).await {
+            warn!(target: "vinyl::server",
+                "Failed to save the queue of room {}: {}", room.id, err
+            );
+        }
+    }
+
     pub async fn create_room(
         &self,
         db: &Database,
         user: &User,
         name: String,
+        relay: Option<String>,
+        password: Option<String>,
     ) -> Result<SerializedRoom, ApiError> {
-        let room = RoomData::create(db, user, name).await?;
+        let room = RoomData::create(db, user, name, relay, password).await?;
         let id = self.set_up_room(room);
 
         Ok(self.serialize_room(&id))
     }
 
+    pub async fn rename(
+        &self,
+        db: &Database,
+        id: &RoomId,
+        name: String,
+    ) -> Result<SerializedRoom, ApiError> {
+        RoomData::update_name(db, id, &name).await?;
+
+        self.rooms.get_mut(id).expect("room exists").name = name.clone();
+
+        self.emitter.dispatch(RoomEvent::Renamed {
+            room: id.clone(),
+            name,
+        });
+
+        Ok(self.serialize_room(id))
+    }
+
+    /// Closes the streams of every room, used when shutting down.
+    ///
+    /// Players are closed too, so listeners waiting for audio are not left hanging
+    /// once playback stops.
+    pub fn close_all_connections(&self) {
+        let store = self.store();
+
+        for connection in self.connections.iter() {
+            connection.close();
+        }
+
+        for player in self.players.iter() {
+            player.upgrade(&store).close_stream();
+        }
+    }
+
+    /// Deletes a room, closing every stream and removing its queue and player
+    pub async fn delete_room(&self, db: &Database, id: &RoomId) -> Result<(), ApiError> {
+        RoomData::delete(db, id).await?;
+
+        let store = self.store();
+        self.listeners.remove(id);
+
+        for connection in self.connections.iter().filter(|c| c.room == *id) {
+            connection.close();
+        }
+
+        if let Some((_, relay)) = self.relays.remove(id) {
+            relay.stop();
+        }
+
+        if let Some((_, queue)) = self.queues.remove(id) {
+            store.queue_store.remove_queue(queue);
+        }
+
+        if let Some((_, player)) = self.players.remove(id) {
+            store.playback.remove_player(player);
+        }
+
+        self.fanouts.remove(id);
+        self.now_playing.remove(id);
+        self.priority.clear(id);
+        self.skip_votes.reset(id);
+        self.rooms.remove(id);
+
+        self.emitter
+            .dispatch(RoomEvent::Deleted { room: id.clone() });
+
+        Ok(())
+    }
+
+    pub fn room_count(&self) -> usize {
+        self.rooms.len()
+    }
+
+    /// Returns what sends the frames of connections to a room, see [ConnectionHandle::into_frames]
+    pub(super) fn fanout(&self, room: &RoomId) -> Arc<Fanout> {
+        self.fanouts.entry(room.clone()).or_default().clone()
+    }
+
+    /// Returns how many streams are open across every room
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns the queue of every room
+    pub fn queues(&self) -> Vec<(RoomId, QueueId)> {
+        self.queues
+            .iter()
+            .map(|x| (x.key().clone(), *x.value()))
+            .collect()
+    }
+
     pub fn rooms(&self) -> Vec<SerializedRoom> {
         self.rooms
             .iter()
@@ -68,8 +284,69 @@ impl RoomStore {
             .collect()
     }
 
+    /// Returns every room, leaving out what is in private rooms the session was not let into
+    pub fn rooms_for(&self, session: &Session) -> Vec<SerializedRoom> {
+        self.rooms
+            .iter()
+            .map(|r| self.serialize_room_for(&r.id, session))
+            .collect()
+    }
+
+    /// Returns `limit` rooms matching `query` after skipping `offset`, oldest first,
+    /// like [RoomStore::rooms_for]. The total amount of matching rooms is returned along with them.
+    pub fn page_for(
+        &self,
+        session: &Session,
+        query: &str,
+        offset: usize,
+        limit: usize,
+    ) -> (Vec<SerializedRoom>, usize) {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .iter()
+            .filter(|r| r.matches(query))
+            .map(|r| (r.created_at, r.id.id.to_string(), r.id.clone()))
+            .collect();
+
+        // Rooms from before creation times were stored are ordered by id, so pages stay consistent
+        rooms.sort_unstable_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+
+        let total = rooms.len();
+        let page = rooms
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(_, _, id)| self.serialize_room_for(&id, session))
+            .collect();
+
+        (page, total)
+    }
+
+    /// Returns true if the user can listen to the room and see what is in it, see [RoomData::admits].
+    /// Rooms that no longer exist have nothing left to hide.
+    pub fn admits(&self, room: &RoomId, user: &User, joined_rooms: &[RoomId]) -> bool {
+        self.rooms
+            .get(room)
+            .is_none_or(|r| r.admits(user, joined_rooms))
+    }
+
+    pub fn check_access(&self, room: &RoomId, session: &Session) -> Result<(), ApiError> {
+        if !self.admits(room, &session.user, &session.joined_rooms) {
+            return Err(ApiError::NotAllowed("Accessing this private room"));
+        }
+
+        Ok(())
+    }
+
     /// Create a user's connection to a room, returning a streamable handle
-    pub fn connect(&self, user: User, room_id: &RoomId) -> ConnectionHandle {
+    pub fn connect(
+        &self,
+        user: User,
+        room_id: &RoomId,
+        transport: Transport,
+    ) -> Result<ConnectionHandle, ApiError> {
+        self.apply_connection_policy(&user, room_id)?;
+
         let store = self.store();
         let room = self.rooms.get(room_id).expect("room exists");
 
@@ -79,19 +356,254 @@ impl RoomStore {
             .expect("player exists")
             .upgrade(&store);
 
-        let stream = WaveStream::new(player.consumer());
-        let handle = ConnectionHandle::new(self.store.clone(), stream);
+        let (consumer, sync) = match (room.settings.sync_latency, transport) {
+            (0, Transport::Http(..)) => (player.consumer(), None),
+            (0, Transport::WebSocket) => {
+                let (consumer, _) = player.delayed_consumer(Transport::WEBSOCKET_PRELOAD);
+                (consumer, None)
+            }
+            (latency, _) => {
+                let latency = Duration::from_millis(latency as u64);
+                let (consumer, timestamp) = player.delayed_consumer(latency);
+
+                (consumer, Some(SyncReference { timestamp, latency }))
+            }
+        };
+
+        let stream = match transport {
+            Transport::Http(encoding, bit_rate) => {
+                EncodedStream::new(consumer, encoding, room.settings.bit_rate(bit_rate))
+                    .map_err(|_| ApiError::Unavailable("Encoder"))?
+            }
+            Transport::WebSocket => EncodedStream::Wave(WaveStream::headerless(consumer)),
+        };
 
-        let connection = Connection::new(handle.id, room.id.clone(), user.clone());
+        let handle = ConnectionHandle::new(self.store.clone(), stream, sync);
+        let connection = Connection::new(&handle, room.id.clone(), user.clone());
 
         self.connections.insert(handle.id, connection);
 
+        self.emitter.dispatch(RoomEvent::ListenersChanged {
+            room: room.id.clone(),
+            count: self.listeners.connect(&room.id),
+        });
+
         self.emitter.dispatch(RoomEvent::UserEnteredRoom {
             room: room.id.clone(),
             user,
         });
 
-        handle
+        drop(room);
+        self.check_start_gate(room_id);
+
+        Ok(handle)
+    }
+
+    /// Makes room for a new connection by the user, or refuses it, see [ConnectionPolicy]
+    fn apply_connection_policy(&self, user: &User, room: &RoomId) -> Result<(), ApiError> {
+        let mut open: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|c| c.room == *room && c.user.id == user.id && !c.is_closed())
+            .map(|c| c.handle)
+            .collect();
+
+        match *CONNECTION_POLICY {
+            ConnectionPolicy::Allow => Ok(()),
+            ConnectionPolicy::Limit(limit) if open.len() >= limit => {
+                Err(ApiError::NotAllowed("Opening more streams in this room"))
+            }
+            ConnectionPolicy::Limit(_) => Ok(()),
+            ConnectionPolicy::ReplaceOldest(limit) => {
+                // Handle ids only increase, so the lowest is the oldest
+                open.sort_unstable();
+
+                let excess = (open.len() + 1).saturating_sub(limit);
+
+                for handle in open.into_iter().take(excess) {
+                    if let Some(connection) = self.connections.get(&handle) {
+                        connection.close();
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn update_settings(
+        &self,
+        db: &Database,
+        id: &RoomId,
+        settings: RoomSettings,
+    ) -> Result<SerializedRoom, ApiError> {
+        RoomData::update_settings(db, id, &settings).await?;
+
+        let player = self
+            .players
+            .get(id)
+            .expect("player exists")
+            .upgrade(&self.store());
+
+        if settings.start_when_listeners > 0 && !player.has_started() {
+            player.hold();
+        }
+
+        player.keep_history(Duration::from_millis(settings.sync_latency as u64));
+        player.set_normalization(settings.dynamic_normalization);
+        player.set_volume(settings.volume);
+        player.set_loudness_normalization(settings.loudness_normalization);
+        player.set_crossfade(Duration::from_millis(settings.crossfade as u64));
+
+        self.rooms.get_mut(id).expect("room exists").settings = settings;
+        self.check_start_gate(id);
+        self.close_disallowed_streams(id);
+
+        Ok(self.serialize_room(id))
+    }
+
+    /// Lets a user add to the queue, returning false if they already could
+    pub async fn add_member(
+        &self,
+        db: &Database,
+        id: &RoomId,
+        user: &User,
+    ) -> Result<bool, ApiError> {
+        let mut members = self.rooms.get(id).expect("room exists").members.clone();
+
+        if members.contains(&user.id) {
+            return Ok(false);
+        }
+
+        members.push(user.id.clone());
+        RoomData::update_members(db, id, &members).await?;
+
+        self.rooms.get_mut(id).expect("room exists").members = members;
+
+        Ok(true)
+    }
+
+    /// Makes a member a listener again, returning false if they were not a member
+    pub async fn remove_member(
+        &self,
+        db: &Database,
+        id: &RoomId,
+        user: &UserId,
+    ) -> Result<bool, ApiError> {
+        let mut members = self.rooms.get(id).expect("room exists").members.clone();
+
+        if !members.contains(user) {
+            return Ok(false);
+        }
+
+        members.retain(|m| m != user);
+        RoomData::update_members(db, id, &members).await?;
+
+        self.rooms.get_mut(id).expect("room exists").members = members;
+        self.close_disallowed_streams(id);
+
+        Ok(true)
+    }
+
+    /// Returns an error if the user can't act as `role` in the room, see [RoomData::allows]
+    pub fn check_role(
+        &self,
+        room: &RoomId,
+        user: &User,
+        role: RoomRole,
+        action: &'static str,
+    ) -> Result<(), ApiError> {
+        let room = self.rooms.get(room).ok_or(ApiError::NotFound("Room"))?;
+
+        if !room.allows(user, role) {
+            return Err(ApiError::NotAllowed(action));
+        }
+
+        Ok(())
+    }
+
+    /// Changes the volume of the room, clamped between 0 and 1
+    pub async fn set_volume(
+        &self,
+        db: &Database,
+        id: &RoomId,
+        volume: f32,
+    ) -> Result<SerializedRoom, ApiError> {
+        let volume = volume.clamp(0., 1.);
+
+        let mut settings = self.rooms.get(id).expect("room exists").settings.clone();
+        settings.volume = volume;
+
+        let room = self.update_settings(db, id, settings).await?;
+
+        self.emitter.dispatch(RoomEvent::VolumeChanged {
+            room: id.clone(),
+            volume,
+        });
+
+        Ok(room)
+    }
+
+    /// Schedules when playback starts, or clears the schedule if `start` is [None]
+    pub async fn update_schedule(
+        &self,
+        db: &Database,
+        id: &RoomId,
+        start: Option<u64>,
+    ) -> Result<SerializedRoom, ApiError> {
+        RoomData::update_schedule(db, id, start).await?;
+
+        let is_scheduled = {
+            let mut room = self.rooms.get_mut(id).expect("room exists");
+            room.scheduled_start = start;
+            room.is_scheduled()
+        };
+
+        if is_scheduled {
+            self.players
+                .get(id)
+                .expect("player exists")
+                .upgrade(&self.store())
+                .hold();
+        }
+
+        self.check_start_gate(id);
+
+        Ok(self.serialize_room(id))
+    }
+
+    /// Starts playback in rooms where the scheduled time has passed
+    fn check_schedules(&self) {
+        let ids: Vec<_> = self
+            .rooms
+            .iter()
+            .filter(|r| r.scheduled_start.is_some())
+            .map(|r| r.id.clone())
+            .collect();
+
+        for id in ids {
+            self.check_start_gate(&id);
+        }
+    }
+
+    /// Removes queue items that have been waiting for too long
+    fn remove_stale_items(&self) {
+        let store = self.store();
+
+        let rooms: Vec<_> = self
+            .rooms
+            .iter()
+            .filter(|r| r.settings.max_item_age > 0)
+            .map(|r| (r.id.clone(), r.settings.max_item_age))
+            .collect();
+
+        for (id, max_item_age) in rooms {
+            let queue = *self.queues.get(&id).expect("queue exists");
+
+            store
+                .queue_store
+                .remove_older_than(queue, Duration::from_secs(max_item_age));
+        }
     }
 
     pub(super) fn notify_disconnect(&self, id: ConnectionHandleId) {
@@ -100,6 +612,13 @@ impl RoomStore {
             .remove(&id)
             .expect("connection exists upon notify");
 
+        if let Some(count) = self.listeners.disconnect(&connection.room) {
+            self.emitter.dispatch(RoomEvent::ListenersChanged {
+                room: connection.room.clone(),
+                count,
+            });
+        }
+
         let user_not_in_room = self
             .users_in_room(&connection.room)
             .into_iter()
@@ -114,21 +633,609 @@ impl RoomStore {
     }
 
     // TODO: Fix this code when implementing proper queuing later
-    pub fn add_input(&self, user: User, room: &RoomId, input: Input) {
-        let queue = self.queues.get(room).expect("queue exists");
+    /// Adds an input to the queue, returning false if it was merged into the same one.
+    /// Duplicates are only merged if `merge_duplicates` is true, see [QueueStore::add_once].
+    ///
+    /// [QueueStore::add_once]: crate::queue::QueueStore::add_once
+    pub fn add_input(
+        &self,
+        user: User,
+        room: &RoomId,
+        input: Input,
+        merge_duplicates: bool,
+    ) -> bool {
+        let queue = *self.queues.get(room).expect("queue exists");
 
         // TODO: Make this part of the track store
         let track = InternalTrack::new(input);
+        let queue_store = &self.store().queue_store;
+        let fingerprint = track.fingerprint();
+
+        let add = || {
+            if self.priority.take(room, &user.id) {
+                queue_store.add_priority(&queue, user, vec![track.into()]);
+            } else {
+                queue_store.add(&queue, user, vec![track.into()]);
+            }
+        };
+
+        if !merge_duplicates {
+            add();
+            return true;
+        }
+
+        queue_store.add_once(queue, fingerprint, add)
+    }
+
+    /// Adds an input as a voice track, playing on top of the music
+    pub fn add_voice(&self, user: User, room: &RoomId, input: Input) {
+        let queue = *self.queues.get(room).expect("queue exists");
+        let track = InternalTrack::new(input);
 
         self.store()
             .queue_store
-            .add(&queue, user, vec![track.into()]);
+            .add_voice(queue, user, track.into());
+    }
+
+    /// Returns what was recently played in a room, most recent first
+    pub fn history(&self, room: &RoomId) -> Vec<PlayedItem> {
+        let queue = *self.queues.get(room).expect("queue exists");
+        self.store().queue_store.history.recent(queue)
+    }
+
+    /// Adds what was played since `since` back to the queue in the same order,
+    /// resolving every input again. This blocks while inputs are resolved.
+    pub fn replay_history(&self, user: User, room: &RoomId, since: u64, limit: usize) -> Replay {
+        let store = self.store();
+        let queue = *self.queues.get(room).expect("queue exists");
+
+        let mut replay = Replay::default();
+        let mut tracks = vec![];
+
+        for played in store.queue_store.history.since(queue, since) {
+            if tracks.len() == limit {
+                replay.skipped += 1;
+                continue;
+            }
+
+            let track = played.item.track();
+
+            // Settings may have changed since this was played
+            let resolved = match self.check_can_queue(room, &track.input()) {
+                Ok(_) => track.resolve_again().ok(),
+                Err(_) => None,
+            };
+
+            match resolved {
+                Some(track) => tracks.push(track.into()),
+                None => replay.skipped += 1,
+            }
+        }
+
+        replay.requeued = tracks.len();
+
+        if !tracks.is_empty() {
+            store.queue_store.add(&queue, user, tracks);
+        }
+
+        replay
+    }
+
+    /// Returns the current item and the ones after it, referring to tracks by url,
+    /// or [None] if the room does not exist
+    fn saved_queue(&self, room: &RoomId) -> Option<Vec<SnapshotItem>> {
+        let store = self.store();
+        let owner = self.rooms.get(room)?.owner.username.clone();
+        let queue = *self.queues.get(room)?;
+
+        let submitters = store.queue_store.submitters(queue);
+        let username = |id: &UserId| {
+            submitters
+                .iter()
+                .find(|u| u.id == *id)
+                .map(|u| u.username.clone())
+                .unwrap_or_else(|| owner.clone())
+        };
+
+        // Inputs that cannot be queued by url are left out
+        let items = store
+            .queue_store
+            .upcoming(queue)
+            .into_iter()
+            .filter_map(|item| {
+                Some(SnapshotItem {
+                    input: item.track().input().url()?,
+                    submitter: username(item.submitter()),
+                })
+            })
+            .collect();
+
+        Some(items)
+    }
+
+    /// Returns everything needed to recreate the room on another instance
+    pub fn export(&self, room: &RoomId) -> RoomSnapshot {
+        let data = self.rooms.get(room).expect("room exists").clone();
+        let queue = self.saved_queue(room).expect("room exists");
+
+        let priority = self
+            .priority
+            .active(room)
+            .into_iter()
+            .map(|grant| SnapshotGrant {
+                user: grant.user.username,
+                expires_at: grant.expires_at,
+                remaining_tracks: grant.remaining_tracks,
+            })
+            .collect();
+
+        RoomSnapshot {
+            version: RoomSnapshot::VERSION,
+            name: data.name.clone(),
+            owner: data.owner.username.clone(),
+            relay: data.relay.clone(),
+            settings: data.settings.clone(),
+            scheduled_start: data.scheduled_start,
+            password: data.password.clone(),
+            members: data.members.iter().map(|m| m.id.to_raw()).collect(),
+            queue,
+            priority,
+        }
+    }
+
+    /// Recreates a room from a snapshot under a new id, resolving the queue again.
+    ///
+    /// Users that do not exist on this instance are replaced by `importer`,
+    /// and priority grants and memberships for them are dropped.
+    pub async fn import(
+        &self,
+        db: &Database,
+        importer: &User,
+        snapshot: RoomSnapshot,
+    ) -> Result<RoomImport, ApiError> {
+        let mut users: HashMap<String, Option<User>> = HashMap::new();
+
+        let usernames = [&snapshot.owner]
+            .into_iter()
+            .chain(snapshot.queue.iter().map(|i| &i.submitter))
+            .chain(snapshot.priority.iter().map(|g| &g.user))
+            .chain(snapshot.members.iter());
+
+        for username in usernames {
+            if !users.contains_key(username) {
+                let user = User::get(db, username).await.ok();
+                users.insert(username.clone(), user);
+            }
+        }
+
+        let user_or_importer = |username: &String| {
+            users
+                .get(username)
+                .cloned()
+                .flatten()
+                .unwrap_or_else(|| importer.clone())
+        };
+
+        let mut data = RoomData::create(
+            db,
+            &user_or_importer(&snapshot.owner),
+            snapshot.name,
+            snapshot.relay,
+            snapshot.password,
+        )
+        .await?;
+
+        let members: Vec<_> = snapshot
+            .members
+            .iter()
+            .filter_map(|username| users.get(username).cloned().flatten())
+            .map(|user| user.id)
+            .collect();
+
+        RoomData::update_settings(db, &data.id, &snapshot.settings).await?;
+        RoomData::update_schedule(db, &data.id, snapshot.scheduled_start).await?;
+        RoomData::update_members(db, &data.id, &members).await?;
+
+        data.settings = snapshot.settings;
+        data.scheduled_start = snapshot.scheduled_start;
+        data.members = members;
+
+        let id = self.set_up_room(data);
+
+        for grant in snapshot.priority {
+            if let Some(user) = users.get(&grant.user).cloned().flatten() {
+                let grant = PriorityGrant {
+                    user,
+                    expires_at: grant.expires_at,
+                    remaining_tracks: grant.remaining_tracks,
+                };
+
+                self.priority.grant(&id, grant);
+            }
+        }
+
+        let items: Vec<_> = snapshot
+            .queue
+            .into_iter()
+            .map(|item| (user_or_importer(&item.submitter), item.input))
+            .collect();
+
+        let store = self.store();
+        let room = id.clone();
+
+        let replay = spawn_blocking(move || store.room_store.restore_queue(&room, items))
+            .await
+            .unwrap();
+
+        Ok(RoomImport {
+            room: id.id.to_string(),
+            requeued: replay.requeued,
+            skipped: replay.skipped,
+        })
+    }
+
+    /// Resolves and adds inputs to the queue in order, skipping the ones that fail.
+    /// This blocks while inputs are resolved, and only adds them once all of them are.
+    fn restore_queue(&self, room: &RoomId, items: Vec<(User, String)>) -> Replay {
+        let mut replay = Replay::default();
+        let mut resolved: Vec<(User, Vec<Track>)> = vec![];
+        let format = self.audio_format(room);
+
+        for (user, input) in items {
+            let input = Input::parse(&input, &format)
+                .ok()
+                .filter(|input| self.check_can_queue(room, input).is_ok());
+
+            let Some(input) = input else {
+                replay.skipped += 1;
+                continue;
+            };
+
+            let track = InternalTrack::new(input).into();
+            replay.requeued += 1;
+
+            // Consecutive items by the same user are added together
+            match resolved.last_mut() {
+                Some((last, tracks)) if last.id == user.id => tracks.push(track),
+                _ => resolved.push((user, vec![track])),
+            }
+        }
+
+        // The room may have been deleted while resolving
+        let Some(queue) = self.queues.get(room).map(|q| *q) else {
+            return replay;
+        };
+
+        for (user, tracks) in resolved {
+            self.store().queue_store.add(&queue, user, tracks);
+        }
+
+        replay
+    }
+
+    /// Finds a room by the id given to clients
+    pub fn find_room(&self, id: &str) -> Result<RoomId, ApiError> {
+        self.rooms
+            .iter()
+            .find(|r| r.id.id.to_string() == id)
+            .map(|r| r.id.clone())
+            .ok_or(ApiError::NotFound("Room"))
+    }
+
+    /// Finds a room by the id given to clients, like [RoomStore::find_room],
+    /// returning a copy of everything about it
+    pub fn find_room_data(&self, id: &str) -> Result<RoomData, ApiError> {
+        let room = self.find_room(id)?;
+        let data = self.rooms.get(&room).ok_or(ApiError::NotFound("Room"))?;
+
+        Ok(data.clone())
+    }
+
+    /// Returns what listeners heard most recently, as a .wav file.
+    /// This is everything the player keeps if `duration` is [None] or longer than that,
+    /// see [Player::history](crate::audio::Player::history).
+    pub fn snapshot(&self, room: &RoomId, duration: Option<Duration>) -> Vec<u8> {
+        let player = self
+            .players
+            .get(room)
+            .expect("player exists")
+            .upgrade(&self.store());
+
+        let kept = player.history();
+        let duration = duration.map_or(kept, |d| d.min(kept));
+
+        WaveStream::encode(&player.snapshot(duration))
+    }
+
+    pub fn now_playing(&self, room: &RoomId) -> NowPlaying {
+        let queue = *self.queues.get(room).expect("queue exists");
+
+        NowPlaying {
+            item: self.store().queue_store.current_item(queue),
+            label: self.now_playing.get(room).map(|o| o.clone()),
+        }
+    }
+
+    /// Returns the item playing and how far into it playback is, or [None] if nothing is playing
+    pub fn current_track(&self, room: &RoomId) -> Option<CurrentTrack> {
+        let queue = *self.queues.get(room).expect("queue exists");
+        let item = self.store().queue_store.current_item(queue)?;
+
+        let player = self
+            .players
+            .get(room)
+            .expect("player exists")
+            .upgrade(&self.store());
+
+        if player.is_held() {
+            return None;
+        }
+
+        Some(CurrentTrack {
+            item,
+            position: player.position().as_millis() as u64,
+        })
+    }
+
+    /// Skips the current item, which the queue advances past when the player reports it.
+    /// Returns false if nothing is playing.
+    pub fn skip(&self, room: &RoomId) -> bool {
+        let queue = *self.queues.get(room).expect("queue exists");
+
+        if self.store().queue_store.current_item(queue).is_none() {
+            return false;
+        }
+
+        self.store().queue_store.mark_skipped(queue);
+
+        self.players
+            .get(room)
+            .expect("player exists")
+            .upgrade(&self.store())
+            .skip();
+
+        true
+    }
+
+    /// Votes to skip the current item, which is skipped once enough listeners voted,
+    /// see [RoomSettings::skip_vote_fraction]. Returns [None] if nothing is playing.
+    pub fn vote_to_skip(&self, room: &RoomId, user: UserId) -> Option<SkipVote> {
+        let queue = *self.queues.get(room).expect("queue exists");
+        let item = self.store().queue_store.current_item(queue)?;
+
+        let fraction = self
+            .rooms
+            .get(room)
+            .expect("room exists")
+            .settings
+            .skip_vote_fraction;
+
+        let votes = self.skip_votes.vote(room, item.id(), user);
+        let required = required_votes(self.listeners.get(room), fraction);
+
+        self.emitter.dispatch(RoomEvent::SkipVoted {
+            room: room.clone(),
+            votes,
+            required,
+        });
+
+        let skipped = votes >= required && self.skip(room);
+
+        if skipped {
+            self.skip_votes.reset(room);
+        }
+
+        Some(SkipVote {
+            votes,
+            required,
+            skipped,
+        })
+    }
+
+    /// Sets what happens when the current item ends, returning false if it was already set
+    pub fn set_repeat(&self, room: &RoomId, mode: RepeatMode) -> bool {
+        let queue = *self.queues.get(room).expect("queue exists");
+        self.store().queue_store.set_repeat(queue, mode)
+    }
+
+    /// Pauses or resumes playback for everyone in the room, returning false if it already was
+    pub fn set_paused(&self, room: &RoomId, paused: bool) -> bool {
+        let player_id = *self.players.get(room).expect("player exists");
+        let player = player_id.upgrade(&self.store());
+
+        let changed = if paused {
+            player.pause()
+        } else {
+            player.resume()
+        };
+
+        if changed {
+            self.emitter.dispatch(AudioEvent::PlaybackStateChanged {
+                player: player_id,
+                paused,
+            });
+        }
+
+        changed
+    }
+
+    /// Moves playback within the current track for everyone in the room,
+    /// returning where it moved to, or [None] if nothing that can be seeked is playing
+    pub fn seek(&self, room: &RoomId, position: Duration) -> Option<Duration> {
+        let player_id = *self.players.get(room).expect("player exists");
+        let player = player_id.upgrade(&self.store());
+
+        let position = player.seek(position)?;
+
+        self.emitter.dispatch(AudioEvent::Seeked {
+            player: player_id,
+            offset: duration_to_samples(position),
+        });
+
+        Some(position)
+    }
+
+    /// Returns true if the user is connected to the room
+    pub fn is_listening(&self, room: &RoomId, user: &UserId) -> bool {
+        self.connections
+            .iter()
+            .any(|c| c.room == *room && c.user.id == *user)
+    }
+
+    /// Overrides what is shown as playing, or restores the current track if `label` is [None]
+    pub fn set_now_playing(&self, room: &RoomId, label: Option<NowPlayingOverride>) {
+        let changed = match label.clone() {
+            Some(label) => {
+                self.now_playing.insert(room.clone(), label);
+                true
+            }
+            None => self.now_playing.remove(room).is_some(),
+        };
+
+        if changed {
+            self.emitter.dispatch(RoomEvent::NowPlayingChanged {
+                room: room.clone(),
+                label,
+            });
+        }
+    }
+
+    pub fn queue_eta(&self, room: &RoomId, item: QueueItemId) -> Option<Eta> {
+        let queue = *self.queues.get(room).expect("queue exists");
+        self.store().queue_store.eta(queue, item)
+    }
+
+    pub fn queue_item(&self, room: &RoomId, item: QueueItemId) -> Option<QueueItem> {
+        let queue = *self.queues.get(room).expect("queue exists");
+        self.store().queue_store.item(queue, item)
+    }
+
+    /// Removes an item from the room's queue, returning false if it is not in it
+    pub fn remove_queue_item(&self, room: &RoomId, item: QueueItemId) -> bool {
+        let queue = *self.queues.get(room).expect("queue exists");
+        self.store().queue_store.remove(queue, item)
+    }
+
+    /// Moves an item in the room's queue, returning where it ended up
+    pub fn move_queue_item(
+        &self,
+        room: &RoomId,
+        item: QueueItemId,
+        position: usize,
+    ) -> Option<usize> {
+        let queue = *self.queues.get(room).expect("queue exists");
+        self.store().queue_store.move_item(queue, item, position)
+    }
+
+    /// Removes every item from the room's queue, except the current one if `keep_current` is true,
+    /// returning how many were removed
+    pub fn clear_queue(&self, room: &RoomId, keep_current: bool) -> usize {
+        let queue = *self.queues.get(room).expect("queue exists");
+        self.store().queue_store.clear(queue, keep_current)
+    }
+
+    /// Randomly reorders the items after the current one in the room's queue,
+    /// returning false if there was nothing to shuffle
+    pub fn shuffle_queue(&self, room: &RoomId) -> bool {
+        let queue = *self.queues.get(room).expect("queue exists");
+        self.store().queue_store.shuffle(queue, rand::random())
+    }
+
+    /// Returns which audio yt-dlp picks for tracks queued in the room, see [RoomSettings::audio_format]
+    pub fn audio_format(&self, room: &RoomId) -> AudioFormat {
+        self.rooms
+            .get(room)
+            .map(|r| r.settings.audio_format())
+            .unwrap_or_else(AudioFormat::configured)
+    }
+
+    /// Returns an error if the input cannot be queued in the room
+    pub fn check_can_queue(&self, room: &RoomId, input: &Input) -> Result<(), ApiError> {
+        if self.relays.contains_key(room) {
+            return Err(ApiError::NotAllowed("Queueing in a relay room"));
+        }
+
+        let room = self.rooms.get(room).expect("room exists");
+
+        if !room.settings.allows_source(input.source()) {
+            return Err(ApiError::NotAllowed("Queueing from this source"));
+        }
+
+        Ok(())
+    }
+
+    /// Lets users know that an input was submitted and is being resolved
+    pub fn report_resolving(&self, room: &RoomId, id: InputId, user: &User, input: String) {
+        let Some(queue) = self.queues.get(room).map(|q| *q) else {
+            return;
+        };
+
+        self.emitter.dispatch(IngestionEvent::Resolving {
+            queue,
+            id,
+            user: user.id.clone(),
+            input,
+        });
+    }
+
+    /// Lets users know that an input they submitted was resolved and queued
+    pub fn report_ready(&self, room: &RoomId, id: InputId, added: usize) {
+        let Some(queue) = self.queues.get(room).map(|q| *q) else {
+            return;
+        };
+
+        self.emitter
+            .dispatch(IngestionEvent::Ready { queue, id, added });
+    }
+
+    /// Lets users know that an input they submitted did not work.
+    /// Nothing is reported if the room was deleted in the meantime.
+    pub fn report_failure(
+        &self,
+        room: &RoomId,
+        id: Option<InputId>,
+        input: String,
+        err: &ApiError,
+    ) {
+        let Some(queue) = self.queues.get(room).map(|q| *q) else {
+            return;
+        };
+
+        self.emitter.dispatch(IngestionEvent::Failed {
+            queue,
+            id,
+            input,
+            reason: err.to_string(),
+            status: err.status().as_u16(),
+        });
     }
 
     fn store(&self) -> Arc<Store> {
         self.store.upgrade().expect("upgrade store in room manager")
     }
 
+    pub fn handler(&self) -> RoomHandler {
+        RoomHandler {
+            store: self.store.clone(),
+        }
+    }
+
+    /// Sets up a public room without storing it, so tests do not need a database
+    #[cfg(test)]
+    pub fn mock_room(&self, owner: User) -> RoomId {
+        self.set_up_room(RoomData {
+            id: User::mock("room").id,
+            name: "Room".to_string(),
+            owner,
+            relay: None,
+            settings: RoomSettings::default(),
+            scheduled_start: None,
+            password: None,
+            members: vec![],
+            created_at: 0,
+        })
+    }
+
     fn set_up_room(&self, room: RoomData) -> RoomId {
         let store = self.store();
 
@@ -137,6 +1244,25 @@ impl RoomStore {
 
         let id = room.id.clone();
 
+        if let Some(url) = &room.relay {
+            let relay = Relay::spawn(url.clone());
+
+            player.upgrade(&store).set_sinks(vec![relay.sink()]);
+            self.relays.insert(id.clone(), relay);
+        }
+
+        if room.settings.start_when_listeners > 0 || room.is_scheduled() {
+            player.upgrade(&store).hold();
+        }
+
+        let upgraded = player.upgrade(&store);
+
+        upgraded.keep_history(Duration::from_millis(room.settings.sync_latency as u64));
+        upgraded.set_normalization(room.settings.dynamic_normalization);
+        upgraded.set_volume(room.settings.volume);
+        upgraded.set_loudness_normalization(room.settings.loudness_normalization);
+        upgraded.set_crossfade(Duration::from_millis(room.settings.crossfade as u64));
+
         self.players.insert(id.clone(), player);
         self.queues.insert(id.clone(), queue);
         self.rooms.insert(id.clone(), room);
@@ -152,13 +1278,87 @@ impl RoomStore {
         let users = self.users_in_room(id);
 
         let current_queue_item = store.queue_store.current_item(*queue);
+        let private = room.is_private();
+
+        let paused = self
+            .players
+            .get(id)
+            .expect("player exists")
+            .upgrade(&store)
+            .is_paused();
 
         SerializedRoom {
             id: room.id.id.to_string(),
             name: room.name,
             owner: room.owner,
             connections: users,
+            listener_count: self.listeners.get(id),
             current_queue_item,
+            relay: room.relay,
+            members: room.members,
+            created_at: room.created_at,
+            allowed_sources: room.settings.allowed_sources(),
+            settings: room.settings,
+            scheduled_start: room.scheduled_start,
+            now_playing_override: self.now_playing.get(id).map(|o| o.clone()),
+            paused,
+            private,
+        }
+    }
+
+    /// Starts playback if it is waiting for listeners and there are enough of them,
+    /// and the scheduled start has passed.
+    fn check_start_gate(&self, id: &RoomId) {
+        let (threshold, is_scheduled) = self
+            .rooms
+            .get(id)
+            .map(|r| (r.settings.start_when_listeners, r.is_scheduled()))
+            .expect("room exists");
+
+        if is_scheduled {
+            return;
+        }
+
+        let player = self
+            .players
+            .get(id)
+            .expect("player exists")
+            .upgrade(&self.store());
+
+        if player.is_held() && self.listener_count(id) >= threshold {
+            player.release();
+
+            self.emitter
+                .dispatch(RoomEvent::PlaybackStarted { room: id.clone() });
+        }
+    }
+
+    fn listener_count(&self, id: &RoomId) -> usize {
+        self.connections.iter().filter(|c| c.room == *id).count()
+    }
+
+    /// Closes the streams of listeners who are no longer allowed to listen,
+    /// such as when the stream becomes members only
+    fn close_disallowed_streams(&self, id: &RoomId) {
+        let room = self.rooms.get(id).expect("room exists").clone();
+
+        if !room.settings.members_only_stream {
+            return;
+        }
+
+        self.connections
+            .iter()
+            .filter(|c| c.room == *id && !room.allows(&c.user, RoomRole::Member))
+            .for_each(|c| c.close());
+    }
+
+    fn serialize_room_for(&self, id: &RoomId, session: &Session) -> SerializedRoom {
+        let room = self.serialize_room(id);
+
+        if self.admits(id, &session.user, &session.joined_rooms) {
+            room
+        } else {
+            room.redacted()
         }
     }
 
@@ -171,6 +1371,21 @@ impl RoomStore {
     }
 }
 
+impl FromId<QueueId> for RoomId {
+    type Output = RoomId;
+
+    fn from_id(store: &Store, id: &QueueId) -> Option<Self::Output>
+    where
+        Self: Sized,
+    {
+        store
+            .room_store
+            .queues
+            .iter()
+            .find_map(|x| (x.value() == id).then(|| x.key().clone()))
+    }
+}
+
 impl FromId<PlayerId> for RoomId {
     type Output = RoomId;
 
@@ -185,3 +1400,74 @@ impl FromId<PlayerId> for RoomId {
             .find_map(|x| (x.value() == id).then(|| x.key().clone()))
     }
 }
+
+/// Starts rooms when their scheduled time comes
+pub fn spawn_schedule_thread(store: Weak<Store>) {
+    let run = move || loop {
+        thread::sleep(Duration::from_secs(1));
+
+        store
+            .upgrade()
+            .expect("upgrade store in schedule thread")
+            .room_store
+            .check_schedules();
+    };
+
+    thread::Builder::new()
+        .name("room_schedule".to_string())
+        .spawn(run)
+        .unwrap();
+}
+
+/// Removes queue items that are too old in rooms with a maximum item age
+pub fn spawn_stale_item_thread(store: Weak<Store>) {
+    let run = move || loop {
+        thread::sleep(Duration::from_secs(60));
+
+        store
+            .upgrade()
+            .expect("upgrade store in stale item thread")
+            .room_store
+            .remove_stale_items();
+    };
+
+    thread::Builder::new()
+        .name("room_stale_items".to_string())
+        .spawn(run)
+        .unwrap();
+}
+
+pub struct RoomHandler {
+    store: Weak<Store>,
+}
+
+impl Handler<VinylEvent> for RoomHandler {
+    type Incoming = QueueEvent;
+
+    fn handle(&self, incoming: Self::Incoming) {
+        let store = self.store.upgrade().expect("upgrade store in room handler");
+        let room_store = &store.room_store;
+
+        let queue = match &incoming {
+            QueueEvent::Update { queue, .. }
+            | QueueEvent::Advance { queue, .. }
+            | QueueEvent::Cleared { queue, .. } => *queue,
+            QueueEvent::ActivationError { .. }
+            | QueueEvent::RepeatChanged { .. }
+            | QueueEvent::Shuffled { .. } => return,
+        };
+
+        // The room was deleted along with its queue
+        let Some(room) = queue.try_upgrade_into::<RoomId>(&store) else {
+            return;
+        };
+
+        // An override is about what was playing when it was set, and votes about what was playing
+        if let QueueEvent::Advance { .. } = incoming {
+            room_store.set_now_playing(&room, None);
+            room_store.skip_votes.reset(&room);
+        }
+
+        room_store.saves.request(room);
+    }
+}
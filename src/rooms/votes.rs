@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::{auth::UserId, queue::QueueItemId};
+
+use super::RoomId;
+
+/// Where a vote to skip the current item stands, see [SkipMode::Vote](super::SkipMode::Vote)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SkipVote {
+    pub votes: usize,
+    pub required: usize,
+    /// True if the vote passed and the item was skipped
+    pub skipped: bool,
+}
+
+/// Votes to skip the current item of each room
+#[derive(Debug, Default)]
+pub struct SkipVotes {
+    votes: DashMap<RoomId, Ballot>,
+}
+
+#[derive(Debug)]
+struct Ballot {
+    item: QueueItemId,
+    voters: HashSet<UserId>,
+}
+
+impl SkipVotes {
+    /// Registers a vote to skip `item`, returning how many votes it has.
+    /// Votes for an item that is no longer current are thrown away.
+    pub fn vote(&self, room: &RoomId, item: QueueItemId, user: UserId) -> usize {
+        let mut ballot = self.votes.entry(room.clone()).or_insert_with(|| Ballot {
+            item,
+            voters: HashSet::new(),
+        });
+
+        if ballot.item != item {
+            ballot.item = item;
+            ballot.voters.clear();
+        }
+
+        ballot.voters.insert(user);
+        ballot.voters.len()
+    }
+
+    /// Forgets the votes in a room, such as when the current item changes
+    pub fn reset(&self, room: &RoomId) {
+        self.votes.remove(room);
+    }
+}
+
+/// Returns how many votes it takes to skip, with `listeners` listening.
+/// At least one vote is always needed.
+pub fn required_votes(listeners: usize, fraction: f32) -> usize {
+    ((listeners as f32 * fraction).ceil() as usize).max(1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{required_votes, SkipVotes};
+    use crate::{auth::User, store::Id};
+
+    #[test]
+    fn counts_votes_per_item() {
+        let votes = SkipVotes::default();
+        let room = User::mock("room").id;
+        let item = Id::new();
+
+        assert_eq!(votes.vote(&room, item, User::mock("john").id), 1);
+        assert_eq!(votes.vote(&room, item, User::mock("mary").id), 2);
+
+        // Voting twice does not count
+        assert_eq!(votes.vote(&room, item, User::mock("john").id), 2);
+
+        // A vote for the next item starts over
+        assert_eq!(votes.vote(&room, Id::new(), User::mock("john").id), 1);
+
+        votes.reset(&room);
+        assert_eq!(votes.vote(&room, item, User::mock("mary").id), 1);
+    }
+
+    #[test]
+    fn requires_a_fraction_of_listeners() {
+        assert_eq!(required_votes(3, 0.5), 2);
+        assert_eq!(required_votes(4, 0.5), 2);
+        assert_eq!(required_votes(3, 1.), 3);
+        assert_eq!(required_votes(0, 0.5), 1);
+    }
+}
@@ -0,0 +1,169 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::Response,
+    routing::{get, post},
+    Json,
+};
+use hyper::StatusCode;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    audio::WaveStream,
+    auth::Superuser,
+    rooms::{RoomImport, RoomSnapshot},
+    util::ApiError,
+};
+
+use super::{Context, Router, ServerEvent, Severity};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/broadcast", post(broadcast_announcement))
+        .route("/rooms/:id/snapshot", get(get_room_snapshot))
+        .route("/rooms/:id/export", get(export_room))
+        .route("/rooms/import", post(import_room))
+        .route("/state", get(get_state))
+}
+
+#[derive(Deserialize)]
+struct AnnouncementBody {
+    message: String,
+    #[serde(default)]
+    severity: Severity,
+}
+
+/// Sends a message to every connected client, to be shown as a banner
+async fn broadcast_announcement(
+    Superuser(session): Superuser,
+    State(context): Context,
+    Json(body): Json<AnnouncementBody>,
+) -> Result<StatusCode, ApiError> {
+    const MAX_MESSAGE_LENGTH: usize = 500;
+
+    let message = body.message.trim().to_string();
+
+    if message.is_empty() || message.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(ApiError::Invalid("Message"));
+    }
+
+    info!(target: "vinyl::server",
+        "{} broadcasted an announcement: {}",
+        session.user.username, message
+    );
+
+    context.emitter.dispatch(ServerEvent::Announcement {
+        message,
+        severity: body.severity,
+    });
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Deserialize)]
+struct SnapshotQuery {
+    seconds: Option<u64>,
+}
+
+/// Returns the last few seconds a room played as a .wav file, to debug what listeners hear.
+///
+/// Rooms only keep a second of what they played, or the sync latency if it is longer,
+/// so longer snapshots are clamped to that. Without `seconds`, all of it is returned.
+async fn get_room_snapshot(
+    Superuser(session): Superuser,
+    State(context): Context,
+    Path(id): Path<String>,
+    Query(query): Query<SnapshotQuery>,
+) -> Result<Response<hyper::Body>, ApiError> {
+    const MAX_SECONDS: u64 = 10;
+
+    if query
+        .seconds
+        .is_some_and(|seconds| seconds == 0 || seconds > MAX_SECONDS)
+    {
+        return Err(ApiError::Invalid("Snapshot length"));
+    }
+
+    let room = context.store.room_store.find_room(&id)?;
+
+    let wave = context
+        .store
+        .room_store
+        .snapshot(&room, query.seconds.map(Duration::from_secs));
+
+    info!(target: "vinyl::server",
+        "{} took a snapshot of room {}",
+        session.user.username, id
+    );
+
+    let content_disposition = format!(
+        "attachment; filename=\"{}-snapshot.{}\"",
+        room.id,
+        WaveStream::EXTENSION
+    );
+
+    let response = Response::builder()
+        .status(200)
+        .header("Content-Type", WaveStream::MIME)
+        .header("Cache-Control", "no-store")
+        .header("Content-Disposition", content_disposition)
+        .body(wave.into())
+        .unwrap();
+
+    Ok(response)
+}
+
+/// Returns the room, its settings and queue as JSON, to move it to another instance
+async fn export_room(
+    Superuser(session): Superuser,
+    State(context): Context,
+    Path(id): Path<String>,
+) -> Result<Json<RoomSnapshot>, ApiError> {
+    let room = context.store.room_store.find_room(&id)?;
+
+    info!(target: "vinyl::server",
+        "{} exported room {}",
+        session.user.username, id
+    );
+
+    Ok(Json(context.store.room_store.export(&room)))
+}
+
+/// Creates a room from an exported snapshot, see [RoomSnapshot]
+async fn import_room(
+    Superuser(session): Superuser,
+    State(context): Context,
+    Json(body): Json<Value>,
+) -> Result<(StatusCode, Json<RoomImport>), ApiError> {
+    let snapshot = RoomSnapshot::from_value(body)?;
+
+    let import = context
+        .store
+        .room_store
+        .import(&context.db, &session.user, snapshot)
+        .await?;
+
+    info!(target: "vinyl::server",
+        "{} imported room {}, skipping {} items",
+        session.user.username, import.room, import.skipped
+    );
+
+    Ok((StatusCode::CREATED, Json(import)))
+}
+
+/// Internal state that helps operators find bottlenecks
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AdminState {
+    /// Events waiting to be handled, which should stay close to 0
+    event_backlog: usize,
+}
+
+async fn get_state(_: Superuser, State(context): Context) -> Json<AdminState> {
+    Json(AdminState {
+        event_backlog: context.emitter.backlog(),
+    })
+}
@@ -0,0 +1,93 @@
+use std::env;
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Which origins browsers may send requests from, set with `VINYL_CORS_ORIGINS`
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorsOrigins {
+    /// Only the origin the server is on, which needs no CORS headers at all
+    SameOrigin,
+    /// Any origin, set with `*`
+    Any,
+    /// A comma-separated list of origins, like `https://vinyl.example.com`
+    List(Vec<HeaderValue>),
+}
+
+impl CorsOrigins {
+    fn parse(value: &str) -> Self {
+        let value = value.trim();
+
+        if value == "*" {
+            return Self::Any;
+        }
+
+        let origins: Vec<_> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                HeaderValue::from_str(x.trim_end_matches('/'))
+                    .expect("CORS origins must be valid header values")
+            })
+            .collect();
+
+        if origins.is_empty() {
+            Self::SameOrigin
+        } else {
+            Self::List(origins)
+        }
+    }
+}
+
+pub fn cors_origins() -> CorsOrigins {
+    env::var("VINYL_CORS_ORIGINS")
+        .map(|x| CorsOrigins::parse(&x))
+        .unwrap_or(CorsOrigins::SameOrigin)
+}
+
+/// Answers preflight requests, and allows the headers used for auth and event streams
+pub fn cors_layer(origins: CorsOrigins) -> CorsLayer {
+    let layer = CorsLayer::new()
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::ACCEPT,
+            HeaderName::from_static("last-event-id"),
+        ])
+        .expose_headers([header::RETRY_AFTER, header::CONTENT_DISPOSITION]);
+
+    match origins {
+        CorsOrigins::SameOrigin => layer,
+        CorsOrigins::Any => layer.allow_origin(AllowOrigin::any()),
+        CorsOrigins::List(origins) => layer.allow_origin(AllowOrigin::list(origins)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+
+    use super::CorsOrigins;
+
+    #[test]
+    fn parses_origins() {
+        assert_eq!(CorsOrigins::parse("*"), CorsOrigins::Any);
+        assert_eq!(CorsOrigins::parse(" "), CorsOrigins::SameOrigin);
+
+        assert_eq!(
+            CorsOrigins::parse("https://a.example.com/, http://localhost:3000"),
+            CorsOrigins::List(vec![
+                HeaderValue::from_static("https://a.example.com"),
+                HeaderValue::from_static("http://localhost:3000"),
+            ])
+        );
+    }
+}
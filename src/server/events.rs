@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events::{Filter, IntoEvent},
+    VinylEvent,
+};
+
+/// Events concerning the whole server, rather than a single room
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// A message from a superuser to everyone, like a maintenance notice
+    Announcement { message: String, severity: Severity },
+}
+
+/// How clients should style an announcement
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+}
+
+impl IntoEvent<VinylEvent> for ServerEvent {
+    fn into_event(self) -> VinylEvent {
+        VinylEvent::Server(self)
+    }
+}
+
+impl Filter<VinylEvent> for ServerEvent {
+    fn filter(event: VinylEvent) -> Option<Self> {
+        match event {
+            VinylEvent::Server(x) => Some(x),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::{env, fmt::Display, fmt::Write, sync::Arc};
+
+use axum::{extract::State, http::header, response::IntoResponse, routing::get};
+use crossbeam::atomic::AtomicCell;
+
+use crate::{events::Handler, ingest, ingest::IngestionEvent, store::Store, VinylEvent};
+
+use super::{Context, Router};
+
+/// Returns true if `/metrics` is served, set with `VINYL_METRICS`
+pub fn metrics_enabled() -> bool {
+    env::var("VINYL_METRICS")
+        .map(|x| x.parse::<bool>().expect("Metrics must be true or false"))
+        .unwrap_or(false)
+}
+
+pub fn router() -> Router {
+    Router::new().route("/", get(get_metrics))
+}
+
+/// Counts what flows through the event bus, see [Metrics::render]
+#[derive(Debug, Default)]
+pub struct Metrics {
+    room_events: AtomicCell<u64>,
+    audio_events: AtomicCell<u64>,
+    queue_events: AtomicCell<u64>,
+    ingestion_events: AtomicCell<u64>,
+    server_events: AtomicCell<u64>,
+    ingest_failures: AtomicCell<u64>,
+}
+
+pub struct MetricsHandler {
+    metrics: Arc<Metrics>,
+}
+
+impl Metrics {
+    pub fn handler(self: &Arc<Self>) -> MetricsHandler {
+        MetricsHandler {
+            metrics: self.clone(),
+        }
+    }
+
+    fn record(&self, event: &VinylEvent) {
+        let counter = match event {
+            VinylEvent::Room(_) => &self.room_events,
+            VinylEvent::Audio(_) => &self.audio_events,
+            VinylEvent::Queue(_) => &self.queue_events,
+            VinylEvent::Ingestion(_) => &self.ingestion_events,
+            VinylEvent::Server(_) => &self.server_events,
+        };
+
+        counter.fetch_add(1);
+
+        if let VinylEvent::Ingestion(IngestionEvent::Failed { .. }) = event {
+            self.ingest_failures.fetch_add(1);
+        }
+    }
+
+    /// Returns the counters, and gauges read from the store, in the Prometheus text format
+    pub fn render(&self, store: &Store) -> String {
+        let mut out = Exposition::default();
+
+        out.family("vinyl_rooms", "gauge", "Rooms that exist");
+        out.sample("vinyl_rooms", &[], store.room_store.room_count());
+
+        out.family(
+            "vinyl_listeners",
+            "gauge",
+            "Open stream connections across every room",
+        );
+        out.sample("vinyl_listeners", &[], store.room_store.connection_count());
+
+        out.family(
+            "vinyl_queue_length",
+            "gauge",
+            "Upcoming items in the queue of each room",
+        );
+        for (room, queue) in store.room_store.queues() {
+            let length = store.queue_store.upcoming(queue).len();
+            out.sample(
+                "vinyl_queue_length",
+                &[("room", &room.id.to_string())],
+                length,
+            );
+        }
+
+        out.family("vinyl_ingest_jobs", "gauge", "yt-dlp processes running");
+        out.sample("vinyl_ingest_jobs", &[], ingest::ytdlp_jobs());
+
+        out.family(
+            "vinyl_ingest_jobs_waiting",
+            "gauge",
+            "yt-dlp processes waiting for others to finish",
+        );
+        out.sample(
+            "vinyl_ingest_jobs_waiting",
+            &[],
+            ingest::ytdlp_jobs_waiting(),
+        );
+
+        if let Some(cache) = &store.ingestion.cache {
+            out.family(
+                "vinyl_cache_bytes",
+                "gauge",
+                "Size of the audio cache on disk",
+            );
+            out.sample("vinyl_cache_bytes", &[], cache.size());
+        }
+
+        self.render_counters(&mut out);
+        out.0
+    }
+
+    fn render_counters(&self, out: &mut Exposition) {
+        out.family(
+            "vinyl_ingest_failures_total",
+            "counter",
+            "Inputs that could not be parsed or activated",
+        );
+        out.sample(
+            "vinyl_ingest_failures_total",
+            &[],
+            self.ingest_failures.load(),
+        );
+
+        out.family(
+            "vinyl_events_total",
+            "counter",
+            "Events dispatched through the event bus",
+        );
+
+        let events = [
+            ("room", &self.room_events),
+            ("audio", &self.audio_events),
+            ("queue", &self.queue_events),
+            ("ingestion", &self.ingestion_events),
+            ("server", &self.server_events),
+        ];
+
+        for (kind, count) in events {
+            out.sample("vinyl_events_total", &[("kind", kind)], count.load());
+        }
+    }
+}
+
+impl Handler<VinylEvent> for MetricsHandler {
+    type Incoming = VinylEvent;
+
+    fn handle(&self, incoming: Self::Incoming) {
+        self.metrics.record(&incoming);
+    }
+}
+
+/// Writes metrics in the Prometheus text exposition format
+#[derive(Debug, Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let _ = write!(self.0, "{}", name);
+
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+                .collect();
+
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+async fn get_metrics(State(context): Context) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        context.metrics.render(&context.store),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{Exposition, Metrics};
+    use crate::{
+        events::Handler,
+        ingest::IngestionEvent,
+        server::{ServerEvent, Severity},
+        store::Id,
+        VinylEvent,
+    };
+
+    #[test]
+    fn counts_events() {
+        let metrics = Arc::new(Metrics::default());
+        let handler = metrics.handler();
+
+        handler.handle(VinylEvent::Ingestion(IngestionEvent::Failed {
+            queue: Id::new(),
+            id: None,
+            input: "nothing".to_string(),
+            reason: "Not found".to_string(),
+            status: 404,
+        }));
+        handler.handle(VinylEvent::Server(ServerEvent::Announcement {
+            message: "hello".to_string(),
+            severity: Severity::Info,
+        }));
+
+        let mut out = Exposition::default();
+        metrics.render_counters(&mut out);
+
+        assert!(out.0.contains("vinyl_ingest_failures_total 1\n"));
+        assert!(out.0.contains("vinyl_events_total{kind=\"ingestion\"} 1\n"));
+        assert!(out.0.contains("vinyl_events_total{kind=\"server\"} 1\n"));
+        assert!(out.0.contains("vinyl_events_total{kind=\"room\"} 0\n"));
+        assert!(out.0.contains("# TYPE vinyl_events_total counter\n"));
+    }
+
+    #[test]
+    fn escapes_labels() {
+        let mut out = Exposition::default();
+        out.sample("vinyl_queue_length", &[("room", "a\"b\\c")], 2);
+
+        assert_eq!(out.0, "vinyl_queue_length{room=\"a\\\"b\\\\c\"} 2\n");
+    }
+}
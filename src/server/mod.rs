@@ -2,41 +2,272 @@ use axum::{extract::State, Router as AxumRouter};
 use std::{
     env,
     net::{Ipv6Addr, SocketAddr},
+    time::Duration,
 };
-use tower_http::cors::{Any, CorsLayer};
 
-use crate::{auth, rooms, VinylContext};
+use log::info;
+use tokio::{signal, time::sleep};
 
+use crate::{
+    aliases, auth,
+    auth::UserId,
+    favorites, queue, rooms,
+    store::Store,
+    util::limit::{Cooldown, RateLimiter},
+    VinylContext,
+};
+
+mod admin;
+mod cors;
+mod events;
+mod metrics;
 pub mod sse;
 
+use sse::SseManager;
+
+pub use cors::*;
+pub use events::*;
+pub use metrics::{metrics_enabled, Metrics};
+
 pub const DEFAULT_PORT: u16 = 9050;
+
+/// How long streams may keep going after a shutdown was requested, before they are closed
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
 pub type Router = AxumRouter<VinylContext>;
 pub type Context = State<VinylContext>;
 
-pub async fn run_server(context: VinylContext) {
-    let port = env::var("VINYL_SERVER_PORT")
+/// Rate limiters shared between handlers
+#[derive(Debug)]
+pub struct Limits {
+    /// Playback error reports sent by clients
+    pub stream_reports: RateLimiter<UserId>,
+
+    /// Attempts at joining private rooms, so their passwords can't be guessed quickly
+    pub room_joins: RateLimiter<UserId>,
+
+    /// Inputs added by each user, since adding one can spawn yt-dlp.
+    /// This is disabled if `VINYL_ADD_RATE_LIMIT` is 0.
+    pub adds: Option<RateLimiter<UserId>>,
+
+    /// Prevents users from adding the same input over and over,
+    /// and merges adds of what is already waiting to play in the queue.
+    /// This is disabled unless `VINYL_DUPLICATE_COOLDOWN` is set.
+    pub duplicate_adds: Option<Cooldown<(UserId, String)>>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            stream_reports: RateLimiter::new(10, Duration::from_secs(60)),
+            room_joins: RateLimiter::new(10, Duration::from_secs(60)),
+            adds: add_limiter(add_rate_limit()),
+            duplicate_adds: duplicate_cooldown().map(Cooldown::new),
+        }
+    }
+}
+
+/// Returns how long users have to wait to add the same input again, if at all
+pub fn duplicate_cooldown() -> Option<Duration> {
+    env::var("VINYL_DUPLICATE_COOLDOWN")
+        .map(|x| {
+            x.parse::<u64>()
+                .expect("Cooldown must be a number of seconds")
+        })
+        .ok()
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Returns how many inputs each user can add per minute, or 0 if unlimited
+pub fn add_rate_limit() -> u32 {
+    const DEFAULT_ADD_RATE_LIMIT: u32 = 30;
+
+    env::var("VINYL_ADD_RATE_LIMIT")
+        .map(|x| {
+            x.parse::<u32>()
+                .expect("Add rate limit must be a number of additions per minute")
+        })
+        .unwrap_or(DEFAULT_ADD_RATE_LIMIT)
+}
+
+fn add_limiter(per_minute: u32) -> Option<RateLimiter<UserId>> {
+    (per_minute > 0).then(|| RateLimiter::new(per_minute, Duration::from_secs(60)))
+}
+
+/// Returns the port to listen on, set with `VINYL_SERVER_PORT`
+pub fn port() -> u16 {
+    env::var("VINYL_SERVER_PORT")
         .map(|x| x.parse::<u16>().expect("Port must be a number"))
-        .unwrap_or(DEFAULT_PORT);
+        .unwrap_or(DEFAULT_PORT)
+}
 
-    let addr = (Ipv6Addr::UNSPECIFIED, port).into();
+pub async fn run_server(context: VinylContext) {
+    let addr = (Ipv6Addr::UNSPECIFIED, port()).into();
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    let cors = cors_layer(cors_origins());
 
     let version_one_router = AxumRouter::new()
         .nest("/auth", auth::router())
         .nest("/events", sse::router())
-        .nest("/rooms", rooms::router());
+        .nest("/rooms", rooms::router())
+        .nest("/queue", queue::router())
+        .nest("/favorites", favorites::router())
+        .nest("/aliases", aliases::router())
+        .nest("/admin", admin::router());
+
+    let mut router = AxumRouter::new().nest("/v1", version_one_router);
 
-    let router = AxumRouter::new()
-        .nest("/v1", version_one_router)
-        .with_state(context)
-        .layer(cors);
+    if metrics_enabled() {
+        router = router.nest("/metrics", metrics::router());
+    }
+
+    let router = router.with_state(context.clone()).layer(cors);
 
     axum::Server::bind(&addr)
         .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal(context))
         .await
         .unwrap();
 }
+
+/// Waits for SIGINT or SIGTERM, then closes every stream after [SHUTDOWN_GRACE]
+/// so the server can finish. Playback keeps going until then, so listeners are not
+/// left waiting for audio, and background loops are stopped once the server is done.
+async fn shutdown_signal(context: VinylContext) {
+    let interrupt = async {
+        signal::ctrl_c().await.expect("listen for interrupt");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("listen for terminate")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+
+    info!(target: "vinyl::server",
+        "Shutting down, closing streams in {:?}...", SHUTDOWN_GRACE
+    );
+
+    tokio::spawn(async move {
+        sleep(SHUTDOWN_GRACE).await;
+        close_streams(&context.store, &context.sse);
+    });
+}
+
+/// Ends every room stream and event stream, including ones waiting for audio
+fn close_streams(store: &Store, sse: &SseManager) {
+    store.room_store.close_all_connections();
+    sse.close_all();
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{routing::get, Router as AxumRouter};
+    use hyper::Response;
+    use parking_lot::Mutex;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        runtime,
+        sync::oneshot,
+        time::{sleep, timeout},
+    };
+
+    use super::{add_limiter, close_streams, SseManager};
+    use crate::{
+        audio::Encoding, auth::User, events::Channel, rooms::Transport, store::Store, EventBus,
+    };
+
+    #[test]
+    fn shutdown_ends_waiting_listeners() {
+        let runtime = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let event_bus = EventBus::new(Channel::new());
+            let store = Store::new(event_bus.emitter());
+            let sse = SseManager::new(Arc::downgrade(&store));
+            let room = store.room_store.mock_room(User::mock("owner"));
+
+            let connection = store
+                .room_store
+                .connect(
+                    User::mock("listener"),
+                    &room,
+                    Transport::Http(Encoding::Wave, None),
+                )
+                .unwrap();
+
+            let connection = Arc::new(Mutex::new(Some(connection)));
+            let app = AxumRouter::new().route(
+                "/",
+                get(move || {
+                    let connection = connection.lock().take().expect("one listener connects");
+                    async move { Response::new(hyper::Body::wrap_stream(connection)) }
+                }),
+            );
+
+            let (stop, stopped) = oneshot::channel::<()>();
+            let server =
+                axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(app.into_make_service());
+
+            let address = server.local_addr();
+            let server = tokio::spawn(server.with_graceful_shutdown(async {
+                stopped.await.ok();
+            }));
+
+            // Playback is not running, so the listener waits for audio that never comes
+            let mut listener = TcpStream::connect(address).await.unwrap();
+            listener
+                .write_all(b"GET / HTTP/1.1\r\nHost: vinyl\r\n\r\n")
+                .await
+                .unwrap();
+
+            let mut response = [0; 12];
+            listener.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, b"HTTP/1.1 200");
+
+            // Like the grace period, streams are only closed a while after the server stops
+            stop.send(()).unwrap();
+            sleep(Duration::from_millis(100)).await;
+            close_streams(&store, &sse);
+
+            timeout(Duration::from_secs(5), server)
+                .await
+                .expect("server finishes while a listener is connected")
+                .unwrap()
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn limits_adds_per_user() {
+        let limiter = add_limiter(3).unwrap();
+        let user = User::mock("user").id;
+
+        for _ in 0..3 {
+            assert!(limiter.check(user.clone()).is_ok());
+        }
+
+        let retry_after = limiter.check(user).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(20));
+
+        // Other users have their own limit
+        assert!(limiter.check(User::mock("other").id).is_ok());
+        assert!(add_limiter(0).is_none());
+    }
+}
@@ -1,79 +1,348 @@
 use std::{
+    collections::VecDeque,
     convert::Infallible,
+    env,
+    future::poll_fn,
     pin::Pin,
     sync::{Arc, Weak},
     task::{Context, Poll, Waker},
+    time::Duration,
 };
 
 use axum::{
-    extract::State,
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::HeaderMap,
     response::{
         sse::{Event, KeepAlive},
-        Sse,
+        Response, Sse,
     },
     routing::get,
 };
+use crossbeam::atomic::AtomicCell;
 use futures_util::Stream;
 use parking_lot::Mutex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    audio::{AudioEvent, SAMPLE_RATE},
+    audio::{AudioEvent, SAMPLES_PER_SEC},
     auth::{Session, User, UserId},
     events::Handler,
-    queue::{QueueEvent, QueueId, QueueItem, SerializedQueue},
-    rooms::{RoomEvent, RoomId},
+    ingest::{IngestionEvent, InputId},
+    queue::{QueueEvent, QueueId, QueueItem, QueueItemId, QueuePatch, RepeatMode, SerializedQueue},
+    rooms::{NowPlayingOverride, RoomEvent, RoomId, RoomStore},
+    server::{ServerEvent, Severity},
     store::Store,
     track::TrackId,
-    util::ID_COUNTER,
-    VinylEvent,
+    util::{unix_millis, ApiError, ID_COUNTER},
+    VinylContext, VinylEvent,
 };
 
 use super::Router;
 
+/// Every message is sent wrapped in an [Envelope], with a stable type string.
+/// See [Message::kind] for the list of types.
 #[derive(Debug, Serialize, Clone)]
-#[serde(tag = "type")]
-#[serde(rename_all = "kebab-case")]
+#[serde(untagged)]
 enum Message {
     /// A new user connected the room stream
-    UserEnteredRoom {
-        user: User,
+    UserEnteredRoom { user: User, room: RoomId },
+    /// A user disconnected from the room stream
+    UserLeftRoom { user: UserId, room: RoomId },
+    /// Playback started after waiting for enough listeners
+    RoomPlaybackStarted { room: RoomId },
+    /// Playback was paused or resumed for everyone in the room
+    RoomPlaybackState { room: RoomId, paused: bool },
+    /// A stream connection to the room was opened or closed
+    RoomListeners { room: RoomId, count: usize },
+    /// The room was given a new name
+    RoomRenamed { room: RoomId, name: String },
+    /// The room was deleted, and every stream of it was closed
+    RoomDeleted { room: RoomId },
+    /// The volume of the room changed, between 0 and 1
+    RoomVolume { room: RoomId, volume: f32 },
+    /// Someone voted to skip the current track, which is skipped once `votes` reaches `required`
+    RoomSkipVotes {
         room: RoomId,
+        votes: usize,
+        required: usize,
     },
-    /// A user disconnected from the room stream
-    UserLeftRoom {
-        user: UserId,
+    /// What is shown as playing was overridden, or restored if the override is null
+    RoomNowPlaying {
         room: RoomId,
+        #[serde(rename = "override")]
+        label: Option<NowPlayingOverride>,
     },
     /// The current track in a room changed
     QueueAdvance {
+        room: RoomId,
         queue: QueueId,
         item: QueueItem,
     },
-    QueueUpdate(SerializedQueue),
+    /// The full queue, sent instead of patches unless they were asked for
+    QueueUpdate {
+        room: RoomId,
+        #[serde(flatten)]
+        queue: SerializedQueue,
+    },
+    /// What changed in the queue, for connections that asked for patches.
+    ///
+    /// Clients that miss a sequence should get the queue again from `GET /rooms/:id/queue`.
+    QueuePatch {
+        room: RoomId,
+        #[serde(flatten)]
+        patch: QueuePatch,
+    },
+    /// What happens when the current track in a room ends was changed
+    QueueRepeat {
+        room: RoomId,
+        queue: QueueId,
+        mode: RepeatMode,
+    },
+    /// Every item in a room was removed at once, except `kept` if it is not null
+    QueueClear {
+        room: RoomId,
+        queue: QueueId,
+        removed: Vec<QueueItemId>,
+        kept: Option<QueueItemId>,
+    },
+    /// The upcoming items in a room were shuffled, `order` has their ids in the new order
+    QueueShuffle {
+        room: RoomId,
+        queue: QueueId,
+        order: Vec<QueueItemId>,
+    },
     /// Scheduler read a sink and set a new offset
     PlayerTime {
         room: RoomId,
         seconds: f32,
         total_seconds: f32,
     },
+    /// Someone moved playback within the current track, which is now `seconds` in.
+    /// Remote tracks are loaded from the start, so after seeking ahead of what was loaded,
+    /// playback stays silent until ingestion gets there.
+    PlayerSeek { room: RoomId, seconds: f32 },
     /// Track activation failed
     TrackActivationError {
+        room: RoomId,
         queue: QueueId,
         track: TrackId,
     },
+    /// A submitted input is being resolved, see `POST /rooms/:id/queue`
+    InputResolving {
+        room: RoomId,
+        id: InputId,
+        user: UserId,
+        input: String,
+    },
+    /// A submitted input was resolved, and `added` tracks of it were queued
+    InputReady {
+        room: RoomId,
+        id: InputId,
+        added: usize,
+    },
+    /// An input did not work. `id` is null if it failed after it was queued, when it was activated.
+    /// `status` tells why, e.g. 404 if unavailable, 403 if private, 451 if region blocked, 502 if the source could not be reached
+    InputFailed {
+        room: RoomId,
+        id: Option<InputId>,
+        input: String,
+        reason: String,
+        status: u16,
+    },
+    /// A message from a superuser that should be shown as a banner
+    ServerAnnouncement { message: String, severity: Severity },
+    /// Messages were missed while reconnecting, and can no longer be replayed.
+    /// Clients should fetch everything they show again.
+    ResyncRequired,
 }
 
+impl Message {
+    /// Returns the type string clients use to tell messages apart.
+    ///
+    /// These must never change, clients should ignore types they do not know.
+    ///
+    /// | Type                      | Message                           |
+    /// |---------------------------|-----------------------------------|
+    /// | `room.user_entered`       | [Message::UserEnteredRoom]        |
+    /// | `room.user_left`          | [Message::UserLeftRoom]           |
+    /// | `room.playback_started`   | [Message::RoomPlaybackStarted]    |
+    /// | `room.playback_state`     | [Message::RoomPlaybackState]      |
+    /// | `room.listeners`          | [Message::RoomListeners]          |
+    /// | `room.renamed`            | [Message::RoomRenamed]            |
+    /// | `room.deleted`            | [Message::RoomDeleted]            |
+    /// | `room.volume`             | [Message::RoomVolume]             |
+    /// | `room.skip_votes`         | [Message::RoomSkipVotes]          |
+    /// | `room.now_playing`        | [Message::RoomNowPlaying]         |
+    /// | `queue.advanced`          | [Message::QueueAdvance]           |
+    /// | `queue.updated`           | [Message::QueueUpdate]            |
+    /// | `queue.patched`           | [Message::QueuePatch]             |
+    /// | `queue.repeat`            | [Message::QueueRepeat]            |
+    /// | `queue.cleared`           | [Message::QueueClear]             |
+    /// | `queue.shuffled`          | [Message::QueueShuffle]           |
+    /// | `player.time`             | [Message::PlayerTime]             |
+    /// | `player.seeked`           | [Message::PlayerSeek]             |
+    /// | `track.activation_failed` | [Message::TrackActivationError]   |
+    /// | `input.resolving`         | [Message::InputResolving]         |
+    /// | `input.ready`             | [Message::InputReady]             |
+    /// | `input.failed`            | [Message::InputFailed]            |
+    /// | `server.announcement`     | [Message::ServerAnnouncement]     |
+    /// | `stream.resync_required`  | [Message::ResyncRequired]         |
+    fn kind(&self) -> &'static str {
+        match self {
+            Message::UserEnteredRoom { .. } => "room.user_entered",
+            Message::UserLeftRoom { .. } => "room.user_left",
+            Message::RoomPlaybackStarted { .. } => "room.playback_started",
+            Message::RoomPlaybackState { .. } => "room.playback_state",
+            Message::RoomListeners { .. } => "room.listeners",
+            Message::RoomRenamed { .. } => "room.renamed",
+            Message::RoomDeleted { .. } => "room.deleted",
+            Message::RoomVolume { .. } => "room.volume",
+            Message::RoomSkipVotes { .. } => "room.skip_votes",
+            Message::RoomNowPlaying { .. } => "room.now_playing",
+            Message::QueueAdvance { .. } => "queue.advanced",
+            Message::QueueUpdate { .. } => "queue.updated",
+            Message::QueuePatch { .. } => "queue.patched",
+            Message::QueueRepeat { .. } => "queue.repeat",
+            Message::QueueClear { .. } => "queue.cleared",
+            Message::QueueShuffle { .. } => "queue.shuffled",
+            Message::PlayerTime { .. } => "player.time",
+            Message::PlayerSeek { .. } => "player.seeked",
+            Message::TrackActivationError { .. } => "track.activation_failed",
+            Message::InputResolving { .. } => "input.resolving",
+            Message::InputReady { .. } => "input.ready",
+            Message::InputFailed { .. } => "input.failed",
+            Message::ServerAnnouncement { .. } => "server.announcement",
+            Message::ResyncRequired => "stream.resync_required",
+        }
+    }
+
+    /// Returns the version of the data for this message.
+    ///
+    /// This is increased when the data changes in a way existing clients cannot handle.
+    fn version(&self) -> u32 {
+        1
+    }
+
+    /// Returns the room this is about, or [None] if it is for everyone
+    fn room(&self) -> Option<&RoomId> {
+        match self {
+            Message::UserEnteredRoom { room, .. }
+            | Message::UserLeftRoom { room, .. }
+            | Message::RoomPlaybackStarted { room }
+            | Message::RoomPlaybackState { room, .. }
+            | Message::RoomListeners { room, .. }
+            | Message::RoomRenamed { room, .. }
+            | Message::RoomDeleted { room }
+            | Message::RoomVolume { room, .. }
+            | Message::RoomSkipVotes { room, .. }
+            | Message::RoomNowPlaying { room, .. }
+            | Message::QueueAdvance { room, .. }
+            | Message::QueueUpdate { room, .. }
+            | Message::QueuePatch { room, .. }
+            | Message::QueueRepeat { room, .. }
+            | Message::QueueClear { room, .. }
+            | Message::QueueShuffle { room, .. }
+            | Message::PlayerTime { room, .. }
+            | Message::PlayerSeek { room, .. }
+            | Message::TrackActivationError { room, .. }
+            | Message::InputResolving { room, .. }
+            | Message::InputReady { room, .. }
+            | Message::InputFailed { room, .. } => Some(room),
+            Message::ServerAnnouncement { .. } | Message::ResyncRequired => None,
+        }
+    }
+
+    fn envelope(&self) -> Envelope<'_> {
+        Envelope {
+            kind: self.kind(),
+            version: self.version(),
+            data: self,
+        }
+    }
+}
+
+/// The shape every message is sent in, `{ "type", "version", "data" }`
+#[derive(Debug, Serialize)]
+struct Envelope<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    version: u32,
+    data: &'a Message,
+}
+
+/// An [Envelope] sent over a WebSocket, which has no event ids of its own
+#[derive(Debug, Serialize)]
+struct WsFrame<'a> {
+    /// Resumes from this message when reconnecting, see [ws_stream]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(flatten)]
+    envelope: Envelope<'a>,
+}
+
+#[derive(Clone)]
 pub enum Recipients {
     All,
     Superuser,
     Some(Vec<UserId>),
+    /// Connections that asked for queue patches, or the ones that did not
+    QueuePatches(bool),
+}
+
+/// Returns how many messages are kept for reconnecting clients,
+/// set with `VINYL_SSE_HISTORY`. 0 means nothing is replayed.
+pub fn history_size() -> usize {
+    env::var("VINYL_SSE_HISTORY")
+        .map(|x| x.parse::<usize>().expect("SSE history must be a number"))
+        .unwrap_or(DEFAULT_HISTORY_SIZE)
+}
+
+const DEFAULT_HISTORY_SIZE: usize = 500;
+
+/// Returns how long a connection can go without messages before a comment is sent,
+/// set with `VINYL_SSE_KEEPALIVE_SECS`. This keeps proxies from closing idle connections.
+pub fn keep_alive_interval() -> Duration {
+    let secs = env::var("VINYL_SSE_KEEPALIVE_SECS")
+        .map(|x| {
+            x.parse::<u64>()
+                .expect("Keep-alive interval must be a number of seconds")
+        })
+        .unwrap_or(DEFAULT_KEEP_ALIVE_SECS);
+
+    assert!(secs > 0, "Keep-alive interval must be above 0");
+
+    Duration::from_secs(secs)
+}
+
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 15;
+
+/// Identifies a broadcast message, sent as the event id.
+///
+/// Sequences start over when the server restarts, so they are prefixed with
+/// when the manager was created, making ids from before a restart unknown instead of wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EventId {
+    epoch: u64,
+    sequence: u64,
+}
+
+/// Recently broadcast messages, oldest first, so reconnecting clients can catch up
+struct EventHistory {
+    epoch: u64,
+    capacity: usize,
+    last_sequence: u64,
+    entries: VecDeque<(u64, Message, Recipients)>,
 }
 
 pub struct SseManager {
     me: Weak<Self>,
     store: Weak<Store>,
     connections: Mutex<Vec<Arc<Connection>>>,
+    history: Mutex<EventHistory>,
+    keep_alive: Duration,
 }
 
 pub struct SseManagerHandler {
@@ -83,9 +352,17 @@ pub struct SseManagerHandler {
 
 pub struct Connection {
     user: User,
+    /// Private rooms the session was let into, see [Session::joined_rooms]
+    joined_rooms: Vec<RoomId>,
     handle: ConnectionHandleId,
-    pending_messages: Mutex<Vec<Message>>,
+    /// Receives queue changes as patches instead of the full queue
+    patches: bool,
+    /// Only receives messages about this room, and ones for everyone
+    room: Option<RoomId>,
+    pending_messages: Mutex<VecDeque<(Option<EventId>, Message)>>,
     waker: Mutex<Option<Waker>>,
+    /// Ends the stream once pending messages are sent, see [SseManager::close_all]
+    closed: AtomicCell<bool>,
 }
 
 pub type ConnectionHandleId = u64;
@@ -102,6 +379,8 @@ impl SseManager {
             store,
             me: me.clone(),
             connections: Default::default(),
+            history: EventHistory::new(unix_millis(), history_size()).into(),
+            keep_alive: keep_alive_interval(),
         })
     }
 
@@ -113,29 +392,86 @@ impl SseManager {
     }
 
     fn broadcast(&self, message: Message, recipients: Recipients) {
+        let store = self.store.upgrade().expect("store");
         let connections = self.connections.lock();
+        let id = self
+            .history
+            .lock()
+            .push(message.clone(), recipients.clone());
 
         connections
             .iter()
-            .filter(|x| match &recipients {
-                Recipients::All => true,
-                Recipients::Superuser => todo!(),
-                Recipients::Some(targets) => targets.contains(&x.user.id),
-            })
-            .for_each(|c| c.send(message.clone()));
+            .filter(|x| recipients.includes(x) && x.wants(&message))
+            .filter(|x| x.admitted(&message, &store.room_store))
+            .for_each(|c| c.send(Some(id), message.clone()));
     }
 
-    fn connect(&self, user: User) -> ConnectionHandle {
+    /// Connects a client, replaying what it missed if it is reconnecting after `last_event_id`
+    fn connect(
+        &self,
+        session: Session,
+        patches: bool,
+        room: Option<RoomId>,
+        last_event_id: Option<&str>,
+    ) -> ConnectionHandle {
         let handle_id = ID_COUNTER.fetch_add(1);
+        let store = self.store.upgrade().expect("store");
 
         let connection = Arc::new(Connection {
-            user,
+            user: session.user,
+            joined_rooms: session.joined_rooms,
+            patches,
+            room,
             handle: handle_id,
             waker: Default::default(),
+            closed: Default::default(),
             pending_messages: Default::default(),
         });
 
-        self.connections.lock().push(connection.clone());
+        // Held until the connection is added, so nothing is broadcast in between
+        let mut connections = self.connections.lock();
+        let history = self.history.lock();
+
+        let replay = last_event_id.map(|id| history.since(id));
+        let caught_up = matches!(replay, Some(Some(_)));
+
+        match replay {
+            Some(Some(missed)) => {
+                for (id, message, recipients) in missed {
+                    if recipients.includes(&connection)
+                        && connection.wants(message)
+                        && connection.admitted(message, &store.room_store)
+                    {
+                        connection.send(Some(id), message.clone());
+                    }
+                }
+            }
+            Some(None) => connection.send(None, Message::ResyncRequired),
+            None => {}
+        }
+
+        // Patches can only be applied to a full queue, which a caught up client already has
+        if patches && !caught_up {
+            let queues = store
+                .room_store
+                .queues()
+                .into_iter()
+                .filter(|(room, _)| connection.room.as_ref().is_none_or(|r| r == room))
+                .filter(|(room, _)| {
+                    store
+                        .room_store
+                        .admits(room, &connection.user, &connection.joined_rooms)
+                });
+
+            for (room, queue) in queues {
+                let queue = store.queue_store.serialized(queue);
+                connection.send(None, Message::QueueUpdate { room, queue });
+            }
+        }
+
+        drop(history);
+        connections.push(connection.clone());
+        drop(connections);
 
         ConnectionHandle {
             manager: self.me.clone(),
@@ -144,31 +480,203 @@ impl SseManager {
         }
     }
 
+    /// Ends every stream after sending what is pending, used when shutting down
+    pub fn close_all(&self) {
+        for connection in self.connections.lock().iter() {
+            connection.close();
+        }
+    }
+
     fn disconnect(&self, handle: ConnectionHandleId) {
         self.connections.lock().retain(|x| x.handle != handle);
     }
 }
 
+impl Recipients {
+    fn includes(&self, connection: &Connection) -> bool {
+        match self {
+            Recipients::All => true,
+            Recipients::Superuser => connection.user.superuser,
+            Recipients::Some(targets) => targets.contains(&connection.user.id),
+            Recipients::QueuePatches(patches) => connection.patches == *patches,
+        }
+    }
+}
+
+impl EventId {
+    fn parse(id: &str) -> Option<Self> {
+        let (epoch, sequence) = id.split_once('-')?;
+
+        Some(Self {
+            epoch: epoch.parse().ok()?,
+            sequence: sequence.parse().ok()?,
+        })
+    }
+}
+
+impl std::fmt::Display for EventId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.sequence)
+    }
+}
+
+impl EventHistory {
+    fn new(epoch: u64, capacity: usize) -> Self {
+        Self {
+            epoch,
+            capacity,
+            last_sequence: 0,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Remembers a message, dropping the oldest one if full, and returns its id
+    fn push(&mut self, message: Message, recipients: Recipients) -> EventId {
+        self.last_sequence += 1;
+
+        if self.capacity > 0 {
+            if self.entries.len() == self.capacity {
+                self.entries.pop_front();
+            }
+
+            self.entries
+                .push_back((self.last_sequence, message, recipients));
+        }
+
+        EventId {
+            epoch: self.epoch,
+            sequence: self.last_sequence,
+        }
+    }
+
+    /// Returns every message after `id`, or [None] if some of them were already dropped,
+    /// or the id is not from this history
+    fn since(&self, id: &str) -> Option<Vec<(EventId, &Message, &Recipients)>> {
+        let id = EventId::parse(id).filter(|id| id.epoch == self.epoch)?;
+
+        if id.sequence > self.last_sequence {
+            return None;
+        }
+
+        let oldest = self
+            .entries
+            .front()
+            .map(|(sequence, _, _)| *sequence)
+            .unwrap_or(self.last_sequence + 1);
+
+        if id.sequence + 1 < oldest {
+            return None;
+        }
+
+        let missed = self
+            .entries
+            .iter()
+            .filter(|(sequence, _, _)| *sequence > id.sequence)
+            .map(|(sequence, message, recipients)| {
+                let id = EventId {
+                    epoch: self.epoch,
+                    sequence: *sequence,
+                };
+
+                (id, message, recipients)
+            })
+            .collect();
+
+        Some(missed)
+    }
+}
+
 impl SseManagerHandler {
-    fn handle_queue_event(&self, event: QueueEvent) -> Option<(Message, Recipients)> {
+    fn handle_queue_event(&self, event: QueueEvent) -> Vec<(Message, Recipients)> {
         match event {
             QueueEvent::Update {
                 queue,
                 new_items: _,
+                patch,
+            } => self.queue_update(queue, patch),
+            QueueEvent::Advance { queue, item } => {
+                let Some(room) = queue.try_upgrade_into::<RoomId>(&self.store()) else {
+                    return vec![];
+                };
+
+                vec![(Message::QueueAdvance { room, queue, item }, Recipients::All)]
+            }
+            QueueEvent::RepeatChanged { queue, mode } => {
+                let Some(room) = queue.try_upgrade_into::<RoomId>(&self.store()) else {
+                    return vec![];
+                };
+
+                vec![(Message::QueueRepeat { room, queue, mode }, Recipients::All)]
+            }
+            QueueEvent::Cleared {
+                queue,
+                removed,
+                kept,
+                patch,
             } => {
-                let queue = self.store().queue_store.serialized(queue);
-                Some((Message::QueueUpdate(queue), Recipients::All))
+                let Some(room) = queue.try_upgrade_into::<RoomId>(&self.store()) else {
+                    return vec![];
+                };
+
+                let message = Message::QueueClear {
+                    room,
+                    queue,
+                    removed,
+                    kept,
+                };
+
+                // Clients still get the update that comes with it
+                let mut messages = vec![(message, Recipients::All)];
+                messages.extend(self.queue_update(queue, patch));
+
+                messages
             }
-            QueueEvent::Advance { queue, item } => {
-                Some((Message::QueueAdvance { queue, item }, Recipients::All))
+            QueueEvent::Shuffled { queue, order } => {
+                let Some(room) = queue.try_upgrade_into::<RoomId>(&self.store()) else {
+                    return vec![];
+                };
+
+                vec![(
+                    Message::QueueShuffle { room, queue, order },
+                    Recipients::All,
+                )]
+            }
+            QueueEvent::ActivationError { queue, track } => {
+                let Some(room) = queue.try_upgrade_into::<RoomId>(&self.store()) else {
+                    return vec![];
+                };
+
+                vec![(
+                    Message::TrackActivationError { room, queue, track },
+                    Recipients::All,
+                )]
             }
-            QueueEvent::ActivationError { queue, track } => Some((
-                Message::TrackActivationError { queue, track },
-                Recipients::All,
-            )),
         }
     }
 
+    /// Sends the whole queue to clients that do not take patches, and the patch to the rest
+    fn queue_update(&self, queue: QueueId, patch: QueuePatch) -> Vec<(Message, Recipients)> {
+        let store = self.store();
+
+        // The queue is removed along with its room
+        let Some(room) = queue.try_upgrade_into::<RoomId>(&store) else {
+            return vec![];
+        };
+
+        let update = Message::QueueUpdate {
+            room: room.clone(),
+            queue: store.queue_store.serialized(queue),
+        };
+
+        vec![
+            (update, Recipients::QueuePatches(false)),
+            (
+                Message::QueuePatch { room, patch },
+                Recipients::QueuePatches(true),
+            ),
+        ]
+    }
+
     fn handle_audio_event(&self, event: AudioEvent) -> Option<(Message, Recipients)> {
         match event {
             AudioEvent::Time {
@@ -176,11 +684,11 @@ impl SseManagerHandler {
                 total_offset,
                 offset,
             } => {
-                // cannot get room here yet
-                let room = player.upgrade_into::<RoomId>(&self.store());
+                // The room may have been deleted since
+                let room = player.try_upgrade_into::<RoomId>(&self.store())?;
 
-                let seconds = offset as f32 / (SAMPLE_RATE * 2) as f32;
-                let total_seconds = total_offset as f32 / (SAMPLE_RATE * 2) as f32;
+                let seconds = offset as f32 / SAMPLES_PER_SEC as f32;
+                let total_seconds = total_offset as f32 / SAMPLES_PER_SEC as f32;
 
                 Some((
                     Message::PlayerTime {
@@ -191,6 +699,16 @@ impl SseManagerHandler {
                     Recipients::All,
                 ))
             }
+            AudioEvent::PlaybackStateChanged { player, paused } => {
+                let room = player.try_upgrade_into::<RoomId>(&self.store())?;
+                Some((Message::RoomPlaybackState { room, paused }, Recipients::All))
+            }
+            AudioEvent::Seeked { player, offset } => {
+                let room = player.try_upgrade_into::<RoomId>(&self.store())?;
+                let seconds = offset as f32 / SAMPLES_PER_SEC as f32;
+
+                Some((Message::PlayerSeek { room, seconds }, Recipients::All))
+            }
             _ => None,
         }
     }
@@ -203,6 +721,80 @@ impl SseManagerHandler {
             RoomEvent::UserLeftRoom { user, room } => {
                 Some((Message::UserLeftRoom { user, room }, Recipients::All))
             }
+            RoomEvent::PlaybackStarted { room } => {
+                Some((Message::RoomPlaybackStarted { room }, Recipients::All))
+            }
+            RoomEvent::ListenersChanged { room, count } => {
+                Some((Message::RoomListeners { room, count }, Recipients::All))
+            }
+            RoomEvent::Renamed { room, name } => {
+                Some((Message::RoomRenamed { room, name }, Recipients::All))
+            }
+            RoomEvent::Deleted { room } => Some((Message::RoomDeleted { room }, Recipients::All)),
+            RoomEvent::VolumeChanged { room, volume } => {
+                Some((Message::RoomVolume { room, volume }, Recipients::All))
+            }
+            RoomEvent::SkipVoted {
+                room,
+                votes,
+                required,
+            } => Some((
+                Message::RoomSkipVotes {
+                    room,
+                    votes,
+                    required,
+                },
+                Recipients::All,
+            )),
+            RoomEvent::NowPlayingChanged { room, label } => {
+                Some((Message::RoomNowPlaying { room, label }, Recipients::All))
+            }
+        }
+    }
+
+    fn handle_ingestion_event(&self, event: IngestionEvent) -> Option<(Message, Recipients)> {
+        let message = match event {
+            IngestionEvent::Resolving {
+                queue,
+                id,
+                user,
+                input,
+            } => Message::InputResolving {
+                room: queue.try_upgrade_into::<RoomId>(&self.store())?,
+                id,
+                user,
+                input,
+            },
+            IngestionEvent::Ready { queue, id, added } => Message::InputReady {
+                room: queue.try_upgrade_into::<RoomId>(&self.store())?,
+                id,
+                added,
+            },
+            IngestionEvent::Failed {
+                queue,
+                id,
+                input,
+                reason,
+                status,
+            } => Message::InputFailed {
+                room: queue.try_upgrade_into::<RoomId>(&self.store())?,
+                id,
+                input,
+                reason,
+                status,
+            },
+            _ => return None,
+        };
+
+        Some((message, Recipients::All))
+    }
+
+    fn handle_server_event(&self, event: ServerEvent) -> Option<(Message, Recipients)> {
+        match event {
+            ServerEvent::Announcement { message, severity } => Some((
+                Message::ServerAnnouncement { message, severity },
+                Recipients::All,
+            )),
         }
     }
 
@@ -215,53 +807,98 @@ impl Handler<VinylEvent> for SseManagerHandler {
     type Incoming = VinylEvent;
 
     fn handle(&self, incoming: Self::Incoming) {
-        let response = match incoming {
+        let responses = match incoming {
             VinylEvent::Queue(event) => self.handle_queue_event(event),
-            VinylEvent::Audio(event) => self.handle_audio_event(event),
-            VinylEvent::Room(event) => self.handle_room_event(event),
-            _ => {
-                return;
+            VinylEvent::Audio(event) => self.handle_audio_event(event).into_iter().collect(),
+            VinylEvent::Room(event) => self.handle_room_event(event).into_iter().collect(),
+            VinylEvent::Server(event) => self.handle_server_event(event).into_iter().collect(),
+            VinylEvent::Ingestion(event) => {
+                self.handle_ingestion_event(event).into_iter().collect()
             }
         };
 
-        if let Some((message, recipients)) = response {
-            self.manager
-                .upgrade()
-                .expect("manager upgrades")
-                .broadcast(message, recipients);
+        let manager = self.manager.upgrade().expect("manager upgrades");
+
+        for (message, recipients) in responses {
+            manager.broadcast(message, recipients);
         }
     }
 }
 
 impl Connection {
-    fn send(&self, message: Message) {
-        self.pending_messages.lock().push(message);
+    /// Returns false if the message is about a room other than the one this is scoped to
+    fn wants(&self, message: &Message) -> bool {
+        match (&self.room, message.room()) {
+            (Some(scope), Some(room)) => scope == room,
+            _ => true,
+        }
+    }
+
+    /// Returns false if the message is about a private room this was not let into
+    fn admitted(&self, message: &Message, rooms: &RoomStore) -> bool {
+        message
+            .room()
+            .is_none_or(|room| rooms.admits(room, &self.user, &self.joined_rooms))
+    }
+
+    fn send(&self, id: Option<EventId>, message: Message) {
+        self.pending_messages.lock().push_back((id, message));
+        self.wake();
+    }
+
+    fn close(&self) {
+        self.closed.store(true);
+        self.wake();
+    }
 
+    fn wake(&self) {
         if let Some(waker) = self.waker.lock().take() {
             waker.wake()
         }
     }
 }
 
-impl Stream for ConnectionHandle {
-    type Item = Result<Event, Infallible>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+impl ConnectionHandle {
+    /// Returns the next message to send, or [None] once the connection is closed.
+    /// Both transports read messages through this, so they always send the same ones.
+    fn poll_message(&self, cx: &mut Context<'_>) -> Poll<Option<(Option<EventId>, Message)>> {
         let mut pending_messages = self.connection.pending_messages.lock();
 
-        let next_event = pending_messages
-            .pop()
-            .map(|m| serde_json::to_string(&m).expect("serializes properly"));
-
-        if let Some(event) = next_event {
-            return Poll::Ready(Some(Ok(Event::default().data(event))));
+        if let Some(pending) = pending_messages.pop_front() {
+            return Poll::Ready(Some(pending));
         }
 
         *self.connection.waker.lock() = Some(cx.waker().clone());
+
+        // Checked after setting the waker, so closing in between is not missed
+        if self.connection.closed.load() {
+            return Poll::Ready(None);
+        }
+
         Poll::Pending
     }
 }
 
+impl Stream for ConnectionHandle {
+    type Item = Result<Event, Infallible>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_message(cx).map(|pending| {
+            pending.map(|(id, message)| {
+                let data = serde_json::to_string(&message.envelope()).expect("serializes properly");
+                let mut event = Event::default().data(data);
+
+                // Messages sent to one connection only can't be replayed, and have no id
+                if let Some(id) = id {
+                    event = event.id(id.to_string());
+                }
+
+                Ok(event)
+            })
+        })
+    }
+}
+
 impl Drop for ConnectionHandle {
     fn drop(&mut self) {
         self.manager
@@ -272,13 +909,405 @@ impl Drop for ConnectionHandle {
 }
 
 pub(super) fn router() -> Router {
-    Router::new().route("/", get(sse_stream))
+    Router::new()
+        .route("/", get(sse_stream))
+        .route("/ws", get(ws_stream))
 }
 
+#[derive(Deserialize)]
+struct StreamQuery {
+    /// Send queue changes as patches, see [Message::QueuePatch]
+    #[serde(default)]
+    patches: bool,
+    /// Only send messages about this room, along with ones for everyone
+    room: Option<String>,
+    /// Used instead of the `Last-Event-ID` header by clients that can't set it, like WebSockets
+    last_event_id: Option<String>,
+}
+
+/// Streams messages to a client. Browsers reconnecting with `Last-Event-ID` get what they missed,
+/// or a [Message::ResyncRequired] if it is no longer available.
 async fn sse_stream(
     session: Session,
     State(context): crate::server::Context,
-) -> Sse<ConnectionHandle> {
-    let handle = context.sse.connect(session.user);
-    Sse::new(handle).keep_alive(KeepAlive::default())
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<ConnectionHandle>, ApiError> {
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|h| h.to_str().ok())
+        .map(|id| id.to_string());
+
+    let handle = connect(&context, session, query, last_event_id)?;
+
+    // Comments are ignored by EventSource, and stop with the stream
+    let keep_alive = KeepAlive::new()
+        .interval(context.sse.keep_alive)
+        .text("ping");
+
+    Ok(Sse::new(handle).keep_alive(keep_alive))
+}
+
+/// Streams the same messages as [sse_stream] over a WebSocket, for clients behind proxies that break SSE.
+///
+/// Every message is a text frame containing a [WsFrame]. Clients resume from where they left off
+/// by reconnecting with the id of the last frame they got as the `last_event_id` query parameter.
+/// Anything clients send is ignored, other than closing the socket.
+async fn ws_stream(
+    session: Session,
+    State(context): crate::server::Context,
+    Query(query): Query<StreamQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    // Connected before upgrading, so nothing is missed in between and errors are normal responses
+    let handle = connect(&context, session, query, None)?;
+    let keep_alive = context.sse.keep_alive;
+
+    Ok(upgrade.on_upgrade(move |socket| send_messages(socket, handle, keep_alive)))
+}
+
+/// Sends messages until either side closes, dropping the handle so the connection is removed
+async fn send_messages(mut socket: WebSocket, handle: ConnectionHandle, keep_alive: Duration) {
+    let start = tokio::time::Instant::now() + keep_alive;
+    let mut pings = tokio::time::interval_at(start, keep_alive);
+
+    loop {
+        tokio::select! {
+            pending = poll_fn(|cx| handle.poll_message(cx)) => {
+                // The connection was closed on our end
+                let Some((id, message)) = pending else {
+                    break;
+                };
+
+                if socket.send(WsMessage::Text(ws_frame(id, &message))).await.is_err() {
+                    return;
+                }
+            }
+            incoming = socket.recv() => {
+                if let None | Some(Err(_)) | Some(Ok(WsMessage::Close(_))) = incoming {
+                    return;
+                }
+            }
+            _ = pings.tick() => {
+                if socket.send(WsMessage::Ping(vec![])).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    let _ = socket.close().await;
+}
+
+fn ws_frame(id: Option<EventId>, message: &Message) -> String {
+    let frame = WsFrame {
+        id: id.map(|id| id.to_string()),
+        envelope: message.envelope(),
+    };
+
+    serde_json::to_string(&frame).expect("serializes properly")
+}
+
+/// Connects a client to either transport, scoped to a room if the query asks for one
+fn connect(
+    context: &VinylContext,
+    session: Session,
+    query: StreamQuery,
+    last_event_id: Option<String>,
+) -> Result<ConnectionHandle, ApiError> {
+    let room = query
+        .room
+        .map(|id| context.store.room_store.find_room(&id))
+        .transpose()?;
+
+    let last_event_id = last_event_id.or(query.last_event_id);
+
+    Ok(context
+        .sse
+        .connect(session, query.patches, room, last_event_id.as_deref()))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+
+    use super::{ws_frame, Connection, EventHistory, EventId, Message, Recipients};
+    use crate::{
+        auth::User,
+        queue::{QueueItem, QueuePatch, RepeatMode, SerializedQueue},
+        server::Severity,
+        store::Id,
+    };
+
+    fn envelope(message: Message) -> Value {
+        serde_json::to_value(message.envelope()).unwrap()
+    }
+
+    fn assert_envelope(message: Message, kind: &str) {
+        let data = serde_json::to_value(&message).unwrap();
+        let envelope = envelope(message);
+
+        assert_eq!(envelope["type"], kind);
+        assert_eq!(envelope["version"], 1);
+        assert_eq!(envelope["data"], data);
+        assert_eq!(envelope.as_object().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn envelopes() {
+        let user = User::mock("john");
+        let room = User::mock("room").id;
+
+        assert_envelope(
+            Message::UserEnteredRoom {
+                user: user.clone(),
+                room: room.clone(),
+            },
+            "room.user_entered",
+        );
+        assert_envelope(
+            Message::UserLeftRoom {
+                user: user.id,
+                room: room.clone(),
+            },
+            "room.user_left",
+        );
+        assert_envelope(
+            Message::RoomPlaybackStarted { room: room.clone() },
+            "room.playback_started",
+        );
+        assert_envelope(
+            Message::RoomPlaybackState {
+                room: room.clone(),
+                paused: true,
+            },
+            "room.playback_state",
+        );
+        assert_envelope(
+            Message::RoomListeners {
+                room: room.clone(),
+                count: 2,
+            },
+            "room.listeners",
+        );
+        assert_envelope(
+            Message::RoomRenamed {
+                room: room.clone(),
+                name: "Fruit".to_string(),
+            },
+            "room.renamed",
+        );
+        assert_envelope(Message::RoomDeleted { room: room.clone() }, "room.deleted");
+        assert_envelope(
+            Message::RoomVolume {
+                room: room.clone(),
+                volume: 0.5,
+            },
+            "room.volume",
+        );
+        assert_envelope(
+            Message::RoomSkipVotes {
+                room: room.clone(),
+                votes: 2,
+                required: 3,
+            },
+            "room.skip_votes",
+        );
+        assert_envelope(
+            Message::RoomNowPlaying {
+                room: room.clone(),
+                label: None,
+            },
+            "room.now_playing",
+        );
+        assert_envelope(
+            Message::QueueAdvance {
+                room: room.clone(),
+                queue: Id::new(),
+                item: QueueItem::mock("bananas"),
+            },
+            "queue.advanced",
+        );
+        assert_envelope(
+            Message::QueueUpdate {
+                room: room.clone(),
+                queue: SerializedQueue::mock(),
+            },
+            "queue.updated",
+        );
+        assert_envelope(
+            Message::QueuePatch {
+                room: room.clone(),
+                patch: QueuePatch {
+                    queue: Id::new(),
+                    sequence: 1,
+                    operations: vec![],
+                },
+            },
+            "queue.patched",
+        );
+        assert_envelope(
+            Message::QueueRepeat {
+                room: room.clone(),
+                queue: Id::new(),
+                mode: RepeatMode::One,
+            },
+            "queue.repeat",
+        );
+        assert_envelope(
+            Message::QueueClear {
+                room: room.clone(),
+                queue: Id::new(),
+                removed: vec![Id::new()],
+                kept: None,
+            },
+            "queue.cleared",
+        );
+        assert_envelope(
+            Message::QueueShuffle {
+                room: room.clone(),
+                queue: Id::new(),
+                order: vec![Id::new()],
+            },
+            "queue.shuffled",
+        );
+        assert_envelope(
+            Message::PlayerTime {
+                room: room.clone(),
+                seconds: 1.,
+                total_seconds: 2.,
+            },
+            "player.time",
+        );
+        assert_envelope(
+            Message::PlayerSeek {
+                room: room.clone(),
+                seconds: 90.,
+            },
+            "player.seeked",
+        );
+        assert_envelope(
+            Message::TrackActivationError {
+                room: room.clone(),
+                queue: Id::new(),
+                track: Id::new(),
+            },
+            "track.activation_failed",
+        );
+        assert_envelope(
+            Message::InputResolving {
+                room: room.clone(),
+                id: Id::new(),
+                user: User::mock("john").id,
+                input: "bananas".to_string(),
+            },
+            "input.resolving",
+        );
+        assert_envelope(
+            Message::InputReady {
+                room: room.clone(),
+                id: Id::new(),
+                added: 1,
+            },
+            "input.ready",
+        );
+        assert_envelope(
+            Message::InputFailed {
+                room,
+                id: None,
+                input: "bananas".to_string(),
+                reason: "Input did not match".to_string(),
+                status: 400,
+            },
+            "input.failed",
+        );
+        assert_envelope(
+            Message::ServerAnnouncement {
+                message: "hello".to_string(),
+                severity: Severity::Info,
+            },
+            "server.announcement",
+        );
+        assert_envelope(Message::ResyncRequired, "stream.resync_required");
+    }
+
+    #[test]
+    fn replays_history() {
+        let mut history = EventHistory::new(1000, 3);
+        let ids: Vec<_> = (0..5)
+            .map(|_| history.push(Message::ResyncRequired, Recipients::All))
+            .map(|id| id.to_string())
+            .collect();
+
+        assert_eq!(ids[0], "1000-1");
+
+        // Only the last 3 are kept
+        let missed = history.since(&ids[1]).unwrap();
+        assert_eq!(missed.len(), 3);
+        assert_eq!(missed[0].0.to_string(), ids[2]);
+
+        assert_eq!(history.since(&ids[4]).unwrap().len(), 0);
+
+        // Dropped, from before a restart, from the future, or not an id at all
+        assert!(history.since(&ids[0]).is_none());
+        assert!(history.since("999-4").is_none());
+        assert!(history.since("1000-6").is_none());
+        assert!(history.since("bananas").is_none());
+    }
+
+    #[test]
+    fn scopes_to_room() {
+        let room = User::mock("room").id;
+        let other = User::mock("other").id;
+
+        let connection = Connection {
+            user: User::mock("user"),
+            joined_rooms: vec![],
+            handle: 0,
+            patches: false,
+            room: Some(room.clone()),
+            pending_messages: Default::default(),
+            waker: Default::default(),
+            closed: Default::default(),
+        };
+
+        assert!(connection.wants(&Message::RoomPlaybackStarted { room }));
+        assert!(!connection.wants(&Message::RoomPlaybackStarted { room: other }));
+        assert!(connection.wants(&Message::ResyncRequired));
+    }
+
+    #[test]
+    fn data_is_not_tagged() {
+        let envelope = envelope(Message::ServerAnnouncement {
+            message: "hello".to_string(),
+            severity: Severity::Warning,
+        });
+
+        assert_eq!(
+            envelope,
+            json!({
+                "type": "server.announcement",
+                "version": 1,
+                "data": { "message": "hello", "severity": "warning" }
+            })
+        );
+    }
+
+    #[test]
+    fn ws_frames_include_ids() {
+        let id = EventId {
+            epoch: 1000,
+            sequence: 4,
+        };
+
+        let frame: Value =
+            serde_json::from_str(&ws_frame(Some(id), &Message::ResyncRequired)).unwrap();
+        let mut expected = envelope(Message::ResyncRequired);
+        expected["id"] = json!("1000-4");
+
+        assert_eq!(frame, expected);
+
+        let frame: Value = serde_json::from_str(&ws_frame(None, &Message::ResyncRequired)).unwrap();
+        assert_eq!(frame, envelope(Message::ResyncRequired));
+    }
 }
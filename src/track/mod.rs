@@ -1,13 +1,21 @@
-use std::sync::Arc;
+use std::{
+    env,
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
+};
 
 use crossbeam::atomic::AtomicCell;
 use dashmap::DashMap;
-use serde::Serialize;
+use log::{info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    audio::Input,
+    audio::{duration_to_samples, Input},
     ingest::{Ingestion, InputError, ProbeResult, SinkId},
     store::{FromId, Id, Insert, Store},
+    util::unix_millis,
 };
 
 #[derive(Debug, Default)]
@@ -24,13 +32,21 @@ pub struct InternalTrack {
     pub metadata: Metadata,
 
     #[serde(skip)]
-    input: Input,
+    input: Arc<RwLock<Input>>,
 
     #[serde(skip)]
     state: Arc<AtomicCell<TrackState>>,
+
+    /// Loudness gain in dB, known once the track was fully ingested
+    #[serde(skip)]
+    gain: Arc<AtomicCell<Option<f32>>>,
+
+    /// How far into the track playback begins, such as from a timestamp in the url
+    #[serde(skip)]
+    start: Duration,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Metadata {
     pub title: String,
     pub artist: String,
@@ -38,8 +54,23 @@ pub struct Metadata {
     pub canonical: String,
     pub source: String,
 
-    pub duration: f32,
+    /// In seconds, [None] if it is unknown, like for live streams
+    pub duration: Option<f32>,
     pub artwork: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chapters: Vec<Chapter>,
+}
+
+/// A named section of a track, such as a song in a mix
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Chapter {
+    pub title: String,
+
+    /// Start of the chapter in seconds
+    pub start: f32,
+    /// End of the chapter in seconds
+    pub end: f32,
 }
 
 /// Describes if this track has been ingested or not
@@ -54,11 +85,19 @@ impl InternalTrack {
     pub fn new(input: Input) -> Self {
         let metadata = input.metadata();
 
+        // Starting past the end would play nothing at all
+        let start = input
+            .start()
+            .filter(|start| metadata.duration.is_none_or(|d| start.as_secs_f32() < d))
+            .unwrap_or_default();
+
         Self {
-            input,
+            input: Arc::new(input.into()),
             metadata,
             id: TrackId::new(),
             state: Arc::new(TrackState::Inactive.into()),
+            gain: Default::default(),
+            start,
         }
     }
 
@@ -70,6 +109,69 @@ impl InternalTrack {
         }
     }
 
+    /// Returns the fingerprint used to tell if two tracks are the same
+    pub fn fingerprint(&self) -> String {
+        self.input.read().fingerprint()
+    }
+
+    /// Returns true if the track has not been ingested yet,
+    /// and resources it needs expire within `duration`.
+    pub fn expires_within(&self, duration: Duration) -> bool {
+        if !matches!(self.state.load(), TrackState::Inactive) {
+            return false;
+        }
+
+        let now = unix_millis() / 1000;
+
+        self.input
+            .read()
+            .expires_at()
+            .map(|expires_at| expires_at.saturating_sub(now) < duration.as_secs())
+            .unwrap_or_default()
+    }
+
+    /// Returns true if the track has not been ingested yet, and its input should be resolved
+    /// again before it is, see [Input::needs_refresh]. Cached inputs are loaded without it.
+    pub fn needs_refresh(&self, ingestion: &Ingestion) -> bool {
+        if !matches!(self.state.load(), TrackState::Inactive) {
+            return false;
+        }
+
+        let input = self.input();
+        input.needs_refresh() && !ingestion.is_cached(&input)
+    }
+
+    /// Resolves the input again, so the track can still be played after it would have expired
+    pub fn refresh(&self) -> Result<(), InputError> {
+        let refreshed = self.input.read().refresh()?;
+        *self.input.write() = refreshed;
+
+        Ok(())
+    }
+
+    pub fn input(&self) -> Input {
+        self.input.read().clone()
+    }
+
+    /// Creates a new track from the same input, resolved again.
+    /// The loudness gain is kept, so it is not measured again.
+    pub fn resolve_again(&self) -> Result<Self, InputError> {
+        let input = self.input.read().refresh()?;
+        let track = Self::new(input);
+
+        track.gain.store(self.gain.load());
+        Ok(track)
+    }
+
+    /// Creates a new track from the same input, so it can play again after its sink was consumed.
+    /// The loudness gain is kept, so it is not measured again.
+    pub fn replay(&self) -> Self {
+        let track = Self::new(self.input());
+
+        track.gain.store(self.gain.load());
+        track
+    }
+
     /// Returns true if the track is suitable in a playback context
     pub fn suitable(&self) -> bool {
         !matches!(self.state.load(), TrackState::Error)
@@ -90,10 +192,16 @@ impl InternalTrack {
     }
 
     fn activate(&self, ingestion: &Ingestion) -> Result<(), InputError> {
-        let loader = self.input.loader()?;
+        let loader = ingestion.loader(&self.input.read())?;
         let result = loader.probe().ok_or(InputError::Unknown)?;
 
-        let sink = ingestion.add(result, loader);
+        // The whole track is loaded so it can be cached, and the start is skipped during playback
+        let sink = ingestion.add(
+            result,
+            loader,
+            self.gain.clone(),
+            duration_to_samples(self.start),
+        );
 
         self.state.store(TrackState::Active {
             sink_id: sink,
@@ -110,8 +218,9 @@ impl InternalTrack {
             artist: "artist".to_string(),
             canonical: "".to_string(),
             source: "mock".to_string(),
-            duration: 0.,
+            duration: None,
             artwork: None,
+            chapters: vec![],
         };
 
         InternalTrack::new(Input::Empty(meta)).into()
@@ -156,3 +265,59 @@ impl Insert for Track {
         store.track_store.tracks.insert(self.id, self);
     }
 }
+
+/// Proactively refreshes tracks in queues before their stream urls expire or get too old,
+/// so they almost always work once they are played.
+///
+/// Refreshes run one at a time, and tracks that are already being ingested are skipped.
+/// This happens here rather than when a track is activated, since resolving can take a while.
+/// See [refresh_interval] for how often this happens.
+pub fn spawn_refresh_thread(store: Weak<Store>) {
+    /// Tracks expiring within this are refreshed
+    const EXPIRY_MARGIN: Duration = Duration::from_secs(60 * 15);
+
+    let Some(interval) = refresh_interval() else {
+        return;
+    };
+
+    let run = move || loop {
+        thread::sleep(interval);
+
+        let store = store.upgrade().expect("upgrade store in refresh thread");
+
+        let expiring = store
+            .queue_store
+            .tracks()
+            .into_iter()
+            .filter(|t| t.expires_within(EXPIRY_MARGIN) || t.needs_refresh(&store.ingestion));
+
+        for track in expiring {
+            match track.refresh() {
+                Ok(_) => {
+                    info!(target: "vinyl::audio", "Refreshed stream url of {}", track.metadata.title)
+                }
+                Err(err) => warn!(target: "vinyl::audio",
+                    "Failed to refresh stream url of {}: {}",
+                    track.metadata.title, err
+                ),
+            }
+        }
+    };
+
+    thread::Builder::new()
+        .name("track_url_refresh".to_string())
+        .spawn(run)
+        .unwrap();
+}
+
+/// Returns how often stream urls are refreshed, set with `VINYL_URL_REFRESH_INTERVAL`
+/// in seconds. Refreshing is disabled when it is 0.
+pub fn refresh_interval() -> Option<Duration> {
+    const DEFAULT_INTERVAL: u64 = 60;
+
+    let interval = env::var("VINYL_URL_REFRESH_INTERVAL")
+        .map(|x| x.parse::<u64>().expect("Refresh interval must be a number"))
+        .unwrap_or(DEFAULT_INTERVAL);
+
+    (interval > 0).then(|| Duration::from_secs(interval))
+}
@@ -1,8 +1,12 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use axum::response::IntoResponse;
 use crossbeam::atomic::AtomicCell;
-use hyper::StatusCode;
+use hyper::{header, StatusCode};
 use thiserror::Error;
 
+use crate::ingest::InputError;
+
 #[derive(Debug, Error)]
 pub enum ApiError {
     #[error("{0} does not exist")]
@@ -11,9 +15,34 @@ pub enum ApiError {
     #[error("{0} already exists")]
     Conflict(&'static str),
 
+    #[error("{0} is invalid")]
+    Invalid(&'static str),
+
+    #[error("{0} is not allowed")]
+    NotAllowed(&'static str),
+
     #[error("Invalid credentials")]
     Unauthorized,
 
+    /// The request is valid, but what it refers to cannot be used, with a reason
+    #[error("{0}")]
+    Rejected(String),
+
+    /// Something the request depends on could not be reached
+    #[error("{0} could not be reached")]
+    Unavailable(&'static str),
+
+    /// An input could not be used, with a status depending on why, see [InputError::status]
+    #[error(transparent)]
+    Input(InputError),
+
+    /// The server is doing too much of something to take more right now
+    #[error("{0} is busy, try again later")]
+    Busy(&'static str),
+
+    #[error("Too many requests, try again in {} seconds", .0.as_secs().max(1))]
+    TooManyRequests(Duration),
+
     #[error(transparent)]
     Database(#[from] surrealdb::Error),
 
@@ -30,6 +59,18 @@ use surrealdb::Error as SurrealErr;
 
 pub static ID_COUNTER: AtomicCell<u64> = AtomicCell::new(1);
 
+/// Returns the current time in milliseconds since the unix epoch
+pub fn unix_millis() -> u64 {
+    unix_millis_at(SystemTime::now())
+}
+
+/// Returns the given time in milliseconds since the unix epoch
+pub fn unix_millis_at(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
 impl ApiError {
     pub fn from_db(err: SurrealErr) -> Self {
         match err {
@@ -59,14 +100,37 @@ impl ApiError {
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> axum::response::Response {
-        let status = match &self {
+        if let ApiError::TooManyRequests(retry_after) = &self {
+            let seconds = retry_after.as_secs().max(1).to_string();
+
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, seconds)],
+                self.to_string(),
+            )
+                .into_response();
+        }
+
+        (self.status(), self.to_string()).into_response()
+    }
+}
+
+impl ApiError {
+    /// Returns the status a request gets when it fails with this error
+    pub fn status(&self) -> StatusCode {
+        match self {
             ApiError::NotFound(_) => StatusCode::NOT_FOUND,
             ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Invalid(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotAllowed(_) => StatusCode::FORBIDDEN,
             ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Rejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unavailable(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Busy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Input(err) => err.status(),
             _ => StatusCode::INTERNAL_SERVER_ERROR,
-        };
-
-        (status, self.to_string()).into_response()
+        }
     }
 }
 
@@ -104,3 +168,110 @@ pub mod sync {
         }
     }
 }
+
+pub mod shutdown {
+    use crossbeam::atomic::AtomicCell;
+
+    static REQUESTED: AtomicCell<bool> = AtomicCell::new(false);
+
+    /// Asks background loops to stop, because the server is shutting down
+    pub fn request() {
+        REQUESTED.store(true);
+    }
+
+    /// Returns true once the server is shutting down
+    pub fn requested() -> bool {
+        REQUESTED.load()
+    }
+}
+
+pub mod limit {
+    use std::{
+        hash::Hash,
+        time::{Duration, Instant},
+    };
+
+    use dashmap::DashMap;
+
+    /// A token bucket rate limiter, keyed by something like a user id
+    #[derive(Debug)]
+    pub struct RateLimiter<K: Eq + Hash> {
+        buckets: DashMap<K, Bucket>,
+        capacity: u32,
+        period: Duration,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct Bucket {
+        tokens: f64,
+        updated_at: Instant,
+    }
+
+    impl<K: Eq + Hash> RateLimiter<K> {
+        /// Allows `capacity` requests per `period`, refilling gradually.
+        pub fn new(capacity: u32, period: Duration) -> Self {
+            Self {
+                buckets: Default::default(),
+                capacity,
+                period,
+            }
+        }
+
+        /// Takes a token for the key, returning how long to wait if there are none left.
+        pub fn check(&self, key: K) -> Result<(), Duration> {
+            let now = Instant::now();
+            let capacity = self.capacity as f64;
+            let refill_per_sec = capacity / self.period.as_secs_f64();
+
+            let mut bucket = self.buckets.entry(key).or_insert(Bucket {
+                tokens: capacity,
+                updated_at: now,
+            });
+
+            let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+
+            bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+            bucket.updated_at = now;
+
+            if bucket.tokens >= 1. {
+                bucket.tokens -= 1.;
+                return Ok(());
+            }
+
+            let missing = 1. - bucket.tokens;
+            Err(Duration::from_secs_f64(missing / refill_per_sec))
+        }
+    }
+
+    /// Prevents the same key from being used again within a window
+    #[derive(Debug)]
+    pub struct Cooldown<K: Eq + Hash> {
+        used_at: DashMap<K, Instant>,
+        window: Duration,
+    }
+
+    impl<K: Eq + Hash> Cooldown<K> {
+        pub fn new(window: Duration) -> Self {
+            Self {
+                used_at: Default::default(),
+                window,
+            }
+        }
+
+        /// Marks the key as used, returning how long to wait if it was used too recently.
+        pub fn check(&self, key: K) -> Result<(), Duration> {
+            let now = Instant::now();
+
+            // Keys that have cooled down are not needed anymore
+            self.used_at
+                .retain(|_, used_at| now.duration_since(*used_at) < self.window);
+
+            if let Some(used_at) = self.used_at.get(&key) {
+                return Err(self.window - now.duration_since(*used_at));
+            }
+
+            self.used_at.insert(key, now);
+            Ok(())
+        }
+    }
+}